@@ -0,0 +1,151 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+use crate::bytecode;
+use crate::cfg::{self, Cfg};
+use crate::instruction::INSTRUCTION_SIZE;
+use crate::assembler::Assembler;
+use crate::verifier::VerifyError;
+
+/// Control-flow problems found by `analyze`
+#[derive(Debug, PartialEq, Copy, Clone, Error)]
+pub enum Violation {
+    #[error("jump at offset {offset} targets {target}, outside of the program")]
+    OutOfBounds { offset: usize, target: i64 },
+    #[error("jump at offset {offset} targets {target}, which is not on an instruction boundary")]
+    Misaligned { offset: usize, target: usize },
+    #[error("instruction at offset {offset} is unreachable")]
+    Unreachable { offset: usize },
+}
+
+impl Violation {
+    /// Offset of the instruction the violation is about
+    pub fn offset(&self) -> usize {
+        match *self {
+            Violation::OutOfBounds { offset, .. } => offset,
+            Violation::Misaligned { offset, .. } => offset,
+            Violation::Unreachable { offset } => offset,
+        }
+    }
+}
+
+/// Checks that every jump with a statically known target lands inside the program on an
+/// instruction boundary. When all targets are known and valid, also reports the first instruction of every
+/// unreachable region, as seen from the instruction at `entry`. Jumping exactly to the end of the
+/// program is allowed, it halts the VM.
+pub fn analyze(program: &[u8], entry: usize) -> Result<Vec<Violation>, VerifyError> {
+    let cfg = Cfg::build_with_entry(program, entry)?;
+    let len = cfg.len() as i64;
+    let mut violations = vec![];
+    for jump in &cfg.jumps {
+        match jump.target {
+            Some(target) if target < 0 || target > len => {
+                violations.push(Violation::OutOfBounds { offset: jump.offset, target: target });
+            },
+            Some(target) if !cfg.is_valid_target(target) => {
+                violations.push(Violation::Misaligned { offset: jump.offset, target: target as usize });
+            },
+            _ => (),
+        }
+    }
+    if cfg.all_targets_known() {
+        violations.extend(unreachable(&cfg));
+    }
+    Ok(violations)
+}
+
+fn unreachable(cfg: &Cfg) -> Vec<Violation> {
+    let mut reached = BTreeSet::new();
+    let mut pending = vec![cfg.entry()];
+    while let Some(start) = pending.pop() {
+        if reached.insert(start) {
+            if let Some(block) = cfg.block_at(start) {
+                pending.extend(cfg.successors(block));
+            }
+        }
+    }
+    let mut violations = vec![];
+    let mut previous_reached = true;
+    for block in &cfg.blocks {
+        let is_reached = reached.contains(&block.start);
+        if !is_reached && previous_reached {
+            violations.push(Violation::Unreachable { offset: block.start });
+        }
+        previous_reached = is_reached;
+    }
+    violations
+}
+
+/// The `analyze <file>` subcommand: assembles a source file and prints every violation with
+/// its source line. Returns true if there was none.
+pub fn analyze_file(path: &Path) -> Result<bool, String> {
+    let src = fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    let (program, lines, entry) = Assembler::new().with_source_path(path).assemble_with_lines(&src)
+        .map_err(|e| format!("{}: {}", path.display(), e.render()))?;
+    let violations = analyze(&program, entry).map_err(|e| e.to_string())?;
+    for violation in &violations {
+        let line = lines[violation.offset() / INSTRUCTION_SIZE];
+        println!("{}:{}: {}", path.display(), line, violation);
+    }
+    Ok(violations.is_empty())
+}
+
+/// The `analyze --cfg-dot <file>` subcommand: prints the control-flow graph of a bytecode
+/// or source file in Graphviz DOT
+pub fn cfg_dot_file(path: &Path) -> Result<(), String> {
+    let bytes = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    let program = if bytes.starts_with(&bytecode::MAGIC) {
+        bytecode::read_program(&bytes).map_err(|e| format!("{}: {}", path.display(), e))?.1
+    } else {
+        let src = String::from_utf8(bytes).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        Assembler::new().with_source_path(path).assemble(&src).map_err(|e| format!("{}: {}", path.display(), e.render()))?
+    };
+    let cfg = Cfg::build(&program).map_err(|e| e.to_string())?;
+    print!("{}", cfg::to_dot(&cfg));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analyze_source(src: &str) -> Vec<Violation> {
+        let (program, entry) = Assembler::new().assemble_with_entry(src).unwrap();
+        analyze(&program, entry).unwrap()
+    }
+
+    #[test]
+    fn test_analyze_valid_jumps() {
+        assert_eq!(analyze_source("load $0 #12\nload $1 #1\njeq $0 $1\nhlt"), vec![]);
+        assert_eq!(analyze_source("load $0 #2\njmpf $0\nhlt"), vec![]);
+    }
+
+    #[test]
+    fn test_analyze_bad_targets() {
+        assert_eq!(analyze_source("load $0 #40\njmp $0\nhlt"), vec![
+            Violation::OutOfBounds { offset: 4, target: 40 },
+        ]);
+        assert_eq!(analyze_source("load $0 #6\njmp $0\nhlt"), vec![
+            Violation::Misaligned { offset: 4, target: 6 },
+        ]);
+        assert_eq!(analyze_source("load $0 #20\njmpb $0"), vec![
+            Violation::OutOfBounds { offset: 4, target: -14 },
+        ]);
+    }
+
+    #[test]
+    fn test_analyze_unreachable_code() {
+        assert_eq!(analyze_source("load $0 #16\njmp $0\nload $1 #1\nload $1 #2\nhlt"), vec![
+            Violation::Unreachable { offset: 8 },
+        ]);
+        // The target register is clobbered by the addition, so nothing can be said
+        assert_eq!(analyze_source("load $0 #16\nadd $0 $0 $0\njmp $0\nload $1 #1\nhlt"), vec![]);
+        assert_eq!(analyze_source("load $0 #16\nbanksw #1\njmp $0\nload $1 #1\nhlt"), vec![]);
+        // The walk starts from the entry point
+        assert_eq!(analyze_source("done: hlt\n.entry @main\nmain: load $0 @done\njmp $0"), vec![]);
+        assert_eq!(analyze_source("load $1 #1\n.entry @main\nmain: hlt"), vec![
+            Violation::Unreachable { offset: 0 },
+        ]);
+    }
+}
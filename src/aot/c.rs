@@ -0,0 +1,461 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use crate::bytecode::Endianness;
+use crate::cfg::{Block, Cfg};
+use crate::instruction::Opcode;
+use crate::syscall::Syscall;
+use super::{counter_increments, immediate, int_registers, locals, reads_counters, register, Local};
+use crate::vm::{VMError, HEAP_SIZE, MAX_HEAP_STRING, MAX_TRAP_HANDLERS, REGISTER_COUNT};
+
+/// Support code shared by every generated C file. Arithmetic goes through unsigned or 64-bit
+/// integers so that it wraps like the VM instead of overflowing.
+const PRELUDE: &str = r#"enum { EPIE_NEXT, EPIE_HALT, EPIE_ERROR };
+
+static inline int32_t wrapping_add(int32_t a, int32_t b) { return (int32_t)((uint32_t)a + (uint32_t)b); }
+static inline int32_t wrapping_sub(int32_t a, int32_t b) { return (int32_t)((uint32_t)a - (uint32_t)b); }
+static inline int32_t wrapping_mul(int32_t a, int32_t b) { return (int32_t)((uint32_t)a * (uint32_t)b); }
+static inline int32_t wrapping_div(int32_t a, int32_t b) { return b == -1 ? wrapping_sub(0, a) : a / b; }
+static inline int32_t wrapping_rem(int32_t a, int32_t b) { return b == -1 ? 0 : a % b; }
+static inline int fits(int64_t v) { return v >= INT32_MIN && v <= INT32_MAX; }
+static inline int32_t saturate(int64_t v) { return v < INT32_MIN ? INT32_MIN : v > INT32_MAX ? INT32_MAX : (int32_t)v; }
+
+/* Truncates towards zero, saturating out of range values and mapping NaN to 0 */
+static inline int32_t ftoi(double v) {
+    if (v != v) return 0;
+    if (v <= (double)INT32_MIN) return INT32_MIN;
+    if (v >= (double)INT32_MAX) return INT32_MAX;
+    return (int32_t)v;
+}
+
+/* Length of the NUL-terminated string at addr, cut like the VM does */
+static inline size_t string_len(const uint8_t *heap, uint32_t addr) {
+    size_t len = 0;
+    while ((size_t)addr + len < HEAP_SIZE && len < MAX_HEAP_STRING && heap[addr + len] != 0) len++;
+    return len;
+}
+
+static inline const char *string_at(const uint8_t *heap, uint32_t addr) {
+    return addr < HEAP_SIZE ? (const char *)heap + addr : "";
+}
+
+/* A recoverable trap, its error already formatted: resumes at the innermost trap handler with the trap code, or stops */
+static inline int trap(struct epie_state *s, size_t *pc, int32_t code) {
+    if (s->handler_count == 0) return EPIE_ERROR;
+    s->handler_count--;
+    s->registers[s->handler_registers[s->handler_count]] = code;
+    *pc = s->handler_offsets[s->handler_count];
+    return EPIE_NEXT;
+}
+
+/* Formats the error of ABORT from the message at addr */
+static inline void abort_error(char *error, size_t size, unsigned pc, const uint8_t *heap, uint32_t addr) {
+    snprintf(error, size, "program aborted at pc %u: %.*s", pc, (int)string_len(heap, addr), string_at(heap, addr));
+}
+
+/* PRINTF, formatting its arguments like the VM does */
+static inline void epie_printf(const uint8_t *heap, const int32_t *registers) {
+    const char *format = string_at(heap, (uint32_t)registers[0]);
+    size_t len = string_len(heap, (uint32_t)registers[0]), i;
+    int arg = 1;
+    for (i = 0; i < len; i++) {
+        char conversion = i + 1 < len ? format[i + 1] : 0;
+        int32_t v;
+        if (format[i] != '%') {
+            putchar(format[i]);
+            continue;
+        }
+        if (conversion == '%') {
+            putchar('%');
+            i++;
+            continue;
+        }
+        /* Unknown conversions and those left without a register are printed as is */
+        if (conversion == 0 || !strchr("ducxs", conversion) || arg == REGISTER_COUNT) {
+            putchar('%');
+            continue;
+        }
+        i++;
+        v = registers[arg++];
+        switch (conversion) {
+        case 'd': printf("%ld", (long)v); break;
+        case 'u': printf("%lu", (unsigned long)(uint32_t)v); break;
+        case 'x': printf("%lx", (unsigned long)(uint32_t)v); break;
+        case 'c': putchar(v >= 0 && v < 128 ? (int)v : '?'); break;
+        default: printf("%.*s", (int)string_len(heap, (uint32_t)v), string_at(heap, (uint32_t)v));
+        }
+    }
+}
+
+/* LOG, written to stderr with its severity as the VM has no log pipeline to route it to */
+static inline void epie_log(const uint8_t *heap, uint32_t addr, int32_t severity, unsigned pc) {
+    static const char *const levels[] = {"ERROR", "WARN", "INFO", "DEBUG", "TRACE"};
+    fprintf(stderr, "%s guest: [vm 0] pc %04x: %.*s\n", levels[severity < 0 ? 0 : severity > 4 ? 4 : severity], pc,
+        (int)string_len(heap, addr), string_at(heap, addr));
+}
+
+static inline void print_float(double v) {
+    char buf[32];
+    int precision;
+    for (precision = 1; precision < 17; precision++) {
+        snprintf(buf, sizeof buf, "%.*g", precision, v);
+        if (strtod(buf, NULL) == v) break;
+    }
+    snprintf(buf, sizeof buf, "%.*g", precision, v);
+    printf("%s%s\n", buf, strpbrk(buf, ".eni") ? "" : ".0");
+}
+"#;
+
+/// Emits the C statements of one block function
+struct BlockWriter {
+    out: String,
+    locals: BTreeSet<Local>,
+}
+
+impl BlockWriter {
+    fn line(&mut self, indent: usize, line: &str) {
+        let _ = writeln!(self.out, "{:width$}{}", "", line, width = indent * 4);
+    }
+
+    /// Stores the locals back into the state, then runs the `exit` statements
+    fn exit(&mut self, indent: usize, exit: &[String]) {
+        for local in self.locals.clone() {
+            self.line(indent, &format!("{} = {};", local.slot("s->"), local.name()));
+        }
+        for statement in exit {
+            self.line(indent, statement);
+        }
+    }
+
+    /// Leaves the block when `condition` holds, with an error message formatted from `args`
+    fn fail_if(&mut self, indent: usize, condition: &str, args: &str) {
+        self.line(indent, &format!("if ({}) {{", condition));
+        self.exit(indent + 1, &error(args));
+        self.line(indent, "}");
+    }
+
+    /// Raises the recoverable trap of `error` when `condition` holds, with a message formatted
+    /// from `args`
+    fn trap_if(&mut self, indent: usize, condition: &str, error: VMError, args: &str) {
+        self.line(indent, &format!("if ({}) {{", condition));
+        self.exit(indent + 1, &trap(error, args));
+        self.line(indent, "}");
+    }
+
+    fn fail(&mut self, error: VMError) {
+        self.exit(1, &self::error(&message(error)));
+    }
+}
+
+fn error(args: &str) -> Vec<String> {
+    vec![
+        format!("snprintf(s->error, sizeof s->error, {});", args),
+        "return EPIE_ERROR;".to_string(),
+    ]
+}
+
+fn trap(error: VMError, args: &str) -> Vec<String> {
+    vec![
+        format!("snprintf(s->error, sizeof s->error, {});", args),
+        format!("return trap(s, pc, {});", error.trap_code().expect("a recoverable trap")),
+    ]
+}
+
+/// Arguments of `snprintf` formatting the message of an error known when generating the code
+fn message(error: VMError) -> String {
+    format!("\"%s\", {:?}", error.to_string())
+}
+
+fn next(pc: &str) -> Vec<String> {
+    vec![format!("*pc = {};", pc), "return EPIE_NEXT;".to_string()]
+}
+
+fn write_block(out: &mut String, block: &Block, counted: bool) {
+    let locals: BTreeSet<Local> = block.instructions.iter().flat_map(|(_, i)| locals(i)).collect();
+    let mut w = BlockWriter { out: String::new(), locals: locals };
+    let _ = writeln!(w.out, "static int block_{:04x}(struct epie_state *s, size_t *pc) {{", block.start);
+    for local in w.locals.clone() {
+        let kind = match local {
+            Local::Int(_) => "int32_t",
+            Local::Float(_) => "double",
+        };
+        w.line(1, &format!("{} {} = {};", kind, local.name(), local.slot("s->")));
+    }
+    if counted {
+        for (id, increment) in counter_increments(block, 0).iter().enumerate().filter(|(_, n)| **n > 0) {
+            w.line(1, &format!("s->counters[{}] += {};", id, increment));
+        }
+    }
+    let mut tail = next(&format!("0x{:04x}", block.end()));
+    for (index, (offset, instruction)) in block.instructions.iter().enumerate() {
+        let pc = *offset;
+        w.line(1, &format!("/* {:04x}: {} */", pc, instruction));
+        let r = |i| format!("r{}", register(instruction, i));
+        let f = |i| format!("f{}", register(instruction, i));
+        let division_by_zero = VMError::DivisionByZero { pc: pc };
+        match instruction.opcode() {
+            Opcode::LOAD => w.line(1, &format!("{} = {};", r(0), immediate(instruction, 1))),
+            Opcode::ADD => w.line(1, &format!("{} = wrapping_add({}, {});", r(2), r(0), r(1))),
+            Opcode::SUB => w.line(1, &format!("{} = wrapping_sub({}, {});", r(2), r(0), r(1))),
+            Opcode::MUL => w.line(1, &format!("{} = wrapping_mul({}, {});", r(2), r(0), r(1))),
+            Opcode::DIV => {
+                w.trap_if(1, &format!("{} == 0", r(1)), division_by_zero, &message(division_by_zero));
+                w.line(1, &format!("s->remainder = (uint32_t)wrapping_rem({}, {});", r(0), r(1)));
+                w.line(1, &format!("{} = wrapping_div({}, {});", r(2), r(0), r(1)));
+            },
+            Opcode::ADDO | Opcode::SUBO | Opcode::MULO => {
+                let operator = match instruction.opcode() {
+                    Opcode::ADDO => "+",
+                    Opcode::SUBO => "-",
+                    _ => "*",
+                };
+                w.line(1, "{");
+                w.line(2, &format!("int64_t v = (int64_t){} {} {};", r(0), operator, r(1)));
+                let overflow = VMError::Overflow { pc: pc };
+                w.trap_if(2, "!fits(v)", overflow, &message(overflow));
+                w.line(2, &format!("{} = (int32_t)v;", r(2)));
+                w.line(1, "}");
+            },
+            Opcode::ADDS => w.line(1, &format!("{} = saturate((int64_t){} + {});", r(2), r(0), r(1))),
+            Opcode::SUBS => w.line(1, &format!("{} = saturate((int64_t){} - {});", r(2), r(0), r(1))),
+            Opcode::MAC => w.line(1, &format!("{} = wrapping_add({}, wrapping_mul({}, {}));", r(0), r(0), r(1), r(2))),
+            Opcode::EQ => w.line(1, &format!("{} = {} == {};", r(2), r(0), r(1))),
+            Opcode::NEQ => w.line(1, &format!("{} = {} != {};", r(2), r(0), r(1))),
+            Opcode::GT => w.line(1, &format!("{} = {} > {};", r(2), r(0), r(1))),
+            Opcode::LT => w.line(1, &format!("{} = {} < {};", r(2), r(0), r(1))),
+            Opcode::GTQ => w.line(1, &format!("{} = {} >= {};", r(2), r(0), r(1))),
+            Opcode::LTQ => w.line(1, &format!("{} = {} <= {};", r(2), r(0), r(1))),
+            Opcode::JMP => tail = next(&format!("(size_t){}", r(0))),
+            // JMPF and JMPB are relative to the pc after their register byte
+            Opcode::JMPF => tail = next(&format!("(size_t){} + (size_t){}", pc + 2, r(0))),
+            Opcode::JMPB => tail = next(&format!("(size_t){} - (size_t){}", pc + 2, r(0))),
+            Opcode::JEQ => {
+                w.line(1, &format!("if ({} == 1) {{", r(1)));
+                w.exit(2, &next(&format!("(size_t){}", r(0))));
+                w.line(1, "}");
+            },
+            Opcode::LW | Opcode::SW => {
+                w.line(1, "{");
+                w.line(2, &format!("size_t addr = (size_t){} + {};", r(1), immediate(instruction, 2)));
+                let args = format!("\"heap address %lu is out of bounds at pc {}\", (unsigned long)addr", pc);
+                w.trap_if(2, "addr > HEAP_SIZE - 4", VMError::OutOfBounds { pc: pc, addr: 0 }, &args);
+                if instruction.opcode() == Opcode::LW {
+                    w.line(2, &format!("{} = load_word(s->heap + addr);", r(0)));
+                } else {
+                    w.line(2, &format!("store_word(s->heap + addr, {});", r(0)));
+                }
+                w.line(1, "}");
+            },
+            Opcode::QMUL => w.line(1, &format!("{} = (int32_t)(((int64_t){} * {}) >> 16);", r(2), r(0), r(1))),
+            Opcode::QDIV => {
+                w.trap_if(1, &format!("{} == 0", r(1)), division_by_zero, &message(division_by_zero));
+                w.line(1, &format!("{} = (int32_t)((int64_t){} * 65536 / {});", r(2), r(0), r(1)));
+            },
+            Opcode::ITOF => w.line(1, &format!("{} = (double){};", f(1), r(0))),
+            Opcode::FTOI => w.line(1, &format!("{} = ftoi({});", r(1), f(0))),
+            Opcode::FEQ => w.line(1, &format!("{} = {} == {};", r(2), f(0), f(1))),
+            Opcode::FLT => w.line(1, &format!("{} = {} < {};", r(2), f(0), f(1))),
+            Opcode::FGT => w.line(1, &format!("{} = {} > {};", r(2), f(0), f(1))),
+            Opcode::ASSERT => {
+                let args = format!("\"assertion failed at pc {}: %d != %d\", {}, {}", pc, r(0), r(1));
+                w.fail_if(1, &format!("{} != {}", r(0), r(1)), &args);
+            },
+            Opcode::SYS => {
+                let id = immediate(instruction, 0) as u16;
+                match Syscall::from_id(id) {
+                    Some(Syscall::Sqrt) => w.line(1, "f0 = sqrt(f0);"),
+                    Some(Syscall::Sin) => w.line(1, "f0 = sin(f0);"),
+                    Some(Syscall::Cos) => w.line(1, "f0 = cos(f0);"),
+                    Some(Syscall::Pow) => w.line(1, "f0 = pow(f0, f1);"),
+                    Some(Syscall::Abs) => w.line(1, "f0 = fabs(f0);"),
+                    Some(Syscall::Printf) => w.line(1, &format!("epie_printf(s->heap, (const int32_t[]){{{}}});", int_registers())),
+                    Some(Syscall::Log) => w.line(1, &format!("epie_log(s->heap, (uint32_t)r0, r1, {}u);", pc)),
+                    None => w.fail(VMError::UnknownSyscall { pc: pc, id: id }),
+                }
+            },
+            // Generated programs have a single register bank
+            Opcode::BANKSW => match immediate(instruction, 0) {
+                0 => (),
+                bank => w.fail(VMError::InvalidBank { pc: pc, bank: bank as u16 }),
+            },
+            Opcode::RDCNT => {
+                let id = immediate(instruction, 0);
+                match counter_increments(block, index + 1).get(id) {
+                    Some(pending) => w.line(1, &format!("{} = (int32_t)(uint32_t)(s->counters[{}] - {});", r(1), id, pending)),
+                    None => w.fail(VMError::UnknownCounter { pc: pc, counter: id as u16 }),
+                }
+            },
+            // Generated programs run alone, outside of any scheduler
+            Opcode::YIELD => (),
+            Opcode::PUSHTRAP => {
+                w.fail_if(1, "s->handler_count == MAX_TRAP_HANDLERS", &message(VMError::TooManyTrapHandlers { pc: pc }));
+                w.line(1, &format!("s->handler_offsets[s->handler_count] = 0x{:04x};", immediate(instruction, 0)));
+                w.line(1, &format!("s->handler_registers[s->handler_count++] = {};", register(instruction, 1)));
+            },
+            Opcode::POPTRAP => {
+                w.fail_if(1, "s->handler_count == 0", &message(VMError::NoTrapHandler { pc: pc }));
+                w.line(1, "s->handler_count--;");
+            },
+            Opcode::ABORT => tail = vec![
+                format!("abort_error(s->error, sizeof s->error, {}, s->heap, (uint32_t){});", pc, r(0)),
+                "(void)pc;".to_string(),
+                "return EPIE_ERROR;".to_string(),
+            ],
+            Opcode::HLT | Opcode::IGL => tail = vec!["(void)s;".to_string(), "(void)pc;".to_string(), "return EPIE_HALT;".to_string()],
+        }
+    }
+    w.exit(1, &tail);
+    w.line(0, "}");
+    out.push_str(&w.out);
+}
+
+/// Writes the C translation of a program, see `aot::transpile`. Besides `main`, the file
+/// exports `struct epie_state` and `int epie_run(struct epie_state *s)`, so that it can be
+/// linked into a host program when compiled with `-DEPIE_NO_MAIN`.
+pub fn emit(cfg: &Cfg, endianness: Endianness) -> String {
+    let (load, store) = match endianness {
+        Endianness::Big => (
+            "return (int32_t)((uint32_t)p[0] << 24 | (uint32_t)p[1] << 16 | (uint32_t)p[2] << 8 | p[3]);",
+            "p[0] = (uint8_t)(v >> 24); p[1] = (uint8_t)(v >> 16); p[2] = (uint8_t)(v >> 8); p[3] = (uint8_t)v;",
+        ),
+        Endianness::Little => (
+            "return (int32_t)((uint32_t)p[3] << 24 | (uint32_t)p[2] << 16 | (uint32_t)p[1] << 8 | p[0]);",
+            "p[3] = (uint8_t)(v >> 24); p[2] = (uint8_t)(v >> 16); p[1] = (uint8_t)(v >> 8); p[0] = (uint8_t)v;",
+        ),
+    };
+    let mut out = String::new();
+    out.push_str(&[
+        "/* Generated by the `aot` subcommand */",
+        "#include <math.h>",
+        "#include <stddef.h>",
+        "#include <stdint.h>",
+        "#include <stdio.h>",
+        "#include <stdlib.h>",
+        "#include <string.h>",
+        "",
+        &format!("#define PROGRAM_LEN {}", cfg.len()),
+        &format!("#define HEAP_SIZE {}", HEAP_SIZE),
+        &format!("#define MAX_HEAP_STRING {}", MAX_HEAP_STRING),
+        &format!("#define REGISTER_COUNT {}", REGISTER_COUNT),
+        &format!("#define MAX_TRAP_HANDLERS {}", MAX_TRAP_HANDLERS),
+        "",
+        "struct epie_state {",
+        &format!("    int32_t registers[{}];", REGISTER_COUNT),
+        &format!("    double float_registers[{}];", REGISTER_COUNT),
+        "    uint32_t remainder;",
+        "    uint8_t heap[HEAP_SIZE];",
+        "    /* Performance counters of RDCNT, only maintained when the program reads them */",
+        "    uint64_t counters[3];",
+        "    /* Trap handlers pushed by PUSHTRAP, innermost last */",
+        "    size_t handler_offsets[MAX_TRAP_HANDLERS];",
+        "    uint8_t handler_registers[MAX_TRAP_HANDLERS];",
+        "    size_t handler_count;",
+        "    char error[128];",
+        "};",
+        "",
+        PRELUDE,
+        "static inline int32_t load_word(const uint8_t *p) {",
+        &format!("    {}", load),
+        "}",
+        "",
+        "static inline void store_word(uint8_t *p, int32_t value) {",
+        "    uint32_t v = (uint32_t)value;",
+        &format!("    {}", store),
+        "}",
+        "",
+        "",
+    ].join("\n"));
+    let counted = reads_counters(cfg);
+    for block in &cfg.blocks {
+        write_block(&mut out, block, counted);
+        out.push('\n');
+    }
+    out.push_str(&[
+        "/* Runs the program on a zeroed state, returns 0 once it halted or -1 if it stopped on the error described in s->error */",
+        "int epie_run(struct epie_state *s) {",
+        &format!("    size_t pc = {};", cfg.entry()),
+        "    for (;;) {",
+        "        int status;",
+        "        switch (pc) {",
+        "",
+    ].join("\n"));
+    for block in &cfg.blocks {
+        let _ = writeln!(out, "        case 0x{0:04x}: status = block_{0:04x}(s, &pc); break;", block.start);
+    }
+    out.push_str(&[
+        "        default:",
+        "            if (pc >= PROGRAM_LEN) return 0;",
+        "            snprintf(s->error, sizeof s->error, \"jump to offset %lu, which does not start a block\", (unsigned long)pc);",
+        "            return -1;",
+        "        }",
+        "        if (status == EPIE_HALT) return 0;",
+        "        if (status == EPIE_ERROR) return -1;",
+        "    }",
+        "}",
+        "",
+        "#ifndef EPIE_NO_MAIN",
+        "int main(void) {",
+        "    static struct epie_state s;",
+        "    int i;",
+        "    int result = epie_run(&s);",
+        "    printf(\"remainder: %u\\n\", (unsigned)s.remainder);",
+        "    printf(\"registers:\\n\");",
+        &format!("    for (i = 0; i < {}; i++) {{", REGISTER_COUNT),
+        "        if (s.registers[i] != 0) printf(\"  $%d = %d\\n\", i, (int)s.registers[i]);",
+        "    }",
+        "    printf(\"float_registers:\\n\");",
+        &format!("    for (i = 0; i < {}; i++) {{", REGISTER_COUNT),
+        "        if (s.float_registers[i] != 0.0 || signbit(s.float_registers[i])) {",
+        "            printf(\"  $f%d = \", i);",
+        "            print_float(s.float_registers[i]);",
+        "        }",
+        "    }",
+        "    if (result != 0) {",
+        "        fprintf(stderr, \"error: %s\\n\", s.error);",
+        "        return 1;",
+        "    }",
+        "    return 0;",
+        "}",
+        "#endif",
+        "",
+    ].join("\n"));
+    out
+}
+
+#[cfg(all(test, feature = "float"))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process::Command;
+    use crate::aot::{transpile, Target};
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_compiled_c_program_matches_vm() {
+        let src = [
+            "load $0 #0", "load $1 #1", "load $2 #101", "load $3 #1", "load $4 #20",
+            "add $0 $1 $0", "add $1 $3 $1", "lt $1 $2 $5", "jeq $4 $5",
+            "load $6 #7", "div $0 $6 $7", "itof $7 $0", "sys #0", "load $8 #0", "sw $0 $8 #4", "lw $9 $8 #4", "hlt",
+        ].join("\n");
+        let program = Assembler::new().assemble(&src).unwrap();
+        let source = transpile(&program, Endianness::Little, 0, Target::C).unwrap();
+        assert!(source.contains("    p[3] = (uint8_t)(v >> 24);"));
+        let dir = std::env::temp_dir().join(format!("aot-c-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("prog.c"), source).unwrap();
+        let status = Command::new("cc").arg("-std=c99").arg("-Wall").arg("-Wextra").arg("-Werror")
+            .arg("-o").arg(dir.join("prog")).arg(dir.join("prog.c")).arg("-lm")
+            .status().unwrap();
+        assert!(status.success());
+        let output = Command::new(dir.join("prog")).output().unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), [
+            "remainder: 3",
+            "registers:",
+            "  $0 = 5050", "  $1 = 101", "  $2 = 101", "  $3 = 1", "  $4 = 20", "  $6 = 7", "  $7 = 721", "  $9 = 5050",
+            "float_registers:",
+            "  $f0 = 26.851443164195103",
+            "",
+        ].join("\n"));
+    }
+}
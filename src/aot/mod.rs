@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::Path;
+use crate::bytecode::{self, Endianness};
+use crate::cfg::{Block, Cfg};
+use crate::instruction::{Instruction, Opcode, Operand};
+use crate::syscall::Syscall;
+use crate::verifier::VerifyError;
+use crate::vm::{Counter, REGISTER_COUNT};
+
+pub mod rust;
+pub mod c;
+
+/// Language the `aot` subcommand translates to
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Target {
+    Rust,
+    C,
+}
+
+impl Target {
+    pub fn parse(src: &str) -> Result<Target, String> {
+        match src {
+            "rust" => Ok(Target::Rust),
+            "c" => Ok(Target::C),
+            _ => Err(format!("Unknown target '{}', expected rust or c", src))
+        }
+    }
+}
+
+/// A register held in a local variable of a block function
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+enum Local {
+    Int(u8),
+    Float(u8),
+}
+
+impl Local {
+    fn name(&self) -> String {
+        match self {
+            Local::Int(r) => format!("r{}", r),
+            Local::Float(r) => format!("f{}", r),
+        }
+    }
+
+    /// The register in the state, reached through `state` (`s.` or `s->`)
+    fn slot(&self, state: &str) -> String {
+        match self {
+            Local::Int(r) => format!("{}registers[{}]", state, r),
+            Local::Float(r) => format!("{}float_registers[{}]", state, r),
+        }
+    }
+}
+
+/// The counters of RDCNT, indexed by id
+const COUNTERS: [Counter; 3] = [Counter::Instructions, Counter::Branches, Counter::Syscalls];
+
+/// Whether the program reads performance counters, which the generated code then maintains.
+/// Every block adds its whole contribution to the counters on entry, and RDCNT subtracts what
+/// the rest of its block has not executed yet.
+fn reads_counters(cfg: &Cfg) -> bool {
+    cfg.blocks.iter().flat_map(|b| &b.instructions).any(|(_, i)| i.opcode() == Opcode::RDCNT)
+}
+
+/// How much each counter grows when running the instructions of `block` from index `from`
+fn counter_increments(block: &Block, from: usize) -> [usize; 3] {
+    let mut increments = [0; 3];
+    for (_, instruction) in block.instructions.iter().skip(from) {
+        for (increment, counter) in increments.iter_mut().zip(COUNTERS) {
+            *increment += counter.counts(instruction.opcode()) as usize;
+        }
+    }
+    increments
+}
+
+/// The integer registers as the elements of an array literal, for PRINTF
+fn int_registers() -> String {
+    (0..REGISTER_COUNT).map(|r| format!("r{}", r)).collect::<Vec<String>>().join(", ")
+}
+
+fn register(instruction: &Instruction, index: usize) -> u8 {
+    match instruction.operands()[index] {
+        Operand::Register(r) => r,
+        _ => panic!("verified programs have a register operand here"),
+    }
+}
+
+fn immediate(instruction: &Instruction, index: usize) -> usize {
+    match instruction.operands()[index] {
+        Operand::Integer(i) => i as usize,
+        Operand::Byte(b) => b as usize,
+        _ => panic!("verified programs have an immediate operand here"),
+    }
+}
+
+/// Registers read or written by an instruction
+fn locals(instruction: &Instruction) -> Vec<Local> {
+    let int = |i| Local::Int(register(instruction, i));
+    let float = |i| Local::Float(register(instruction, i));
+    match instruction.opcode() {
+        Opcode::ITOF => vec![int(0), float(1)],
+        Opcode::FTOI => vec![float(0), int(1)],
+        Opcode::FEQ | Opcode::FLT | Opcode::FGT => vec![float(0), float(1), int(2)],
+        Opcode::SYS => match Syscall::from_id(immediate(instruction, 0) as u16) {
+            // PRINTF takes its format and arguments from the integer registers
+            Some(Syscall::Printf) => (0..REGISTER_COUNT as u8).map(Local::Int).collect(),
+            Some(Syscall::Log) => vec![Local::Int(0), Local::Int(1)],
+            _ => vec![Local::Float(0), Local::Float(1)],
+        },
+        _ => instruction.operands().iter()
+            .filter_map(|o| match o {
+                Operand::Register(r) => Some(Local::Int(*r)),
+                _ => None,
+            })
+            .collect(),
+    }
+}
+
+/// Translates a program into a standalone source file. Every basic block becomes a function
+/// keeping the registers it uses in locals, and a loop dispatches between them on the offset of
+/// the next block. When some jump target is not statically known, every instruction gets its
+/// own block so that any instruction boundary can be jumped to.
+///
+/// The generated program starts from the instruction at `entry`, runs with the default VM
+/// configuration, prints its registers on exit and exits with status 1 if it stopped on an error.
+pub fn transpile(program: &[u8], endianness: Endianness, entry: usize, target: Target) -> Result<String, VerifyError> {
+    let mut cfg = Cfg::build_with_entry(program, entry)?;
+    if !cfg.all_targets_known() {
+        cfg = cfg.split_all();
+    }
+    Ok(match target {
+        Target::Rust => rust::emit(&cfg, endianness),
+        Target::C => c::emit(&cfg, endianness),
+    })
+}
+
+/// The `aot <bytecode> <output> [--target rust|c]` subcommand: writes the translation of a
+/// bytecode file
+pub fn aot_file(input: &Path, output: &Path, target: Target) -> Result<usize, String> {
+    let bytes = fs::read(input).map_err(|e| format!("Unable to read {}: {}", input.display(), e))?;
+    let (endianness, program, entry) = bytecode::read_program(&bytes).map_err(|e| format!("{}: {}", input.display(), e))?;
+    let source = transpile(&program, endianness, entry, target).map_err(|e| format!("{}: {}", input.display(), e))?;
+    fs::write(output, &source).map_err(|e| format!("Unable to write {}: {}", output.display(), e))?;
+    Ok(source.len())
+}
@@ -0,0 +1,403 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use crate::bytecode::Endianness;
+use crate::cfg::{Block, Cfg};
+use crate::instruction::Opcode;
+use crate::syscall::Syscall;
+use super::{counter_increments, immediate, int_registers, locals, reads_counters, register, Local};
+use crate::vm::{VMError, FIXED_POINT_SHIFT, HEAP_SIZE, MAX_HEAP_STRING, MAX_TRAP_HANDLERS, REGISTER_COUNT};
+
+/// Emits the Rust statements of one block function
+struct BlockWriter {
+    out: String,
+    locals: BTreeSet<Local>,
+}
+
+impl BlockWriter {
+    fn line(&mut self, indent: usize, line: &str) {
+        let _ = writeln!(self.out, "{:width$}{}", "", line, width = indent * 4);
+    }
+
+    /// Stores the locals back into the state, then leaves the block with `value`
+    fn exit(&mut self, indent: usize, value: &str, tail: bool) {
+        for local in self.locals.clone() {
+            self.line(indent, &format!("{} = {};", local.slot("s."), local.name()));
+        }
+        if tail {
+            self.line(indent, value);
+        } else {
+            self.line(indent, &format!("return {};", value));
+        }
+    }
+
+    /// Leaves the block with an error when `condition` holds
+    fn fail_if(&mut self, condition: &str, error: &str) {
+        self.line(1, &format!("if {} {{", condition));
+        self.exit(2, &format!("Err({})", error), false);
+        self.line(1, "}");
+    }
+
+    /// Raises the recoverable trap of `error` when `condition` holds, with the message `text`
+    fn trap_if(&mut self, indent: usize, condition: &str, error: VMError, text: &str) {
+        self.line(indent, &format!("if {} {{", condition));
+        self.exit(indent + 1, &trap(error, text), false);
+        self.line(indent, "}");
+    }
+
+    fn fail(&mut self, error: VMError) {
+        self.exit(1, &format!("Err({})", message(error)), false);
+    }
+}
+
+/// Resumes at the innermost trap handler with the code of `error`, or stops with the message `text`
+fn trap(error: VMError, text: &str) -> String {
+    format!("trap(s, {}, {})", error.trap_code().expect("a recoverable trap"), text)
+}
+
+/// The message of an error known when generating the code
+fn message(error: VMError) -> String {
+    format!("{:?}.to_string()", error.to_string())
+}
+
+fn write_block(out: &mut String, block: &Block, counted: bool) {
+    let locals: BTreeSet<Local> = block.instructions.iter().flat_map(|(_, i)| locals(i)).collect();
+    let mut w = BlockWriter { out: String::new(), locals: locals };
+    let _ = writeln!(w.out, "fn block_{:04x}(s: &mut State) -> Next {{", block.start);
+    for local in w.locals.clone() {
+        w.line(1, &format!("let mut {} = {};", local.name(), local.slot("s.")));
+    }
+    if counted {
+        for (id, increment) in counter_increments(block, 0).iter().enumerate().filter(|(_, n)| **n > 0) {
+            w.line(1, &format!("s.counters[{}] += {};", id, increment));
+        }
+    }
+    let mut tail = format!("Ok(Some(0x{:04x}))", block.end());
+    for (index, (offset, instruction)) in block.instructions.iter().enumerate() {
+        let pc = *offset;
+        w.line(1, &format!("// {:04x}: {}", pc, instruction));
+        let r = |i| format!("r{}", register(instruction, i));
+        let f = |i| format!("f{}", register(instruction, i));
+        match instruction.opcode() {
+            Opcode::LOAD => w.line(1, &format!("{} = {};", r(0), immediate(instruction, 1))),
+            Opcode::ADD => w.line(1, &format!("{} = {}.wrapping_add({});", r(2), r(0), r(1))),
+            Opcode::SUB => w.line(1, &format!("{} = {}.wrapping_sub({});", r(2), r(0), r(1))),
+            Opcode::MUL => w.line(1, &format!("{} = {}.wrapping_mul({});", r(2), r(0), r(1))),
+            Opcode::DIV => {
+                let error = VMError::DivisionByZero { pc: pc };
+                w.trap_if(1, &format!("{} == 0", r(1)), error, &message(error));
+                w.line(1, &format!("s.remainder = {}.wrapping_rem({}) as u32;", r(0), r(1)));
+                w.line(1, &format!("{} = {}.wrapping_div({});", r(2), r(0), r(1)));
+            },
+            Opcode::ADDO | Opcode::SUBO | Opcode::MULO => {
+                let method = match instruction.opcode() {
+                    Opcode::ADDO => "checked_add",
+                    Opcode::SUBO => "checked_sub",
+                    _ => "checked_mul",
+                };
+                w.line(1, &format!("match {}.{}({}) {{", r(0), method, r(1)));
+                w.line(2, &format!("Some(v) => {} = v,", r(2)));
+                w.line(2, "None => {");
+                let error = VMError::Overflow { pc: pc };
+                w.exit(3, &trap(error, &message(error)), false);
+                w.line(2, "}");
+                w.line(1, "}");
+            },
+            Opcode::ADDS => w.line(1, &format!("{} = {}.saturating_add({});", r(2), r(0), r(1))),
+            Opcode::SUBS => w.line(1, &format!("{} = {}.saturating_sub({});", r(2), r(0), r(1))),
+            Opcode::MAC => w.line(1, &format!("{} = {}.wrapping_add({}.wrapping_mul({}));", r(0), r(0), r(1), r(2))),
+            Opcode::EQ => w.line(1, &format!("{} = ({} == {}) as i32;", r(2), r(0), r(1))),
+            Opcode::NEQ => w.line(1, &format!("{} = ({} != {}) as i32;", r(2), r(0), r(1))),
+            Opcode::GT => w.line(1, &format!("{} = ({} > {}) as i32;", r(2), r(0), r(1))),
+            Opcode::LT => w.line(1, &format!("{} = ({} < {}) as i32;", r(2), r(0), r(1))),
+            Opcode::GTQ => w.line(1, &format!("{} = ({} >= {}) as i32;", r(2), r(0), r(1))),
+            Opcode::LTQ => w.line(1, &format!("{} = ({} <= {}) as i32;", r(2), r(0), r(1))),
+            Opcode::JMP => tail = format!("Ok(Some({} as usize))", r(0)),
+            // JMPF and JMPB are relative to the pc after their register byte
+            Opcode::JMPF => tail = format!("Ok(Some({}usize.wrapping_add({} as usize)))", pc + 2, r(0)),
+            Opcode::JMPB => tail = format!("Ok(Some({}usize.wrapping_sub({} as usize)))", pc + 2, r(0)),
+            Opcode::JEQ => {
+                w.line(1, &format!("if {} == 1 {{", r(1)));
+                w.exit(2, &format!("Ok(Some({} as usize))", r(0)), false);
+                w.line(1, "}");
+            },
+            Opcode::LW | Opcode::SW => {
+                w.line(1, "{");
+                w.line(2, &format!("let addr = ({} as usize).wrapping_add({});", r(1), immediate(instruction, 2)));
+                let text = format!("format!(\"heap address {{}} is out of bounds at pc {}\", addr)", pc);
+                w.trap_if(2, "addr > HEAP_SIZE - 4", VMError::OutOfBounds { pc: pc, addr: 0 }, &text);
+                if instruction.opcode() == Opcode::LW {
+                    w.line(2, &format!("{} = load_word(&s.heap, addr);", r(0)));
+                } else {
+                    w.line(2, &format!("store_word(&mut s.heap, addr, {});", r(0)));
+                }
+                w.line(1, "}");
+            },
+            Opcode::QMUL => w.line(1, &format!("{} = (({} as i64 * {} as i64) >> {}) as i32;", r(2), r(0), r(1), FIXED_POINT_SHIFT)),
+            Opcode::QDIV => {
+                let error = VMError::DivisionByZero { pc: pc };
+                w.trap_if(1, &format!("{} == 0", r(1)), error, &message(error));
+                w.line(1, &format!("{} = ((({} as i64) << {}) / {} as i64) as i32;", r(2), r(0), FIXED_POINT_SHIFT, r(1)));
+            },
+            Opcode::ITOF => w.line(1, &format!("{} = {} as f64;", f(1), r(0))),
+            Opcode::FTOI => w.line(1, &format!("{} = {} as i32;", r(1), f(0))),
+            Opcode::FEQ => w.line(1, &format!("{} = ({} == {}) as i32;", r(2), f(0), f(1))),
+            Opcode::FLT => w.line(1, &format!("{} = ({} < {}) as i32;", r(2), f(0), f(1))),
+            Opcode::FGT => w.line(1, &format!("{} = ({} > {}) as i32;", r(2), f(0), f(1))),
+            Opcode::ASSERT => {
+                let error = format!("format!(\"assertion failed at pc {}: {{}} != {{}}\", {}, {})", pc, r(0), r(1));
+                w.fail_if(&format!("{} != {}", r(0), r(1)), &error);
+            },
+            Opcode::SYS => {
+                let id = immediate(instruction, 0) as u16;
+                match Syscall::from_id(id) {
+                    Some(Syscall::Sqrt) => w.line(1, "f0 = f0.sqrt();"),
+                    Some(Syscall::Sin) => w.line(1, "f0 = f0.sin();"),
+                    Some(Syscall::Cos) => w.line(1, "f0 = f0.cos();"),
+                    Some(Syscall::Pow) => w.line(1, "f0 = f0.powf(f1);"),
+                    Some(Syscall::Abs) => w.line(1, "f0 = f0.abs();"),
+                    Some(Syscall::Printf) => w.line(1, &format!("printf(&s.heap, &[{}]);", int_registers())),
+                    Some(Syscall::Log) => w.line(1, &format!("log(&s.heap, r0, r1, {});", pc)),
+                    None => w.fail(VMError::UnknownSyscall { pc: pc, id: id }),
+                }
+            },
+            // Generated programs have a single register bank
+            Opcode::BANKSW => match immediate(instruction, 0) {
+                0 => (),
+                bank => w.fail(VMError::InvalidBank { pc: pc, bank: bank as u16 }),
+            },
+            Opcode::RDCNT => {
+                let id = immediate(instruction, 0);
+                match counter_increments(block, index + 1).get(id) {
+                    Some(pending) => w.line(1, &format!("{} = s.counters[{}].wrapping_sub({}) as i32;", r(1), id, pending)),
+                    None => w.fail(VMError::UnknownCounter { pc: pc, counter: id as u16 }),
+                }
+            },
+            // Generated programs run alone, outside of any scheduler
+            Opcode::YIELD => (),
+            Opcode::PUSHTRAP => {
+                w.fail_if("s.handlers.len() == MAX_TRAP_HANDLERS", &message(VMError::TooManyTrapHandlers { pc: pc }));
+                w.line(1, &format!("s.handlers.push((0x{:04x}, {}));", immediate(instruction, 0), register(instruction, 1)));
+            },
+            Opcode::POPTRAP => w.fail_if("s.handlers.pop().is_none()", &message(VMError::NoTrapHandler { pc: pc })),
+            Opcode::ABORT => {
+                tail = format!("Err(format!(\"program aborted at pc {}: {{}}\", heap_string(&s.heap, {} as u32 as usize)))", pc, r(0));
+            },
+            Opcode::HLT | Opcode::IGL => tail = "Ok(None)".to_string(),
+        }
+    }
+    w.exit(1, &tail, true);
+    w.line(0, "}");
+    out.push_str(&w.out);
+}
+
+/// Writes the Rust translation of a program, see `aot::transpile`
+pub fn emit(cfg: &Cfg, endianness: Endianness) -> String {
+    let bytes = match endianness {
+        Endianness::Big => "be_bytes",
+        Endianness::Little => "le_bytes",
+    };
+    let mut out = String::new();
+    out.push_str("// Generated by the `aot` subcommand\n");
+    out.push_str("#![allow(dead_code, unused_mut, unused_assignments, unused_variables, unreachable_code)]\n\n");
+    let _ = writeln!(out, "const PROGRAM_LEN: usize = {};", cfg.len());
+    let _ = writeln!(out, "const HEAP_SIZE: usize = {};", HEAP_SIZE);
+    let _ = writeln!(out, "const MAX_HEAP_STRING: usize = {};", MAX_HEAP_STRING);
+    let _ = writeln!(out, "const MAX_TRAP_HANDLERS: usize = {};", MAX_TRAP_HANDLERS);
+    out.push_str(&[
+        "",
+        "struct State {",
+        &format!("    registers: [i32; {}],", REGISTER_COUNT),
+        &format!("    float_registers: [f64; {}],", REGISTER_COUNT),
+        "    remainder: u32,",
+        "    heap: Vec<u8>,",
+        "    /// Performance counters of RDCNT, only maintained when the program reads them",
+        "    counters: [u64; 3],",
+        "    /// Offset and code register of the trap handlers pushed by PUSHTRAP, innermost last",
+        "    handlers: Vec<(usize, usize)>,",
+        "}",
+        "",
+        "/// Offset of the next block to run, None once halted",
+        "type Next = Result<Option<usize>, String>;",
+        "",
+        "fn load_word(heap: &[u8], addr: usize) -> i32 {",
+        "    let bytes = heap.get(addr..addr.saturating_add(4)).expect(\"heap address out of bounds\");",
+        &format!("    i32::from_{}([bytes[0], bytes[1], bytes[2], bytes[3]])", bytes),
+        "}",
+        "",
+        "fn store_word(heap: &mut [u8], addr: usize, value: i32) {",
+        "    let bytes = heap.get_mut(addr..addr.saturating_add(4)).expect(\"heap address out of bounds\");",
+        &format!("    bytes.copy_from_slice(&value.to_{}());", bytes),
+        "}",
+        "",
+        "/// A recoverable trap: resumes at the innermost trap handler with the trap code, or stops",
+        "/// with `error` when there is none",
+        "fn trap(s: &mut State, code: i32, error: String) -> Next {",
+        "    match s.handlers.pop() {",
+        "        Some((offset, register)) => {",
+        "            s.registers[register] = code;",
+        "            Ok(Some(offset))",
+        "        },",
+        "        None => Err(error),",
+        "    }",
+        "}",
+        "",
+        "/// The NUL-terminated message of an ABORT, cut like the VM does",
+        "fn heap_string(heap: &[u8], addr: usize) -> String {",
+        "    let bytes = heap.get(addr..).unwrap_or(&[]);",
+        "    let bytes = &bytes[..bytes.len().min(MAX_HEAP_STRING)];",
+        "    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());",
+        "    String::from_utf8_lossy(&bytes[..len]).into_owned()",
+        "}",
+        "",
+        "/// PRINTF, formatting its arguments like the VM does",
+        "fn printf(heap: &[u8], registers: &[i32]) {",
+        "    let format = heap_string(heap, registers[0] as u32 as usize);",
+        "    let mut args = registers[1..].iter();",
+        "    let mut out = String::new();",
+        "    let mut chars = format.chars();",
+        "    while let Some(c) = chars.next() {",
+        "        if c != '%' {",
+        "            out.push(c);",
+        "            continue;",
+        "        }",
+        "        match chars.next() {",
+        "            Some('%') => out.push('%'),",
+        "            Some(conversion) if \"ducxs\".contains(conversion) => match args.next() {",
+        "                Some(&v) => match conversion {",
+        "                    'd' => out.push_str(&v.to_string()),",
+        "                    'u' => out.push_str(&(v as u32).to_string()),",
+        "                    'x' => out.push_str(&format!(\"{:x}\", v as u32)),",
+        "                    'c' => out.push(if (0..128).contains(&v) { v as u8 as char } else { '?' }),",
+        "                    _ => out.push_str(&heap_string(heap, v as u32 as usize)),",
+        "                },",
+        "                None => {",
+        "                    out.push('%');",
+        "                    out.push(conversion);",
+        "                },",
+        "            },",
+        "            Some(other) => {",
+        "                out.push('%');",
+        "                out.push(other);",
+        "            },",
+        "            None => out.push('%'),",
+        "        }",
+        "    }",
+        "    print!(\"{}\", out);",
+        "}",
+        "",
+        "/// LOG, written to stderr as the program has no log pipeline to route it to",
+        "fn log(heap: &[u8], addr: i32, severity: i32, pc: usize) {",
+        "    let level = [\"ERROR\", \"WARN\", \"INFO\", \"DEBUG\", \"TRACE\"][severity.clamp(0, 4) as usize];",
+        "    eprintln!(\"{} guest: [vm 0] pc {:04x}: {}\", level, pc, heap_string(heap, addr as u32 as usize));",
+        "}",
+        "",
+        "",
+    ].join("\n"));
+    let counted = reads_counters(cfg);
+    for block in &cfg.blocks {
+        write_block(&mut out, block, counted);
+        out.push('\n');
+    }
+    out.push_str(&[
+        "fn main() {",
+        &format!("    let mut s = State {{ registers: [0; {0}], float_registers: [0.0; {0}], remainder: 0, heap: vec![0; HEAP_SIZE], counters: [0; 3], handlers: vec![] }};", REGISTER_COUNT),
+        &format!("    let mut pc = {};", cfg.entry()),
+        "    let result = loop {",
+        "        let next = match pc {",
+        "",
+    ].join("\n"));
+    for block in &cfg.blocks {
+        let _ = writeln!(out, "            0x{0:04x} => block_{0:04x}(&mut s),", block.start);
+    }
+    out.push_str(&[
+        "            _ if pc >= PROGRAM_LEN => Ok(None),",
+        "            _ => Err(format!(\"jump to offset {}, which does not start a block\", pc)),",
+        "        };",
+        "        match next {",
+        "            Ok(Some(target)) => pc = target,",
+        "            Ok(None) => break Ok(()),",
+        "            Err(e) => break Err(e),",
+        "        }",
+        "    };",
+        "    println!(\"remainder: {}\", s.remainder);",
+        "    println!(\"registers:\");",
+        "    for (i, value) in s.registers.iter().enumerate().filter(|(_, v)| **v != 0) {",
+        "        println!(\"  ${} = {}\", i, value);",
+        "    }",
+        "    println!(\"float_registers:\");",
+        "    for (i, value) in s.float_registers.iter().enumerate().filter(|(_, v)| v.to_bits() != 0) {",
+        "        println!(\"  $f{} = {:?}\", i, value);",
+        "    }",
+        "    if let Err(e) = result {",
+        "        eprintln!(\"error: {}\", e);",
+        "        std::process::exit(1);",
+        "    }",
+        "}",
+        "",
+    ].join("\n"));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process::Command;
+    use crate::aot::{transpile, Target};
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_transpile_blocks() {
+        let program = Assembler::new().assemble("load $0 #12\nload $1 #1\njeq $0 $1\nhlt").unwrap();
+        let source = transpile(&program, Endianness::Big, 0, Target::Rust).unwrap();
+        assert!(source.contains("fn block_0000(s: &mut State) -> Next {\n    let mut r0 = s.registers[0];"));
+        assert!(source.contains("    // 0008: jeq $0 $1\n    if r1 == 1 {\n"));
+        assert!(source.contains("fn block_000c(s: &mut State) -> Next {\n    // 000c: hlt\n    Ok(None)\n}"));
+        assert!(source.contains("            0x000c => block_000c(&mut s),"));
+        assert!(source.contains("    let mut pc = 0;\n"));
+        let (program, entry) = Assembler::new().assemble_with_entry("load $1 #1\n.entry @main\nmain: load $1 #9\nhlt").unwrap();
+        let source = transpile(&program, Endianness::Big, entry, Target::Rust).unwrap();
+        assert!(source.contains("    let mut pc = 4;\n"));
+        assert!(source.contains("            0x0004 => block_0004(&mut s),"));
+    }
+
+    #[test]
+    fn test_compiled_program_matches_vm() {
+        let src = [
+            "load $0 #0", "load $1 #1", "load $2 #101", "load $3 #1", "load $4 #20",
+            "add $0 $1 $0", "add $1 $3 $1", "lt $1 $2 $5", "jeq $4 $5",
+            "load $6 #7", "div $0 $6 $7", "rdcnt #0 $8", "rdcnt #1 $9",
+            "pushtrap @caught $10", "div $0 $11 $11", "hlt", "caught: hlt",
+        ].join("\n");
+        let program = Assembler::new().assemble(&src).unwrap();
+        let dir = std::env::temp_dir().join(format!("aot-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("prog.rs"), transpile(&program, Endianness::Big, 0, Target::Rust).unwrap()).unwrap();
+        let status = Command::new("rustc").arg("-O").arg("-o").arg(dir.join("prog")).arg(dir.join("prog.rs")).status().unwrap();
+        assert!(status.success());
+        let output = Command::new(dir.join("prog")).output().unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), [
+            "remainder: 3",
+            "registers:",
+            "  $0 = 5050", "  $1 = 101", "  $2 = 101", "  $3 = 1", "  $4 = 20", "  $6 = 7", "  $7 = 721", "  $8 = 408", "  $9 = 100", "  $10 = 1",
+            "float_registers:",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_compiled_program_aborts() {
+        let program = Assembler::new().assemble("load $0 #20333\nload $1 #0\nsw $0 $1 #0\nload $2 #2\nabort $2").unwrap();
+        let dir = std::env::temp_dir().join(format!("aot-abort-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("prog.rs"), transpile(&program, Endianness::Big, 0, Target::Rust).unwrap()).unwrap();
+        let status = Command::new("rustc").arg("-o").arg(dir.join("prog")).arg(dir.join("prog.rs")).status().unwrap();
+        assert!(status.success());
+        let output = Command::new(dir.join("prog")).output().unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(String::from_utf8(output.stderr).unwrap(), "error: program aborted at pc 16: Om\n");
+    }
+}
@@ -0,0 +1,207 @@
+use std::convert::{TryFrom, TryInto};
+use crate::bytecode::Endianness;
+use crate::instruction::{Instruction, Opcode, Operand, INSTRUCTION_SIZE};
+use crate::lexer::{is_identifier, parse_integer, AssemblerError};
+use crate::relocation::Relocation;
+
+/// Highest heap address the init code can reach, LOAD taking a 16-bit immediate
+pub const MAX_DATA_SIZE: usize = 1 << 16;
+
+/// A source line starting with `.`
+#[derive(Debug, PartialEq, Clone)]
+pub enum Directive {
+    /// `.data`: the following lines declare constants
+    Data,
+    /// `.code`: the following lines hold instructions, the default
+    Code,
+    /// `.asciiz "text"`: a NUL-terminated string, with the `\n \t \0 \\ \"` escapes
+    Asciiz(Vec<u8>),
+    /// `.word 1, -2, 0xff, 'A'`: 32-bit integers, separated by commas or spaces
+    Word(Vec<u32>),
+    /// `.entry @label`: the instruction execution starts from
+    Entry(String),
+    /// `.equ NAME 100`, or `.const`: a constant the following lines can use as `#NAME`
+    Equ(String, i32),
+}
+
+impl Directive {
+    pub fn parse(line: &str) -> Result<Directive, AssemblerError> {
+        let (name, args) = line.split_at(line.find(char::is_whitespace).unwrap_or(line.len()));
+        let args = args.trim();
+        let invalid = || AssemblerError::InvalidDirective(line.to_string());
+        match name {
+            ".data" | ".code" if !args.is_empty() => Err(invalid()),
+            ".data" => Ok(Directive::Data),
+            ".code" => Ok(Directive::Code),
+            ".asciiz" => string(args).map(Directive::Asciiz).ok_or_else(invalid),
+            ".word" => {
+                let words: Option<Vec<u32>> = args.split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|word| !word.is_empty())
+                    .map(word)
+                    .collect();
+                words.filter(|words| !words.is_empty()).map(Directive::Word).ok_or_else(invalid)
+            },
+            ".entry" => args.strip_prefix('@')
+                .filter(|label| is_identifier(label))
+                .map(|label| Directive::Entry(label.to_string()))
+                .ok_or_else(invalid),
+            ".equ" | ".const" => match args.split_whitespace().collect::<Vec<&str>>()[..] {
+                [name, value] if is_identifier(name) => parse_integer(value).and_then(|v| i32::try_from(v).ok())
+                    .map(|value| Directive::Equ(name.to_string(), value))
+                    .ok_or_else(invalid),
+                _ => Err(invalid()),
+            },
+            _ => Err(AssemblerError::UnknownDirective(name.to_string())),
+        }
+    }
+
+    /// Bytes the directive adds to the data section, padded to a whole number of words
+    pub fn bytes(&self, endianness: Endianness) -> Vec<u8> {
+        let mut bytes = match self {
+            Directive::Asciiz(text) => text.iter().copied().chain(Some(0)).collect(),
+            Directive::Word(words) => words.iter().flat_map(|w| endianness.word_to_bytes(*w)).collect(),
+            Directive::Data | Directive::Code | Directive::Entry(_) | Directive::Equ(..) => vec![],
+        };
+        bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+        bytes
+    }
+}
+
+/// The arguments of a line holding the directive `name`, `None` for other lines
+pub fn args<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let rest = line.strip_prefix(name)?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+/// The text between the quotes of a string literal, escapes replaced
+pub fn string(src: &str) -> Option<Vec<u8>> {
+    let inner = src.strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        out.push(match c {
+            '"' => return None,
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                '0' => '\0',
+                c @ ('\\' | '"') => c,
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(out.into_bytes())
+}
+
+/// A word written like an integer operand, negative ones in two's complement
+fn word(src: &str) -> Option<u32> {
+    match parse_integer(src)? {
+        v if (i32::MIN as i64..0).contains(&v) => Some(v as i32 as u32),
+        v => u32::try_from(v).ok(),
+    }
+}
+
+fn load(register: u8, value: u16) -> Instruction {
+    Instruction::with_operands(Opcode::LOAD, [Operand::Register(register), Operand::Integer(value), Operand::None])
+}
+
+fn arithmetic(opcode: Opcode, left: u8, right: u8, destination: u8) -> Instruction {
+    Instruction::with_operands(opcode, [Operand::Register(left), Operand::Register(right), Operand::Register(destination)])
+}
+
+/// Instructions writing `value` into `$0`. LOAD only takes 16 bits, so larger values are built
+/// from their high half, shifted with two multiplications by 256, then their low half. A high
+/// half of 0x8000 or more is loaded as its negation, the sum then wrapping like the word does.
+fn load_word(value: u32) -> Vec<Instruction> {
+    let (high, low) = ((value >> 16) as u16, value as u16);
+    if high == 0 {
+        return vec![load(0, low)];
+    }
+    let mut code = match high {
+        0..=0x7fff => vec![load(0, high)],
+        _ => vec![load(0, high.wrapping_neg()), load(1, 0), arithmetic(Opcode::SUB, 1, 0, 0)],
+    };
+    code.extend([load(1, 256), arithmetic(Opcode::MUL, 0, 1, 0), arithmetic(Opcode::MUL, 0, 1, 0)]);
+    if low != 0 {
+        code.extend([load(1, low), arithmetic(Opcode::ADD, 0, 1, 0)]);
+    }
+    code
+}
+
+/// The instructions of `init_code`, each with the address of the word it stores, and their
+/// relocations
+pub struct InitCode {
+    pub code: Vec<(usize, Instruction)>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// Code copying the data section into the heap from address 0, run before the program. Every
+/// non-zero word is built in `$0` and stored with SW relative to `$2`, which holds the start of
+/// the current 256-byte window. `$0` to `$2` are cleared at the end, unless the program has an
+/// `entry` point, the offset of an instruction following the init code: the init code then ends
+/// jumping there with `$2`. The LOADs of the window starts and of the entry point are relocated.
+/// `data` must be a whole number of words within `MAX_DATA_SIZE`.
+pub fn init_code(data: &[u8], endianness: Endianness, entry: Option<usize>) -> Result<InitCode, AssemblerError> {
+    let mut code = vec![];
+    let mut relocations = vec![];
+    let mut window = None;
+    for (i, chunk) in data.chunks(4).enumerate() {
+        let addr = i * 4;
+        let value = endianness.word_from_bytes(chunk.try_into().expect("the data section holds whole words"));
+        if value == 0 {
+            continue;
+        }
+        let base = addr & !0xff;
+        if window != Some(base) {
+            relocations.push(Relocation::Data(code.len() * INSTRUCTION_SIZE));
+            code.push((addr, load(2, base as u16)));
+            window = Some(base);
+        }
+        code.extend(load_word(value).into_iter().map(|instruction| (addr, instruction)));
+        let store = Instruction::with_operands(Opcode::SW, [Operand::Register(0), Operand::Register(2), Operand::Byte((addr - base) as u8)]);
+        code.push((addr, store));
+    }
+    let addr = match code.last() {
+        Some(&(addr, _)) => addr,
+        None => return Ok(InitCode { code: code, relocations: relocations }),
+    };
+    code.extend((0..2).map(|register| (addr, load(register, 0))));
+    match entry {
+        Some(entry) => {
+            let target = (code.len() + 2) * INSTRUCTION_SIZE + entry;
+            let value = u16::try_from(target).map_err(|_| AssemblerError::OutOfRange {
+                opcode: Opcode::LOAD, position: 2, value: target as i32, min: -0x8000, max: 0xffff,
+            })?;
+            relocations.push(Relocation::Code(code.len() * INSTRUCTION_SIZE));
+            code.push((addr, load(2, value)));
+            code.push((addr, Instruction::with_operands(Opcode::JMP, [Operand::Register(2), Operand::None, Operand::None])));
+        },
+        None => code.push((addr, load(2, 0))),
+    }
+    Ok(InitCode { code: code, relocations: relocations })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Directive::parse(".asciiz \"a\\tb\\\"\\n\""), Ok(Directive::Asciiz(b"a\tb\"\n".to_vec())));
+        assert_eq!(Directive::parse(".word 1, -1 0xffffffff 'A'"), Ok(Directive::Word(vec![1, 0xffff_ffff, 0xffff_ffff, 65])));
+        assert_eq!(Directive::parse(".data"), Ok(Directive::Data));
+        assert_eq!(Directive::parse(".entry @_start"), Ok(Directive::Entry("_start".to_string())));
+        assert_eq!(Directive::parse(".entry start").unwrap_err().to_string(), "invalid directive '.entry start'");
+        assert_eq!(Directive::parse(".const NEWLINE '\\n'"), Ok(Directive::Equ("NEWLINE".to_string(), 10)));
+        assert_eq!(Directive::parse(".equ 1MAX 100").unwrap_err().to_string(), "invalid directive '.equ 1MAX 100'");
+        assert_eq!(Directive::parse(".word 1 x").unwrap_err().to_string(), "invalid directive '.word 1 x'");
+        assert_eq!(Directive::parse(".asciiz \"a\"b\"").unwrap_err().to_string(), "invalid directive '.asciiz \"a\"b\"'");
+        assert_eq!(Directive::parse(".byte 1").unwrap_err().to_string(), "unknown directive '.byte'");
+        assert_eq!(Directive::Asciiz(b"abcd".to_vec()).bytes(Endianness::Big), b"abcd\0\0\0\0".to_vec());
+    }
+}
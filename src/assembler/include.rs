@@ -0,0 +1,104 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::lexer::{strip_comment, AssemblerError};
+use super::directive;
+
+/// A line holding code or a directive, comments removed, once the included files are inlined
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceLine {
+    /// 1-based line number in the assembled source, that of the `.include` line for the lines of
+    /// an included file
+    pub number: usize,
+    /// For the lines of an included file, every file of the include chain with the 1-based line
+    /// the text comes from in it, outermost first
+    pub origin: Vec<(String, usize)>,
+    /// Columns of whitespace before the code on the line
+    pub indent: usize,
+    pub text: String,
+}
+
+impl SourceLine {
+    /// `error` located at this line, naming the files it was included from
+    pub fn error(&self, error: AssemblerError) -> AssemblerError {
+        let error = self.origin.iter().rev().fold(error, |error, (file, line)| {
+            AssemblerError::Included { file: file.clone(), line: *line, source: Box::new(error) }
+        });
+        error.at_line(self.number, self.indent, &self.text)
+    }
+}
+
+/// The lines of `src`, read from `path` when it comes from a file, with every `.include "file"`
+/// line replaced by the lines of that file. The file is found relative to the directory of the
+/// including one, or to the current directory for a source without a path. A file cannot
+/// include itself, directly or not.
+pub fn expand(src: &str, path: Option<&Path>) -> Result<Vec<SourceLine>, AssemblerError> {
+    let mut lines = vec![];
+    let mut chain: Vec<PathBuf> = path.and_then(|path| fs::canonicalize(path).ok()).into_iter().collect();
+    let dir = path.and_then(Path::parent).unwrap_or_else(|| Path::new(""));
+    inline(src, dir, None, &mut chain, &mut lines)?;
+    Ok(lines)
+}
+
+/// Appends the lines of `src` to `out`. `including` is the `.include` line `src` comes from, with
+/// the file name it gives, and `chain` the canonical paths of the files being included.
+fn inline(src: &str, dir: &Path, including: Option<(&SourceLine, &str)>, chain: &mut Vec<PathBuf>, out: &mut Vec<SourceLine>) -> Result<(), AssemblerError> {
+    for (i, text) in src.lines().enumerate() {
+        let code = strip_comment(text).trim_end();
+        let text = code.trim_start();
+        if text.is_empty() {
+            continue;
+        }
+        let indent = code.chars().count() - text.chars().count();
+        let line = match including {
+            Some((including, file)) => {
+                let mut origin = including.origin.clone();
+                origin.push((file.to_string(), i + 1));
+                SourceLine { number: including.number, origin: origin, indent: indent, text: text.to_string() }
+            },
+            None => SourceLine { number: i + 1, origin: vec![], indent: indent, text: text.to_string() },
+        };
+        let name = match directive::args(text, ".include") {
+            Some(args) => directive::string(args)
+                .and_then(|name| String::from_utf8(name).ok())
+                .ok_or_else(|| line.error(AssemblerError::InvalidDirective(text.to_string())))?,
+            None => {
+                out.push(line);
+                continue;
+            },
+        };
+        let target = dir.join(&name);
+        let failed = |reason: String| line.error(AssemblerError::IncludeFailed { file: name.clone(), reason: reason });
+        let canonical = fs::canonicalize(&target).map_err(|e| failed(e.to_string()))?;
+        if chain.contains(&canonical) {
+            return Err(line.error(AssemblerError::IncludeCycle(name)));
+        }
+        let included = fs::read_to_string(&canonical).map_err(|e| failed(e.to_string()))?;
+        chain.push(canonical);
+        inline(&included, target.parent().unwrap_or(dir), Some((&line, &name)), chain, out)?;
+        chain.pop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let dir = std::env::temp_dir().join(format!("simple-vm-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/a.iasm"), "; helpers\nload $1 #1\n.include \"b.iasm\"").unwrap();
+        fs::write(dir.join("lib/b.iasm"), "\nhlt").unwrap();
+        fs::write(dir.join("lib/loop.iasm"), ".include \"loop.iasm\"").unwrap();
+        let main = dir.join("main.iasm");
+        let lines = expand("load $0 #0\n.include \"lib/a.iasm\"", Some(&main)).unwrap();
+        let texts: Vec<(usize, &str)> = lines.iter().map(|line| (line.number, line.text.as_str())).collect();
+        assert_eq!(texts, vec![(1, "load $0 #0"), (2, "load $1 #1"), (2, "hlt")]);
+        assert_eq!(lines[2].origin, vec![("lib/a.iasm".to_string(), 3), ("b.iasm".to_string(), 2)]);
+        assert_eq!(expand(".include \"lib/loop.iasm\"", Some(&main)).unwrap_err().to_string(),
+            "line 1: lib/loop.iasm, line 1: 'loop.iasm' is already being included");
+        assert!(expand(".include \"missing.iasm\"", Some(&main)).unwrap_err().to_string().starts_with("line 1: unable to include 'missing.iasm': "));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use crate::instruction::Opcode;
+use crate::lexer::{is_identifier, AssemblerError};
+use super::directive;
+use super::include::SourceLine;
+
+/// Deepest nesting of macro invocations, past which a macro is taken to invoke itself
+const MAX_DEPTH: usize = 16;
+
+/// A `.macro name param...` definition
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+    /// Labels declared in the body, renamed on every expansion
+    labels: Vec<String>,
+}
+
+impl Macro {
+    /// A line of the body for the expansion number `expansion`, its `\param` references replaced
+    /// with the arguments and its labels made unique
+    fn instantiate(&self, line: &str, args: &[&str], expansion: usize) -> String {
+        let mut out = String::new();
+        let mut rest = line;
+        while let Some(i) = rest.find('\\') {
+            out.push_str(&rest[..i]);
+            let after = &rest[i + 1..];
+            let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+            match self.params.iter().position(|param| *param == after[..end]) {
+                Some(k) => {
+                    out.push_str(args[k]);
+                    rest = &after[end..];
+                },
+                None => {
+                    out.push('\\');
+                    rest = after;
+                },
+            }
+        }
+        out.push_str(rest);
+        let rename = |word: &str| {
+            let label = word.strip_suffix(':').or_else(|| word.strip_prefix('@'))?;
+            let renamed = format!("{}__{}", label, expansion);
+            self.labels.iter().any(|l| l == label)
+                .then(|| if word.starts_with('@') { format!("@{}", renamed) } else { format!("{}:", renamed) })
+        };
+        out.split(' ').map(|word| rename(word).unwrap_or_else(|| word.to_string())).collect::<Vec<String>>().join(" ")
+    }
+}
+
+/// The name and parameters of a `.macro` directive. A macro cannot be named after an instruction.
+fn parse_header(line: &str, args: &str) -> Result<(String, Vec<String>), AssemblerError> {
+    let mut words = args.split_whitespace();
+    let name = words.next().filter(|name| is_identifier(name) && Opcode::try_from(*name).is_err());
+    let params: Vec<String> = words.map(String::from).collect();
+    let unique = params.iter().enumerate().all(|(i, param)| is_identifier(param) && !params[..i].contains(param));
+    match name {
+        Some(name) if unique => Ok((name.to_string(), params)),
+        _ => Err(AssemblerError::InvalidDirective(line.to_string())),
+    }
+}
+
+/// Appends `line` to `out`, or the body of the macro it invokes, expanded in turn. A label
+/// declared on the invoking line names the first line of the body.
+fn expand_line(line: &str, macros: &HashMap<String, Macro>, expansions: &mut usize, depth: usize, out: &mut Vec<String>) -> Result<(), AssemblerError> {
+    let mut words = line.split_whitespace().peekable();
+    let label = words.next_if(|word| word.ends_with(':'));
+    let (name, definition) = match words.next().and_then(|name| macros.get_key_value(name)) {
+        Some(found) => found,
+        None => {
+            out.push(line.to_string());
+            return Ok(());
+        },
+    };
+    if depth == MAX_DEPTH {
+        return Err(AssemblerError::RecursiveMacro(name.clone()));
+    }
+    let args: Vec<&str> = words.collect();
+    if args.len() != definition.params.len() {
+        return Err(AssemblerError::MacroArguments { name: name.clone(), expected: definition.params.len(), found: args.len() });
+    }
+    *expansions += 1;
+    let expansion = *expansions;
+    out.extend(label.map(String::from));
+    for body_line in &definition.body {
+        expand_line(&definition.instantiate(body_line, &args, expansion), macros, expansions, depth + 1, out)?;
+    }
+    Ok(())
+}
+
+/// The source lines once macros are expanded. `.macro name param...` starts the definition of a
+/// macro, ended by `.endmacro`, and a later `name arg...` line is replaced with its body, where
+/// every `\param` stands for the matching argument. The labels declared in the body get a unique
+/// name on every expansion. The lines of an expansion are located at the invoking line.
+pub fn expand(source: Vec<SourceLine>) -> Result<Vec<SourceLine>, AssemblerError> {
+    let mut macros = HashMap::new();
+    let mut lines = vec![];
+    let mut expansions = 0;
+    let mut source = source.into_iter();
+    while let Some(line) = source.next() {
+        let text = line.text.as_str();
+        let at_line = |error| line.error(error);
+        if let Some(args) = directive::args(text, ".macro") {
+            let (name, params) = parse_header(text, args).map_err(at_line)?;
+            let mut body = vec![];
+            loop {
+                match source.next() {
+                    Some(end) if end.text == ".endmacro" => break,
+                    Some(nested) if directive::args(&nested.text, ".macro").is_some() => {
+                        return Err(nested.error(AssemblerError::InvalidDirective(nested.text.clone())));
+                    },
+                    Some(body_line) => body.push(body_line.text),
+                    None => return Err(at_line(AssemblerError::UnterminatedMacro(name))),
+                }
+            }
+            let labels = body.iter()
+                .filter_map(|line| line.split_whitespace().next()?.strip_suffix(':').map(String::from))
+                .collect();
+            if macros.insert(name.clone(), Macro { params: params, body: body, labels: labels }).is_some() {
+                return Err(at_line(AssemblerError::DuplicateMacro(name)));
+            }
+        } else if directive::args(text, ".endmacro").is_some() {
+            return Err(at_line(AssemblerError::InvalidDirective(text.to_string())));
+        } else {
+            let mut expanded = vec![];
+            expand_line(text, &macros, &mut expansions, 0, &mut expanded).map_err(at_line)?;
+            lines.extend(expanded.into_iter().map(|text| SourceLine { text: text, ..line.clone() }));
+        }
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::include;
+
+    #[test]
+    fn test_expand() {
+        let src = ".macro twice reg\nloop: add \\reg \\reg \\reg ; double\njmp @loop\n.endmacro\nstart: twice $1\ntwice $2\nhlt";
+        let expand = |src| expand(include::expand(src, None).unwrap());
+        let lines: Vec<(usize, String)> = expand(src).unwrap().into_iter().map(|line| (line.number, line.text)).collect();
+        assert_eq!(lines, vec![
+            (5, "start:".to_string()), (5, "loop__1: add $1 $1 $1".to_string()), (5, "jmp @loop__1".to_string()),
+            (6, "loop__2: add $2 $2 $2".to_string()), (6, "jmp @loop__2".to_string()), (7, "hlt".to_string()),
+        ]);
+        assert_eq!(expand(".macro a x\nhlt\n.endmacro\na").unwrap_err().to_string(), "line 4: macro 'a' expects 1 arguments, found 0");
+        assert_eq!(expand(".macro a\na\n.endmacro\na").unwrap_err().to_string(), "line 4: macro 'a' invokes itself");
+        assert_eq!(expand(".macro add\n.endmacro").unwrap_err().to_string(), "line 1: invalid directive '.macro add'");
+        assert_eq!(expand(".macro a\nhlt").unwrap_err().to_string(), "line 1: macro 'a' has no .endmacro");
+    }
+}
@@ -0,0 +1,473 @@
+mod directive;
+mod include;
+mod macros;
+pub mod parser;
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use crate::bytecode::Endianness;
+use crate::instruction::{self, Encode, Opcode, INSTRUCTION_SIZE};
+use crate::lexer::{AssemblerError, AssemblerInstruction, Lexer};
+use crate::relocation::Relocation;
+use self::directive::{InitCode, MAX_DATA_SIZE};
+use self::parser::Program;
+pub use self::directive::Directive;
+pub use self::include::SourceLine;
+
+/// Turns a whole assembly program into the bytecode the VM runs, one 4-byte instruction per
+/// source line. The parser builds the syntax tree of the program, the assembler lays it out and
+/// resolves its labels.
+#[derive(Debug)]
+pub struct Assembler {
+    lexer: Lexer,
+    endianness: Endianness,
+    /// File the source comes from, see `with_source_path`
+    source_path: Option<PathBuf>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Assembler::new()
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler { lexer: Lexer::new(), endianness: Endianness::Big, source_path: None }
+    }
+
+    /// Creates an assembler for a VM with `register_count` registers
+    pub fn with_register_count(register_count: usize) -> Self {
+        Assembler { lexer: Lexer::with_register_count(register_count), ..Assembler::new() }
+    }
+
+    /// Adds register aliases to the default ones, see `Lexer::with_register_aliases`
+    pub fn with_register_aliases(self, aliases: &BTreeMap<String, u8>) -> Self {
+        Assembler { lexer: self.lexer.with_register_aliases(aliases), ..self }
+    }
+
+    /// Lays out the data section for a VM of the given endianness, big endian by default
+    pub fn with_endianness(self, endianness: Endianness) -> Self {
+        Assembler { endianness: endianness, ..self }
+    }
+
+    /// Assembles sources read from `path`, the files they include being found relative to its
+    /// directory rather than to the current one
+    pub fn with_source_path(self, path: &Path) -> Self {
+        Assembler { source_path: Some(path.to_path_buf()), ..self }
+    }
+
+    /// Assembles a whole source text, one instruction per line. Comments, from `;` or `#;` to the
+    /// end of the line, and blank lines are ignored. A line can start with a `name:` label declaration, naming
+    /// the offset of its instruction, or of the next one when it has none. `@name` operands are
+    /// replaced with that offset, labels declared further down included.
+    ///
+    /// Lines after a `.data` directive declare constants with `.asciiz "text"` and `.word 1, 2`
+    /// instead, until a `.code` directive. The data is laid out in the heap from address 0, each
+    /// constant starting on a word boundary, and a label in the data section names the heap
+    /// address of the next constant. The program starts with code writing the data into the
+    /// heap, which must be large enough to hold it, and leaves `$0` to `$2` cleared.
+    ///
+    /// `.equ NAME 100`, or `.const NAME 100`, defines a constant the lines below can use as a
+    /// `#NAME` integer operand. A constant cannot be redefined.
+    ///
+    /// Lines between `.macro name param...` and `.endmacro` define a macro, which a later
+    /// `name arg...` line expands, see `macros::expand`. Expansion comes first, so the labels of
+    /// a macro resolve like any other.
+    ///
+    /// `.include "lib.iasm"` inlines another source file, before macros are expanded. Errors in
+    /// an included file are reported at the `.include` line, followed by the file and line they
+    /// come from.
+    pub fn assemble(&self, src: &str) -> Result<Vec<u8>, AssemblerError> {
+        self.assemble_all(src).map(|assembled| assembled.program)
+    }
+
+    /// Same as `assemble_with_entry`, also returning the 1-based source line of every instruction,
+    /// in order. The code writing the data into the heap comes from the lines declaring it.
+    pub fn assemble_with_lines(&self, src: &str) -> Result<(Vec<u8>, Vec<usize>, usize), AssemblerError> {
+        self.assemble_all(src).map(|assembled| (assembled.program, assembled.lines, assembled.entry))
+    }
+
+    /// Same as `assemble`, also returning the offset execution must start from. A `.entry @label`
+    /// directive, anywhere in the source, makes the program start from the instruction of that
+    /// label rather than the first one. When there is a data section, the code writing it into
+    /// the heap still runs first, then jumps to the entry point, leaving its offset in `$2`, and
+    /// the program starts from offset 0.
+    pub fn assemble_with_entry(&self, src: &str) -> Result<(Vec<u8>, usize), AssemblerError> {
+        self.assemble_all(src).map(|assembled| (assembled.program, assembled.entry))
+    }
+
+    /// Same as `assemble_with_entry`, also returning the relocations of the program: every LOAD
+    /// or PUSHTRAP of a label, and those of the code writing the data into the heap. `VM::link_program`
+    /// adjusts them to load the program after another one.
+    pub fn assemble_relocatable(&self, src: &str) -> Result<Relocatable, AssemblerError> {
+        self.assemble_all(src).map(|assembled| Relocatable {
+            program: assembled.program,
+            entry: assembled.entry,
+            relocations: assembled.relocations,
+            data_size: assembled.data_size,
+        })
+    }
+
+    /// The syntax tree of a whole source text, once the included files are inlined and the macros
+    /// expanded
+    pub fn parse(&self, src: &str) -> Result<Program, AssemblerError> {
+        let source = macros::expand(include::expand(src, self.source_path.as_deref())?)?;
+        parser::parse(&self.lexer, source)
+    }
+
+    fn assemble_all(&self, src: &str) -> Result<Assembled, AssemblerError> {
+        let parsed = self.parse(src)?;
+        let layout = layout(&parsed, self.endianness)?;
+        let entry = match &layout.entry {
+            Some((name, line)) => match layout.symbols.get(name) {
+                Some(Symbol::Code(offset)) => Some(*offset),
+                Some(Symbol::Data(_)) => return Err(line.error(AssemblerError::InvalidEntry(name.clone()))),
+                None => return Err(line.error(AssemblerError::UnknownLabel(name.clone()))),
+            },
+            None => None,
+        };
+        let mut program: Vec<u8> = vec!();
+        let mut numbers = vec![];
+        let InitCode { code: init, mut relocations } = directive::init_code(&layout.data, self.endianness, entry)
+            .map_err(|e| e.at_line(layout.line_of(0), 0, ""))?;
+        for (addr, instruction) in &init {
+            instruction.encode(&mut program);
+            numbers.push(layout.line_of(*addr));
+        }
+        let labels: HashMap<String, Symbol> = layout.symbols.into_iter()
+            .map(|(name, symbol)| match symbol {
+                Symbol::Code(offset) => (name, Symbol::Code(init.len() * INSTRUCTION_SIZE + offset)),
+                symbol => (name, symbol),
+            })
+            .collect();
+        for instruction in parsed.instructions() {
+            let source = parsed.line(instruction);
+            let absolute = matches!(instruction.node.opcode(), Some(Opcode::LOAD | Opcode::PUSHTRAP));
+            let reference = instruction.node.label_usages().find_map(|name| labels.get(name).copied());
+            let mut bytes = resolve_labels(&instruction.node, &labels)
+                .and_then(|inst| inst.compile())
+                .map_err(|e| source.error(e))?;
+            match reference {
+                Some(Symbol::Code(_)) if absolute => relocations.push(Relocation::Code(program.len())),
+                Some(Symbol::Data(_)) if absolute => relocations.push(Relocation::Data(program.len())),
+                _ => (),
+            }
+            program.append(&mut bytes);
+            numbers.push(source.number);
+        }
+        let entry = match entry {
+            Some(offset) if init.is_empty() => offset,
+            _ => 0,
+        };
+        Ok(Assembled { program: program, lines: numbers, entry: entry, relocations: relocations, data_size: layout.data.len() })
+    }
+
+    /// Warnings for the deprecated or renamed mnemonics used by a source text once the included
+    /// files are inlined and the macros expanded, located like errors. None for a source that
+    /// fails to expand, whose assembly reports the error.
+    pub fn deprecations(&self, src: &str) -> Vec<String> {
+        let source = match include::expand(src, self.source_path.as_deref()).and_then(macros::expand) {
+            Ok(source) => source,
+            Err(_) => return vec![],
+        };
+        source.iter()
+            .filter_map(|line| {
+                let mnemonic = line.text.split_whitespace().find(|word| !word.ends_with(':'))?;
+                let warning = instruction::deprecation(mnemonic)?;
+                let files: String = line.origin.iter().map(|(file, number)| format!("{}, line {}: ", file, number)).collect();
+                Some(format!("line {}: {}{}", line.number, files, warning))
+            })
+            .collect()
+    }
+}
+
+/// What `assemble_all` produces
+struct Assembled {
+    program: Vec<u8>,
+    /// Source line of every instruction
+    lines: Vec<usize>,
+    entry: usize,
+    relocations: Vec<Relocation>,
+    data_size: usize,
+}
+
+/// A program assembled by `Assembler::assemble_relocatable`
+#[derive(Debug, PartialEq)]
+pub struct Relocatable {
+    pub program: Vec<u8>,
+    pub entry: usize,
+    pub relocations: Vec<Relocation>,
+    /// Bytes of heap the data section takes, from the data base the program is linked with
+    pub data_size: usize,
+}
+
+/// What a label names: the offset of an instruction in the code written by the user, or the
+/// heap address of a constant
+#[derive(Debug, Copy, Clone)]
+enum Symbol {
+    Code(usize),
+    Data(usize),
+}
+
+/// Where the first pass put every label and constant
+struct Layout<'a> {
+    symbols: HashMap<String, Symbol>,
+    /// The data section, a whole number of words
+    data: Vec<u8>,
+    /// Heap address and source line of every constant, in order
+    constants: Vec<(usize, usize)>,
+    /// Label of the entry point and line declaring it
+    entry: Option<(String, &'a SourceLine)>,
+}
+
+impl Layout<'_> {
+    /// Source line of the constant holding the heap address `addr`
+    fn line_of(&self, addr: usize) -> usize {
+        let index = self.constants.partition_point(|(start, _)| *start <= addr);
+        self.constants[index.saturating_sub(1)].1
+    }
+}
+
+/// First pass over the program: the data section and what every declared label names
+fn layout(program: &Program, endianness: Endianness) -> Result<Layout<'_>, AssemblerError> {
+    let mut layout = Layout { symbols: HashMap::new(), data: vec![], constants: vec![], entry: None };
+    let mut in_data = false;
+    let mut offset = 0;
+    for line in program.statements() {
+        let at_line = |error| line.source.error(error);
+        match line.directive {
+            Some(Directive::Data) => in_data = true,
+            Some(Directive::Code) => in_data = false,
+            Some(_) | None => (),
+        }
+        if let Some(name) = line.label {
+            let symbol = if in_data { Symbol::Data(layout.data.len()) } else { Symbol::Code(offset) };
+            if layout.symbols.insert(name.to_string(), symbol).is_some() {
+                return Err(at_line(AssemblerError::DuplicateLabel(name.to_string())));
+            }
+        }
+        match line.directive {
+            Some(constant @ (Directive::Asciiz(_) | Directive::Word(_))) => {
+                if !in_data {
+                    let src = line.source.text.as_str();
+                    let name = src.split_whitespace().find(|word| word.starts_with('.')).unwrap_or(src);
+                    return Err(at_line(AssemblerError::DataInCode(name.to_string())));
+                }
+                layout.constants.push((layout.data.len(), line.source.number));
+                layout.data.extend(constant.bytes(endianness));
+                if layout.data.len() > MAX_DATA_SIZE {
+                    return Err(at_line(AssemblerError::DataTooLarge(MAX_DATA_SIZE)));
+                }
+            },
+            Some(Directive::Entry(name)) => {
+                if layout.entry.replace((name.clone(), line.source)).is_some() {
+                    return Err(at_line(AssemblerError::DuplicateEntry));
+                }
+            },
+            Some(_) => (),
+            None if line.instruction.is_none() => (),
+            None if in_data => return Err(at_line(AssemblerError::InstructionInData)),
+            None => offset += INSTRUCTION_SIZE,
+        }
+    }
+    Ok(layout)
+}
+
+/// Second pass: replaces the `@name` operands of an instruction with the offset or address their
+/// label names
+fn resolve_labels(instruction: &AssemblerInstruction, labels: &HashMap<String, Symbol>) -> Result<AssemblerInstruction, AssemblerError> {
+    instruction.resolve_labels(|name| labels.get(name).map(|symbol| match symbol {
+        Symbol::Code(offset) | Symbol::Data(offset) => *offset as i32,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::{OperandKind, OPCODES};
+    use crate::vm::VM;
+
+    #[test]
+    fn test_assemble() {
+        let asm = Assembler::new();
+        let src = "; a comment\nload $0 #100 ; the answer\n\n  hlt #; stop\n";
+        assert_eq!(asm.assemble(src), Ok(vec![1, 0, 0, 100, 0, 0, 0, 0]));
+        assert_eq!(asm.assemble("load $0 #1\nload $0 !").unwrap_err().to_string(), "line 2: no matching token for '!'");
+        assert_eq!(Assembler::with_register_count(4).assemble("load $4 #1").unwrap_err().to_string(),
+            "line 1: register '$4' does not exist, the VM has 4 registers");
+    }
+
+    #[test]
+    fn test_assemble_every_opcode() {
+        let src: Vec<String> = OPCODES.iter().map(|info| {
+            let operands = info.operands.iter().filter(|kind| **kind != OperandKind::None).enumerate().map(|(i, kind)| match kind {
+                OperandKind::Register => format!(" ${}", i + 1),
+                _ => format!(" #{}", 200 + i),
+            });
+            info.mnemonic.to_string() + &operands.collect::<String>()
+        }).collect();
+        let program = Assembler::new().assemble(&src.join("\n")).unwrap();
+        assert_eq!(program.len(), OPCODES.len() * INSTRUCTION_SIZE);
+        for (info, bytes) in OPCODES.iter().zip(program.chunks(INSTRUCTION_SIZE)) {
+            assert_eq!(bytes[0], info.byte, "{}", info.mnemonic);
+        }
+        assert_eq!(&program[4..8], [1, 1, 0, 201]);
+    }
+
+    #[test]
+    fn test_deprecated_mnemonics() {
+        let asm = Assembler::new();
+        let src = "load $0 #1\nlmpb $0\nend: lmpb $0";
+        assert_eq!(asm.assemble(src), asm.assemble("load $0 #1\njmpb $0\nend: jmpb $0"));
+        assert_eq!(asm.deprecations(src), vec!["line 2: 'lmpb' was renamed to 'jmpb'".to_string(), "line 3: 'lmpb' was renamed to 'jmpb'".to_string()]);
+        let src = ".macro back reg\nlmpb \\reg\n.endmacro\nload $0 #1\nback $0\nback $0";
+        assert_eq!(asm.deprecations(src), vec!["line 5: 'lmpb' was renamed to 'jmpb'".to_string(), "line 6: 'lmpb' was renamed to 'jmpb'".to_string()]);
+    }
+
+    #[test]
+    fn test_labels() {
+        let asm = Assembler::new();
+        let src = "load $0 @end\nloop:\nload $1 @loop\njmp $0\nend:\nhlt";
+        assert_eq!(asm.assemble(src), Ok(vec![1, 0, 0, 12, 1, 1, 0, 4, 6, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(asm.assemble("jmp @nowhere").unwrap_err().to_string(), "line 1: unknown label 'nowhere'");
+        assert_eq!(asm.assemble("a:\nhlt\na:").unwrap_err().to_string(), "line 3: label 'a' is already declared");
+    }
+
+    #[test]
+    fn test_labels_on_instruction_lines() {
+        let asm = Assembler::new();
+        let src = "load $0 #0\nload $1 #1\nload $2 @loop\nloop: add $0 $1 $0\n  load $3 @done\njmp $2\ndone:  hlt";
+        let (program, lines, _) = asm.assemble_with_lines(src).unwrap();
+        assert_eq!(&program[8..12], [1, 2, 0, 12]);
+        assert_eq!(&program[16..20], [1, 3, 0, 24]);
+        assert_eq!(lines, vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(asm.assemble("loop: hlt\nloop: hlt").unwrap_err().to_string(), "line 2: label 'loop' is already declared");
+        assert_eq!(asm.assemble("hlt\nload $0 @exit\nexit:"), Ok(vec![0, 0, 0, 0, 1, 0, 0, 8]));
+    }
+
+    #[test]
+    fn test_data_section() {
+        let src = [
+            ".data", "greeting: .asciiz \"%d\\n\"", "words:", ".word 70000, -2, 0, -2147483648", "", ".code",
+            "load $0 @greeting", "load $1 @words", "lw $1 $1 #4", "load $2 @end", "jmp $2", "end: sys #5", "hlt",
+        ].join("\n");
+        for endianness in [Endianness::Big, Endianness::Little] {
+            let (program, lines, _) = Assembler::new().with_endianness(endianness).assemble_with_lines(&src).unwrap();
+            assert_eq!(lines.len() * INSTRUCTION_SIZE, program.len());
+            assert_eq!((lines[0], lines[8], lines[lines.len() - 2]), (2, 4, 12));
+            let mut vm = VM::new();
+            vm.set_endianness(endianness);
+            vm.load_program(&program).unwrap();
+            vm.run();
+            assert_eq!(vm.take_output(), "-2\n");
+            assert_eq!(vm.heap_word(4), Some(70000));
+            assert_eq!(vm.heap_word(12), Some(0));
+            assert_eq!(vm.heap_word(16), Some(i32::MIN));
+            assert_eq!(vm.register(2), Ok(program.len() as i32 - 8));
+        }
+    }
+
+    #[test]
+    fn test_directive_errors() {
+        let asm = Assembler::new();
+        assert_eq!(asm.assemble(".data\nhlt").unwrap_err().to_string(), "line 2: instructions belong in the .code section");
+        assert_eq!(asm.assemble("s: .asciiz \"a\"").unwrap_err().to_string(), "line 1: '.asciiz' belongs in the .data section");
+        assert_eq!(asm.assemble(".text").unwrap_err().to_string(), "line 1: unknown directive '.text'");
+        assert_eq!(asm.assemble(".data\na: .word 1\n.code\na: hlt").unwrap_err().to_string(), "line 4: label 'a' is already declared");
+        assert_eq!(asm.assemble(".code\nhlt"), asm.assemble("hlt"));
+    }
+
+    #[test]
+    fn test_relocations() {
+        let asm = Assembler::new();
+        let relocatable = asm.assemble_relocatable("load $1 @end\nload $2 #4\njmpf $2\nend: hlt").unwrap();
+        assert_eq!((relocatable.relocations, relocatable.data_size), (vec![Relocation::Code(0)], 0));
+        let Relocatable { program, entry, relocations, data_size } = asm.assemble_relocatable(".entry @main\n.data\ns: .word 1\n.code\nmain: load $1 @s").unwrap();
+        assert_eq!(data_size, 4);
+        assert_eq!(&program[..4], [1, 2, 0, 0]);
+        assert_eq!((entry, relocations.first(), relocations.last()), (0, Some(&Relocation::Data(0)), Some(&Relocation::Data(program.len() - 4))));
+        assert!(relocations.contains(&Relocation::Code(program.len() - 12)));
+    }
+
+    #[test]
+    fn test_error_location() {
+        let asm = Assembler::new();
+        let location = |src| match asm.assemble(src).unwrap_err() {
+            AssemblerError::Line { line, column, token, .. } => (line, column, token),
+            e => panic!("{} is not located", e),
+        };
+        assert_eq!(location("hlt\n  loop: jmp @end ; forever"), (2, Some(13), Some("@end".to_string())));
+        assert_eq!(location("a: hlt\n\ta: hlt"), (2, Some(2), Some("a:".to_string())));
+        assert_eq!(location("load $0"), (1, None, None));
+        assert_eq!(asm.assemble("\tsw $1 $2 #300").unwrap_err().render(), [
+            "line 1, column 11: operand 3 of 'sw' is out of range: 300 is not within 0..=255",
+            "  |",
+            "1 |  sw $1 $2 #300",
+            "  |           ^^^^ expected a value within 0..=255",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_entry() {
+        let asm = Assembler::new();
+        let src = "helper: load $1 #2\njmp $3\n.entry @main\nmain: load $3 @done\nload $0 @helper\njmp $0\ndone: hlt";
+        let (program, entry) = asm.assemble_with_entry(src).unwrap();
+        assert_eq!(entry, 8);
+        let mut vm = VM::new();
+        vm.load_program(&program).unwrap();
+        vm.set_entry(entry).unwrap();
+        vm.run();
+        assert_eq!((vm.register(1), vm.stats().instructions), (Ok(2), 6));
+
+        let (program, entry) = asm.assemble_with_entry(&format!(".data\n.word 7\n.code\n{}", src)).unwrap();
+        assert_eq!(entry, 0);
+        vm.load_program(&program).unwrap();
+        vm.run();
+        assert_eq!((vm.register(1), vm.heap_word(0)), (Ok(2), Some(7)));
+        assert_eq!(asm.assemble_with_entry("hlt"), Ok((vec![0, 0, 0, 0], 0)));
+        assert_eq!(asm.assemble(".entry @nowhere").unwrap_err().to_string(), "line 1: unknown label 'nowhere'");
+        assert_eq!(asm.assemble(".data\nx: .word 1\n.entry @x").unwrap_err().to_string(), "line 3: entry point 'x' is not the label of an instruction");
+        assert_eq!(asm.assemble("a: hlt\n.entry @a\n.entry @a").unwrap_err().to_string(), "line 3: the entry point is already declared");
+    }
+
+    #[test]
+    fn test_constants() {
+        let asm = Assembler::new();
+        let src = ".equ MAX 0x100\n.data\n.const ANSWER -42\n.code\nload $1 #MAX ; 256\nload $2 #ANSWER";
+        assert_eq!(asm.assemble(src), asm.assemble("load $1 #256\nload $2 #-42"));
+        assert_eq!(asm.assemble("load $1 #MAX\n.equ MAX 1").unwrap_err().to_string(), "line 1: undefined constant 'MAX'");
+        assert_eq!(asm.assemble(".equ MAX 1\n.const MAX 2").unwrap_err().to_string(), "line 2: constant 'MAX' is already defined");
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("simple-vm-asm-include-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.iasm"), ".macro set reg value\nload \\reg \\value\n.endmacro\n.equ ANSWER 42").unwrap();
+        std::fs::write(dir.join("bad.iasm"), "hlt\nload $1 #ANSWER").unwrap();
+        std::fs::write(dir.join("old.iasm"), "load $0 #4\nlmpb $0").unwrap();
+        let asm = Assembler::new().with_source_path(&dir.join("main.iasm"));
+        assert_eq!(asm.assemble(".include \"lib.iasm\"\nset $1 #ANSWER"), Assembler::new().assemble("load $1 #42"));
+        assert_eq!(asm.assemble("hlt\n.include \"bad.iasm\"").unwrap_err().to_string(),
+            "line 2: bad.iasm, line 2: undefined constant 'ANSWER'");
+        assert_eq!(asm.deprecations("hlt\n.include \"old.iasm\""), vec!["line 2: old.iasm, line 2: 'lmpb' was renamed to 'jmpb'".to_string()]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_macros() {
+        // Adds `step` to `acc` three times, the loop label being renamed for every expansion
+        let src = [
+            ".macro add3 acc step", "load $10 #3", "load $11 #1", "loop: add \\acc \\step \\acc", "sub $10 $11 $10",
+            "load $12 #0", "neq $10 $12 $13", "load $14 @loop", "jeq $14 $13", ".endmacro",
+            "load $1 #2", "add3 $0 $1", "load $1 #5", "add3 $0 $1", "hlt",
+        ].join("\n");
+        let program = Assembler::new().assemble(&src).unwrap();
+        let mut vm = VM::new();
+        vm.load_program(&program).unwrap();
+        vm.run();
+        assert_eq!(vm.register(0), Ok(21));
+        assert_eq!(Assembler::new().assemble(".macro m\nload $0 #x\n.endmacro\n\nm").unwrap_err().to_string(), "line 5: undefined constant 'x'");
+    }
+}
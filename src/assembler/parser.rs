@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use crate::lexer::{AssemblerError, AssemblerInstruction, Lexer, Token};
+use super::directive::Directive;
+use super::include::SourceLine;
+
+/// A node of the syntax tree with the index, among the lines of its program, of the line it
+/// comes from
+#[derive(Debug, PartialEq, Clone)]
+pub struct Located<T> {
+    pub line: usize,
+    pub node: T,
+}
+
+/// What a line declares, in the order `Program::statements` walks them
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Statement<'a> {
+    pub source: &'a SourceLine,
+    pub label: Option<&'a str>,
+    pub directive: Option<&'a Directive>,
+    pub instruction: Option<&'a AssemblerInstruction>,
+}
+
+/// The syntax tree of a whole source text once its included files are inlined and its macros
+/// expanded: the instructions, their `@name` operands not resolved yet, the declared labels and
+/// the directives, each located at its line. `#NAME` operands are already replaced with the
+/// value of their constant.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Program {
+    lines: Vec<SourceLine>,
+    instructions: Vec<Located<AssemblerInstruction>>,
+    labels: Vec<Located<String>>,
+    directives: Vec<Located<Directive>>,
+}
+
+impl Program {
+    /// The lines holding code or a directive, which the nodes refer to by index
+    pub fn lines(&self) -> &[SourceLine] {
+        &self.lines
+    }
+
+    pub fn instructions(&self) -> &[Located<AssemblerInstruction>] {
+        &self.instructions
+    }
+
+    /// The `name:` declarations, naming the instruction or constant of their line, or of the next
+    /// line holding one
+    pub fn labels(&self) -> &[Located<String>] {
+        &self.labels
+    }
+
+    pub fn directives(&self) -> &[Located<Directive>] {
+        &self.directives
+    }
+
+    /// The line `node` comes from
+    pub fn line<T>(&self, node: &Located<T>) -> &SourceLine {
+        &self.lines[node.line]
+    }
+
+    /// The label, directive and instruction of every line, in source order
+    pub fn statements(&self) -> impl Iterator<Item = Statement<'_>> {
+        let mut labels = self.labels.iter().peekable();
+        let mut directives = self.directives.iter().peekable();
+        let mut instructions = self.instructions.iter().peekable();
+        self.lines.iter().enumerate().map(move |(i, source)| Statement {
+            source: source,
+            label: labels.next_if(|label| label.line == i).map(|label| label.node.as_str()),
+            directive: directives.next_if(|directive| directive.line == i).map(|directive| &directive.node),
+            instruction: instructions.next_if(|instruction| instruction.line == i).map(|instruction| &instruction.node),
+        })
+    }
+}
+
+/// Parses expanded source lines into a program. Constants are replaced as the lines go, so they
+/// must be defined before they are used.
+pub fn parse(lexer: &Lexer, lines: Vec<SourceLine>) -> Result<Program, AssemblerError> {
+    let mut program = Program { lines: vec![], instructions: vec![], labels: vec![], directives: vec![] };
+    let mut constants = HashMap::new();
+    for (i, line) in lines.into_iter().enumerate() {
+        let parsed = parse_line(lexer, &line.text, &mut constants).map_err(|e| line.error(e))?;
+        program.labels.extend(parsed.label.map(|name| Located { line: i, node: name }));
+        program.directives.extend(parsed.directive.map(|directive| Located { line: i, node: directive }));
+        program.instructions.extend(parsed.instruction.map(|instruction| Located { line: i, node: instruction }));
+        program.lines.push(line);
+    }
+    Ok(program)
+}
+
+/// What `parse_line` finds on a line: a directive or an instruction, or neither when the line
+/// only declares a label
+struct ParsedLine {
+    label: Option<String>,
+    directive: Option<Directive>,
+    instruction: Option<AssemblerInstruction>,
+}
+
+fn parse_line(lexer: &Lexer, line: &str, constants: &mut HashMap<String, i32>) -> Result<ParsedLine, AssemblerError> {
+    if let Some((label, directive)) = split_directive(line) {
+        let label = match label.map(|label| lexer.parse_str(label)).transpose()? {
+            Some(Token::LabelDeclaration(name)) => Some(name),
+            Some(_) => return Err(AssemblerError::InvalidDirective(directive.to_string())),
+            None => None,
+        };
+        let directive = Directive::parse(directive)?;
+        if let Directive::Equ(name, value) = &directive {
+            if constants.insert(name.clone(), *value).is_some() {
+                return Err(AssemblerError::DuplicateConstant(name.clone()));
+            }
+        }
+        return Ok(ParsedLine { label: label, directive: Some(directive), instruction: None });
+    }
+    let mut tokens = lexer.tokenize_with_constants(line, constants)?;
+    let label = match tokens.first() {
+        Some(Token::LabelDeclaration(name)) => Some(name.clone()),
+        _ => None,
+    };
+    if label.is_some() {
+        tokens.remove(0);
+    }
+    let instruction = match tokens.is_empty() {
+        true => None,
+        false => Some(AssemblerInstruction::from_tokens(line, tokens)?),
+    };
+    Ok(ParsedLine { label: label, directive: None, instruction: instruction })
+}
+
+/// The optional label and the directive of a line holding one, `None` for other lines
+fn split_directive(line: &str) -> Option<(Option<&str>, &str)> {
+    if line.starts_with('.') {
+        return Some((None, line));
+    }
+    let end = line.find(char::is_whitespace)?;
+    let (label, rest) = line.split_at(end);
+    let rest = rest.trim_start();
+    if label.ends_with(':') && rest.starts_with('.') {
+        Some((Some(label), rest))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::instruction::Opcode;
+
+    #[test]
+    fn test_parse() {
+        let src = ".equ N 3\n.data\nmsg: .asciiz \"hi\"\n.code\n\nstart:\n  load $1 #N ; count\nloop: jmp @loop";
+        let program = Assembler::new().parse(src).unwrap();
+        assert_eq!(program.lines().len(), 7);
+        let labels: Vec<(usize, &str)> = program.labels().iter().map(|label| (label.line, label.node.as_str())).collect();
+        assert_eq!(labels, vec![(2, "msg"), (4, "start"), (6, "loop")]);
+        assert_eq!(program.directives()[2], Located { line: 2, node: Directive::Asciiz(b"hi".to_vec()) });
+        let load = &program.instructions()[0];
+        assert_eq!((program.line(load).number, load.node.opcode()), (7, Some(Opcode::LOAD)));
+        assert_eq!(load.node.compile(), Ok(vec![1, 1, 0, 3]));
+        assert_eq!(program.instructions()[1].node.label_usages().collect::<Vec<&str>>(), vec!["loop"]);
+        let statements: Vec<(Option<&str>, bool, bool)> = program.statements()
+            .map(|statement| (statement.label, statement.directive.is_some(), statement.instruction.is_some()))
+            .collect();
+        assert_eq!(statements[2..], [(Some("msg"), true, false), (None, true, false), (Some("start"), false, false), (None, false, true), (Some("loop"), false, true)]);
+        assert_eq!(Assembler::new().parse("hlt\nload $1 #N").unwrap_err().to_string(), "line 2: undefined constant 'N'");
+        assert_eq!(Assembler::new().parse("hlt $1 $2 $3 $4").unwrap_err().to_string(), "line 1: invalid instruction 'hlt $1 $2 $3 $4', too many arguments");
+    }
+}
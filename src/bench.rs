@@ -0,0 +1,87 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+use crate::config::Config;
+use crate::assembler::Assembler;
+use crate::runner;
+use crate::vm::VM;
+
+/// Runs of each execution mode timed by the `bench` subcommand
+pub const DEFAULT_ITERATIONS: usize = 20;
+
+/// Workload of the `bench` subcommand when no program is given: a loop counting to 30000
+const COUNTING_LOOP: &str = "load $0 #0\nload $1 #1\nload $2 #30000\nload $3 #16\nadd $0 $1 $0\nlt $0 $2 $4\njeq $3 $4\nhlt";
+
+/// Instructions executed and time spent by the runs of one execution mode
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub struct Timing {
+    pub instructions: u64,
+    pub elapsed: Duration,
+}
+
+impl Timing {
+    pub fn ns_per_instruction(&self) -> f64 {
+        match self.instructions {
+            0 => 0.0,
+            n => self.elapsed.as_nanos() as f64 / n as f64,
+        }
+    }
+}
+
+/// Runs the program of `vm` `iterations` times, each from a reset state. The program must halt.
+pub fn time_runs(vm: &mut VM, iterations: usize) -> Timing {
+    let mut timing = Timing::default();
+    for _ in 0..iterations {
+        vm.reset();
+        let start = Instant::now();
+        vm.run();
+        timing.elapsed += start.elapsed();
+        timing.instructions += vm.stats().instructions;
+    }
+    timing
+}
+
+/// The `bench [file] [--iterations <n>]` subcommand: times the program, a counting loop by
+/// default, with the checked fetch then in trusted mode, and prints the speedup of the latter.
+/// The program must halt within the step limit of `config`. Only release builds give
+/// meaningful numbers.
+pub fn bench_file(path: Option<&Path>, iterations: usize, config: &Config) -> Result<(), String> {
+    let mut vm = config.vm_builder().trusted(false).build();
+    match path {
+        Some(path) => runner::load_file(&mut vm, path, config)?,
+        None => {
+            let program = Assembler::new().assemble(COUNTING_LOOP).map_err(|e| e.to_string())?;
+            vm.load_program(&program).map_err(|e| e.to_string())?;
+        },
+    }
+    // Also warms up the caches before the timed runs
+    if runner::run_traced(&mut vm, None, config.max_steps()).exit_code == runner::EXIT_STEP_LIMIT {
+        return Err(format!("The program does not halt within {} steps", config.max_steps()));
+    }
+    let safe = time_runs(&mut vm, iterations);
+    vm.set_trusted(true);
+    let trusted = time_runs(&mut vm, iterations);
+    for (mode, timing) in [("checked", safe), ("trusted", trusted)] {
+        println!("{}: {} instructions in {:?}, {:.2} ns/instruction", mode, timing.instructions, timing.elapsed, timing.ns_per_instruction());
+    }
+    if trusted.ns_per_instruction() > 0.0 {
+        println!("speedup: {:.2}x", safe.ns_per_instruction() / trusted.ns_per_instruction());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_runs() {
+        let mut vm = VM::new();
+        vm.load_program(&Assembler::new().assemble(COUNTING_LOOP).unwrap()).unwrap();
+        let safe = time_runs(&mut vm, 2);
+        vm.set_trusted(true);
+        let trusted = time_runs(&mut vm, 2);
+        assert_eq!((safe.instructions, trusted.instructions), (2 * 90_005, 2 * 90_005));
+        assert_eq!(vm.register(0), Ok(30000));
+        assert_eq!(Timing::default().ns_per_instruction(), 0.0);
+    }
+}
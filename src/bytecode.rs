@@ -0,0 +1,382 @@
+use std::convert::{TryFrom, TryInto};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::instruction::{Extension, Opcode, OperandKind, INSTRUCTION_SIZE};
+
+/// Magic number opening every bytecode file
+pub const MAGIC: [u8; 4] = *b"EPIE";
+/// Current version of the bytecode format
+pub const VERSION: u8 = 1;
+/// Size of the header: magic, version, flags, capabilities and a reserved byte
+pub const HEADER_SIZE: usize = 8;
+
+/// Flag bit set when the file uses little-endian byte order
+const FLAG_LITTLE_ENDIAN: u8 = 0b1;
+/// Flag bit set when the program uses the compact instruction encoding
+const FLAG_COMPACT: u8 = 0b10;
+/// Flag bit set when the header is followed by the entry point of the program
+const FLAG_ENTRY: u8 = 0b100;
+/// Size of the entry point, a word in the declared byte order
+const ENTRY_SIZE: usize = 4;
+/// Flag bit set when the file ends with a signature, see `signing`
+const FLAG_SIGNED: u8 = 0b1000;
+/// Size of the signature ending a signed file: the ed25519 signature of everything before it,
+/// then the public key it verifies with
+pub const SIGNATURE_SIZE: usize = 96;
+
+/// Byte order of the 16-bit LOAD and SYS immediates and of the heap words accessed by LW/SW
+#[derive(Debug, PartialEq, Copy, Clone, Default, Serialize, Deserialize)]
+pub enum Endianness {
+    #[default]
+    Big,
+    Little,
+}
+
+impl Endianness {
+    pub fn parse(src: &str) -> Result<Endianness, String> {
+        match src {
+            "big" => Ok(Endianness::Big),
+            "little" => Ok(Endianness::Little),
+            _ => Err(format!("Unknown endianness '{}', expected big or little", src))
+        }
+    }
+
+    /// Encodes a heap word
+    pub fn word_to_bytes(&self, value: u32) -> [u8; 4] {
+        match self {
+            Endianness::Big => value.to_be_bytes(),
+            Endianness::Little => value.to_le_bytes(),
+        }
+    }
+
+    /// Decodes a heap word
+    pub fn word_from_bytes(&self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Endianness::Big => u32::from_be_bytes(bytes),
+            Endianness::Little => u32::from_le_bytes(bytes),
+        }
+    }
+}
+
+/// How the instructions following the header are stored
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub enum Encoding {
+    /// Fixed 4-byte instructions, loaded as they are
+    #[default]
+    Standard,
+    /// The opcode followed by its operands only, with 16-bit immediates as LEB128 varints.
+    /// Expanded into the standard form when loaded.
+    Compact,
+}
+
+/// Errors raised when reading a bytecode header
+#[derive(Debug, PartialEq, Copy, Clone, Error)]
+pub enum HeaderError {
+    #[error("missing bytecode header")]
+    MissingMagic,
+    #[error("unsupported bytecode version {0}")]
+    UnsupportedVersion(u8),
+    #[error("the program needs the {0} extension, which this VM was built without")]
+    MissingExtension(Extension),
+    #[error("the program needs unknown extensions {0:#010b}")]
+    UnknownExtensions(u8),
+    #[error("malformed compact instruction at byte {0}")]
+    MalformedCompact(usize),
+    #[error("truncated bytecode header")]
+    Truncated,
+}
+
+/// Header of a bytecode file, declaring how the program that follows is encoded
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub struct Header {
+    pub endianness: Endianness,
+    pub encoding: Encoding,
+    /// Capability bits of the instruction set extensions the program uses, see `Extension::bit`
+    pub extensions: u8,
+    /// Offset of the first instruction to execute in the native program, only stored when not 0
+    pub entry: usize,
+    /// Whether the file ends with a signature, which is not part of the program
+    pub signed: bool,
+}
+
+impl Header {
+    pub fn new(endianness: Endianness) -> Header {
+        Header { endianness: endianness, encoding: Encoding::Standard, extensions: 0, entry: 0, signed: false }
+    }
+
+    pub fn with_encoding(mut self, encoding: Encoding) -> Header {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn with_extensions(mut self, extensions: u8) -> Header {
+        self.extensions = extensions;
+        self
+    }
+
+    pub fn with_entry(mut self, entry: usize) -> Header {
+        self.entry = entry;
+        self
+    }
+
+    pub fn with_signature(mut self, signed: bool) -> Header {
+        self.signed = signed;
+        self
+    }
+
+    /// Checks that this build supports every extension the program declares
+    pub fn check_extensions(&self) -> Result<(), HeaderError> {
+        let missing = self.extensions & !Extension::enabled_mask();
+        if missing == 0 {
+            return Ok(());
+        }
+        match Extension::ALL.iter().find(|e| missing & e.bit() != 0) {
+            Some(extension) => Err(HeaderError::MissingExtension(*extension)),
+            None => Err(HeaderError::UnknownExtensions(missing)),
+        }
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let mut flags = match self.endianness {
+            Endianness::Big => 0,
+            Endianness::Little => FLAG_LITTLE_ENDIAN,
+        };
+        if self.encoding == Encoding::Compact {
+            flags |= FLAG_COMPACT;
+        }
+        if self.entry != 0 {
+            flags |= FLAG_ENTRY;
+        }
+        if self.signed {
+            flags |= FLAG_SIGNED;
+        }
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&[VERSION, flags, self.extensions, 0]);
+        if self.entry != 0 {
+            out.extend_from_slice(&self.endianness.word_to_bytes(self.entry as u32));
+        }
+    }
+
+    /// Splits a bytecode file into its header and program, leaving out the signature of a
+    /// signed file
+    pub fn read(bytes: &[u8]) -> Result<(Header, &[u8]), HeaderError> {
+        if bytes.len() < HEADER_SIZE || bytes[..4] != MAGIC {
+            return Err(HeaderError::MissingMagic);
+        }
+        if bytes[4] != VERSION {
+            return Err(HeaderError::UnsupportedVersion(bytes[4]));
+        }
+        let endianness = if bytes[5] & FLAG_LITTLE_ENDIAN != 0 { Endianness::Little } else { Endianness::Big };
+        let encoding = if bytes[5] & FLAG_COMPACT != 0 { Encoding::Compact } else { Encoding::Standard };
+        let mut header = Header::new(endianness)
+            .with_encoding(encoding)
+            .with_extensions(bytes[6])
+            .with_signature(bytes[5] & FLAG_SIGNED != 0);
+        let mut start = HEADER_SIZE;
+        if bytes[5] & FLAG_ENTRY != 0 {
+            let entry = bytes.get(HEADER_SIZE..HEADER_SIZE + ENTRY_SIZE).ok_or(HeaderError::Truncated)?;
+            header.entry = endianness.word_from_bytes(entry.try_into().expect("the entry point is a word")) as usize;
+            start += ENTRY_SIZE;
+        }
+        let end = if header.signed {
+            bytes.len().checked_sub(SIGNATURE_SIZE).filter(|end| *end >= start).ok_or(HeaderError::Truncated)?
+        } else {
+            bytes.len()
+        };
+        Ok((header, &bytes[start..end]))
+    }
+
+    /// Turns the program following this header into the native form: standard 4-byte instructions
+    /// with big-endian immediates
+    pub fn decode_program(&self, program: &[u8]) -> Result<Vec<u8>, HeaderError> {
+        let mut program = match self.encoding {
+            Encoding::Standard => program.to_vec(),
+            Encoding::Compact => return expand(program),
+        };
+        if self.endianness == Endianness::Little {
+            swap_immediates(&mut program);
+        }
+        Ok(program)
+    }
+}
+
+/// Compacts a program in the native encoding: every instruction keeps its opcode byte and the
+/// bytes of its operands, without padding, and 16-bit immediates become LEB128 varints so small
+/// values take a single byte. Trailing bytes that do not form an instruction are dropped.
+pub fn compact(program: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    for instruction in program.chunks_exact(INSTRUCTION_SIZE) {
+        out.push(instruction[0]);
+        let mut offset = 1;
+        for kind in Opcode::from_byte(instruction[0]).operand_kinds() {
+            match kind {
+                OperandKind::Integer => {
+                    let mut value = u16::from_be_bytes([instruction[offset], instruction[offset + 1]]);
+                    while value >= 0x80 {
+                        out.push((value as u8 & 0x7f) | 0x80);
+                        value >>= 7;
+                    }
+                    out.push(value as u8);
+                },
+                OperandKind::Register | OperandKind::Byte => out.push(instruction[offset]),
+                OperandKind::None => (),
+            }
+            offset += kind.size();
+        }
+    }
+    out
+}
+
+/// Expands a compact program back into standard 4-byte instructions
+pub fn expand(compact: &[u8]) -> Result<Vec<u8>, HeaderError> {
+    let mut out = Vec::with_capacity(compact.len() * 2);
+    let mut bytes = compact.iter().copied().enumerate().peekable();
+    while let Some((start, opcode)) = bytes.next() {
+        let malformed = HeaderError::MalformedCompact(start);
+        let mut instruction = vec![opcode];
+        for kind in Opcode::try_from(opcode).map_err(|_| malformed)?.operand_kinds() {
+            match kind {
+                OperandKind::Integer => {
+                    let mut value: u32 = 0;
+                    for shift in [0, 7, 14] {
+                        let (_, byte) = bytes.next().ok_or(malformed)?;
+                        value |= ((byte & 0x7f) as u32) << shift;
+                        if byte & 0x80 == 0 {
+                            break;
+                        }
+                        if shift == 14 {
+                            return Err(malformed);
+                        }
+                    }
+                    let value = u16::try_from(value).map_err(|_| malformed)?;
+                    instruction.extend_from_slice(&value.to_be_bytes());
+                },
+                OperandKind::Register | OperandKind::Byte => instruction.push(bytes.next().ok_or(malformed)?.1),
+                OperandKind::None => (),
+            }
+        }
+        instruction.resize(INSTRUCTION_SIZE, 0);
+        out.extend_from_slice(&instruction);
+    }
+    Ok(out)
+}
+
+/// Swaps the two bytes of every 16-bit immediate, converting a program between big and little
+/// endian. Instructions with an unknown opcode are left untouched.
+pub fn swap_immediates(program: &mut [u8]) {
+    for instruction in program.chunks_mut(INSTRUCTION_SIZE) {
+        let mut offset = 1;
+        for kind in Opcode::from_byte(instruction[0]).operand_kinds() {
+            if kind == OperandKind::Integer && offset + 1 < instruction.len() {
+                instruction.swap(offset, offset + 1);
+            }
+            offset += kind.size();
+        }
+    }
+}
+
+/// Prepends a header to a program assembled in the native big-endian encoding, converting its
+/// immediates to the requested byte order and declaring the extensions it uses
+pub fn write(program: &[u8], endianness: Endianness) -> Vec<u8> {
+    write_encoded(program, endianness, Encoding::Standard, 0)
+}
+
+/// Same as `write`, storing the instructions with `encoding` and declaring `entry` as the offset
+/// execution starts from. Compact programs have no byte order of their own, `endianness` then
+/// only applies to heap words.
+pub fn write_encoded(program: &[u8], endianness: Endianness, encoding: Encoding, entry: usize) -> Vec<u8> {
+    let mut out = vec![];
+    Header::new(endianness)
+        .with_encoding(encoding)
+        .with_extensions(Extension::required_by(program))
+        .with_entry(entry)
+        .encode(&mut out);
+    let start = out.len();
+    match encoding {
+        Encoding::Standard => out.extend_from_slice(program),
+        Encoding::Compact => out.extend_from_slice(&compact(program)),
+    }
+    if endianness == Endianness::Little && encoding == Encoding::Standard {
+        swap_immediates(&mut out[start..]);
+    }
+    out
+}
+
+/// Reads a bytecode file as a program in the native big-endian encoding, along with the byte
+/// order it declares for heap words and its entry point. Files without a header are taken as
+/// big-endian programs starting from their first instruction.
+pub fn read_program(bytes: &[u8]) -> Result<(Endianness, Vec<u8>, usize), HeaderError> {
+    if !bytes.starts_with(&MAGIC) {
+        return Ok((Endianness::Big, bytes.to_vec(), 0));
+    }
+    let (header, program) = Header::read(bytes)?;
+    Ok((header.endianness, header.decode_program(program)?, header.entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trip() {
+        let bytes = write(&[1, 0, 1, 244, 25, 0, 3, 0, 2, 0, 1, 2], Endianness::Little);
+        assert_eq!(&bytes[..HEADER_SIZE], &[b'E', b'P', b'I', b'E', VERSION, 1, 0, 0]);
+        let (header, program) = Header::read(&bytes).unwrap();
+        assert_eq!(header.endianness, Endianness::Little);
+        assert_eq!(program, &[1, 0, 244, 1, 25, 3, 0, 0, 2, 0, 1, 2]);
+        let bytes = write(&[1, 0, 1, 244], Endianness::Big);
+        let (header, program) = Header::read(&bytes).unwrap();
+        assert_eq!((header.endianness, program), (Endianness::Big, &[1, 0, 1, 244][..]));
+    }
+
+    #[test]
+    fn test_entry() {
+        let program = [1, 0, 0, 1, 0, 0, 0, 0];
+        for endianness in [Endianness::Big, Endianness::Little] {
+            let bytes = write_encoded(&program, endianness, Encoding::Standard, 4);
+            let (header, rest) = Header::read(&bytes).unwrap();
+            assert_eq!((header.entry, header.decode_program(rest).unwrap()), (4, program.to_vec()));
+        }
+        assert_eq!(write_encoded(&program, Endianness::Big, Encoding::Standard, 0), write(&program, Endianness::Big));
+        assert_eq!(Header::read(b"EPIE\x01\x04\x00\x00\x00\x00"), Err(HeaderError::Truncated));
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        assert_eq!(Header::read(&[1, 0, 1, 244]), Err(HeaderError::MissingMagic));
+        assert_eq!(Header::read(b"EPIE\x07\x00\x00\x00"), Err(HeaderError::UnsupportedVersion(7)));
+    }
+
+    #[test]
+    fn test_extensions() {
+        let bytes = write(&[1, 0, 0, 4, 20, 0, 0, 0, 0, 0, 0, 0], Endianness::Big);
+        assert_eq!(bytes[6], Extension::Float.bit());
+        let (header, _) = Header::read(&bytes).unwrap();
+        assert_eq!(header.extensions, Extension::Float.bit());
+        assert_eq!(header.check_extensions().is_ok(), cfg!(feature = "float"));
+        assert_eq!(Header::read(&write(&[0, 0, 0, 0], Endianness::Big)).unwrap().0.extensions, 0);
+        assert_eq!(Header::new(Endianness::Big).with_extensions(0b1000_0000).check_extensions(),
+            Err(HeaderError::UnknownExtensions(0b1000_0000)));
+    }
+
+    #[test]
+    fn test_compact_round_trip() {
+        // load $0 #500, add $0 $1 $2, load $3 #5, jmp $4, hlt
+        let program = [1, 0, 1, 244, 2, 0, 1, 2, 1, 3, 0, 5, 6, 4, 0, 0, 0, 0, 0, 0];
+        assert_eq!(compact(&program), vec![1, 0, 0xf4, 3, 2, 0, 1, 2, 1, 3, 5, 6, 4, 0]);
+        assert_eq!(expand(&compact(&program)), Ok(program.to_vec()));
+        let bytes = write_encoded(&program, Endianness::Little, Encoding::Compact, 0);
+        assert_eq!(bytes[5], FLAG_LITTLE_ENDIAN | FLAG_COMPACT);
+        assert_eq!(read_program(&bytes), Ok((Endianness::Little, program.to_vec(), 0)));
+        let bytes = write_encoded(&program, Endianness::Big, Encoding::Compact, 8);
+        assert_eq!(read_program(&bytes), Ok((Endianness::Big, program.to_vec(), 8)));
+        assert_eq!(expand(&[1, 0, 0xff, 0xff, 0x7f]), Err(HeaderError::MalformedCompact(0)));
+        assert_eq!(expand(&[0, 1, 0]), Err(HeaderError::MalformedCompact(1)));
+    }
+
+    #[test]
+    fn test_words() {
+        assert_eq!(Endianness::Little.word_to_bytes(0x01020304), [4, 3, 2, 1]);
+        assert_eq!(Endianness::Big.word_from_bytes([1, 2, 3, 4]), 0x01020304);
+    }
+}
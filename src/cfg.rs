@@ -0,0 +1,279 @@
+use std::collections::BTreeSet;
+use crate::instruction::{Decode, Instruction, Opcode, Operand, Program, INSTRUCTION_SIZE};
+use crate::verifier::{self, VerifyError};
+
+/// A jump instruction and its target, when the register it reads holds a known constant, or a
+/// PUSHTRAP and its handler
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Jump {
+    pub offset: usize,
+    pub target: Option<i64>,
+    /// Offset of the LOAD that set the register, when the target is known
+    pub load: Option<usize>,
+}
+
+/// A straight-line run of instructions, only entered through its first one
+#[derive(Debug, PartialEq, Clone)]
+pub struct Block {
+    pub start: usize,
+    pub instructions: Vec<(usize, Instruction)>,
+}
+
+impl Block {
+    /// Offset right after the last instruction of the block
+    pub fn end(&self) -> usize {
+        self.start + self.instructions.len() * INSTRUCTION_SIZE
+    }
+}
+
+/// Control-flow graph of a verified program
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    pub jumps: Vec<Jump>,
+    len: usize,
+    entry: usize,
+}
+
+pub fn is_jump(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JEQ)
+}
+
+/// Whether control may not fall through to the next instruction. PUSHTRAP ends its block
+/// like a conditional jump, since a later trap can resume at its handler.
+fn ends_block(opcode: Opcode) -> bool {
+    is_jump(opcode) || matches!(opcode, Opcode::HLT | Opcode::IGL | Opcode::ABORT | Opcode::PUSHTRAP)
+}
+
+/// Computes jump targets by propagating the constants set by LOAD within each basic block.
+/// `leaders` are the offsets starting a block, where nothing is known about the registers.
+/// Any register used by another instruction is conservatively forgotten.
+fn resolve_jumps(instructions: &[(usize, Instruction)], leaders: &BTreeSet<usize>) -> Vec<Jump> {
+    // Value of each register and offset of the LOAD that set it
+    let mut known: [Option<(i64, usize)>; 32] = [None; 32];
+    let mut jumps = vec![];
+    for (offset, instruction) in instructions {
+        if leaders.contains(offset) {
+            known = [None; 32];
+        }
+        let operands = instruction.operands();
+        let (source, load) = match operands[0] {
+            Operand::Register(r) => known.get(r as usize).copied().flatten().unzip(),
+            _ => (None, None),
+        };
+        // JMPF and JMPB are relative to the pc after their register byte
+        let next = *offset as i64 + 2;
+        match instruction.opcode() {
+            Opcode::LOAD => {
+                if let [Operand::Register(r), Operand::Integer(value), _] = *operands {
+                    if let Some(k) = known.get_mut(r as usize) {
+                        *k = Some((value as i64, *offset));
+                    }
+                }
+            },
+            Opcode::JMP | Opcode::JEQ => jumps.push(Jump { offset: *offset, target: source, load: load }),
+            Opcode::JMPF => jumps.push(Jump { offset: *offset, target: source.map(|v| next + v), load: load }),
+            Opcode::JMPB => jumps.push(Jump { offset: *offset, target: source.map(|v| next - v), load: load }),
+            Opcode::PUSHTRAP => {
+                if let Operand::Integer(handler) = operands[0] {
+                    jumps.push(Jump { offset: *offset, target: Some(handler as i64), load: None });
+                }
+            },
+            Opcode::BANKSW => known = [None; 32],
+            _ => {
+                for operand in operands {
+                    if let Operand::Register(r) = operand {
+                        if let Some(k) = known.get_mut(*r as usize) {
+                            *k = None;
+                        }
+                    }
+                }
+            }
+        }
+        if is_jump(instruction.opcode()) {
+            known = [None; 32];
+        }
+    }
+    jumps
+}
+
+fn split(instructions: &[(usize, Instruction)], leaders: &BTreeSet<usize>) -> Vec<Block> {
+    let mut blocks: Vec<Block> = vec![];
+    for (offset, instruction) in instructions {
+        match blocks.last_mut() {
+            Some(block) if !leaders.contains(offset) => block.instructions.push((*offset, *instruction)),
+            _ => blocks.push(Block { start: *offset, instructions: vec![(*offset, *instruction)] }),
+        }
+    }
+    blocks
+}
+
+impl Cfg {
+    /// Verifies a program and splits it into basic blocks. Blocks start at the beginning of the
+    /// program, after every jump or halt and at every statically known jump target.
+    pub fn build(program: &[u8]) -> Result<Cfg, VerifyError> {
+        Cfg::build_with_entry(program, 0)
+    }
+
+    /// Same as `build` for a program starting from the instruction at `entry`, which also
+    /// starts a block
+    pub fn build_with_entry(program: &[u8], entry: usize) -> Result<Cfg, VerifyError> {
+        verifier::verify(program)?;
+        if !entry.is_multiple_of(INSTRUCTION_SIZE) || (entry != 0 && entry >= program.len()) {
+            return Err(VerifyError::InvalidEntry { entry: entry });
+        }
+        let decoded = Program::decode(program).expect("the program was verified");
+        let instructions = decoded.instructions();
+        let mut cfg = Cfg { blocks: vec![], jumps: vec![], len: program.len(), entry: entry };
+
+        let mut leaders: BTreeSet<usize> = instructions.iter()
+            .filter(|(_, i)| ends_block(i.opcode()))
+            .map(|(offset, _)| offset + INSTRUCTION_SIZE)
+            .collect();
+        leaders.insert(0);
+        leaders.insert(entry);
+        let first_pass = resolve_jumps(instructions, &leaders);
+        leaders.extend(first_pass.iter().filter_map(|j| j.target).filter(|t| cfg.is_valid_target(*t)).map(|t| t as usize));
+        cfg.jumps = resolve_jumps(instructions, &leaders);
+        cfg.blocks = split(instructions, &leaders);
+        Ok(cfg)
+    }
+
+    /// The same graph with every instruction in its own block, so that any instruction
+    /// boundary can be jumped to
+    pub fn split_all(&self) -> Cfg {
+        let instructions: Vec<(usize, Instruction)> = self.blocks.iter().flat_map(|b| b.instructions.iter().copied()).collect();
+        let leaders = instructions.iter().map(|(offset, _)| *offset).collect();
+        Cfg { blocks: split(&instructions, &leaders), jumps: self.jumps.clone(), len: self.len, entry: self.entry }
+    }
+
+    /// Offset execution starts from
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// Size of the program in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether `target` is an instruction boundary of the program. Jumping exactly to the end
+    /// of the program is allowed, it halts the VM.
+    pub fn is_valid_target(&self, target: i64) -> bool {
+        target >= 0 && target <= self.len as i64 && (target as usize).is_multiple_of(INSTRUCTION_SIZE)
+    }
+
+    /// Whether every jump has a statically known and valid target
+    pub fn all_targets_known(&self) -> bool {
+        self.jumps.iter().all(|j| j.target.is_some_and(|t| self.is_valid_target(t)))
+    }
+
+    /// The known and valid target of the jump at `offset`
+    pub fn target(&self, offset: usize) -> Option<usize> {
+        self.jumps.iter().find(|j| j.offset == offset)
+            .and_then(|j| j.target)
+            .filter(|t| self.is_valid_target(*t))
+            .map(|t| t as usize)
+    }
+
+    /// Start offsets of the blocks control can reach from `block`. Unknown jump targets and
+    /// jumps to the end of the program are left out.
+    pub fn successors(&self, block: &Block) -> Vec<usize> {
+        let (offset, last) = block.instructions.last().expect("blocks are never empty");
+        let target = self.target(*offset).filter(|t| *t < self.len);
+        let next = Some(block.end()).filter(|n| *n < self.len);
+        match last.opcode() {
+            Opcode::HLT | Opcode::IGL | Opcode::ABORT => vec![],
+            Opcode::JMP | Opcode::JMPF | Opcode::JMPB => target.into_iter().collect(),
+            Opcode::JEQ | Opcode::PUSHTRAP => {
+                let mut successors: Vec<usize> = target.into_iter().chain(next).collect();
+                successors.dedup();
+                successors
+            },
+            _ => next.into_iter().collect(),
+        }
+    }
+
+    /// The block starting at `offset`
+    pub fn block_at(&self, offset: usize) -> Option<&Block> {
+        self.blocks.iter().find(|b| b.start == offset)
+    }
+}
+
+/// Renders the graph in Graphviz DOT. Nodes are labelled with the disassembly of their block,
+/// the taken branch of a JEQ is labelled and jumps whose target is not statically known point
+/// to a dashed `?` node.
+pub fn to_dot(cfg: &Cfg) -> String {
+    let mut out = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+    let mut has_unknown = false;
+    for block in &cfg.blocks {
+        let label: String = block.instructions.iter().map(|(offset, i)| format!("{:04x}: {}\\l", offset, i)).collect();
+        out.push_str(&format!("    b{:04x} [label=\"{}\"];\n", block.start, label));
+    }
+    for block in &cfg.blocks {
+        let (offset, last) = block.instructions.last().expect("blocks are never empty");
+        let (taken, label) = match last.opcode() {
+            Opcode::JEQ => (cfg.target(*offset), "taken"),
+            Opcode::PUSHTRAP => (cfg.target(*offset), "trap"),
+            _ => (None, ""),
+        };
+        for successor in cfg.successors(block) {
+            let attributes = if Some(successor) == taken { format!(" [label=\"{}\"]", label) } else { String::new() };
+            out.push_str(&format!("    b{:04x} -> b{:04x}{};\n", block.start, successor, attributes));
+        }
+        if is_jump(last.opcode()) && cfg.target(*offset).is_none() {
+            has_unknown = true;
+            out.push_str(&format!("    b{:04x} -> unknown [style=dashed];\n", block.start));
+        }
+    }
+    if has_unknown {
+        out.push_str("    unknown [shape=ellipse, style=dashed, label=\"?\"];\n");
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_blocks_and_successors() {
+        let program = Assembler::new().assemble("load $0 #20\nload $1 #1\neq $1 $1 $1\njeq $0 $1\nload $2 #3\nhlt").unwrap();
+        let cfg = Cfg::build(&program).unwrap();
+        let starts: Vec<usize> = cfg.blocks.iter().map(|b| b.start).collect();
+        assert_eq!(starts, vec![0, 16, 20]);
+        assert_eq!(cfg.successors(&cfg.blocks[0]), vec![20, 16]);
+        assert_eq!(cfg.successors(&cfg.blocks[1]), vec![20]);
+        assert!(cfg.successors(&cfg.blocks[2]).is_empty());
+        assert!(cfg.all_targets_known());
+        assert_eq!(cfg.split_all().blocks.len(), 6);
+        let cfg = Cfg::build_with_entry(&program, 8).unwrap();
+        let starts: Vec<usize> = cfg.blocks.iter().map(|b| b.start).collect();
+        assert_eq!((cfg.entry(), starts), (8, vec![0, 8, 16]));
+        assert_eq!(Cfg::build_with_entry(&program, 6), Err(VerifyError::InvalidEntry { entry: 6 }));
+        assert_eq!(Cfg::build_with_entry(&program, 24), Err(VerifyError::InvalidEntry { entry: 24 }));
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let program = Assembler::new().assemble("load $0 #12\nload $1 #1\njeq $0 $1\nhlt\nadd $0 $0 $0\njmp $0").unwrap();
+        assert_eq!(to_dot(&Cfg::build(&program).unwrap()), [
+            "digraph cfg {",
+            "    node [shape=box, fontname=\"monospace\"];",
+            "    b0000 [label=\"0000: load $0 #12\\l0004: load $1 #1\\l0008: jeq $0 $1\\l\"];",
+            "    b000c [label=\"000c: hlt\\l\"];",
+            "    b0010 [label=\"0010: add $0 $0 $0\\l0014: jmp $0\\l\"];",
+            "    b0000 -> b000c [label=\"taken\"];",
+            "    b0010 -> unknown [style=dashed];",
+            "    unknown [shape=ellipse, style=dashed, label=\"?\"];",
+            "}",
+            "",
+        ].join("\n"));
+    }
+}
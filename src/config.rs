@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use ed25519_dalek::VerifyingKey;
+use serde::Deserialize;
+use crate::assembler::Assembler;
+use crate::signing;
+use crate::syscall::{SyscallGroup, SyscallPolicy};
+use crate::test_runner::MAX_STEPS;
+use crate::vm::VMBuilder;
+
+/// Name of the configuration file, read from the home directory then from the current one
+pub const CONFIG_FILE: &str = ".iridium.toml";
+
+/// Startup defaults read from the configuration files. Every setting is optional, a missing
+/// one keeping the built-in default.
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Heap size of new VMs, in bytes
+    pub heap_size: Option<usize>,
+    /// Allocate heap pages on first write, see `VMBuilder::sparse_heap`
+    pub sparse_heap: Option<bool>,
+    /// Register banks of new VMs
+    pub register_banks: Option<usize>,
+    pub trap_on_nan: Option<bool>,
+    /// Fetch the instructions of verified programs unchecked, see `VM::set_trusted`
+    pub trusted: Option<bool>,
+    /// Syscall groups guest programs may not use, such as `["console"]`
+    pub deny_syscalls: Option<Vec<SyscallGroup>>,
+    /// Keep `$0` at zero, see `VM::set_zero_register`
+    pub zero_register: Option<bool>,
+    /// Watchdog of the `run` subcommand: instructions executed before giving up on a program
+    pub max_steps: Option<usize>,
+    /// Wall-clock watchdog of the `run` subcommand in milliseconds, replacing `max_steps`
+    pub timeout_ms: Option<u64>,
+    /// REPL shortcuts, expanding the first word of a line into a command
+    pub aliases: BTreeMap<String, String>,
+    /// Register names usable in assembly, such as `fp = 29` for `$fp`, on top of the default ones
+    pub register_aliases: BTreeMap<String, u8>,
+    /// Public keys, in base64, whose signed bytecode the `run`, `schedule` and `bench`
+    /// subcommands accept. Once set, they refuse any other program, sources included.
+    pub trusted_keys: Option<Vec<String>>,
+}
+
+impl Config {
+    pub fn parse(src: &str) -> Result<Config, String> {
+        toml::from_str(src).map_err(|e| e.to_string())
+    }
+
+    /// Reads a configuration file, an empty configuration if it does not exist
+    pub fn read(path: &Path) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Ok(src) => Config::parse(&src).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(_) => Ok(Config::default()),
+        }
+    }
+
+    /// Reads `~/.iridium.toml`, then the project-local `.iridium.toml` whose settings win
+    pub fn load() -> Result<Config, String> {
+        let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")).map(PathBuf::from);
+        let global = match home {
+            Some(home) => Config::read(&home.join(CONFIG_FILE))?,
+            None => Config::default(),
+        };
+        Ok(global.merge(Config::read(Path::new(CONFIG_FILE))?))
+    }
+
+    /// This configuration with every setting of `other` applied over it
+    pub fn merge(mut self, other: Config) -> Config {
+        self.heap_size = other.heap_size.or(self.heap_size);
+        self.sparse_heap = other.sparse_heap.or(self.sparse_heap);
+        self.register_banks = other.register_banks.or(self.register_banks);
+        self.trap_on_nan = other.trap_on_nan.or(self.trap_on_nan);
+        self.trusted = other.trusted.or(self.trusted);
+        self.deny_syscalls = other.deny_syscalls.or(self.deny_syscalls);
+        self.zero_register = other.zero_register.or(self.zero_register);
+        self.max_steps = other.max_steps.or(self.max_steps);
+        self.timeout_ms = other.timeout_ms.or(self.timeout_ms);
+        self.trusted_keys = other.trusted_keys.or(self.trusted_keys);
+        self.aliases.extend(other.aliases);
+        self.register_aliases.extend(other.register_aliases);
+        self
+    }
+
+    pub fn max_steps(&self) -> usize {
+        self.max_steps.unwrap_or(MAX_STEPS)
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis)
+    }
+
+    /// The decoded trusted keys, `None` when programs need no signature
+    pub fn trusted_keys(&self) -> Result<Option<Vec<VerifyingKey>>, String> {
+        self.trusted_keys.as_ref()
+            .map(|keys| keys.iter().map(|key| signing::parse_verifying_key(key)).collect::<Result<_, _>>())
+            .transpose()
+            .map_err(|e| format!("trusted_keys: {}", e))
+    }
+
+    /// A builder for VMs with the configured defaults
+    pub fn vm_builder(&self) -> VMBuilder {
+        let mut builder = VMBuilder::new();
+        if let Some(size) = self.heap_size {
+            builder = builder.heap_size(size);
+        }
+        if let Some(sparse) = self.sparse_heap {
+            builder = builder.sparse_heap(sparse);
+        }
+        if let Some(banks) = self.register_banks {
+            builder = builder.register_banks(banks);
+        }
+        if let Some(trap) = self.trap_on_nan {
+            builder = builder.trap_on_nan(trap);
+        }
+        if let Some(trusted) = self.trusted {
+            builder = builder.trusted(trusted);
+        }
+        if let Some(zero) = self.zero_register {
+            builder = builder.zero_register(zero);
+        }
+        if let Some(groups) = &self.deny_syscalls {
+            builder = builder.syscall_policy(groups.iter().fold(SyscallPolicy::default(), |policy, group| policy.deny(*group)));
+        }
+        builder
+    }
+
+    /// An assembler knowing the configured register aliases
+    pub fn assembler(&self) -> Assembler {
+        Assembler::new().with_register_aliases(&self.register_aliases)
+    }
+
+    /// Replaces the first word of a REPL line with its alias, if it has one
+    pub fn expand_alias(&self, line: &str) -> String {
+        let (word, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match self.aliases.get(word) {
+            Some(command) if rest.is_empty() => command.clone(),
+            Some(command) => format!("{} {}", command, rest),
+            None => line.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_merge() {
+        let global = Config::parse("heap_size = 4096\nmax_steps = 10\n[aliases]\nc = \".continue\"\n").unwrap();
+        let local = Config::parse("max_steps = 20\n[aliases]\nr = \".registers\"\n").unwrap();
+        let config = global.merge(local);
+        assert_eq!((config.heap_size, config.max_steps()), (Some(4096), 20));
+        assert_eq!(config.vm_builder().build().dump_state().heap.size, 4096);
+        assert_eq!(config.expand_alias("c"), ".continue");
+        assert_eq!(config.expand_alias("r 1"), ".registers 1");
+        assert_eq!(config.expand_alias("load $0 #1"), "load $0 #1");
+        assert!(Config::parse("colour = true").is_err());
+        let sandbox = Config::parse("deny_syscalls = [\"console\", \"file\"]").unwrap();
+        assert_eq!(sandbox.deny_syscalls, Some(vec![SyscallGroup::Console, SyscallGroup::File]));
+        assert!(Config::parse("deny_syscalls = [\"disk\"]").is_err());
+        let conventions = Config::parse("zero_register = true\n[register_aliases]\nfp = 29\n").unwrap();
+        assert_eq!(conventions.assembler().assemble("load $fp #1"), Assembler::new().assemble("load $29 #1"));
+        let mut vm = conventions.vm_builder().build();
+        vm.load_program(&conventions.assembler().assemble("load $zero #5\nhlt").unwrap()).unwrap();
+        vm.run();
+        assert_eq!(vm.register(0), Ok(0));
+        let keys = Config::parse("trusted_keys = [\"A6EHv/POEL4dcN0Y50vAmWfk1jCbpQ1fHdyGZBJVMbg=\"]").unwrap();
+        assert_eq!(keys.trusted_keys().unwrap().map(|keys| keys.len()), Some(1));
+        assert_eq!(Config::default().trusted_keys(), Ok(None));
+        assert!(Config::parse("trusted_keys = [\"AAAA\"]").unwrap().trusted_keys().is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::Path;
+use crate::bytecode;
+use crate::instruction::{Decode, Instruction, INSTRUCTION_SIZE};
+
+/// Width of the left column of the side-by-side rendering
+const COLUMN_WIDTH: usize = 32;
+
+/// One disassembled instruction slot: its offset and text. Slots that do not decode are shown
+/// as raw hex bytes.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Row {
+    pub offset: usize,
+    pub text: String,
+}
+
+/// One line of an instruction-level diff
+#[derive(Debug, PartialEq, Clone)]
+pub enum DiffLine {
+    Same(Row, Row),
+    Changed(Row, Row),
+    Removed(Row),
+    Added(Row),
+}
+
+fn disassemble(program: &[u8]) -> Vec<Row> {
+    program.chunks(INSTRUCTION_SIZE).enumerate()
+        .map(|(i, chunk)| {
+            let text = match Instruction::decode(chunk) {
+                Ok(instruction) => instruction.to_string(),
+                Err(_) => chunk.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "),
+            };
+            Row { offset: i * INSTRUCTION_SIZE, text: text }
+        })
+        .collect()
+}
+
+/// Aligns the instructions of two programs along their longest common subsequence. A removal
+/// directly followed by an addition is reported as a single changed line.
+pub fn diff(old: &[u8], new: &[u8]) -> Vec<DiffLine> {
+    let (old, new) = (disassemble(old), disassemble(new));
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i].text == new[j].text {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i].text == new[j].text {
+            lines.push(DiffLine::Same(old[i].clone(), new[j].clone()));
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(DiffLine::Removed(old[i].clone()));
+            i += 1;
+        } else {
+            match lines.pop() {
+                Some(DiffLine::Removed(removed)) => lines.push(DiffLine::Changed(removed, new[j].clone())),
+                Some(other) => {
+                    lines.push(other);
+                    lines.push(DiffLine::Added(new[j].clone()));
+                },
+                None => lines.push(DiffLine::Added(new[j].clone())),
+            }
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Renders a diff side by side, prefixed with ` ` (same), `~` (changed), `-` (removed) or `+` (added)
+pub fn render(lines: &[DiffLine]) -> String {
+    let cell = |row: Option<&Row>| row.map_or(String::new(), |r| format!("{:04x}: {}", r.offset, r.text));
+    let mut out = String::new();
+    for line in lines {
+        let (marker, left, right) = match line {
+            DiffLine::Same(a, b) => (' ', Some(a), Some(b)),
+            DiffLine::Changed(a, b) => ('~', Some(a), Some(b)),
+            DiffLine::Removed(a) => ('-', Some(a), None),
+            DiffLine::Added(b) => ('+', None, Some(b)),
+        };
+        let row = format!("{} {:<width$} | {}", marker, cell(left), cell(right), width = COLUMN_WIDTH);
+        out.push_str(row.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// The `diff <old> <new>` subcommand on two bytecode files. Returns true if they are identical.
+/// Files with a header are compared in the native big-endian encoding.
+pub fn diff_files(old: &Path, new: &Path) -> Result<bool, String> {
+    let read = |path: &Path| -> Result<Vec<u8>, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let (_, program, _) = bytecode::read_program(&bytes).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(program)
+    };
+    let lines = diff(&read(old)?, &read(new)?);
+    print!("{}", render(&lines));
+    Ok(lines.iter().all(|l| matches!(l, DiffLine::Same(..))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_alignment() {
+        let old = [1, 0, 0, 5, 2, 0, 1, 2, 3, 0, 1, 2, 0, 0, 0, 0];
+        let new = [1, 0, 0, 5, 1, 1, 0, 7, 2, 0, 1, 2, 4, 0, 1, 2, 0, 0, 0, 0];
+        assert_eq!(render(&diff(&old, &new)), [
+            "  0000: load $0 #5                 | 0000: load $0 #5",
+            "+                                  | 0004: load $1 #7",
+            "  0004: add $0 $1 $2               | 0008: add $0 $1 $2",
+            "~ 0008: sub $0 $1 $2               | 000c: mul $0 $1 $2",
+            "  000c: hlt                        | 0010: hlt",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_diff_removed_and_undecodable() {
+        let lines = diff(&[0, 0, 0, 0, 200, 1], &[0, 0, 0, 0]);
+        assert_eq!(lines, vec![
+            DiffLine::Same(Row { offset: 0, text: "hlt".to_string() }, Row { offset: 0, text: "hlt".to_string() }),
+            DiffLine::Removed(Row { offset: 4, text: "c8 01".to_string() }),
+        ]);
+        assert_eq!(render(&lines[1..]), "- 0004: c8 01                      |\n");
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use crate::bytecode;
+use crate::cfg::Cfg;
+use crate::instruction::{Instruction, Opcode, Operand};
+use crate::verifier::VerifyError;
+
+/// Name of the label synthesized for a jump target
+fn label(offset: usize) -> String {
+    format!("L_{:04x}", offset)
+}
+
+/// An instruction written the way the assembler reads it
+fn source(instruction: &Instruction) -> String {
+    let mut out = instruction.opcode().mnemonic().to_string();
+    for operand in instruction.operands() {
+        match operand {
+            Operand::None => (),
+            Operand::Register(r) => out.push_str(&format!(" ${}", r)),
+            Operand::Integer(i) => out.push_str(&format!(" #{}", i)),
+            Operand::Byte(b) => out.push_str(&format!(" #{}", b)),
+        }
+    }
+    out
+}
+
+/// Disassembles a program into source the assembler accepts. Every statically known jump target
+/// gets an `L_xxxx:` label, and the LOADs setting the target of a JMP or JEQ refer to it with
+/// `@L_xxxx` instead of an absolute offset, like the handlers of PUSHTRAP, so that the output
+/// can be edited and reassembled.
+/// With `annotate`, every instruction is followed by a comment describing its effect.
+pub fn disassemble(program: &[u8], annotate: bool) -> Result<String, VerifyError> {
+    let cfg = Cfg::build(program)?;
+    let instructions: Vec<(usize, Instruction)> = cfg.blocks.iter().flat_map(|b| b.instructions.iter().copied()).collect();
+    let opcodes: HashMap<usize, Opcode> = instructions.iter().map(|(offset, i)| (*offset, i.opcode())).collect();
+    let mut targets = BTreeSet::new();
+    let mut absolute = HashMap::new();
+    let mut relative = HashSet::new();
+    for jump in &cfg.jumps {
+        let target = match jump.target.filter(|t| cfg.is_valid_target(*t)) {
+            Some(target) => target as usize,
+            None => continue,
+        };
+        targets.insert(target);
+        if let Some(load) = jump.load {
+            if matches!(opcodes[&jump.offset], Opcode::JMP | Opcode::JEQ) {
+                absolute.insert(load, target);
+            } else {
+                // The LOAD of a relative jump holds a distance, which must stay a number
+                relative.insert(load);
+            }
+        }
+    }
+    let unknown: HashSet<usize> = cfg.jumps.iter().filter(|j| j.target.is_none()).map(|j| j.offset).collect();
+    let destinations: HashMap<usize, usize> = cfg.jumps.iter()
+        .filter_map(|j| j.target.filter(|t| cfg.is_valid_target(*t)).map(|t| (j.offset, t as usize)))
+        .collect();
+
+    let mut lines = vec![];
+    for (offset, instruction) in &instructions {
+        if targets.contains(offset) {
+            lines.push(format!("{}:", label(*offset)));
+        }
+        if unknown.contains(offset) {
+            lines.push("    ; target unknown".to_string());
+        }
+        let text = match (absolute.get(offset), destinations.get(offset), *instruction.operands()) {
+            (Some(target), _, [Operand::Register(r), ..]) if !relative.contains(offset) => format!("load ${} @{}", r, label(*target)),
+            (_, Some(handler), [_, Operand::Register(r), _]) if instruction.opcode() == Opcode::PUSHTRAP => {
+                format!("pushtrap @{} ${}", label(*handler), r)
+            },
+            _ => source(instruction),
+        };
+        if annotate {
+            lines.push(format!("    {:<24}; {}", text, instruction.effect(destinations.get(offset).copied())));
+        } else {
+            lines.push(format!("    {}", text));
+        }
+    }
+    if targets.contains(&program.len()) {
+        lines.push(format!("{}:", label(program.len())));
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+/// The `disasm <bytecode> [--annotate]` subcommand: prints the labelled source of a bytecode file
+pub fn disasm_file(path: &Path, annotate: bool) -> Result<(), String> {
+    let bytes = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    let (_, program, _) = bytecode::read_program(&bytes).map_err(|e| e.to_string())?;
+    print!("{}", disassemble(&program, annotate).map_err(|e| e.to_string())?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_disassemble_with_labels() {
+        let src = "load $0 #16\nload $1 #6\njmpf $1\nhlt\nload $2 #28\njmp $2\njmp $0\nload $3 #0\njmp $3";
+        let program = Assembler::new().assemble(src).unwrap();
+        let disassembly = disassemble(&program, false).unwrap();
+        assert_eq!(disassembly, [
+            "L_0000:",
+            "    load $0 #16",
+            "    load $1 #6",
+            "    jmpf $1",
+            "    hlt",
+            "L_0010:",
+            "    load $2 @L_001c",
+            "    jmp $2",
+            "    ; target unknown",
+            "    jmp $0",
+            "L_001c:",
+            "    load $3 @L_0000",
+            "    jmp $3",
+            "",
+        ].join("\n"));
+        assert_eq!(Assembler::new().assemble(&disassembly), Ok(program));
+    }
+
+    #[test]
+    fn test_disassemble_with_annotations() {
+        let program = Assembler::new().assemble("load $1 #100\nload $0 #12\njeq $0 $1\nhlt").unwrap();
+        let disassembly = disassemble(&program, true).unwrap();
+        assert_eq!(disassembly, [
+            "    load $1 #100            ; $1 <- 100",
+            "    load $0 @L_000c         ; $0 <- 12",
+            "    jeq $0 $1               ; jump to 0xc if $1 == 1",
+            "L_000c:",
+            "    hlt                     ; halt",
+            "",
+        ].join("\n"));
+        assert_eq!(Assembler::new().assemble(&disassembly), Ok(program));
+        let program = Assembler::new().assemble("pushtrap @caught $5
+poptrap
+caught: hlt").unwrap();
+        let disassembly = disassemble(&program, true).unwrap();
+        assert_eq!(disassembly, [
+            "    pushtrap @L_0008 $5     ; on a trap, $5 <- trap code and jump to 0x8",
+            "    poptrap                 ; pop the innermost trap handler",
+            "L_0008:",
+            "    hlt                     ; halt",
+            "",
+        ].join("\n"));
+        assert_eq!(Assembler::new().assemble(&disassembly), Ok(program));
+    }
+}
@@ -0,0 +1,159 @@
+use std::fs;
+use std::path::Path;
+use crate::instruction::{OpcodeInfo, OperandKind, ALIASES, INSTRUCTION_SIZE, OPCODES};
+
+/// Output format of the `doc` subcommand
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum DocFormat {
+    Markdown,
+    Html,
+}
+
+impl DocFormat {
+    pub fn parse(src: &str) -> Result<DocFormat, String> {
+        match src {
+            "markdown" | "md" => Ok(DocFormat::Markdown),
+            "html" => Ok(DocFormat::Html),
+            _ => Err(format!("Unknown documentation format '{}', expected markdown or html", src))
+        }
+    }
+}
+
+const INTRODUCTION: &str = "Every instruction takes 4 bytes: the opcode byte followed by its operands, \
+    padded with zeroes. Registers and byte offsets take one byte, integers two, in big-endian order \
+    unless the bytecode header declares little-endian.";
+
+/// Assembly syntax of an opcode, with placeholders for its operands
+fn syntax(info: &OpcodeInfo) -> String {
+    let mut out = info.mnemonic.to_string();
+    let mut registers = 0;
+    for kind in info.operands {
+        match kind {
+            OperandKind::Register => {
+                registers += 1;
+                out.push_str(&format!(" $r{}", registers));
+            },
+            OperandKind::Integer => out.push_str(" #imm"),
+            OperandKind::Byte => out.push_str(" #offset"),
+            OperandKind::None => (),
+        }
+    }
+    out
+}
+
+/// Bytes of an encoded instruction: the opcode byte in hex, then `rr` for registers, `ii ii` for
+/// integers, `bb` for byte offsets and `00` for padding
+fn encoding(info: &OpcodeInfo) -> String {
+    let mut bytes = vec![format!("{:02x}", info.byte)];
+    for kind in info.operands {
+        match kind {
+            OperandKind::Register => bytes.push("rr".to_string()),
+            OperandKind::Integer => bytes.extend(["ii".to_string(), "ii".to_string()]),
+            OperandKind::Byte => bytes.push("bb".to_string()),
+            OperandKind::None => (),
+        }
+    }
+    bytes.resize(INSTRUCTION_SIZE, "00".to_string());
+    bytes.join(" ")
+}
+
+/// Extension, deprecation and former names of an opcode
+fn notes(info: &OpcodeInfo) -> String {
+    let mut notes = vec![];
+    if let Some(extension) = info.opcode.extension() {
+        notes.push(format!("{} extension", extension));
+    }
+    if let Some(replacement) = info.deprecated {
+        notes.push(format!("deprecated, use {}", replacement));
+    }
+    for alias in ALIASES.iter().filter(|alias| alias.opcode == info.opcode) {
+        notes.push(format!("formerly {}", alias.mnemonic));
+    }
+    notes.join(", ")
+}
+
+fn rows() -> Vec<[String; 5]> {
+    OPCODES.iter()
+        .map(|info| [info.mnemonic.to_string(), syntax(info), encoding(info), info.description.to_string(), notes(info)])
+        .collect()
+}
+
+const HEADERS: [&str; 5] = ["Mnemonic", "Syntax", "Encoding", "Description", "Notes"];
+
+fn escape_html(src: &str) -> String {
+    src.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Renders the instruction set reference from the opcode table
+pub fn render(format: DocFormat) -> String {
+    let mut out = String::new();
+    match format {
+        DocFormat::Markdown => {
+            out.push_str("# Instruction set\n\n");
+            out.push_str(INTRODUCTION);
+            out.push_str("\n\n");
+            out.push_str(&format!("| {} |\n", HEADERS.join(" | ")));
+            out.push_str(&format!("|{}\n", "---|".repeat(HEADERS.len())));
+            for row in rows() {
+                let cells: Vec<String> = row.iter().enumerate()
+                    .map(|(i, cell)| if (1..3).contains(&i) { format!("`{}`", cell) } else { cell.replace('|', "\\|") })
+                    .collect();
+                out.push_str(&format!("| {} |\n", cells.join(" | ")));
+            }
+        },
+        DocFormat::Html => {
+            out.push_str("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Instruction set</title></head>\n<body>\n");
+            out.push_str(&format!("<h1>Instruction set</h1>\n<p>{}</p>\n<table>\n", escape_html(INTRODUCTION)));
+            let headers: Vec<String> = HEADERS.iter().map(|h| format!("<th>{}</th>", h)).collect();
+            out.push_str(&format!("<tr>{}</tr>\n", headers.concat()));
+            for row in rows() {
+                let cells: Vec<String> = row.iter().enumerate()
+                    .map(|(i, cell)| if (1..3).contains(&i) {
+                        format!("<td><code>{}</code></td>", escape_html(cell))
+                    } else {
+                        format!("<td>{}</td>", escape_html(cell))
+                    })
+                    .collect();
+                out.push_str(&format!("<tr>{}</tr>\n", cells.concat()));
+            }
+            out.push_str("</table>\n</body>\n</html>\n");
+        },
+    }
+    out
+}
+
+/// The `doc [--format markdown|html] [output]` subcommand: writes the reference to `output`,
+/// or prints it
+pub fn doc_file(format: DocFormat, output: Option<&Path>) -> Result<(), String> {
+    let doc = render(format);
+    match output {
+        Some(path) => fs::write(path, doc).map_err(|e| format!("Unable to write {}: {}", path.display(), e)),
+        None => {
+            print!("{}", doc);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_markdown() {
+        let doc = render(DocFormat::Markdown);
+        assert_eq!(doc.lines().filter(|line| line.starts_with("| ")).count(), OPCODES.len() + 1);
+        assert!(doc.contains("| load | `load $r1 #imm` | `01 rr ii ii` | Loads a 16-bit integer into a register |  |\n"));
+        assert!(doc.contains("| `jeq $r1 $r2` | `0f rr rr 00` |"));
+        assert!(doc.contains("| `itof $r1 $r2` | `14 rr rr 00` | Converts an integer register into a float register | float extension |"));
+        assert!(doc.contains("formerly lmpb"));
+    }
+
+    #[test]
+    fn test_render_html() {
+        let doc = render(DocFormat::Html);
+        assert!(doc.contains("<tr><td>sw</td><td><code>sw $r1 $r2 #offset</code></td><td><code>11 rr rr bb</code></td>"));
+        assert_eq!(doc.matches("<tr>").count(), OPCODES.len() + 1);
+        assert_eq!(DocFormat::parse("pdf"), Err("Unknown documentation format 'pdf', expected markdown or html".to_string()));
+    }
+}
@@ -0,0 +1,57 @@
+use thiserror::Error;
+use crate::assembler::Assembler;
+use crate::lexer::AssemblerError;
+use crate::test_runner::MAX_STEPS;
+use crate::vm::{LoadError, VMError, VmState, VM};
+
+/// Errors of the whole assemble, verify and run pipeline of `eval`
+#[derive(Debug, PartialEq, Clone, Error)]
+pub enum EvalError {
+    #[error(transparent)]
+    Assembler(#[from] AssemblerError),
+    #[error(transparent)]
+    Load(#[from] LoadError),
+    #[error("execution stopped: {0}")]
+    Execution(#[from] VMError),
+    #[error("no halt after {0} instructions")]
+    InstructionLimit(usize),
+}
+
+/// Assembles, verifies and runs a program in a fresh VM from its entry point, with an instruction
+/// limit of `MAX_STEPS`, and returns its final state
+pub fn eval(source: &str) -> Result<VmState, EvalError> {
+    eval_with_limit(source, MAX_STEPS)
+}
+
+/// Same as `eval`, failing once `limit` instructions were executed without halting
+pub fn eval_with_limit(source: &str, limit: usize) -> Result<VmState, EvalError> {
+    let (program, entry) = Assembler::new().assemble_with_entry(source)?;
+    let mut vm = VM::new();
+    vm.load_program(&program)?;
+    vm.set_entry(entry)?;
+    for _ in 0..limit {
+        if !vm.run_once() {
+            return match vm.last_error() {
+                Some(e) => Err(e.into()),
+                None => Ok(vm.dump_state()),
+            };
+        }
+    }
+    Err(EvalError::InstructionLimit(limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval() {
+        let state = eval("load $0 #20\nload $1 #22\nadd $0 $1 $2\nhlt").unwrap();
+        assert_eq!(state.registers[2], 42);
+        assert_eq!(eval("load $0 #1\nload $1 #2\nassert $0 $1"),
+            Err(EvalError::Execution(VMError::AssertionFailed { pc: 8, left: 1, right: 2 })));
+        assert!(matches!(eval("load $0"), Err(EvalError::Assembler(_))));
+        assert_eq!(eval_with_limit("load $0 #0\njmp $0", 10), Err(EvalError::InstructionLimit(10)));
+        assert_eq!(eval("load $1 #1\n.entry @main\nmain: load $1 #9\nhlt").unwrap().registers[1], 9);
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Size of a heap page, the unit shared between forked VMs
+pub const PAGE_SIZE: usize = 256;
+
+pub type Page = [u8; PAGE_SIZE];
+
+/// Storage of the byte-addressed guest memory, split into pages. Cloning a heap shares all its
+/// pages, and a page is only copied the first time one of the clones writes to it.
+pub trait HeapBackend: fmt::Debug {
+    /// Size of the address space in bytes
+    fn len(&self) -> usize;
+
+    /// The page at `index`, None if it was never allocated and reads as zeroes
+    fn page(&self, index: usize) -> Option<&Arc<Page>>;
+
+    /// The page at `index`, allocating it if needed
+    fn page_mut(&mut self, index: usize) -> &mut Arc<Page>;
+
+    /// Every allocated page with its index, in address order
+    fn pages(&self) -> Vec<(usize, &Arc<Page>)>;
+
+    /// A zeroed heap of the same kind and size
+    fn cleared(&self) -> Box<dyn HeapBackend>;
+
+    fn clone_box(&self) -> Box<dyn HeapBackend>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fills `buf` with the bytes starting at `addr`, returns None if they are out of bounds
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Option<()> {
+        if addr.checked_add(buf.len())? > self.len() {
+            return None;
+        }
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let a = addr + i;
+            *byte = self.page(a / PAGE_SIZE).map_or(0, |page| page[a % PAGE_SIZE]);
+        }
+        Some(())
+    }
+
+    /// Writes `bytes` from `addr`, copying the pages still shared with another heap. Returns
+    /// None, writing nothing, if they are out of bounds.
+    fn write(&mut self, addr: usize, bytes: &[u8]) -> Option<()> {
+        if addr.checked_add(bytes.len())? > self.len() {
+            return None;
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            let a = addr + i;
+            Arc::make_mut(self.page_mut(a / PAGE_SIZE))[a % PAGE_SIZE] = *byte;
+        }
+        Some(())
+    }
+
+    /// Copies the whole heap out
+    fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = vec![0; self.len()];
+        for (index, page) in self.pages() {
+            let start = index * PAGE_SIZE;
+            let end = (start + PAGE_SIZE).min(bytes.len());
+            bytes[start..end].copy_from_slice(&page[..end - start]);
+        }
+        bytes
+    }
+
+    /// Number of pages still shared with `other`
+    fn shared_pages(&self, other: &dyn HeapBackend) -> usize {
+        self.pages().into_iter()
+            .filter(|(index, page)| other.page(*index).is_some_and(|p| Arc::ptr_eq(p, page)))
+            .count()
+    }
+}
+
+impl Clone for Box<dyn HeapBackend> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Heap allocating its whole address space up front
+#[derive(Debug, Clone)]
+pub struct FlatHeap {
+    pages: Vec<Arc<Page>>,
+    len: usize,
+}
+
+impl FlatHeap {
+    /// Creates a zeroed heap of `len` bytes
+    pub fn new(len: usize) -> FlatHeap {
+        let zero = Arc::new([0; PAGE_SIZE]);
+        FlatHeap {
+            pages: vec![zero; len.div_ceil(PAGE_SIZE)],
+            len: len,
+        }
+    }
+}
+
+impl HeapBackend for FlatHeap {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn page(&self, index: usize) -> Option<&Arc<Page>> {
+        self.pages.get(index)
+    }
+
+    fn page_mut(&mut self, index: usize) -> &mut Arc<Page> {
+        &mut self.pages[index]
+    }
+
+    fn pages(&self) -> Vec<(usize, &Arc<Page>)> {
+        self.pages.iter().enumerate().collect()
+    }
+
+    fn cleared(&self) -> Box<dyn HeapBackend> {
+        Box::new(FlatHeap::new(self.len))
+    }
+
+    fn clone_box(&self) -> Box<dyn HeapBackend> {
+        Box::new(self.clone())
+    }
+}
+
+/// Heap allocating a page the first time it is written, so that a large and mostly empty
+/// address space only costs the pages in use
+#[derive(Debug, Clone)]
+pub struct SparseHeap {
+    pages: HashMap<usize, Arc<Page>>,
+    len: usize,
+}
+
+impl SparseHeap {
+    /// Creates a zeroed heap of `len` bytes, without allocating any page
+    pub fn new(len: usize) -> SparseHeap {
+        SparseHeap { pages: HashMap::new(), len: len }
+    }
+}
+
+impl HeapBackend for SparseHeap {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn page(&self, index: usize) -> Option<&Arc<Page>> {
+        self.pages.get(&index)
+    }
+
+    fn page_mut(&mut self, index: usize) -> &mut Arc<Page> {
+        self.pages.entry(index).or_insert_with(|| Arc::new([0; PAGE_SIZE]))
+    }
+
+    fn pages(&self) -> Vec<(usize, &Arc<Page>)> {
+        let mut pages: Vec<(usize, &Arc<Page>)> = self.pages.iter().map(|(index, page)| (*index, page)).collect();
+        pages.sort_by_key(|(index, _)| *index);
+        pages
+    }
+
+    fn cleared(&self) -> Box<dyn HeapBackend> {
+        Box::new(SparseHeap::new(self.len))
+    }
+
+    fn clone_box(&self) -> Box<dyn HeapBackend> {
+        Box::new(self.clone())
+    }
+}
+
+/// Address, old and new value of every byte that differs between two heaps. Pages still shared
+/// between them, typically a snapshot and the heap it was cloned from, are skipped.
+pub fn diff(old: &dyn HeapBackend, new: &dyn HeapBackend) -> Vec<(usize, u8, u8)> {
+    let mut indexes: Vec<usize> = old.pages().into_iter().chain(new.pages()).map(|(index, _)| index).collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    let zero = [0; PAGE_SIZE];
+    let mut changes = vec![];
+    for index in indexes {
+        let (old_page, new_page) = (old.page(index), new.page(index));
+        if let (Some(a), Some(b)) = (old_page, new_page) {
+            if Arc::ptr_eq(a, b) {
+                continue;
+            }
+        }
+        let old_bytes = old_page.map_or(&zero, |page| page.as_ref());
+        let new_bytes = new_page.map_or(&zero, |page| page.as_ref());
+        for (i, (a, b)) in old_bytes.iter().zip(new_bytes.iter()).enumerate().filter(|(_, (a, b))| a != b) {
+            changes.push((index * PAGE_SIZE + i, *a, *b));
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_write() {
+        let backends: [Box<dyn HeapBackend>; 2] = [Box::new(FlatHeap::new(1000)), Box::new(SparseHeap::new(1000))];
+        for mut heap in backends {
+            assert_eq!(heap.write(254, &[1, 2, 3, 4]), Some(()));
+            let mut word = [0; 4];
+            assert_eq!(heap.read(254, &mut word), Some(()));
+            assert_eq!(word, [1, 2, 3, 4]);
+            assert_eq!(heap.write(998, &[1, 2, 3, 4]), None);
+            assert_eq!(heap.read(usize::MAX, &mut word), None);
+            assert_eq!(heap.to_vec().len(), 1000);
+            assert_eq!(heap.to_vec()[255], 2);
+        }
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let mut parent = FlatHeap::new(1000);
+        parent.write(0, &[7]).unwrap();
+        let mut child = parent.clone();
+        assert_eq!(child.shared_pages(&parent), 4);
+        child.write(300, &[9]).unwrap();
+        assert_eq!(child.shared_pages(&parent), 3);
+        assert_eq!((parent.to_vec()[300], child.to_vec()[300], child.to_vec()[0]), (0, 9, 7));
+    }
+
+    #[test]
+    fn test_sparse_heap_allocates_written_pages() {
+        let mut heap = SparseHeap::new(1 << 31);
+        let mut word = [9; 4];
+        assert_eq!(heap.read(0x4000_0000, &mut word), Some(()));
+        assert_eq!((word, heap.pages().len()), ([0; 4], 0));
+        heap.write(0x4000_00fe, &[1, 2, 3, 4]).unwrap();
+        heap.write(16, &[5]).unwrap();
+        let indexes: Vec<usize> = heap.pages().iter().map(|(index, _)| *index).collect();
+        assert_eq!(indexes, vec![0, 0x40_0000, 0x40_0001]);
+        let child = heap.clone();
+        assert_eq!(child.shared_pages(&heap), 3);
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut heap = FlatHeap::new(1000);
+        heap.write(8, &[1, 2]).unwrap();
+        let snapshot = heap.clone();
+        heap.write(9, &[2, 3]).unwrap();
+        heap.write(600, &[4]).unwrap();
+        assert_eq!(diff(&snapshot, &heap), vec![(10, 0, 3), (600, 0, 4)]);
+        assert_eq!(diff(&heap, &SparseHeap::new(1000)), vec![(8, 1, 0), (9, 2, 0), (10, 3, 0), (600, 4, 0)]);
+    }
+}
@@ -1,94 +1,564 @@
+use std::convert::TryFrom;
+use std::fmt;
+use thiserror::Error;
+use self::OperandKind::{Byte, Integer, Register};
+
+/// Size in bytes of every encoded instruction: the opcode and up to 3 operand bytes, zero-padded
+pub const INSTRUCTION_SIZE: usize = 4;
+
+/// Errors raised when decoding bytecode
+#[derive(Debug, PartialEq, Copy, Clone, Error)]
+pub enum DecodeError {
+  #[error("illegal opcode {0}")]
+  IllegalOpcode(u8),
+  #[error("truncated instruction")]
+  Truncated,
+}
+
+/// A mnemonic or a byte naming no opcode
+#[derive(Debug, PartialEq, Clone, Error)]
+pub enum OpcodeError {
+  #[error("unknown mnemonic '{0}'")]
+  UnknownMnemonic(String),
+  #[error("unknown opcode byte {0}")]
+  UnknownByte(u8),
+}
+
+/// Types that can be written as bytecode
+pub trait Encode {
+  fn encode(&self, out: &mut Vec<u8>);
+}
+
+/// Types that can be read back from the bytecode written by their `Encode` implementation,
+/// so that `decode(encode(x)) == x`
+pub trait Decode: Sized {
+  fn decode(bytes: &[u8]) -> Result<Self, DecodeError>;
+}
+
+/// Static description of an opcode
+#[derive(Debug, PartialEq)]
+pub struct OpcodeInfo {
+  pub opcode: Opcode,
+  pub byte: u8,
+  pub mnemonic: &'static str,
+  pub operands: [OperandKind; 3],
+  pub description: &'static str,
+  /// Effect of an instruction in plain language, see `Instruction::effect`
+  pub effect: &'static str,
+  /// Set on opcodes kept only for existing programs, telling what to use instead
+  pub deprecated: Option<&'static str>,
+}
+
+/// Former mnemonic or byte value of a renamed opcode. Old sources keep assembling, with a
+/// warning, and old bytecode keeps decoding.
+#[derive(Debug, PartialEq)]
+pub struct Alias {
+  pub mnemonic: &'static str,
+  /// Former byte value, when the opcode was also renumbered
+  pub byte: Option<u8>,
+  pub opcode: Opcode,
+}
+
+/// Every alias, checked at compile time not to shadow a current mnemonic or byte value
+pub const ALIASES: &[Alias] = &[
+  Alias { mnemonic: "lmpb", byte: None, opcode: Opcode::JMPB },
+];
+
+const _: () = check_aliases(OPCODES, ALIASES);
+
+const N: OperandKind = OperandKind::None;
+
+/// Generates the `Opcode` enum and the `OPCODES` metadata table from a single list of
+/// `byte => NAME, "mnemonic", [operand kinds], "description", effect "template";` entries, the
+/// template being optionally followed by `, deprecated "replacement"`. Byte values must be
+/// contiguous from 0 and mnemonics unique, which is checked at compile time. Since the VM matches
+/// exhaustively on `Opcode`, a new entry also fails to build until it gets an execution handler.
+macro_rules! define_opcodes {
+  (@deprecated) => { None };
+  (@deprecated $deprecated:literal) => { Some($deprecated) };
+  ($($byte:literal => $name:ident, $mnemonic:literal, [$($kind:expr),*], $description:literal, effect $effect:literal $(, deprecated $deprecated:literal)?;)*) => {
+    #[derive(Debug, PartialEq, Copy, Clone)]
+    pub enum Opcode {
+      $($name = $byte,)*
+      IGL
+    }
+
+    /// Every valid opcode, indexed by its byte value. This is the single source of truth for the
+    /// encoding, the mnemonics and the operands of the instruction set.
+    pub const OPCODES: &[OpcodeInfo] = &[
+      $(OpcodeInfo { opcode: Opcode::$name, byte: $byte, mnemonic: $mnemonic, operands: [$($kind),*], description: $description,
+        effect: $effect, deprecated: define_opcodes!(@deprecated $($deprecated)?) },)*
+    ];
+
+    const _: () = check_opcode_table(OPCODES);
+  };
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+  let (a, b) = (a.as_bytes(), b.as_bytes());
+  if a.len() != b.len() {
+    return false;
+  }
+  let mut i = 0;
+  while i < a.len() {
+    if a[i] != b[i] {
+      return false;
+    }
+    i += 1;
+  }
+  true
+}
+
+const fn check_opcode_table(table: &[OpcodeInfo]) {
+  let mut i = 0;
+  while i < table.len() {
+    assert!(table[i].byte as usize == i, "opcode byte values must be contiguous from 0");
+    let mut j = 0;
+    while j < i {
+      assert!(!str_eq(table[i].mnemonic, table[j].mnemonic), "duplicate opcode mnemonic");
+      j += 1;
+    }
+    i += 1;
+  }
+}
+
+const fn check_aliases(table: &[OpcodeInfo], aliases: &[Alias]) {
+  let mut i = 0;
+  while i < aliases.len() {
+    if let Some(byte) = aliases[i].byte {
+      assert!(byte as usize >= table.len(), "alias byte value taken by an opcode");
+    }
+    let mut j = 0;
+    while j < table.len() {
+      assert!(!str_eq(aliases[i].mnemonic, table[j].mnemonic), "alias mnemonic taken by an opcode");
+      j += 1;
+    }
+    i += 1;
+  }
+}
+
+define_opcodes! {
+  0 => HLT, "hlt", [N, N, N], "Halts the VM", effect "halt";
+  1 => LOAD, "load", [Register, Integer, N], "Loads a 16-bit integer into a register", effect "{1} <- {2}";
+  2 => ADD, "add", [Register, Register, Register], "Adds two registers into a third one", effect "{3} <- {1} + {2}";
+  3 => SUB, "sub", [Register, Register, Register], "Subtracts the second register from the first one into a third one", effect "{3} <- {1} - {2}";
+  4 => MUL, "mul", [Register, Register, Register], "Multiplies two registers into a third one", effect "{3} <- {1} * {2}";
+  5 => DIV, "div", [Register, Register, Register], "Divides the first register by the second one into a third one, keeping the remainder", effect "{3} <- {1} / {2}, keeping the remainder";
+  6 => JMP, "jmp", [Register, N, N], "Jumps to the absolute offset held by a register", effect "jump to {target}";
+  7 => JMPF, "jmpf", [Register, N, N], "Jumps forward by the number of bytes held by a register", effect "jump forward to {target}";
+  8 => JMPB, "jmpb", [Register, N, N], "Jumps backward by the number of bytes held by a register", effect "jump backward to {target}";
+  9 => EQ, "eq", [Register, Register, Register], "Sets the third register to 1 if the first two are equal, 0 otherwise", effect "{3} <- {1} == {2}";
+  10 => NEQ, "neq", [Register, Register, Register], "Sets the third register to 1 if the first two differ, 0 otherwise", effect "{3} <- {1} != {2}";
+  11 => GT, "gt", [Register, Register, Register], "Sets the third register to 1 if the first one is greater than the second one", effect "{3} <- {1} > {2}";
+  12 => LT, "lt", [Register, Register, Register], "Sets the third register to 1 if the first one is lesser than the second one", effect "{3} <- {1} < {2}";
+  13 => GTQ, "gtq", [Register, Register, Register], "Sets the third register to 1 if the first one is greater than or equal to the second one", effect "{3} <- {1} >= {2}";
+  14 => LTQ, "ltq", [Register, Register, Register], "Sets the third register to 1 if the first one is lesser than or equal to the second one", effect "{3} <- {1} <= {2}";
+  15 => JEQ, "jeq", [Register, Register, N], "Jumps to the offset held by the first register if the second one holds 1", effect "jump to {target} if {2} == 1";
+  16 => LW, "lw", [Register, Register, Byte], "Loads into the first register the heap word at the address held by the second one plus an offset", effect "{1} <- mem[{2} + {3}]";
+  17 => SW, "sw", [Register, Register, Byte], "Stores the first register into the heap word at the address held by the second one plus an offset", effect "mem[{2} + {3}] <- {1}";
+  18 => QMUL, "qmul", [Register, Register, Register], "Multiplies two Q16.16 fixed-point registers into a third one", effect "{3} <- {1} * {2} in Q16.16";
+  19 => QDIV, "qdiv", [Register, Register, Register], "Divides two Q16.16 fixed-point registers into a third one", effect "{3} <- {1} / {2} in Q16.16";
+  20 => ITOF, "itof", [Register, Register, N], "Converts an integer register into a float register", effect "{f2} <- float({1})";
+  21 => FTOI, "ftoi", [Register, Register, N], "Converts a float register into an integer register, truncating", effect "{2} <- int({f1})";
+  22 => FEQ, "feq", [Register, Register, Register], "Sets the integer register to 1 if the two float registers are equal", effect "{3} <- {f1} == {f2}";
+  23 => FLT, "flt", [Register, Register, Register], "Sets the integer register to 1 if the first float register is lesser than the second one", effect "{3} <- {f1} < {f2}";
+  24 => FGT, "fgt", [Register, Register, Register], "Sets the integer register to 1 if the first float register is greater than the second one", effect "{3} <- {f1} > {f2}";
+  25 => SYS, "sys", [Integer, N, N], "Calls the syscall with the given number", effect "syscall {1}";
+  26 => ADDO, "addo", [Register, Register, Register], "Adds two registers into a third one, trapping on overflow", effect "{3} <- {1} + {2}, trapping on overflow";
+  27 => SUBO, "subo", [Register, Register, Register], "Subtracts two registers into a third one, trapping on overflow", effect "{3} <- {1} - {2}, trapping on overflow";
+  28 => MULO, "mulo", [Register, Register, Register], "Multiplies two registers into a third one, trapping on overflow", effect "{3} <- {1} * {2}, trapping on overflow";
+  29 => ADDS, "adds", [Register, Register, Register], "Adds two registers into a third one, saturating at the i32 bounds", effect "{3} <- {1} + {2}, saturating";
+  30 => SUBS, "subs", [Register, Register, Register], "Subtracts two registers into a third one, saturating at the i32 bounds", effect "{3} <- {1} - {2}, saturating";
+  31 => MAC, "mac", [Register, Register, Register], "Adds the product of the last two registers to the first one", effect "{1} <- {1} + {2} * {3}";
+  32 => ASSERT, "assert", [Register, Register, N], "Traps if the two registers are not equal", effect "trap unless {1} == {2}";
+  33 => BANKSW, "banksw", [Integer, N, N], "Switches the integer registers to the given register bank", effect "switch to register bank {1}";
+  34 => ABORT, "abort", [Register, N, N], "Stops the program with the NUL-terminated message stored in the heap at the address held by a register", effect "abort with the message at mem[{1}]";
+  35 => YIELD, "yield", [N, N, N], "Ends the time slice of the VM under the scheduler, does nothing when it runs alone", effect "yield";
+  36 => RDCNT, "rdcnt", [Integer, Register, N], "Reads a performance counter into a register: 0 for executed instructions, 1 for jumps and 2 for syscalls", effect "{2} <- counter {1}";
+  37 => PUSHTRAP, "pushtrap", [Integer, Register, N], "Pushes a trap handler: a recoverable trap then jumps to the given offset with the trap code in the register", effect "on a trap, {2} <- trap code and jump to {target}";
+  38 => POPTRAP, "poptrap", [N, N, N], "Pops the innermost trap handler", effect "pop the innermost trap handler";
+}
+
+impl TryFrom<u8> for Opcode {
+  type Error = OpcodeError;
+
+  fn try_from(v: u8) -> Result<Self, OpcodeError> {
+    opcode_of_byte(v, ALIASES)
+  }
+}
+
+impl TryFrom<&str> for Opcode {
+  type Error = OpcodeError;
+
+  fn try_from(v: &str) -> Result<Self, OpcodeError> {
+    opcode_of_mnemonic(v, ALIASES)
+  }
+}
+
+/// The opcode `byte` decodes to, current or among the former byte values of `aliases`
+fn opcode_of_byte(byte: u8, aliases: &[Alias]) -> Result<Opcode, OpcodeError> {
+  match OPCODES.get(byte as usize) {
+    Some(info) => Ok(info.opcode),
+    None => aliases.iter().find(|alias| alias.byte == Some(byte)).map(|alias| alias.opcode).ok_or(OpcodeError::UnknownByte(byte))
+  }
+}
+
+/// The opcode `mnemonic` names, current or among the former mnemonics of `aliases`
+fn opcode_of_mnemonic(mnemonic: &str, aliases: &[Alias]) -> Result<Opcode, OpcodeError> {
+  match OPCODES.iter().find(|info| info.mnemonic == mnemonic) {
+    Some(info) => Ok(info.opcode),
+    None => aliases.iter().find(|alias| alias.mnemonic == mnemonic).map(|alias| alias.opcode)
+      .ok_or_else(|| OpcodeError::UnknownMnemonic(mnemonic.to_string()))
+  }
+}
+
+
+/// Warning for a mnemonic that still assembles but should be replaced, `None` for current ones
+pub fn deprecation(mnemonic: &str) -> Option<String> {
+  deprecation_among(mnemonic, ALIASES)
+}
+
+fn deprecation_among(mnemonic: &str, aliases: &[Alias]) -> Option<String> {
+  if let Some(alias) = aliases.iter().find(|alias| alias.mnemonic == mnemonic) {
+    return Some(format!("'{}' was renamed to '{}'", mnemonic, alias.opcode));
+  }
+  let info = OPCODES.iter().find(|info| info.mnemonic == mnemonic)?;
+  info.deprecated.map(|replacement| format!("'{}' is deprecated, use {} instead", mnemonic, replacement))
+}
+
+impl Opcode {
+  /// The opcode `byte` decodes to, IGL for a byte naming none, see `TryFrom<u8>` to reject it
+  pub fn from_byte(byte: u8) -> Opcode {
+    Opcode::try_from(byte).unwrap_or(Opcode::IGL)
+  }
+
+  /// Metadata of the opcode, `None` for IGL
+  pub fn info(&self) -> Option<&'static OpcodeInfo> {
+    OPCODES.get(*self as usize)
+  }
+
+  /// Assembly mnemonic of the opcode
+  pub fn mnemonic(&self) -> &'static str {
+    self.info().map_or("igl", |info| info.mnemonic)
+  }
+
+  /// Kinds of the operands encoded after the opcode byte
+  pub fn operand_kinds(&self) -> [OperandKind; 3] {
+    self.info().map_or([N; 3], |info| info.operands)
+  }
+
+  /// Optional instruction set extension the opcode belongs to, `None` for the core opcodes
+  pub fn extension(&self) -> Option<Extension> {
+    match self {
+      Opcode::ITOF | Opcode::FTOI | Opcode::FEQ | Opcode::FLT | Opcode::FGT => Some(Extension::Float),
+      _ => None,
+    }
+  }
+}
+
+/// Groups of opcodes left out of the core instruction set. Each one is built in with the cargo
+/// feature of the same name and has a bit in the capabilities of the bytecode header.
 #[derive(Debug, PartialEq, Copy, Clone)]
-pub enum Opcode {
-  HLT,
-  LOAD,
-  ADD,
-  SUB,
-  MUL,
-  DIV,
-  JMP,
-  JMPF,
-  JMPB,
-  EQ,     //equal
-  NEQ,    //non equal
-  GT,     //greater than
-  LT,     //lesser than
-  GTQ,    //greater or equal
-  LTQ,    //lesser or equal
-  JEQ,    //jump if equal
-  LW,
-  SW,
-  IGL
-}
-
-impl From<u8> for Opcode {
-    fn from(v: u8) -> Self {
-        match v {
-            0 => return Opcode::HLT,
-            1 => return Opcode::LOAD,
-            2 => return Opcode::ADD,
-            3 => return Opcode::SUB,
-            4 => return Opcode::MUL,
-            5 => return Opcode::DIV,
-            6 => return Opcode::JMP,
-            7 => return Opcode::JMPF,
-            8 => return Opcode::JMPB,
-            9 => return Opcode::EQ,
-            10 => return Opcode::NEQ,
-            11 => return Opcode::GT,
-            12 => return Opcode::LT,
-            13 => return Opcode::GTQ,
-            14 => return Opcode::LTQ,
-            15 => return Opcode::JEQ,
-            16 => return Opcode::LW,
-            17 => return Opcode::SW,
-            _ => return Opcode::IGL
-        }
+pub enum Extension {
+  Float,
+}
+
+impl Extension {
+  pub const ALL: [Extension; 1] = [Extension::Float];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Extension::Float => "float",
+    }
+  }
+
+  /// Capability bit of the extension in the bytecode header
+  pub fn bit(&self) -> u8 {
+    match self {
+      Extension::Float => 0b1,
+    }
+  }
+
+  /// Whether this build of the VM can execute the extension
+  pub fn is_enabled(&self) -> bool {
+    match self {
+      Extension::Float => cfg!(feature = "float"),
+    }
+  }
+
+  /// Capability bits of every extension this build supports
+  pub fn enabled_mask() -> u8 {
+    Extension::ALL.iter().filter(|e| e.is_enabled()).fold(0, |mask, e| mask | e.bit())
+  }
+
+  /// Capability bits of the extensions used by a program
+  pub fn required_by(program: &[u8]) -> u8 {
+    program.chunks(INSTRUCTION_SIZE)
+      .filter_map(|instruction| Opcode::from_byte(instruction[0]).extension())
+      .fold(0, |mask, e| mask | e.bit())
+  }
+}
+
+impl fmt::Display for Extension {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.name())
+  }
+}
+
+impl fmt::Display for Opcode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.mnemonic())
+  }
+}
+
+/// Kind of operand an opcode expects at a given position
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum OperandKind {
+  None,
+  Register,
+  Integer,
+  Byte,
+}
+
+impl OperandKind {
+  /// Number of bytes the operand takes in an encoded instruction
+  pub fn size(&self) -> usize {
+    match self {
+      OperandKind::None => 0,
+      OperandKind::Register | OperandKind::Byte => 1,
+      OperandKind::Integer => 2,
     }
+  }
 }
 
-impl From<&str> for Opcode {
-  fn from(v: &str) -> Self {
-    match v {
-      "hlt" => return Opcode::HLT,
-      "load" => return Opcode::LOAD,
-      "add" => return Opcode::ADD,
-      "sub" => return Opcode::SUB,
-      "mul" => return Opcode::MUL,
-      "div" => return Opcode::DIV,
-      "jmp" => return Opcode::JMP,
-      "jmpf" => return Opcode::JMPF,
-      "lmpb" => return Opcode::JMPB,
-      "eq" => return Opcode::EQ,
-      "neq" => return Opcode::NEQ,
-      "gt" => return Opcode::GT,
-      "lt" => return Opcode::LT,
-      "gtq" => return Opcode::GTQ,
-      "ltq" => return Opcode::LTQ,
-      "jeq" => return Opcode::JEQ,
-      "lw" => return Opcode::LW,
-      "sw" => return Opcode::SW,
-      _ => return Opcode::IGL
+impl fmt::Display for OperandKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      OperandKind::None => write!(f, "no operand"),
+      OperandKind::Register => write!(f, "a register"),
+      OperandKind::Integer => write!(f, "an integer"),
+      OperandKind::Byte => write!(f, "a byte offset"),
     }
   }
 }
 
-#[derive(Debug, PartialEq)]
+/// Decoded operand of an instruction
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Operand {
+  None,
+  Register(u8),
+  /// 16-bit immediate, as used by LOAD and SYS
+  Integer(u16),
+  /// 8-bit immediate, as used for the offset of LW and SW
+  Byte(u8),
+}
+
+impl fmt::Display for Operand {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Operand::None => Ok(()),
+      Operand::Register(r) => write!(f, "${}", r),
+      Operand::Integer(i) => write!(f, "#{}", i),
+      Operand::Byte(b) => write!(f, "{}", b),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub struct Instruction {
-  opcode: Opcode
+  opcode: Opcode,
+  operands: [Operand; 3]
 }
 
 impl Instruction {
   pub fn new(opcode: Opcode) -> Instruction {
     Instruction {
-      opcode: opcode
+      opcode: opcode,
+      operands: [Operand::None; 3]
+    }
+  }
+
+  pub fn with_operands(opcode: Opcode, operands: [Operand; 3]) -> Instruction {
+    Instruction {
+      opcode: opcode,
+      operands: operands
+    }
+  }
+
+  pub fn opcode(&self) -> Opcode {
+    self.opcode
+  }
+
+  pub fn operands(&self) -> &[Operand; 3] {
+    &self.operands
+  }
+
+  /// The effect of the instruction in plain language, from the template of its opcode: `{1}` to
+  /// `{3}` become its operands, `{f1}` to `{f3}` the float registers they name, and `{target}`
+  /// the destination of a jump, when it is statically known
+  pub fn effect(&self, target: Option<usize>) -> String {
+    let mut effect = match self.opcode.info() {
+      Some(info) => info.effect.to_string(),
+      None => return "illegal opcode".to_string(),
+    };
+    for (i, operand) in self.operands.iter().enumerate() {
+      let text = match operand {
+        Operand::None => continue,
+        Operand::Register(r) => {
+          effect = effect.replace(&format!("{{f{}}}", i + 1), &format!("$f{}", r));
+          format!("${}", r)
+        },
+        Operand::Integer(v) => v.to_string(),
+        Operand::Byte(b) => b.to_string(),
+      };
+      effect = effect.replace(&format!("{{{}}}", i + 1), &text);
+    }
+    let target = target.map_or("an unknown target".to_string(), |target| format!("0x{:x}", target));
+    effect.replace("{target}", &target)
+  }
+}
+
+impl Decode for Instruction {
+  /// Decodes the instruction at the start of `bytes`
+  fn decode(bytes: &[u8]) -> Result<Instruction, DecodeError> {
+    let opcode = match bytes.first() {
+      Some(b) => Opcode::try_from(*b).map_err(|_| DecodeError::IllegalOpcode(*b))?,
+      None => return Err(DecodeError::Truncated)
+    };
+    if bytes.len() < INSTRUCTION_SIZE {
+      return Err(DecodeError::Truncated);
+    }
+    let mut operands = [Operand::None; 3];
+    let mut offset = 1;
+    for (operand, kind) in operands.iter_mut().zip(opcode.operand_kinds().iter()) {
+      *operand = match kind {
+        OperandKind::None => Operand::None,
+        OperandKind::Register => Operand::Register(bytes[offset]),
+        OperandKind::Integer => Operand::Integer(((bytes[offset] as u16) << 8) | bytes[offset + 1] as u16),
+        OperandKind::Byte => Operand::Byte(bytes[offset]),
+      };
+      offset += kind.size();
+    }
+    Ok(Instruction::with_operands(opcode, operands))
+  }
+}
+
+impl Encode for Instruction {
+  /// Writes the instruction in its zero-padded 4-byte form
+  fn encode(&self, out: &mut Vec<u8>) {
+    let start = out.len();
+    out.push(self.opcode as u8);
+    for operand in &self.operands {
+      match *operand {
+        Operand::None => (),
+        Operand::Register(b) | Operand::Byte(b) => out.push(b),
+        Operand::Integer(i) => {
+          out.push((i >> 8) as u8);
+          out.push(i as u8);
+        }
+      }
+    }
+    out.resize(start + INSTRUCTION_SIZE, 0);
+  }
+}
+
+impl fmt::Display for Instruction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.opcode)?;
+    for operand in self.operands.iter().filter(|o| **o != Operand::None) {
+      write!(f, " {}", operand)?;
+    }
+    Ok(())
+  }
+}
+
+/// A decoded program, rendered one `offset: instruction` line per instruction
+#[derive(Debug, PartialEq, Clone)]
+pub struct Program {
+  instructions: Vec<(usize, Instruction)>
+}
+
+impl Program {
+  /// The instructions along with their byte offset
+  pub fn instructions(&self) -> &[(usize, Instruction)] {
+    &self.instructions
+  }
+}
+
+impl Decode for Program {
+  fn decode(bytes: &[u8]) -> Result<Program, DecodeError> {
+    let instructions = bytes.chunks(INSTRUCTION_SIZE)
+      .enumerate()
+      .map(|(i, chunk)| Instruction::decode(chunk).map(|inst| (i * INSTRUCTION_SIZE, inst)))
+      .collect::<Result<Vec<_>, DecodeError>>()?;
+    Ok(Program { instructions: instructions })
+  }
+}
+
+impl Encode for Program {
+  fn encode(&self, out: &mut Vec<u8>) {
+    for (_, instruction) in &self.instructions {
+      instruction.encode(out);
     }
   }
 }
 
+impl fmt::Display for Program {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (offset, instruction) in &self.instructions {
+      writeln!(f, "{:04x}: {}", offset, instruction)?;
+    }
+    Ok(())
+  }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+
+    fn encoded(instruction: &Instruction) -> Vec<u8> {
+      let mut bytes = vec![];
+      instruction.encode(&mut bytes);
+      bytes
+    }
+
+    fn arb_instruction() -> impl Strategy<Value = Instruction> {
+      let opcodes: Vec<Opcode> = (0..=255u8).map(Opcode::from_byte).filter(|o| *o != Opcode::IGL).collect();
+      (prop::sample::select(opcodes), any::<[u8; 3]>(), any::<u16>()).prop_map(|(opcode, bytes, integer)| {
+        let mut operands = [Operand::None; 3];
+        for (i, kind) in opcode.operand_kinds().iter().enumerate() {
+          operands[i] = match kind {
+            OperandKind::None => Operand::None,
+            OperandKind::Register => Operand::Register(bytes[i]),
+            OperandKind::Integer => Operand::Integer(integer),
+            OperandKind::Byte => Operand::Byte(bytes[i]),
+          };
+        }
+        Instruction::with_operands(opcode, operands)
+      })
+    }
+
+    proptest! {
+      #[test]
+      fn prop_instruction_roundtrip(instruction in arb_instruction()) {
+        let bytes = encoded(&instruction);
+        prop_assert_eq!(bytes.len(), INSTRUCTION_SIZE);
+        prop_assert_eq!(Instruction::decode(&bytes), Ok(instruction));
+      }
+
+      #[test]
+      fn prop_program_roundtrip(instructions in prop::collection::vec(arb_instruction(), 0..16)) {
+        let mut bytes = vec![];
+        for instruction in &instructions {
+          instruction.encode(&mut bytes);
+        }
+        let program = Program::decode(&bytes).unwrap();
+        let mut reencoded = vec![];
+        program.encode(&mut reencoded);
+        prop_assert_eq!(reencoded, bytes);
+      }
+    }
 
     #[test]
     fn test_create_hlt() {
@@ -101,4 +571,74 @@ mod tests {
       let instruction = Instruction::new(Opcode::HLT);
       assert_eq!(instruction.opcode, Opcode::HLT);
     }
+
+    #[test]
+    fn test_opcode_table_is_indexed_by_byte() {
+      for (i, info) in OPCODES.iter().enumerate() {
+        assert_eq!(info.byte as usize, i);
+        assert_eq!(info.opcode as usize, i);
+        assert_eq!(Opcode::try_from(info.byte), Ok(info.opcode));
+        assert_eq!(Opcode::try_from(info.mnemonic), Ok(info.opcode));
+      }
+      assert_eq!(Opcode::IGL as usize, OPCODES.len());
+      assert_eq!(Opcode::try_from("jmpb"), Ok(Opcode::JMPB));
+      assert_eq!(Opcode::from_byte(OPCODES.len() as u8), Opcode::IGL);
+      assert_eq!(Opcode::try_from("lod").unwrap_err().to_string(), "unknown mnemonic 'lod'");
+      assert_eq!(Opcode::try_from(OPCODES.len() as u8), Err(OpcodeError::UnknownByte(OPCODES.len() as u8)));
+    }
+
+    #[test]
+    fn test_aliases() {
+      assert_eq!(Opcode::try_from("lmpb"), Ok(Opcode::JMPB));
+      assert_eq!(deprecation("lmpb"), Some("'lmpb' was renamed to 'jmpb'".to_string()));
+      assert_eq!(deprecation("jmpb"), None);
+      assert_eq!(deprecation("nope"), None);
+      // A renumbered opcode, which the real table has none of yet
+      const RENUMBERED: &[Alias] = &[Alias { mnemonic: "jump", byte: Some(200), opcode: Opcode::JMP }];
+      const _: () = check_aliases(OPCODES, RENUMBERED);
+      assert_eq!(opcode_of_byte(200, RENUMBERED), Ok(Opcode::JMP));
+      assert_eq!(opcode_of_byte(201, RENUMBERED), Err(OpcodeError::UnknownByte(201)));
+      assert_eq!(opcode_of_byte(6, RENUMBERED), Ok(Opcode::JMP));
+      assert_eq!(opcode_of_mnemonic("jump", RENUMBERED), Ok(Opcode::JMP));
+      assert_eq!(deprecation_among("jump", RENUMBERED), Some("'jump' was renamed to 'jmp'".to_string()));
+    }
+
+    #[test]
+    fn test_display_opcode() {
+      assert_eq!(Opcode::JMPB.to_string(), "jmpb");
+      assert_eq!(Opcode::ASSERT.to_string(), "assert");
+    }
+
+    #[test]
+    fn test_display_instruction() {
+      assert_eq!(Instruction::decode(&[1, 3, 1, 244]).unwrap().to_string(), "load $3 #500");
+      assert_eq!(Instruction::decode(&[17, 1, 2, 8]).unwrap().to_string(), "sw $1 $2 8");
+      assert_eq!(Instruction::decode(&[6, 4, 0, 0]).unwrap().to_string(), "jmp $4");
+      assert_eq!(Instruction::decode(&[0, 0, 0, 0]).unwrap().to_string(), "hlt");
+    }
+
+    #[test]
+    fn test_effect() {
+      assert_eq!(Instruction::decode(&[1, 1, 0, 100]).unwrap().effect(None), "$1 <- 100");
+      assert_eq!(Instruction::decode(&[15, 0, 2, 0]).unwrap().effect(Some(24)), "jump to 0x18 if $2 == 1");
+      assert_eq!(Instruction::decode(&[7, 0, 0, 0]).unwrap().effect(None), "jump forward to an unknown target");
+      assert_eq!(Instruction::decode(&[17, 1, 2, 8]).unwrap().effect(None), "mem[$2 + 8] <- $1");
+      assert_eq!(Instruction::decode(&[20, 3, 4, 0]).unwrap().effect(None), "$f4 <- float($3)");
+      assert!(OPCODES.iter().all(|info| !info.effect.is_empty()));
+    }
+
+    #[test]
+    fn test_encode_decode_instruction() {
+      let instruction = Instruction::with_operands(Opcode::LOAD, [Operand::Register(3), Operand::Integer(500), Operand::None]);
+      assert_eq!(encoded(&instruction), vec![1, 3, 1, 244]);
+      assert_eq!(encoded(&Instruction::new(Opcode::HLT)), vec![0, 0, 0, 0]);
+      assert_eq!(Instruction::decode(&[1, 3, 1]), Err(DecodeError::Truncated));
+      assert_eq!(Instruction::decode(&[200, 0, 0, 0]), Err(DecodeError::IllegalOpcode(200)));
+    }
+
+    #[test]
+    fn test_display_program() {
+      let program = Program::decode(&[1, 0, 0, 10, 2, 0, 0, 1, 0, 0, 0, 0]).unwrap();
+      assert_eq!(program.to_string(), "0000: load $0 #10\n0004: add $0 $0 $1\n0008: hlt\n");
+    }
 }
@@ -1,5 +1,237 @@
+use std::convert::TryFrom;
+use std::collections::{BTreeMap, HashMap};
 use crate::instruction;
+use crate::instruction::{Encode, Instruction, Opcode, OpcodeError, Operand, OperandKind};
+use crate::vm::REGISTER_COUNT;
 use regex::Regex;
+use thiserror::Error;
+
+
+/// Errors raised while splitting source text into tokens
+#[derive(Debug, PartialEq, Clone, Error)]
+pub enum LexError {
+    #[error("no matching token for '{0}'")]
+    NoMatchingToken(String),
+    #[error("integer '{0}' is out of range")]
+    InvalidInteger(String),
+    #[error("register '{register}' does not exist, the VM has {count} registers")]
+    InvalidRegister { register: String, count: usize },
+    #[error("unknown register alias '{0}'")]
+    UnknownRegisterAlias(String),
+    #[error("undefined constant '{0}'")]
+    UndefinedConstant(String),
+    #[error("invalid instruction '{0}', too many arguments")]
+    TooManyArguments(String),
+    #[error(transparent)]
+    Opcode(#[from] OpcodeError),
+}
+
+/// Errors raised while turning tokens into bytecode
+#[derive(Debug, PartialEq, Clone, Error)]
+pub enum AssemblerError {
+    #[error(transparent)]
+    Lex(#[from] LexError),
+    #[error("no opcode found")]
+    NoOpcode,
+    #[error("too many operands for '{0}'")]
+    TooManyOperands(Opcode),
+    #[error("missing operand {position} for '{opcode}', expected {expected}")]
+    MissingOperand { opcode: Opcode, position: usize, expected: OperandKind },
+    #[error("invalid operand {position} for '{opcode}', expected {expected}")]
+    InvalidOperand { opcode: Opcode, position: usize, expected: OperandKind },
+    #[error("operand {position} of '{opcode}' is out of range: {value} is not within {min}..={max}")]
+    OutOfRange { opcode: Opcode, position: usize, value: i32, min: i32, max: i32 },
+    #[error("unknown label '{0}'")]
+    UnknownLabel(String),
+    #[error("label '{0}' is already declared")]
+    DuplicateLabel(String),
+    #[error("unknown directive '{0}'")]
+    UnknownDirective(String),
+    #[error("invalid directive '{0}'")]
+    InvalidDirective(String),
+    #[error("instructions belong in the .code section")]
+    InstructionInData,
+    #[error("'{0}' belongs in the .data section")]
+    DataInCode(String),
+    #[error("the data section takes more than {0} bytes")]
+    DataTooLarge(usize),
+    #[error("the entry point is already declared")]
+    DuplicateEntry,
+    #[error("constant '{0}' is already defined")]
+    DuplicateConstant(String),
+    #[error("macro '{0}' is already defined")]
+    DuplicateMacro(String),
+    #[error("macro '{0}' has no .endmacro")]
+    UnterminatedMacro(String),
+    #[error("macro '{name}' expects {expected} arguments, found {found}")]
+    MacroArguments { name: String, expected: usize, found: usize },
+    #[error("macro '{0}' invokes itself")]
+    RecursiveMacro(String),
+    #[error("unable to include '{file}': {reason}")]
+    IncludeFailed { file: String, reason: String },
+    #[error("'{0}' is already being included")]
+    IncludeCycle(String),
+    #[error("{file}, line {line}: {source}")]
+    Included { file: String, line: usize, source: Box<AssemblerError> },
+    #[error("entry point '{0}' is not the label of an instruction")]
+    InvalidEntry(String),
+    /// An error found at a line. `column` is the 1-based column of `token`, the offending word
+    /// of `text`, the code on the line, when the error is about one.
+    #[error("line {line}: {source}")]
+    Line { line: usize, column: Option<usize>, token: Option<String>, text: String, source: Box<AssemblerError> },
+}
+
+impl AssemblerError {
+    /// The error located at line `line`, whose code `text` starts after `indent` columns
+    pub fn at_line(self, line: usize, indent: usize, text: &str) -> AssemblerError {
+        let span = self.span(text);
+        AssemblerError::Line {
+            line: line,
+            column: span.map(|(start, _)| indent + text[..start].chars().count() + 1),
+            token: span.map(|(start, end)| text[start..end].to_string()),
+            text: format!("{}{}", " ".repeat(indent), text),
+            source: Box::new(self),
+        }
+    }
+
+    /// Byte range of the word of `text` the error is about
+    fn span(&self, text: &str) -> Option<(usize, usize)> {
+        let words = words(text);
+        let find = |token: &str| words.iter().find(|(_, word)| *word == token);
+        // The opcode is word 0, its operands follow
+        let operand = |position: usize| words.iter().skip_while(|(_, word)| word.ends_with(':')).nth(position);
+        let (start, word) = match self {
+            AssemblerError::Lex(e) => match e {
+                LexError::NoMatchingToken(token) | LexError::InvalidInteger(token) | LexError::UnknownRegisterAlias(token)
+                | LexError::InvalidRegister { register: token, .. } => find(token),
+                LexError::UndefinedConstant(name) => find(&format!("#{}", name)),
+                LexError::TooManyArguments(_) => operand(4),
+                LexError::Opcode(OpcodeError::UnknownMnemonic(mnemonic)) => find(mnemonic),
+                LexError::Opcode(OpcodeError::UnknownByte(_)) => None,
+            },
+            AssemblerError::NoOpcode => words.first(),
+            AssemblerError::MissingOperand { .. } => None,
+            AssemblerError::InvalidOperand { position, .. } | AssemblerError::OutOfRange { position, .. } => operand(*position),
+            AssemblerError::TooManyOperands(opcode) => {
+                operand(opcode.operand_kinds().iter().filter(|kind| **kind != OperandKind::None).count() + 1)
+            },
+            AssemblerError::UnknownLabel(name) | AssemblerError::InvalidEntry(name) => find(&format!("@{}", name)),
+            AssemblerError::DuplicateLabel(name) => find(&format!("{}:", name)),
+            AssemblerError::UnknownDirective(name) | AssemblerError::DataInCode(name) | AssemblerError::DuplicateConstant(name)
+            | AssemblerError::DuplicateMacro(name) | AssemblerError::UnterminatedMacro(name) | AssemblerError::RecursiveMacro(name)
+            | AssemblerError::MacroArguments { name, .. } => find(name),
+            AssemblerError::IncludeFailed { file, .. } | AssemblerError::IncludeCycle(file) => find(&format!("\"{}\"", file)),
+            // The text of a line from an included file is that of the file
+            AssemblerError::Included { source, .. } => return source.span(text),
+            _ => None,
+        }?;
+        Some((*start, start + word.len()))
+    }
+
+    /// What the source should hold instead of the offending token
+    pub fn hint(&self) -> Option<String> {
+        let hint = match self {
+            AssemblerError::Lex(e) => match e {
+                LexError::NoMatchingToken(_) => "expected an opcode, a $register, an #integer, a label: or a @label".to_string(),
+                LexError::InvalidInteger(_) => format!("expected an integer within {}..={}", i32::MIN, i32::MAX),
+                LexError::InvalidRegister { count, .. } => format!("expected $0 to ${}", count - 1),
+                LexError::UnknownRegisterAlias(_) => "expected a register number or a known alias".to_string(),
+                LexError::UndefinedConstant(_) => "expected a constant defined above with .equ".to_string(),
+                LexError::TooManyArguments(_) => "expected at most 3 operands".to_string(),
+                LexError::Opcode(OpcodeError::UnknownMnemonic(mnemonic)) => match closest_mnemonic(mnemonic) {
+                    Some(closest) => format!("did you mean '{}'?", closest),
+                    None => "expected the mnemonic of an instruction".to_string(),
+                },
+                LexError::Opcode(OpcodeError::UnknownByte(_)) => return None,
+            },
+            AssemblerError::NoOpcode => "expected an opcode".to_string(),
+            AssemblerError::MissingOperand { expected, .. } | AssemblerError::InvalidOperand { expected, .. } => format!("expected {}", expected),
+            AssemblerError::OutOfRange { min, max, .. } => format!("expected a value within {}..={}", min, max),
+            AssemblerError::TooManyOperands(_) => "expected the end of the line".to_string(),
+            AssemblerError::UnknownLabel(_) => "expected a label declared with name:".to_string(),
+            AssemblerError::InvalidEntry(_) => "expected the label of an instruction".to_string(),
+            AssemblerError::UnknownDirective(_) => {
+                "expected .data, .code, .asciiz, .word, .entry, .equ, .const, .macro or .include".to_string()
+            },
+            AssemblerError::Included { source, .. } | AssemblerError::Line { source, .. } => return source.hint(),
+            _ => return None,
+        };
+        Some(hint)
+    }
+
+    /// The error as shown to the user: for an error located at a line, the message follows the
+    /// line and column, then comes the code on the line with the offending token underlined and
+    /// what was expected instead
+    pub fn render(&self) -> String {
+        let (line, column, token, text, source) = match self {
+            AssemblerError::Line { line, column, token, text, source } => (line, column, token, text, source),
+            _ => return self.to_string(),
+        };
+        let mut out = match column {
+            Some(column) => format!("line {}, column {}: {}", line, column, source),
+            None => format!("line {}: {}", line, source),
+        };
+        if text.is_empty() {
+            return out;
+        }
+        let gutter = " ".repeat(line.to_string().len());
+        out += &format!("\n{} |\n{} | {}", gutter, line, text);
+        let hint = self.hint();
+        match (column, token) {
+            (Some(column), Some(token)) => {
+                out += &format!("\n{} | {}{}", gutter, " ".repeat(column - 1), "^".repeat(token.chars().count()));
+                if let Some(hint) = hint {
+                    out += &format!(" {}", hint);
+                }
+            },
+            _ => if let Some(hint) = hint {
+                out += &format!("\n{} = {}", gutter, hint);
+            },
+        }
+        out
+    }
+}
+
+/// The current mnemonic closest to `word`, within two edited letters
+fn closest_mnemonic(word: &str) -> Option<&'static str> {
+    let distance = |mnemonic: &str| {
+        // Levenshtein distance, one row of the table at a time
+        let mut row: Vec<usize> = (0..=mnemonic.len()).collect();
+        for (i, a) in word.chars().enumerate() {
+            let mut diagonal = row[0];
+            row[0] = i + 1;
+            for (j, b) in mnemonic.chars().enumerate() {
+                let substitution = diagonal + (a != b) as usize;
+                diagonal = row[j + 1];
+                row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+            }
+        }
+        row[mnemonic.len()]
+    };
+    instruction::OPCODES.iter()
+        .filter(|info| info.deprecated.is_none())
+        .map(|info| (distance(info.mnemonic), info.mnemonic))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, mnemonic)| mnemonic)
+}
+
+/// The whitespace-separated words of `text`, with their byte offset
+fn words(text: &str) -> Vec<(usize, &str)> {
+    let mut words = vec![];
+    let mut start = None;
+    for (i, c) in text.char_indices().chain(Some((text.len(), ' '))) {
+        match start {
+            None if !c.is_whitespace() => start = Some(i),
+            Some(s) if c.is_whitespace() => {
+                words.push((s, &text[s..i]));
+                start = None;
+            },
+            _ => (),
+        }
+    }
+    words
+}
 
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -7,14 +239,18 @@ pub enum TokenType {
     Opcode,
     Register,
     IntegerOperand,
+    LabelDeclaration,
+    LabelUsage,
 }
 
-impl From<Token> for TokenType {
-    fn from(v: Token) -> Self {
+impl From<&Token> for TokenType {
+    fn from(v: &Token) -> Self {
         match v {
-            Token::Opcode(_op) => return TokenType::Opcode,
-            Token::Register(_r) => return TokenType::Register,
-            Token::IntegerOperand(_) => return TokenType::IntegerOperand
+            Token::Opcode(_op) => TokenType::Opcode,
+            Token::Register(_r) => TokenType::Register,
+            Token::IntegerOperand(_) => TokenType::IntegerOperand,
+            Token::LabelDeclaration(_) => TokenType::LabelDeclaration,
+            Token::LabelUsage(_) => TokenType::LabelUsage,
         }
     }
 }
@@ -36,15 +272,19 @@ impl TokenTypeRegex {
 }
 
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Opcode(instruction::Opcode),
     Register(u8),
-    IntegerOperand(i32)
+    IntegerOperand(i32),
+    /// `name:` at the start of a line, naming the offset of its instruction
+    LabelDeclaration(String),
+    /// `@name` operand, standing for the offset of the label
+    LabelUsage(String),
 }
 
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct AssemblerInstruction {
     opcode: Token,
     arg1: Option<Token>,
@@ -53,52 +293,89 @@ pub struct AssemblerInstruction {
 }
 
 impl AssemblerInstruction {
-    pub fn compile(&self) -> Result<Vec<u8>, String> {
-        let mut result: Vec<u8> = vec!();
-        let op = match self.opcode {
-            Token::Opcode(o) => o as u8,
-            _ => return Err(format!("No opcode found!"))
-        };
-        result.push(op);
-        match self.arg1 {
-            Some(a) => {
-                let mut bytes = Self::compile_token(a);
-                result.append(&mut bytes);
-            }
-            None => ()
-        }
-        match self.arg2 {
-            Some(a) => {
-                let mut bytes = Self::compile_token(a);
-                result.append(&mut bytes);
-            }
-            None => ()
+    /// Groups the tokens of a line, `src`, into an opcode and its arguments
+    pub fn from_tokens(src: &str, tokens: Vec<Token>) -> Result<AssemblerInstruction, LexError> {
+        if tokens.len() > 4 {
+            return Err(LexError::TooManyArguments(src.to_string()))
         }
-        match self.arg3 {
-            Some(a) => {
-                let mut bytes = Self::compile_token(a);
-                result.append(&mut bytes);
-            }
-            None => ()
+        let mut tokens = tokens.into_iter();
+        let opcode = tokens.next().ok_or_else(|| LexError::NoMatchingToken(src.to_string()))?;
+        Ok(AssemblerInstruction {
+            opcode: opcode,
+            arg1: tokens.next(),
+            arg2: tokens.next(),
+            arg3: tokens.next(),
+        })
+    }
+
+    /// The opcode, `None` when the instruction starts with another token
+    pub fn opcode(&self) -> Option<Opcode> {
+        match self.opcode {
+            Token::Opcode(opcode) => Some(opcode),
+            _ => None,
         }
+    }
 
-        Ok(result)
+    fn tokens(&self) -> impl Iterator<Item = &Token> {
+        Some(&self.opcode).into_iter().chain(&self.arg1).chain(&self.arg2).chain(&self.arg3)
     }
 
-    fn compile_token(arg: Token) -> Vec<u8> {
-        let mut result: Vec<u8> = vec!();
-        match arg {
-            Token::Opcode(op) => result.push(op as u8),
-            Token::Register(reg) => result.push(reg),
-            Token::IntegerOperand(i) => {
-                let nb = i as u16;
-                let byte1 = (nb >> 8) as u8;
-                let byte2 = nb as u8;
-                result.push(byte1);
-                result.push(byte2);
-            },
+    /// Names of the labels of the `@name` operands
+    pub fn label_usages(&self) -> impl Iterator<Item = &str> {
+        self.tokens().filter_map(|token| match token {
+            Token::LabelUsage(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The instruction with every `@name` operand replaced with the value `resolve` gives its
+    /// label, failing on the first label it does not know
+    pub fn resolve_labels(&self, resolve: impl Fn(&str) -> Option<i32>) -> Result<AssemblerInstruction, AssemblerError> {
+        let resolve = |token: &Option<Token>| match token {
+            Some(Token::LabelUsage(name)) => resolve(name).map(|value| Some(Token::IntegerOperand(value)))
+                .ok_or_else(|| AssemblerError::UnknownLabel(name.clone())),
+            token => Ok(token.clone()),
         };
-        result
+        Ok(AssemblerInstruction {
+            opcode: resolve(&Some(self.opcode.clone()))?.expect("the opcode token is kept"),
+            arg1: resolve(&self.arg1)?,
+            arg2: resolve(&self.arg2)?,
+            arg3: resolve(&self.arg3)?,
+        })
+    }
+
+    /// Checks the tokens against the operands the opcode expects and builds the instruction
+    pub fn to_instruction(&self) -> Result<Instruction, AssemblerError> {
+        let opcode = match self.opcode {
+            Token::Opcode(o) => o,
+            _ => return Err(AssemblerError::NoOpcode)
+        };
+        let args = [&self.arg1, &self.arg2, &self.arg3];
+        let mut operands = [Operand::None; 3];
+        for (i, kind) in opcode.operand_kinds().iter().enumerate() {
+            operands[i] = match (kind, args[i]) {
+                (OperandKind::None, None) => Operand::None,
+                (OperandKind::Register, Some(Token::Register(r))) => Operand::Register(*r),
+                // Negative integers are encoded in two's complement
+                (OperandKind::Integer, Some(Token::IntegerOperand(v))) if (-0x8000..=0xffff).contains(v) => Operand::Integer(*v as u16),
+                (OperandKind::Byte, Some(Token::Register(r))) => Operand::Byte(*r),
+                (OperandKind::Byte, Some(Token::IntegerOperand(v))) if (0..=255).contains(v) => Operand::Byte(*v as u8),
+                (OperandKind::Integer, Some(Token::IntegerOperand(v))) => return Err(AssemblerError::OutOfRange { opcode: opcode, position: i + 1, value: *v, min: -0x8000, max: 0xffff }),
+                (OperandKind::Byte, Some(Token::IntegerOperand(v))) => return Err(AssemblerError::OutOfRange { opcode: opcode, position: i + 1, value: *v, min: 0, max: 255 }),
+                // Labels are resolved by the assembler, one left here was never declared
+                (OperandKind::Integer | OperandKind::Byte, Some(Token::LabelUsage(name))) => return Err(AssemblerError::UnknownLabel(name.clone())),
+                (OperandKind::None, Some(_)) => return Err(AssemblerError::TooManyOperands(opcode)),
+                (_, None) => return Err(AssemblerError::MissingOperand { opcode: opcode, position: i + 1, expected: *kind }),
+                (_, Some(_)) => return Err(AssemblerError::InvalidOperand { opcode: opcode, position: i + 1, expected: *kind }),
+            };
+        }
+        Ok(Instruction::with_operands(opcode, operands))
+    }
+
+    pub fn compile(&self) -> Result<Vec<u8>, AssemblerError> {
+        let mut bytes = vec!();
+        self.to_instruction()?.encode(&mut bytes);
+        Ok(bytes)
     }
 }
 
@@ -121,7 +398,7 @@ impl AssemblerInstructionRule {
         }
     }
 
-    pub fn is_match(&self, inst: AssemblerInstruction) -> bool {
+    pub fn is_match(&self, inst: &AssemblerInstruction) -> bool {
         // test the opcode
         match inst.opcode {
             Token::Opcode(opc) => {
@@ -133,24 +410,24 @@ impl AssemblerInstructionRule {
         };
         
         // test the arg1 type 
-        if !Self::compare_token(inst.arg1, self.arg1) {
+        if !Self::compare_token(inst.arg1.as_ref(), self.arg1) {
             return false
         }
 
         // test the arg2 type 
-        if !Self::compare_token(inst.arg2, self.arg2) {
+        if !Self::compare_token(inst.arg2.as_ref(), self.arg2) {
             return false
         }
 
         // test the arg3 type 
-        if !Self::compare_token(inst.arg3, self.arg3) {
+        if !Self::compare_token(inst.arg3.as_ref(), self.arg3) {
             return false
         }
 
         true
     }
 
-    fn compare_token(token: Option<Token>, token_type: Option<TokenType>) -> bool {
+    fn compare_token(token: Option<&Token>, token_type: Option<TokenType>) -> bool {
         if token.is_none() != token_type.is_none() {
             return false
         }
@@ -159,7 +436,7 @@ impl AssemblerInstructionRule {
         }
         let a = TokenType::from(token.unwrap());
         let b = token_type.unwrap();
-        return a == b
+        a == b
     }
 }
 
@@ -170,6 +447,12 @@ pub struct Grammar {
     pub instruction_rules: Vec<AssemblerInstructionRule>
 }
 
+impl Default for Grammar {
+    fn default() -> Self {
+        Grammar::new()
+    }
+}
+
 impl Grammar {
     pub fn new() -> Self {
         Self {
@@ -187,108 +470,206 @@ impl Grammar {
     }
 }
 
+/// Whether `src` can name a label or a constant: a letter or an underscore, then letters, digits
+/// and underscores
+pub fn is_identifier(src: &str) -> bool {
+    src.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && src.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Value of an integer literal, optionally negated with `-`: decimal, hexadecimal after `0x`, or
+/// an ASCII character between single quotes, `\n`, `\t`, `\0`, `\\` and `\'` included. `None`
+/// for anything else, or a value beyond 64 bits.
+pub fn parse_integer(src: &str) -> Option<i64> {
+    let (negative, literal) = match src.strip_prefix('-') {
+        Some(literal) => (true, literal),
+        None => (false, src),
+    };
+    let value = if let Some(hex) = literal.strip_prefix("0x") {
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(character) = literal.strip_prefix('\'').and_then(|c| c.strip_suffix('\'')) {
+        let c = match character {
+            "\\n" => '\n',
+            "\\t" => '\t',
+            "\\0" => '\0',
+            "\\\\" => '\\',
+            "\\'" => '\'',
+            _ => {
+                let mut chars = character.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if c.is_ascii() => c,
+                    _ => return None,
+                }
+            },
+        };
+        c as i64
+    } else {
+        if literal.is_empty() || !literal.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        literal.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// The line up to its comment, which starts with `;` or `#;` outside of a string or character
+/// literal
+pub fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quote.is_some() => escaped = true,
+            '"' | '\'' if quote.is_none() => quote = Some(c),
+            _ if quote == Some(c) => quote = None,
+            ';' if quote.is_none() => return line[..i].strip_suffix('#').unwrap_or(&line[..i]),
+            _ => (),
+        }
+    }
+    line
+}
+
+/// Register names the lexer knows without configuration: `$zero`, the register the VM can hard-wire
+/// to zero, then the stack pointer and the return address of the usual calling convention
+pub const DEFAULT_REGISTER_ALIASES: [(&str, u8); 3] = [("zero", 0), ("sp", 30), ("ra", 31)];
 
 #[derive(Debug)]
 pub struct Lexer {
-    grammar: Grammar
+    grammar: Grammar,
+    /// Register tokens must be lower than this
+    register_count: usize,
+    /// Register of every `$name` alias
+    register_aliases: HashMap<String, u8>,
+}
+
+impl Default for Lexer {
+    fn default() -> Self {
+        Lexer::new()
+    }
 }
 
 impl Lexer {
     pub fn new() -> Self {
+        Self::with_register_count(REGISTER_COUNT)
+    }
+
+    /// Creates a lexer for a VM with `register_count` registers
+    pub fn with_register_count(register_count: usize) -> Self {
         Self {
-            grammar: build_grammar()
+            grammar: build_grammar(),
+            register_count: register_count,
+            register_aliases: DEFAULT_REGISTER_ALIASES.iter().map(|(name, r)| (name.to_string(), *r)).collect(),
         }
     }
 
+    /// Adds register aliases to the default ones, replacing those of the same name
+    pub fn with_register_aliases(mut self, aliases: &BTreeMap<String, u8>) -> Self {
+        self.register_aliases.extend(aliases.iter().map(|(name, r)| (name.clone(), *r)));
+        self
+    }
+
     pub fn match_instruction(&self, inst: AssemblerInstruction) -> bool {
         for rule in &self.grammar.instruction_rules {
-            if rule.is_match(inst) {
+            if rule.is_match(&inst) {
                 return true
             }
         }
         false
     }
 
-    pub fn parse_instruction(&self, inst: &str) -> Result<AssemblerInstruction, String> {
-        let args: Vec<&str> = inst.split(" ").collect();
-        let mut tokens: Vec<Token> = vec!();
-        if args.len() > 4 {
-            return Err(format!("Invalid instrcution, too many arguments (for '{}')", inst))
-        }
-        if args.len() == 0 {
-            match self.parse_str(inst) {
-                Ok(t) => {
-                    tokens.push(t);
-                },
-                Err(e) => return Err(format!("No matching instruction for '{}' ({})", inst, e))
-            }
-        }
-        else {
-            for arg in &args {
-                match self.parse_str(arg) {
-                    Ok(t) => {
-                        tokens.push(t);
-                    },
-                    Err(e) => return Err(format!("No matching instruction for '{}' ({})", inst, e))
-                }
-            }
-        }
-        let opcode = *tokens.get(0).unwrap();
-        let arg1 = match tokens.get(1) {
-            Some(&t) => Some(t),
-            None => None
-        };
-        let arg2 = match tokens.get(2) {
-            Some(&t) => Some(t),
-            None => None
-        };
-        let arg3 = match tokens.get(3) {
-            Some(&t) => Some(t),
-            None => None
-        };
-        Ok(AssemblerInstruction {
-            opcode: opcode,
-            arg1: arg1,
-            arg2: arg2,
-            arg3: arg3
-        })
-        
+    /// Parses one instruction, ignoring a trailing comment
+    pub fn parse_instruction(&self, inst: &str) -> Result<AssemblerInstruction, LexError> {
+        let inst = strip_comment(inst).trim();
+        AssemblerInstruction::from_tokens(inst, self.tokenize(inst)?)
+    }
+
+    /// Splits a line into its whitespace-separated tokens, up to its comment
+    pub fn tokenize(&self, line: &str) -> Result<Vec<Token>, LexError> {
+        self.tokenize_with_constants(line, &HashMap::new())
+    }
+
+    /// Same as `tokenize`, replacing every `#NAME` operand with the value of that constant
+    pub fn tokenize_with_constants(&self, line: &str, constants: &HashMap<String, i32>) -> Result<Vec<Token>, LexError> {
+        strip_comment(line).split_whitespace()
+            .map(|word| match word.strip_prefix('#').filter(|name| is_identifier(name)) {
+                Some(name) => constants.get(name).map(|value| Token::IntegerOperand(*value))
+                    .ok_or_else(|| LexError::UndefinedConstant(name.to_string())),
+                None => self.parse_str(word),
+            })
+            .collect()
     }
 
-    pub fn parse_str(&self, src: &str) -> Result<Token, String> {
+    pub fn parse_str(&self, src: &str) -> Result<Token, LexError> {
         for t in &self.grammar.terminal_rules {
             if t.regex.is_match(src) {
                 match t.token_type {
                     TokenType::Opcode => {
                         let opcode = t.regex.captures(src).unwrap().name("op").unwrap().as_str();
-                        let op = Token::Opcode(instruction::Opcode::from(opcode));
-                        return Ok(op)
+                        return Ok(Token::Opcode(Opcode::try_from(opcode)?))
                     },
                     TokenType::Register => {
-                        let n: u8 = t.regex.captures(src).unwrap().name("reg").unwrap().as_str().parse().unwrap();
-                        return Ok(Token::Register(n))
+                        let name = t.regex.captures(src).unwrap().name("reg").unwrap().as_str();
+                        let n: usize = match name.parse() {
+                            Ok(n) => n,
+                            Err(_) if name.starts_with(|c: char| c.is_ascii_digit()) => usize::MAX,
+                            Err(_) => *self.register_aliases.get(name)
+                                .ok_or_else(|| LexError::UnknownRegisterAlias(src.to_string()))? as usize,
+                        };
+                        if n >= self.register_count {
+                            return Err(LexError::InvalidRegister { register: src.to_string(), count: self.register_count })
+                        }
+                        return Ok(Token::Register(n as u8))
                     },
                     TokenType::IntegerOperand => {
-                        let i: i32 = t.regex.captures(src).unwrap().name("intop").unwrap().as_str().parse().unwrap();
+                        let literal = t.regex.captures(src).unwrap().name("intop").unwrap().as_str();
+                        let i = parse_integer(literal).and_then(|i| i32::try_from(i).ok())
+                            .ok_or_else(|| LexError::InvalidInteger(src.to_string()))?;
                         return Ok(Token::IntegerOperand(i))
                     },
+                    TokenType::LabelDeclaration => {
+                        let name = t.regex.captures(src).unwrap().name("label").unwrap().as_str();
+                        return Ok(Token::LabelDeclaration(name.to_string()))
+                    },
+                    TokenType::LabelUsage => {
+                        let name = t.regex.captures(src).unwrap().name("label").unwrap().as_str();
+                        return Ok(Token::LabelUsage(name.to_string()))
+                    },
                 }
             }
         }
-        Err(format!("No matching token for '{}'", src))
+        Err(LexError::NoMatchingToken(src.to_string()))
+    }
+}
+
+/// Token type an operand kind is written with in the source
+fn operand_token_type(kind: OperandKind) -> Option<TokenType> {
+    match kind {
+        OperandKind::None => None,
+        OperandKind::Register => Some(TokenType::Register),
+        OperandKind::Integer | OperandKind::Byte => Some(TokenType::IntegerOperand),
     }
 }
 
 pub fn build_grammar() -> Grammar {
     let mut grammar = Grammar::new();
-    grammar.add_rule(r"(?P<op>[a-z]+)", TokenType::Opcode);
-    grammar.add_rule(r"\$(?P<reg>\d{1,2})", TokenType::Register);
-    grammar.add_rule(r"\#(?P<intop>\d+)", TokenType::IntegerOperand);
-    grammar.add_intruction_rule(AssemblerInstructionRule::new(instruction::Opcode::LOAD, Some(TokenType::Register), Some(TokenType::IntegerOperand), None));
+    // Before the opcodes, which would match the name of the label
+    grammar.add_rule(r"^(?P<label>[A-Za-z_][A-Za-z0-9_]*):$", TokenType::LabelDeclaration);
+    grammar.add_rule(r"^@(?P<label>[A-Za-z_][A-Za-z0-9_]*)$", TokenType::LabelUsage);
+    grammar.add_rule(r"^(?P<op>[a-z]+)$", TokenType::Opcode);
+    grammar.add_rule(r"^\$(?P<reg>\d+|[A-Za-z_][A-Za-z0-9_]*)$", TokenType::Register);
+    grammar.add_rule(r"^\#(?P<intop>-?(0x[0-9A-Fa-f]+|\d+|'([^\\]|\\[nt0\\'])'))$", TokenType::IntegerOperand);
+    for info in instruction::OPCODES {
+        let [arg1, arg2, arg3] = info.operands;
+        grammar.add_intruction_rule(AssemblerInstructionRule::new(info.opcode, operand_token_type(arg1), operand_token_type(arg2), operand_token_type(arg3)));
+    }
     grammar 
 }
 
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -297,6 +678,10 @@ mod tests {
         let lex = Lexer::new();
         assert_eq!(lex.parse_str("load"), Ok(Token::Opcode(instruction::Opcode::LOAD)));
         assert!(lex.parse_str("123").is_err());
+        assert_eq!(lex.parse_str("lod"), Err(LexError::Opcode(OpcodeError::UnknownMnemonic("lod".to_string()))));
+        let error = AssemblerError::from(lex.parse_instruction("jmpx $1").unwrap_err()).at_line(1, 0, "jmpx $1");
+        assert_eq!(error.render().lines().last(), Some("  | ^^^^ did you mean 'jmp'?"));
+        assert_eq!(AssemblerError::from(lex.parse_str("frobnicate").unwrap_err()).hint(), Some("expected the mnemonic of an instruction".to_string()));
     }
 
     #[test]
@@ -304,6 +689,21 @@ mod tests {
         let lex = Lexer::new();
         assert_eq!(lex.parse_str("$1"), Ok(Token::Register(1)));
         assert!(lex.parse_str("$").is_err());
+        assert_eq!(lex.parse_str("$40"), Err(LexError::InvalidRegister { register: "$40".to_string(), count: 32 }));
+        assert!(lex.parse_str("$300").is_err());
+        assert_eq!(Lexer::with_register_count(8).parse_str("$8").unwrap_err().to_string(),
+            "register '$8' does not exist, the VM has 8 registers");
+    }
+
+    #[test]
+    fn test_register_aliases() {
+        let lex = Lexer::new();
+        assert_eq!(lex.tokenize("add $zero $sp $ra"), Ok(vec![Token::Opcode(Opcode::ADD), Token::Register(0), Token::Register(30), Token::Register(31)]));
+        assert_eq!(lex.parse_str("$fp").unwrap_err().to_string(), "unknown register alias '$fp'");
+        let aliases = vec![("fp".to_string(), 29), ("sp".to_string(), 7)].into_iter().collect();
+        let lex = Lexer::with_register_count(8).with_register_aliases(&aliases);
+        assert_eq!((lex.parse_str("$sp"), lex.parse_str("$zero")), (Ok(Token::Register(7)), Ok(Token::Register(0))));
+        assert_eq!(lex.parse_str("$fp"), Err(LexError::InvalidRegister { register: "$fp".to_string(), count: 8 }));
     }
 
     #[test]
@@ -311,6 +711,40 @@ mod tests {
         let lex = Lexer::new();
         assert_eq!(lex.parse_str("#100"), Ok(Token::IntegerOperand(100)));
         assert!(lex.parse_str("#").is_err());
+        assert_eq!(lex.parse_str("#99999999999"), Err(LexError::InvalidInteger("#99999999999".to_string())));
+        assert_eq!(lex.parse_str("#-5"), Ok(Token::IntegerOperand(-5)));
+        assert_eq!(lex.parse_str("#0xFF"), Ok(Token::IntegerOperand(255)));
+        assert_eq!(lex.parse_str("#'A'"), Ok(Token::IntegerOperand(65)));
+        assert_eq!(lex.parse_str("#'\\n'"), Ok(Token::IntegerOperand(10)));
+        assert_eq!(lex.parse_str("#0x"), Err(LexError::NoMatchingToken("#0x".to_string())));
+        assert_eq!(lex.parse_str("#12ab"), Err(LexError::NoMatchingToken("#12ab".to_string())));
+    }
+
+    #[test]
+    fn test_integer_operand_range() {
+        let lex = Lexer::new();
+        let compile = |src: &str| lex.parse_instruction(src).map_err(AssemblerError::from).and_then(|inst| inst.compile());
+        assert_eq!(compile("load $0 #-1"), Ok(vec![1, 0, 0xff, 0xff]));
+        assert_eq!(compile("load $0 #0xffff"), compile("load $0 #-1"));
+        assert_eq!(compile("load $0 #'A'"), compile("load $0 #65"));
+        assert_eq!(compile("load $0 #-32769").unwrap_err().to_string(), "operand 2 of 'load' is out of range: -32769 is not within -32768..=65535");
+        assert_eq!(compile("load $0 #0x10000").unwrap_err().to_string(), "operand 2 of 'load' is out of range: 65536 is not within -32768..=65535");
+        assert_eq!(compile("sw $0 $1 #256").unwrap_err().to_string(), "operand 3 of 'sw' is out of range: 256 is not within 0..=255");
+    }
+
+    #[test]
+    fn test_label_tokens() {
+        let lex = Lexer::new();
+        assert_eq!(lex.tokenize("loop:  jmp  $0"), Ok(vec![
+            Token::LabelDeclaration("loop".to_string()),
+            Token::Opcode(Opcode::JMP),
+            Token::Register(0),
+        ]));
+        assert_eq!(lex.parse_str("@L_0010"), Ok(Token::LabelUsage("L_0010".to_string())));
+        assert_eq!(TokenType::from(&Token::LabelUsage("end".to_string())), TokenType::LabelUsage);
+        let compile = |src: &str| lex.parse_instruction(src).unwrap().compile();
+        assert_eq!(compile("load $0 @end"), Err(AssemblerError::UnknownLabel("end".to_string())));
+        assert_eq!(compile("jmp @end").unwrap_err().to_string(), "invalid operand 1 for 'jmp', expected a register");
     }
 
     #[test]
@@ -325,6 +759,38 @@ mod tests {
         assert!(lex.parse_instruction("load load $2 $1 #100").is_err());
     }
 
+    #[test]
+    fn test_comments() {
+        let lex = Lexer::new();
+        let load = lex.parse_instruction("load $1 #100");
+        assert_eq!(lex.parse_instruction("load $1 #100 ; set the counter"), load);
+        assert_eq!(lex.parse_instruction("load $1 #100;counter"), load);
+        assert_eq!(lex.parse_instruction("  load $1 #100 #; counter"), load);
+        assert_eq!(lex.parse_instruction("load $1 #';' ; semicolon"), lex.parse_instruction("load $1 #59"));
+        assert_eq!(lex.tokenize("; only a comment"), Ok(vec![]));
+        assert_eq!(strip_comment(".asciiz \"a;b\\\"#;\" ; text"), ".asciiz \"a;b\\\"#;\" ");
+    }
+
+    #[test]
+    fn test_render() {
+        let lex = Lexer::new();
+        let error = |src: &str| lex.parse_instruction(src).map_err(AssemblerError::from)
+            .and_then(|inst| inst.compile())
+            .unwrap_err()
+            .at_line(12, 4, src);
+        let e = error("add $1 %2 $3");
+        assert!(matches!(&e, AssemblerError::Line { column: Some(12), token: Some(token), .. } if token == "%2"));
+        assert_eq!(e.render(), [
+            "line 12, column 12: no matching token for '%2'",
+            "   |",
+            "12 |     add $1 %2 $3",
+            "   |            ^^ expected an opcode, a $register, an #integer, a label: or a @label",
+        ].join("\n"));
+        assert_eq!(error("load $0 #70000").render().lines().last(), Some("   |             ^^^^^^ expected a value within -32768..=65535"));
+        assert_eq!(error("load $1").render().lines().last(), Some("   = expected an integer"));
+        assert_eq!(AssemblerError::NoOpcode.render(), "no opcode found");
+    }
+
     #[test]
     fn test_rule_load() {
         let lex = Lexer::new();
@@ -332,6 +798,35 @@ mod tests {
         assert!(lex.match_instruction(inst));
     }
 
+    #[test]
+    fn test_rules_from_opcode_table() {
+        let lex = Lexer::new();
+        assert!(lex.match_instruction(lex.parse_instruction("hlt").unwrap()));
+        assert!(lex.match_instruction(lex.parse_instruction("jmpb $1").unwrap()));
+        assert!(lex.match_instruction(lex.parse_instruction("sw $1 $2 #8").unwrap()));
+        assert!(!lex.match_instruction(lex.parse_instruction("jmp #1").unwrap()));
+    }
+
+    #[test]
+    fn test_every_mnemonic_matches_its_rule() {
+        let lex = Lexer::new();
+        for info in instruction::OPCODES {
+            let operands: Vec<String> = info.operands.iter().filter(|kind| **kind != OperandKind::None).enumerate().map(|(i, kind)| match kind {
+                OperandKind::Register => format!("${}", i + 1),
+                _ => format!("#{}", i + 1),
+            }).collect();
+            let src = format!("{} {}", info.mnemonic, operands.join(" "));
+            assert!(lex.match_instruction(lex.parse_instruction(&src).unwrap()), "{}", src);
+            let extra = format!("{} $0", src);
+            assert!(lex.parse_instruction(&extra).map_or(true, |inst| !lex.match_instruction(inst)), "{}", extra);
+            if let Some(last) = operands.last() {
+                let missing = src.strip_suffix(last.as_str()).unwrap();
+                assert!(!lex.match_instruction(lex.parse_instruction(missing).unwrap()), "{}", missing);
+            }
+        }
+        assert_eq!(lex.grammar.instruction_rules.len(), instruction::OPCODES.len());
+    }
+
     #[test]
     fn test_compile_instruction() {
         let lex = Lexer::new();
@@ -340,4 +835,25 @@ mod tests {
         let vec2 = inst.compile().unwrap();
         assert_eq!(vec1, vec2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_compile_pads_instruction() {
+        let lex = Lexer::new();
+        let inst = lex.parse_instruction("jeq $1 $2").unwrap();
+        assert_eq!(inst.compile().unwrap(), vec![15, 1, 2, 0]);
+        let inst = lex.parse_instruction("add #1 #2 #3").unwrap();
+        assert!(inst.compile().is_err());
+    }
+
+    #[test]
+    fn test_compile_checks_operands() {
+        let lex = Lexer::new();
+        let compile = |src: &str| lex.parse_instruction(src).unwrap().compile();
+        assert_eq!(compile("sw $1 $2 #8"), Ok(vec![17, 1, 2, 8]));
+        assert_eq!(compile("sw $1 $2 $8"), Ok(vec![17, 1, 2, 8]));
+        assert_eq!(compile("load $1"), Err(AssemblerError::MissingOperand { opcode: Opcode::LOAD, position: 2, expected: OperandKind::Integer }));
+        assert_eq!(compile("jmp $1 $2"), Err(AssemblerError::TooManyOperands(Opcode::JMP)));
+        assert_eq!(compile("load #1 #2").unwrap_err().to_string(), "invalid operand 1 for 'load', expected a register");
+        assert!(compile("sw $1 $2 #256").is_err());
+    }
+}
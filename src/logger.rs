@@ -0,0 +1,34 @@
+use std::env;
+use std::str::FromStr;
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Variable setting the most verbose level the CLI prints, such as `IRIDIUM_LOG=debug`
+pub const LOG_ENV: &str = "IRIDIUM_LOG";
+
+/// Writes the records of the `log` pipeline, those of the guest LOG syscall included, to stderr
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{} {}: {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Installs the stderr logger, printing the records at the level of `LOG_ENV` or more severe
+/// ones, information by default
+pub fn init() {
+    let level = env::var(LOG_ENV).ok().and_then(|level| LevelFilter::from_str(&level).ok()).unwrap_or(LevelFilter::Info);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
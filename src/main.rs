@@ -1,10 +1,348 @@
+// Struct literals spell out `field: field` throughout the crate
+#![allow(clippy::redundant_field_names)]
+
 pub mod instruction;
 pub mod vm;
 pub mod repl;
 pub mod lexer;
+pub mod assembler;
+pub mod syscall;
+pub mod verifier;
+pub mod test_runner;
+pub mod runner;
+pub mod analyzer;
+pub mod diff;
+pub mod eval;
+pub mod bytecode;
+pub mod heap;
+pub mod cfg;
+pub mod aot;
+pub mod trace;
+pub mod profile;
+pub mod taint;
+pub mod config;
+pub mod doc;
+pub mod disasm;
+pub mod scheduler;
+pub mod bench;
+pub mod logger;
+pub mod relocation;
+pub mod signing;
+
+use std::path::Path;
 
 
 fn main() {
-    let mut repl = repl::REPL::new();
-    repl.run();
+    logger::init();
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|s| s.as_str()) {
+        Some("test") => {
+            let dir = args.get(2).map_or("tests", |s| s.as_str());
+            match test_runner::run_directory(Path::new(dir)) {
+                Ok(true) => (),
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some("analyze") => {
+            let result = match (args.get(2).map(|s| s.as_str()), args.get(3)) {
+                (Some("--cfg-dot"), Some(path)) => analyzer::cfg_dot_file(Path::new(path)).map(|_| true),
+                (Some(path), None) if path != "--cfg-dot" => analyzer::analyze_file(Path::new(path)),
+                _ => Err("Usage: analyze [--cfg-dot] <file>".to_string())
+            };
+            match result {
+                Ok(true) => (),
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some("assemble") => {
+            let result = parse_assemble_args(&args[2..])
+                .and_then(|(source, output, endianness, encoding, key)| {
+                    let config = config::Config::load()?;
+                    runner::assemble_file(Path::new(source), Path::new(output), endianness, encoding, key.map(Path::new), &config)
+                });
+            match result {
+                Ok(len) => println!("Wrote {} bytes", len),
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some("keygen") => {
+            let result = match args.get(2) {
+                Some(path) => signing::keygen_file(Path::new(path)),
+                None => Err("Usage: keygen <key file>".to_string())
+            };
+            match result {
+                Ok(key) => println!("Public key: {}", key),
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some("diff") => {
+            let result = match (args.get(2), args.get(3)) {
+                (Some(old), Some(new)) => diff::diff_files(Path::new(old), Path::new(new)),
+                _ => Err("Usage: diff <old bytecode> <new bytecode>".to_string())
+            };
+            match result {
+                Ok(true) => (),
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some("run") => {
+            let result = parse_run_args(&args[2..]).and_then(|(path, format, trace, overrides)| {
+                let config = config::Config::load()?.merge(overrides);
+                runner::run_file(Path::new(path), format, trace.map(Path::new), &config)
+            });
+            match result {
+                Ok(code) => std::process::exit(code),
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some("schedule") => {
+            let result = parse_schedule_args(&args[2..]).and_then(|(programs, slice, seed)| {
+                let programs: Vec<(&Path, scheduler::Priority)> = programs.into_iter().map(|(p, priority)| (Path::new(p), priority)).collect();
+                scheduler::schedule_files(&programs, slice, seed, &config::Config::load()?)
+            });
+            match result {
+                Ok(code) => std::process::exit(code),
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some("bench") => {
+            let result = parse_bench_args(&args[2..])
+                .and_then(|(path, iterations)| bench::bench_file(path.map(Path::new), iterations, &load_config()));
+            if let Err(e) = result {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Some("aot") => {
+            let result = parse_aot_args(&args[2..])
+                .and_then(|(input, output, target)| aot::aot_file(Path::new(input), Path::new(output), target));
+            match result {
+                Ok(len) => println!("Wrote {} bytes", len),
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some("disasm") => {
+            let result = match &args[2..] {
+                [path] => disasm::disasm_file(Path::new(path), false),
+                [path, flag] if flag == "--annotate" => disasm::disasm_file(Path::new(path), true),
+                _ => Err("Usage: disasm <bytecode> [--annotate]".to_string())
+            };
+            if let Err(e) = result {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Some("doc") => {
+            let result = parse_doc_args(&args[2..])
+                .and_then(|(format, output)| doc::doc_file(format, output.map(Path::new)));
+            if let Err(e) = result {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        #[cfg(feature = "tui")]
+        Some("tui") => {
+            let mut repl = repl::REPL::with_config(load_config());
+            if let Some(path) = args.get(2) {
+                if let Err(e) = repl.load_source_file(path) {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            if let Err(e) = repl::tui::Debugger::new(repl).run() {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => {
+            let mut repl = repl::REPL::with_config(load_config());
+            repl.run();
+        }
+    }
+}
+
+/// Reads the configuration files, exiting if one of them is invalid
+fn load_config() -> config::Config {
+    match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parses `<file> [--output text|json] [--trace <trace.json>] [--heap-size <bytes>] [--sparse-heap]
+/// [--max-steps <n>] [--timeout <ms>] [--trusted]`. The last five override the configuration files.
+fn parse_run_args(args: &[String]) -> Result<(&str, runner::OutputFormat, Option<&str>, config::Config), String> {
+    let mut path = None;
+    let mut format = runner::OutputFormat::Text;
+    let mut trace = None;
+    let mut overrides = config::Config::default();
+    let number = |flag: &str, value: Option<&String>| -> Result<usize, String> {
+        value.and_then(|v| v.parse().ok()).ok_or(format!("{} expects a number", flag))
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" => {
+                let value = args.next().ok_or("--output expects text or json")?;
+                format = runner::OutputFormat::parse(value)?;
+            },
+            "--trace" => {
+                trace = Some(args.next().ok_or("--trace expects a file")?.as_str());
+            },
+            "--heap-size" => overrides.heap_size = Some(number(arg, args.next())?),
+            "--sparse-heap" => overrides.sparse_heap = Some(true),
+            "--max-steps" => overrides.max_steps = Some(number(arg, args.next())?),
+            "--timeout" => overrides.timeout_ms = Some(number(arg, args.next())? as u64),
+            "--trusted" => overrides.trusted = Some(true),
+            file if path.is_none() => path = Some(file),
+            other => return Err(format!("Unexpected argument '{}'", other))
+        }
+    }
+    match path {
+        Some(path) => Ok((path, format, trace, overrides)),
+        None => Err("Usage: run <file> [--output text|json] [--trace <trace.json>] [--heap-size <bytes>] [--sparse-heap] [--max-steps <n>] [--timeout <ms>] [--trusted]".to_string())
+    }
+}
+
+/// A program of the `schedule` subcommand with its priority
+type ScheduledFile<'a> = (&'a str, scheduler::Priority);
+
+/// Parses `[--priority low|normal|high] <file>... [--slice <n>] [--seed <n>]`, a priority
+/// applying to the files after it
+fn parse_schedule_args(args: &[String]) -> Result<(Vec<ScheduledFile<'_>>, usize, Option<u64>), String> {
+    let mut files = vec![];
+    let mut slice = scheduler::DEFAULT_SLICE;
+    let mut seed = None;
+    let mut priority = scheduler::Priority::Normal;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--slice" => {
+                slice = args.next().and_then(|v| v.parse().ok()).ok_or("--slice expects a number")?;
+            },
+            "--seed" => {
+                seed = Some(args.next().and_then(|v| v.parse().ok()).ok_or("--seed expects a number")?);
+            },
+            "--priority" => {
+                let value = args.next().ok_or("--priority expects low, normal or high")?;
+                priority = scheduler::Priority::parse(value)?;
+            },
+            file => files.push((file, priority))
+        }
+    }
+    if files.is_empty() {
+        return Err("Usage: schedule [--priority low|normal|high] <file>... [--slice <n>] [--seed <n>]".to_string());
+    }
+    Ok((files, slice, seed))
+}
+
+/// Parses `[file] [--iterations <n>]`
+fn parse_bench_args(args: &[String]) -> Result<(Option<&str>, usize), String> {
+    let mut path = None;
+    let mut iterations = bench::DEFAULT_ITERATIONS;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--iterations" => {
+                iterations = args.next().and_then(|v| v.parse().ok()).ok_or("--iterations expects a number")?;
+            },
+            file if path.is_none() => path = Some(file),
+            _ => return Err("Usage: bench [file] [--iterations <n>]".to_string())
+        }
+    }
+    Ok((path, iterations))
+}
+
+/// Parses `<source> <output> [--endian big|little] [--compact] [--sign <key file>]`
+fn parse_assemble_args(args: &[String]) -> Result<(&str, &str, bytecode::Endianness, bytecode::Encoding, Option<&str>), String> {
+    let mut files = vec![];
+    let mut endianness = bytecode::Endianness::Big;
+    let mut encoding = bytecode::Encoding::Standard;
+    let mut key = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--endian" => {
+                let value = args.next().ok_or("--endian expects big or little")?;
+                endianness = bytecode::Endianness::parse(value)?;
+            },
+            "--compact" => encoding = bytecode::Encoding::Compact,
+            "--sign" => {
+                key = Some(args.next().ok_or("--sign expects a key file")?.as_str());
+            },
+            file => files.push(file)
+        }
+    }
+    match files.as_slice() {
+        [source, output] => Ok((source, output, endianness, encoding, key)),
+        _ => Err("Usage: assemble <source> <output> [--endian big|little] [--compact] [--sign <key file>]".to_string())
+    }
+}
+
+/// Parses `[--format markdown|html] [output]`
+fn parse_doc_args(args: &[String]) -> Result<(doc::DocFormat, Option<&str>), String> {
+    let mut format = doc::DocFormat::Markdown;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                let value = args.next().ok_or("--format expects markdown or html")?;
+                format = doc::DocFormat::parse(value)?;
+            },
+            file if output.is_none() => output = Some(file),
+            _ => return Err("Usage: doc [--format markdown|html] [output]".to_string())
+        }
+    }
+    Ok((format, output))
+}
+
+/// Parses `<bytecode> <output> [--target rust|c]`
+fn parse_aot_args(args: &[String]) -> Result<(&str, &str, aot::Target), String> {
+    let mut files = vec![];
+    let mut target = aot::Target::Rust;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--target" => {
+                let value = args.next().ok_or("--target expects rust or c")?;
+                target = aot::Target::parse(value)?;
+            },
+            file => files.push(file)
+        }
+    }
+    match files.as_slice() {
+        [input, output] => Ok((input, output, target)),
+        _ => Err("Usage: aot <bytecode> <output> [--target rust|c]".to_string())
+    }
 }
@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use crate::instruction::Opcode;
+
+/// Mean number of executed instructions between two latency samples
+pub const SAMPLE_PERIOD: u64 = 16;
+
+/// Buckets of a latency histogram: bucket 0 counts samples of 0 ticks, bucket k those of
+/// 2^(k-1) to 2^k - 1 ticks
+pub const BUCKETS: usize = 65;
+
+/// A cheap monotonic timer for the latency histograms: the time stamp counter on x86_64,
+/// nanoseconds elsewhere. Only differences between two readings are meaningful.
+#[cfg(target_arch = "x86_64")]
+pub fn ticks() -> u64 {
+    // SAFETY: RDTSC is available on every x86_64 CPU and has no side effect
+    unsafe { std::arch::x86_64::_rdtsc() }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn ticks() -> u64 {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START.get_or_init(std::time::Instant::now).elapsed().as_nanos() as u64
+}
+
+/// Distribution of the sampled latencies of one opcode, in power of two buckets
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Histogram {
+    pub buckets: [u64; BUCKETS],
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram { buckets: [0; BUCKETS] }
+    }
+}
+
+impl Histogram {
+    fn add(&mut self, ticks: u64) {
+        self.buckets[(u64::BITS - ticks.leading_zeros()) as usize] += 1;
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Smallest and largest latency counted by bucket `index`
+    pub fn bucket_range(index: usize) -> (u64, u64) {
+        match index {
+            0 => (0, 0),
+            _ => (1 << (index - 1), ((1u128 << index) - 1) as u64),
+        }
+    }
+}
+
+/// Execution count and cumulated time of one pc or opcode
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub struct Sample {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl Sample {
+    fn add(&mut self, time: Duration) {
+        self.count += 1;
+        self.time += time;
+    }
+}
+
+/// Per-instruction profile collected by a VM with profiling enabled, see `VM::start_profiling`
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Profile {
+    by_pc: BTreeMap<usize, (Opcode, Sample)>,
+    histograms: BTreeMap<&'static str, Histogram>,
+    /// Instructions left before the next latency sample
+    countdown: u64,
+    /// State of the generator spacing the samples, so that they do not stay in step with a loop
+    seed: u64,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile { seed: 0x2545_f491_4f6c_dd1d, ..Profile::default() }
+    }
+
+    /// Whether the next instruction should have its latency sampled. Samples are spaced by 1 to
+    /// `2 * SAMPLE_PERIOD - 1` instructions, drawn at random.
+    pub fn sample_next(&mut self) -> bool {
+        if self.countdown > 0 {
+            self.countdown -= 1;
+            return false;
+        }
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.countdown = self.seed % (2 * SAMPLE_PERIOD - 1);
+        true
+    }
+
+    /// Adds a latency sample of `ticks`, see `ticks()`, to the histogram of `opcode`
+    pub fn record_latency(&mut self, opcode: Opcode, ticks: u64) {
+        self.histograms.entry(opcode.mnemonic()).or_default().add(ticks);
+    }
+
+    /// Latency histogram of every sampled opcode, by mnemonic
+    pub fn histograms(&self) -> &BTreeMap<&'static str, Histogram> {
+        &self.histograms
+    }
+
+    /// Renders the latency histograms, opcodes with the highest median first. Every non-empty
+    /// bucket gets a line with its range, count and a bar scaled to the largest bucket.
+    pub fn histogram_lines(&self) -> Vec<String> {
+        let median = |h: &Histogram| {
+            let mut seen = 0;
+            h.buckets.iter().position(|count| {
+                seen += count;
+                seen * 2 >= h.samples()
+            })
+        };
+        let mut opcodes: Vec<(&&str, &Histogram)> = self.histograms.iter().collect();
+        opcodes.sort_by_key(|(_, h)| std::cmp::Reverse(median(h)));
+        let mut lines = vec![];
+        for (mnemonic, histogram) in opcodes {
+            lines.push(format!("{}: {} samples", mnemonic, histogram.samples()));
+            let largest = histogram.buckets.iter().max().copied().unwrap_or(0).max(1);
+            for (index, count) in histogram.buckets.iter().enumerate().filter(|(_, count)| **count > 0) {
+                let (low, high) = Histogram::bucket_range(index);
+                let bar = "#".repeat(((count * 20).div_ceil(largest)) as usize);
+                lines.push(format!("  {:>6}-{:<6} ticks {:>8} {}", low, high, count, bar));
+            }
+        }
+        lines
+    }
+
+    pub fn record(&mut self, pc: usize, opcode: Opcode, time: Duration) {
+        self.by_pc.entry(pc).or_insert((opcode, Sample::default())).1.add(time);
+    }
+
+    /// Samples of every executed pc, with the opcode found there
+    pub fn by_pc(&self) -> impl Iterator<Item = (usize, Opcode, Sample)> + '_ {
+        self.by_pc.iter().map(|(pc, (opcode, sample))| (*pc, *opcode, *sample))
+    }
+
+    /// Samples summed per opcode mnemonic
+    pub fn by_opcode(&self) -> BTreeMap<&'static str, Sample> {
+        let mut opcodes: BTreeMap<&'static str, Sample> = BTreeMap::new();
+        for (opcode, sample) in self.by_pc.values() {
+            let total = opcodes.entry(opcode.mnemonic()).or_default();
+            total.count += sample.count;
+            total.time += sample.time;
+        }
+        opcodes
+    }
+
+    /// Renders the profile as CSV: one `pc` row per executed offset, then one `opcode` row per
+    /// executed opcode, with counts and times in nanoseconds
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("kind,pc,opcode,count,total_ns,mean_ns\n");
+        let mut row = |kind: &str, pc: String, opcode: &str, sample: Sample| {
+            let total = sample.time.as_nanos();
+            let mean = total / sample.count.max(1) as u128;
+            out.push_str(&format!("{},{},{},{},{},{}\n", kind, pc, opcode, sample.count, total, mean));
+        };
+        for (pc, opcode, sample) in self.by_pc() {
+            row("pc", pc.to_string(), opcode.mnemonic(), sample);
+        }
+        for (mnemonic, sample) in self.by_opcode() {
+            row("opcode", String::new(), mnemonic, sample);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_csv() {
+        let mut profile = Profile::new();
+        profile.record(0, Opcode::LOAD, Duration::from_nanos(30));
+        profile.record(4, Opcode::LOAD, Duration::from_nanos(10));
+        profile.record(4, Opcode::LOAD, Duration::from_nanos(20));
+        profile.record(8, Opcode::HLT, Duration::from_nanos(5));
+        assert_eq!(profile.to_csv(), [
+            "kind,pc,opcode,count,total_ns,mean_ns",
+            "pc,0,load,1,30,30",
+            "pc,4,load,2,30,15",
+            "pc,8,hlt,1,5,5",
+            "opcode,,hlt,1,5,5",
+            "opcode,,load,3,60,20",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_latency_histograms() {
+        let mut profile = Profile::new();
+        for ticks in [0, 1, 5, 6, 7] {
+            profile.record_latency(Opcode::ADD, ticks);
+        }
+        profile.record_latency(Opcode::SYS, 900);
+        assert_eq!(profile.histograms()["add"].buckets[..4], [1, 1, 0, 3]);
+        assert_eq!(Histogram::bucket_range(3), (4, 7));
+        assert_eq!(Histogram::bucket_range(64), (1 << 63, u64::MAX));
+        assert_eq!(profile.histogram_lines(), vec![
+            "sys: 1 samples",
+            "     512-1023   ticks        1 ####################",
+            "add: 5 samples",
+            "       0-0      ticks        1 #######",
+            "       1-1      ticks        1 #######",
+            "       4-7      ticks        3 ####################",
+        ]);
+        let sampled = (0..16_000).filter(|_| profile.sample_next()).count();
+        assert!((800..1200).contains(&sampled));
+    }
+}
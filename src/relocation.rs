@@ -0,0 +1,77 @@
+use std::convert::TryFrom;
+use thiserror::Error;
+use crate::instruction::{Opcode, OperandKind, INSTRUCTION_SIZE};
+
+/// An absolute reference in an assembled program, the LOAD or PUSHTRAP at an offset whose
+/// immediate moves with the part of the image it points into. JMPF and JMPB being relative,
+/// the jumps they make need no relocation.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Relocation {
+    /// The immediate is the offset of an instruction
+    Code(usize),
+    /// The immediate is a heap address in the data section
+    Data(usize),
+}
+
+/// Where a program is loaded: the offset of its first instruction among the program bytes of
+/// the VM, and the heap address its data section starts from
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub struct Placement {
+    pub code_base: usize,
+    pub data_base: usize,
+}
+
+/// Errors raised while relocating a program
+#[derive(Debug, PartialEq, Copy, Clone, Error)]
+pub enum RelocationError {
+    #[error("relocation at offset {0} does not point at an instruction with a 16-bit immediate")]
+    NoImmediate(usize),
+    #[error("the reference at offset {offset} moves to {value}, past a 16-bit immediate")]
+    OutOfRange { offset: usize, value: usize },
+}
+
+/// Position in the encoded instructions of `opcode` of the 16-bit immediate an absolute reference
+/// can be written to, `None` for other opcodes
+fn immediate_at(opcode: Opcode) -> Option<usize> {
+    if !matches!(opcode, Opcode::LOAD | Opcode::PUSHTRAP) {
+        return None;
+    }
+    let kinds = opcode.operand_kinds();
+    let index = kinds.iter().position(|kind| *kind == OperandKind::Integer)?;
+    Some(1 + kinds[..index].iter().map(OperandKind::size).sum::<usize>())
+}
+
+/// Adds the bases of `placement` to the immediates `relocations` point at in `program`, an
+/// image assembled to run from offset 0 with its data at address 0
+pub fn relocate(program: &mut [u8], relocations: &[Relocation], placement: Placement) -> Result<(), RelocationError> {
+    for relocation in relocations {
+        let (offset, base) = match *relocation {
+            Relocation::Code(offset) => (offset, placement.code_base),
+            Relocation::Data(offset) => (offset, placement.data_base),
+        };
+        let (instruction, at) = program.get_mut(offset..offset + INSTRUCTION_SIZE)
+            .and_then(|instruction| immediate_at(Opcode::from_byte(instruction[0])).map(|at| (instruction, at)))
+            .ok_or(RelocationError::NoImmediate(offset))?;
+        let value = u16::from_be_bytes([instruction[at], instruction[at + 1]]) as usize + base;
+        let immediate = u16::try_from(value).map_err(|_| RelocationError::OutOfRange { offset: offset, value: value })?;
+        instruction[at..at + 2].copy_from_slice(&immediate.to_be_bytes());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relocate() {
+        let mut program = vec![1, 0, 0, 8, 1, 2, 0, 16, 6, 0, 0, 0, 37, 0, 4, 3];
+        let placement = Placement { code_base: 40, data_base: 256 };
+        relocate(&mut program, &[Relocation::Code(0), Relocation::Data(4), Relocation::Code(12)], placement).unwrap();
+        assert_eq!(program, vec![1, 0, 0, 48, 1, 2, 1, 16, 6, 0, 0, 0, 37, 0, 44, 3]);
+        assert_eq!(relocate(&mut program, &[Relocation::Code(8)], placement), Err(RelocationError::NoImmediate(8)));
+        let placement = Placement { code_base: 0xfff0, data_base: 0 };
+        assert_eq!(relocate(&mut program, &[Relocation::Code(0)], placement).unwrap_err().to_string(),
+            "the reference at offset 0 moves to 65568, past a 16-bit immediate");
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use super::ReplError;
+
+/// A `.`-command split into its name, positional arguments and `--flags`.
+///
+/// Arguments are separated by whitespace unless quoted with `"` or `'`; inside double quotes `\"`
+/// and `\\` escape. `--name` is a boolean flag and `--name=value` a flag carrying a value.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CommandArgs {
+    pub name: String,
+    positional: Vec<String>,
+    flags: HashMap<String, Option<String>>,
+}
+
+impl CommandArgs {
+    pub fn parse(line: &str) -> Result<CommandArgs, ReplError> {
+        let mut words = split(line)?.into_iter();
+        let name = match words.next() {
+            Some((word, _)) => word,
+            None => return Err(ReplError::MissingArgument("a command")),
+        };
+        let mut positional = vec![];
+        let mut flags = HashMap::new();
+        for (word, quoted) in words {
+            match word.strip_prefix("--") {
+                Some(flag) if !quoted && !flag.is_empty() => {
+                    match flag.split_once('=') {
+                        Some((key, value)) => flags.insert(key.to_string(), Some(value.to_string())),
+                        None => flags.insert(flag.to_string(), None),
+                    };
+                },
+                _ => positional.push(word),
+            }
+        }
+        Ok(CommandArgs { name, positional, flags })
+    }
+
+    /// The positional argument at `index`, `expected` describes it in the error when it is missing
+    pub fn positional(&self, index: usize, expected: &'static str) -> Result<&str, ReplError> {
+        self.positional.get(index).map(|s| s.as_str()).ok_or(ReplError::MissingArgument(expected))
+    }
+
+    /// Every positional argument from `index` onwards
+    pub fn rest(&self, index: usize) -> &[String] {
+        self.positional.get(index..).unwrap_or(&[])
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.contains_key(name)
+    }
+
+    /// The value of a `--name=value` flag
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).and_then(|v| v.as_deref())
+    }
+
+    /// Rejects any flag the command does not know about, so typos are not silently ignored
+    pub fn allow_flags(&self, allowed: &[&str]) -> Result<(), ReplError> {
+        match self.flags.keys().find(|f| !allowed.contains(&f.as_str())) {
+            Some(flag) => Err(ReplError::UnknownFlag(flag.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Splits a line into words, each with whether any part of it was quoted
+fn split(line: &str) -> Result<Vec<(String, bool)>, ReplError> {
+    let mut words = vec![];
+    let mut current: Option<(String, bool)> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(word) = current.take() {
+                    words.push(word);
+                }
+            },
+            '"' | '\'' => {
+                let word = current.get_or_insert_with(|| (String::new(), false));
+                word.1 = true;
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some('\\') if c == '"' => match chars.next() {
+                            Some(escaped) => word.0.push(escaped),
+                            None => return Err(ReplError::UnterminatedQuote),
+                        },
+                        Some(other) => word.0.push(other),
+                        None => return Err(ReplError::UnterminatedQuote),
+                    }
+                }
+            },
+            c => current.get_or_insert_with(|| (String::new(), false)).0.push(c),
+        }
+    }
+    if let Some(word) = current {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quoted_arguments_and_flags() {
+        let args = CommandArgs::parse(r#".load_file "my prog.iasm" --verify --base=0x10"#).unwrap();
+        assert_eq!(args.name, ".load_file");
+        assert_eq!(args.positional(0, "a path"), Ok("my prog.iasm"));
+        assert_eq!(args.positional(1, "a path"), Err(ReplError::MissingArgument("a path")));
+        assert!(args.flag("verify"));
+        assert!(!args.flag("quiet"));
+        assert_eq!(args.value("base"), Some("0x10"));
+        assert_eq!(args.allow_flags(&["verify"]), Err(ReplError::UnknownFlag("base".to_string())));
+    }
+
+    #[test]
+    fn test_parse_escapes_and_quoted_flags() {
+        let args = CommandArgs::parse(r#".echo 'it''s' "a \"b\" \\" "--raw" x"#).unwrap();
+        assert_eq!(args.rest(0), &["its", r#"a "b" \"#, "--raw", "x"]);
+        assert!(!args.flag("raw"));
+        assert_eq!(args.rest(9), &[] as &[String]);
+    }
+
+    #[test]
+    fn test_parse_unterminated_quote() {
+        assert_eq!(CommandArgs::parse(r#".load_file "oops"#), Err(ReplError::UnterminatedQuote));
+        assert_eq!(CommandArgs::parse(""), Err(ReplError::MissingArgument("a command")));
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::vm::VM;
+
+/// Default location of the breakpoints saved for the programs of a project
+pub const DEBUG_FILE: &str = ".iridium/debug.toml";
+
+/// `$register <op> value`, guarding a breakpoint or watchpoint
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Condition {
+    pub register: usize,
+    pub op: String,
+    pub value: i32,
+}
+
+const OPERATORS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+impl Condition {
+    pub fn parse(src: &str) -> Result<Condition, String> {
+        let invalid = || format!("invalid condition '{}', expected $register <op> value", src);
+        let op = *OPERATORS.iter().find(|op| src.contains(*op)).ok_or_else(invalid)?;
+        let (register, value) = src.split_once(op).ok_or_else(invalid)?;
+        let register = register.trim().strip_prefix('$').and_then(|r| r.parse().ok()).ok_or_else(invalid)?;
+        let value = value.trim().parse().map_err(|_| invalid())?;
+        Ok(Condition { register: register, op: op.to_string(), value: value })
+    }
+
+    /// Whether the condition holds on the active registers of `vm`. A missing register never matches.
+    pub fn holds(&self, vm: &VM) -> bool {
+        let left = match vm.register(self.register) {
+            Ok(v) => v,
+            Err(_) => return false,
+        };
+        match self.op.as_str() {
+            "==" => left == self.value,
+            "!=" => left != self.value,
+            "<=" => left <= self.value,
+            ">=" => left >= self.value,
+            "<" => left < self.value,
+            _ => left > self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${} {} {}", self.register, self.op, self.value)
+    }
+}
+
+impl TryFrom<String> for Condition {
+    type Error = String;
+
+    fn try_from(src: String) -> Result<Condition, String> {
+        Condition::parse(&src)
+    }
+}
+
+impl From<Condition> for String {
+    fn from(condition: Condition) -> String {
+        condition.to_string()
+    }
+}
+
+/// Stops `.continue` before the instruction at `offset` is executed
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Breakpoint {
+    pub offset: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<Condition>,
+}
+
+/// Stops `.continue` after an instruction changed the heap word at `addr`
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Watchpoint {
+    pub addr: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<Condition>,
+}
+
+fn describe(kind: &str, at: usize, condition: &Option<Condition>) -> String {
+    match condition {
+        Some(c) => format!("{} {:04x} if {}", kind, at, c),
+        None => format!("{} {:04x}", kind, at),
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", describe("breakpoint", self.offset, &self.condition))
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", describe("watchpoint", self.addr, &self.condition))
+    }
+}
+
+/// Breakpoints and watchpoints of one program
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct DebugPoints {
+    #[serde(default)]
+    pub breakpoints: Vec<Breakpoint>,
+    #[serde(default)]
+    pub watchpoints: Vec<Watchpoint>,
+}
+
+impl DebugPoints {
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty() && self.watchpoints.is_empty()
+    }
+
+    /// The breakpoint stopping the VM before its next instruction, if any
+    pub fn breakpoint_hit(&self, vm: &VM) -> Option<&Breakpoint> {
+        self.breakpoints.iter()
+            .find(|b| b.offset == vm.pc() && b.condition.as_ref().is_none_or(|c| c.holds(vm)))
+    }
+
+    /// Current value of every watched heap word
+    pub fn watched_words(&self, vm: &VM) -> Vec<Option<i32>> {
+        self.watchpoints.iter().map(|w| vm.heap_word(w.addr)).collect()
+    }
+}
+
+/// Contents of the debug file: the points of every program, keyed by program path
+#[derive(Debug, PartialEq, Default, Serialize, Deserialize)]
+struct DebugFile {
+    #[serde(default)]
+    programs: BTreeMap<String, DebugPoints>,
+}
+
+fn read_file(file: &Path) -> Result<DebugFile, String> {
+    match fs::read_to_string(file) {
+        Ok(src) => toml::from_str(&src).map_err(|e| format!("{}: {}", file.display(), e)),
+        Err(_) => Ok(DebugFile::default()),
+    }
+}
+
+/// The points saved in `file` for `program`, none if either is missing
+pub fn load(file: &Path, program: &str) -> Result<DebugPoints, String> {
+    Ok(read_file(file)?.programs.remove(program).unwrap_or_default())
+}
+
+/// Saves the points of `program` into `file`, keeping those of the other programs
+pub fn save(file: &Path, program: &str, points: &DebugPoints) -> Result<(), String> {
+    let mut contents = read_file(file)?;
+    if points.is_empty() {
+        contents.programs.remove(program);
+    } else {
+        contents.programs.insert(program.to_string(), points.clone());
+    }
+    if let Some(dir) = file.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    let src = toml::to_string(&contents).map_err(|e| e.to_string())?;
+    fs::write(file, src).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_condition() {
+        let condition = Condition::parse("$1 >= -3").unwrap();
+        assert_eq!((condition.register, condition.op.as_str(), condition.value), (1, ">=", -3));
+        assert_eq!(condition.to_string(), "$1 >= -3");
+        assert!(Condition::parse("1 == 2").is_err());
+        let mut vm = VM::new();
+        vm.set_register(1, -3).unwrap();
+        assert!(condition.holds(&vm));
+        assert!(!Condition::parse("$99 == 0").unwrap().holds(&vm));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let file = std::env::temp_dir().join(format!("debug-{}", std::process::id())).join("debug.toml");
+        let points = DebugPoints {
+            breakpoints: vec![Breakpoint { offset: 16, condition: Some(Condition::parse("$1 == 5").unwrap()) }],
+            watchpoints: vec![Watchpoint { addr: 8, condition: None }],
+        };
+        save(&file, "a.iasm", &points).unwrap();
+        save(&file, "b.iasm", &DebugPoints::default()).unwrap();
+        assert_eq!(load(&file, "a.iasm"), Ok(points));
+        assert_eq!(load(&file, "b.iasm"), Ok(DebugPoints::default()));
+        let src = fs::read_to_string(&file).unwrap();
+        let _ = fs::remove_dir_all(file.parent().unwrap());
+        assert!(src.contains("condition = \"$1 == 5\""));
+    }
+}
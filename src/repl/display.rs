@@ -0,0 +1,140 @@
+use std::convert::TryFrom;
+use crate::vm::VM;
+
+/// Expression shown by `.display` after every stop: integers, registers `$n`, heap words
+/// `mem[addr]`, parentheses and the `+ - * /` operators, computed like the VM does on i32
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Integer(i32),
+    Register(usize),
+    Memory(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary(Box<Expr>, char, Box<Expr>),
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_spaces(&mut self) {
+        while self.src[self.pos..].starts_with(' ') {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_spaces();
+        self.src[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_spaces();
+        if self.src[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            return true;
+        }
+        false
+    }
+
+    fn number(&mut self) -> Option<i64> {
+        self.skip_spaces();
+        let rest = &self.src[self.pos..];
+        let (digits, radix) = match rest.strip_prefix("0x") {
+            Some(hex) => (hex, 16),
+            None => (rest, 10),
+        };
+        let len = digits.find(|c: char| !c.is_digit(radix)).unwrap_or(digits.len());
+        let value = i64::from_str_radix(&digits[..len], radix).ok()?;
+        self.pos += rest.len() - digits.len() + len;
+        Some(value)
+    }
+
+    fn binary(&mut self, operators: &str, operand: fn(&mut Self) -> Option<Expr>) -> Option<Expr> {
+        let mut left = operand(self)?;
+        while let Some(op) = self.peek().filter(|c| operators.contains(*c)) {
+            self.pos += 1;
+            left = Expr::Binary(Box::new(left), op, Box::new(operand(self)?));
+        }
+        Some(left)
+    }
+
+    fn sum(&mut self) -> Option<Expr> {
+        self.binary("+-", Self::product)
+    }
+
+    fn product(&mut self) -> Option<Expr> {
+        self.binary("*/", Self::atom)
+    }
+
+    fn atom(&mut self) -> Option<Expr> {
+        if self.eat("$") {
+            return Some(Expr::Register(self.number()? as usize));
+        }
+        if self.eat("mem[") {
+            let addr = self.sum()?;
+            return if self.eat("]") { Some(Expr::Memory(Box::new(addr))) } else { None };
+        }
+        if self.eat("(") {
+            let inner = self.sum()?;
+            return if self.eat(")") { Some(inner) } else { None };
+        }
+        if self.eat("-") {
+            return Some(Expr::Negate(Box::new(self.atom()?)));
+        }
+        i32::try_from(self.number()?).ok().map(Expr::Integer)
+    }
+}
+
+impl Expr {
+    pub fn parse(src: &str) -> Result<Expr, String> {
+        let mut parser = Parser { src: src.trim(), pos: 0 };
+        match parser.sum() {
+            Some(expr) if parser.peek().is_none() => Ok(expr),
+            _ => Err(src.trim().to_string()),
+        }
+    }
+
+    /// Value of the expression on the current state of `vm`
+    pub fn eval(&self, vm: &VM) -> Result<i32, String> {
+        match self {
+            Expr::Integer(i) => Ok(*i),
+            Expr::Register(r) => vm.register(*r).map_err(|e| e.to_string()),
+            Expr::Memory(addr) => {
+                let addr = addr.eval(vm)?;
+                usize::try_from(addr).ok().and_then(|a| vm.heap_word(a))
+                    .ok_or(format!("heap address {} is out of bounds", addr))
+            },
+            Expr::Negate(e) => Ok(e.eval(vm)?.wrapping_neg()),
+            Expr::Binary(left, op, right) => {
+                let (left, right) = (left.eval(vm)?, right.eval(vm)?);
+                match op {
+                    '+' => Ok(left.wrapping_add(right)),
+                    '-' => Ok(left.wrapping_sub(right)),
+                    '*' => Ok(left.wrapping_mul(right)),
+                    _ if right == 0 => Err("division by zero".to_string()),
+                    _ => Ok(left.wrapping_div(right)),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_eval() {
+        let mut vm = VM::new();
+        vm.set_register(3, 5).unwrap();
+        vm.set_register(4, 7).unwrap();
+        assert_eq!(Expr::parse("$3 + $4 * 2").unwrap().eval(&vm), Ok(19));
+        assert_eq!(Expr::parse("(0x10 - $3) / -2").unwrap().eval(&vm), Ok(-5));
+        assert_eq!(Expr::parse("mem[0x20]").unwrap().eval(&vm), Ok(0));
+        assert_eq!(Expr::parse("mem[5000]").unwrap().eval(&vm), Err("heap address 5000 is out of bounds".to_string()));
+        assert_eq!(Expr::parse("$3 +"), Err("$3 +".to_string()));
+        assert_eq!(Expr::parse("mem[1"), Err("mem[1".to_string()));
+    }
+}
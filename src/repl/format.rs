@@ -0,0 +1,86 @@
+/// Base `.registers` shows a value in
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Radix {
+    Dec,
+    Hex,
+    Bin,
+}
+
+/// How `.registers` shows a register: a base and a width in bits, only the low `width` bits of
+/// the value being shown
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct RegisterFormat {
+    pub radix: Radix,
+    pub width: u32,
+}
+
+impl Default for RegisterFormat {
+    fn default() -> RegisterFormat {
+        RegisterFormat { radix: Radix::Dec, width: 32 }
+    }
+}
+
+/// Parses a width of 8, 16 or 32 bits
+pub fn parse_width(src: &str) -> Result<u32, String> {
+    match src {
+        "8" => Ok(8),
+        "16" => Ok(16),
+        "32" => Ok(32),
+        _ => Err(src.to_string()),
+    }
+}
+
+impl RegisterFormat {
+    /// Parses `dec`, `hex` or `bin`, optionally followed by a width such as `bin8`
+    pub fn parse(src: &str) -> Result<RegisterFormat, String> {
+        let split = src.find(|c: char| c.is_ascii_digit()).unwrap_or(src.len());
+        let radix = match &src[..split] {
+            "dec" => Radix::Dec,
+            "hex" => Radix::Hex,
+            "bin" => Radix::Bin,
+            _ => return Err(src.to_string()),
+        };
+        let width = match &src[split..] {
+            "" => 32,
+            width => parse_width(width).map_err(|_| src.to_string())?,
+        };
+        Ok(RegisterFormat { radix: radix, width: width })
+    }
+
+    /// The low `width` bits of `value`: zero-padded in hex and binary, the binary digits grouped
+    /// by four, and read as a signed integer of that width in decimal
+    pub fn render(&self, value: i32) -> String {
+        let unused = 32 - self.width;
+        let bits = (value as u32) << unused >> unused;
+        match self.radix {
+            Radix::Dec => (((bits << unused) as i32) >> unused).to_string(),
+            Radix::Hex => format!("0x{:0width$x}", bits, width = self.width as usize / 4),
+            Radix::Bin => {
+                let digits = format!("{:0width$b}", bits, width = self.width as usize);
+                let groups: Vec<&str> = digits.as_bytes().chunks(4)
+                    .map(|group| std::str::from_utf8(group).expect("binary digits are ASCII"))
+                    .collect();
+                format!("0b{}", groups.join("_"))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let format = |src: &str, value: i32| RegisterFormat::parse(src).unwrap().render(value);
+        assert_eq!(format("dec", -1), "-1");
+        assert_eq!(format("dec8", 0x1ff), "-1");
+        assert_eq!(format("dec16", 0x1ff), "511");
+        assert_eq!(format("hex", 31), "0x0000001f");
+        assert_eq!(format("hex16", -1), "0xffff");
+        assert_eq!(format("bin8", 0b1010_0101), "0b1010_0101");
+        assert_eq!(format("bin", 5), "0b0000_0000_0000_0000_0000_0000_0000_0101");
+        assert_eq!(RegisterFormat::parse("oct"), Err("oct".to_string()));
+        assert_eq!(RegisterFormat::parse("hex12"), Err("hex12".to_string()));
+    }
+}
@@ -1,22 +1,202 @@
 use std;
 use std::io;
 use std::io::Write;
-use crate::vm::VM;
-use crate::lexer::Lexer;
+use crate::vm::{ExecutionStats, LoadError, VMError, VmSnapshot, VM};
+use crate::lexer::{strip_comment, AssemblerError};
+use crate::instruction::{self, Decode, Instruction};
+use crate::config::Config;
+use crate::heap::{self, HeapBackend};
+use crate::verifier;
+use crate::profile;
+use thiserror::Error;
+
+pub mod args;
+pub mod debug;
+pub mod display;
+pub mod format;
+pub mod tutorial;
+#[cfg(feature = "tui")]
+pub mod tui;
+use args::CommandArgs;
+use debug::{Breakpoint, Condition, DebugPoints, Watchpoint};
+use display::Expr;
+use format::RegisterFormat;
+use tutorial::Tutorial;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use crate::disasm;
+use crate::relocation::Relocation;
+use crate::scheduler::{self, Priority, Scheduler};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+/// Errors reported to the user by the REPL commands
+#[derive(Debug, PartialEq, Clone, Error)]
+pub enum ReplError {
+    #[error("Unable to parse the instruction! ({0})")]
+    Assembler(#[from] AssemblerError),
+    #[error("Unable to parse the instruction! (expected a single instruction, found '{0}')")]
+    NotAnInstruction(String),
+    #[error("Execution stopped: {error} (after {stats})")]
+    Execution { error: VMError, stats: ExecutionStats },
+    #[error("Program aborted at pc {pc}: {message} (after {stats})")]
+    Aborted { pc: usize, message: String, stats: ExecutionStats },
+    #[error(transparent)]
+    Load(#[from] LoadError),
+    #[error("Unable to decode the base64 program! ({0})")]
+    Base64(#[from] base64::DecodeError),
+    #[error("invalid offset '{0}'")]
+    InvalidOffset(String),
+    #[error("invalid count '{0}'")]
+    InvalidCount(String),
+    #[error("invalid hex bytes '{0}'")]
+    InvalidHex(String),
+    #[error("missing argument, expected {0}")]
+    MissingArgument(&'static str),
+    #[error("unterminated quote in the command")]
+    UnterminatedQuote,
+    #[error("unknown flag --{0}")]
+    UnknownFlag(String),
+    #[error("no fork to go back from")]
+    NoFork,
+    #[error("unknown command {0}")]
+    UnknownCommand(String),
+    #[error("unable to read {path}: {reason}")]
+    Io { path: String, reason: String },
+    #[error("unable to write {path}: {reason}")]
+    Write { path: String, reason: String },
+    #[error("profiling is off, start it with .profile on")]
+    NoProfile,
+    #[error("taint tracking is off, start it with .taint on")]
+    NoTaint,
+    #[error("invalid condition '{0}', expected $register <op> value")]
+    InvalidCondition(String),
+    #[error("no breakpoint at {0:04x}")]
+    NoBreakpoint(usize),
+    #[error("no watchpoint at {0:04x}")]
+    NoWatchpoint(usize),
+    #[error("invalid expression '{0}'")]
+    InvalidExpression(String),
+    #[error("no display {0}")]
+    NoDisplay(usize),
+    #[error("unable to evaluate the expression: {0}")]
+    Evaluation(String),
+    #[error("no program named '{0}'")]
+    NoProgram(String),
+    #[error("no mark named '{0}'")]
+    NoMark(String),
+    #[error("invalid register format '{0}', expected dec, hex or bin with an optional width of 8, 16 or 32")]
+    InvalidFormat(String),
+    #[error("invalid register '{0}'")]
+    InvalidRegister(String),
+    #[error("invalid priority '{0}', expected low, normal or high")]
+    InvalidPriority(String),
+}
+
+impl ReplError {
+    /// The error as shown to the user, an assembler error pointing at the offending token
+    pub fn render(&self) -> String {
+        match self {
+            ReplError::Assembler(e) => format!("Unable to parse the instruction!\n{}", e.render()),
+            e => e.to_string(),
+        }
+    }
+}
+
+/// What the REPL loop should do once a command has been handled
+#[derive(Debug, PartialEq)]
+pub enum CommandOutcome {
+    /// Lines to show the user before reading the next command
+    Output(Vec<String>),
+    /// Leave the REPL
+    Quit,
+}
+
+/// An assembled source file: its bytecode, its relocations, the size of its data section and a
+/// warning per deprecated mnemonic it uses
+struct SourceFile {
+    bytes: Vec<u8>,
+    relocations: Vec<Relocation>,
+    data_size: usize,
+    warnings: Vec<String>,
+}
+
+/// Name of the program the REPL starts with
+pub const MAIN_PROGRAM: &str = "main";
+
+/// A program of the workspace set aside while another one is active, with its own VM and
+/// breakpoints
+struct Program {
+    vm: VM,
+    forks: Vec<VM>,
+    debug_points: DebugPoints,
+    program_file: Option<String>,
+    data_end: usize,
+    priority: Priority,
+}
 
 /// Core structure for the REPL for the Assembler
 pub struct REPL {
     command_buffer: Vec<String>,
     // The VM the REPL will use to execute code
     vm: VM,
+    /// VMs set aside by `.fork`, the last one being restored by `.unfork`
+    forks: Vec<VM>,
+    debug_points: DebugPoints,
+    /// Canonical path of the last file loaded with `.load_file`, which owns `debug_points`
+    program_file: Option<String>,
+    /// End of the data sections of the files linked into the program, where the next one goes
+    data_end: usize,
+    /// Priority of the program under `.schedule`
+    priority: Priority,
+    /// Where breakpoints and watchpoints are saved, `debug::DEBUG_FILE` by default
+    debug_file: PathBuf,
+    config: Config,
+    /// Heap captured by the last `.heapdiff`
+    heap_snapshot: Option<Box<dyn HeapBackend>>,
+    /// Expressions printed after every `.step` and `.continue`
+    displays: Vec<(String, Expr)>,
+    /// Name of the active program, whose state lives in `vm`, `forks`, `debug_points`, `program_file`,
+    /// `data_end` and `priority`
+    current: String,
+    /// The other programs of the workspace, by name
+    programs: BTreeMap<String, Program>,
+    /// VM states saved by `.mark`, by name
+    marks: BTreeMap<String, VmSnapshot>,
+    /// The `.tutorial` in progress, which gets every line until it ends
+    tutorial: Option<Tutorial>,
+}
+
+impl Default for REPL {
+    fn default() -> REPL {
+        REPL::new()
+    }
 }
 
 impl REPL {
     /// Creates and returns a new assembly REPL
     pub fn new() -> REPL {
+        REPL::with_config(Config::default())
+    }
+
+    /// Creates a REPL whose VM and command aliases follow `config`
+    pub fn with_config(config: Config) -> REPL {
         REPL {
-            vm: VM::new(),
-            command_buffer: vec![]
+            vm: config.vm_builder().build(),
+            command_buffer: vec![],
+            forks: vec![],
+            debug_points: DebugPoints::default(),
+            program_file: None,
+            data_end: 0,
+            priority: Priority::Normal,
+            debug_file: PathBuf::from(debug::DEBUG_FILE),
+            heap_snapshot: None,
+            displays: vec![],
+            current: MAIN_PROGRAM.to_string(),
+            programs: BTreeMap::new(),
+            marks: BTreeMap::new(),
+            tutorial: None,
+            config: config,
         }
     }
 
@@ -31,72 +211,1169 @@ impl REPL {
 
             // Annoyingly, `print!` does not automatically flush stdout like `println!` does, so we
             // have to do that there for the user to see our `>>> ` prompt.
-            print!(">>> ");
+            print!("{}", self.prompt());
             io::stdout().flush().expect("Unable to flush stdout");
 
             // Here we'll look at the string the user gave us.
             stdin.read_line(&mut buffer).expect("Unable to read line from user");
-            let buffer = buffer.trim();
-            self.command_buffer.push(buffer.to_string());
-            match buffer {
-                ".quit" => {
+            let outcome = self.execute_command(buffer.trim());
+            for line in self.take_output() {
+                println!("{}", line);
+            }
+            match outcome {
+                Ok(CommandOutcome::Output(lines)) => {
+                    for line in lines {
+                        println!("{}", line);
+                    }
+                },
+                Ok(CommandOutcome::Quit) => {
                     println!("Farewell! Have a great day!");
                     std::process::exit(0);
                 },
-                ".history" => {
-                    for command in &self.command_buffer {
-                        println!("{}", command);
-                    }
-                },
-                ".program" => {
-                    println!("Listing instructions currently in VM's program vector:");
-                    for instruction in &self.vm.program {
-                        println!("{}", instruction);
-                    }
-                    println!("End of Program Listing");
+                Err(e) => println!("{}", e.render())
+            }
+        }
+    }
+
+    /// The prompt shown before reading a command
+    pub fn prompt(&self) -> &'static str {
+        match self.tutorial {
+            Some(_) => "tutorial> ",
+            None => ">>> ",
+        }
+    }
+
+    /// Lines the guest wrote with PRINTF since the last call, shown before the outcome of a command
+    pub fn take_output(&mut self) -> Vec<String> {
+        self.vm.take_output().lines().map(str::to_string).collect()
+    }
+
+    /// Records a line in the history and dispatches it to the matching command handler. Lines that
+    /// are not `.`-commands are assembled and executed as an instruction. During a tutorial, every
+    /// line goes to `tutorial_command`.
+    pub fn execute_command(&mut self, buffer: &str) -> Result<CommandOutcome, ReplError> {
+        self.command_buffer.push(buffer.to_string());
+        if let Some(tutorial) = self.tutorial.take() {
+            return Ok(CommandOutcome::Output(self.tutorial_command(tutorial, buffer)));
+        }
+        let buffer = self.config.expand_alias(buffer);
+        if !buffer.starts_with('.') {
+            return Ok(CommandOutcome::Output(self.execute_source(&buffer)?));
+        }
+        let args = CommandArgs::parse(&buffer)?;
+        args.allow_flags(match args.name.as_str() {
+            ".load_file" => &["verify"],
+            ".registers" => &["format", "width"],
+            _ => &[],
+        })?;
+        match args.name.as_str() {
+            ".quit" => Ok(CommandOutcome::Quit),
+            ".history" => Ok(CommandOutcome::Output(self.command_buffer.clone())),
+            ".program" => self.list_program(),
+            ".registers" => self.list_registers(&args),
+            ".reset" => {
+                self.vm.reset();
+                Ok(CommandOutcome::Output(vec!["VM reset, the program was kept".to_string()]))
+            },
+            ".fork" => {
+                self.forks.push(self.vm.fork());
+                Ok(CommandOutcome::Output(vec![format!("Now running a fork, .unfork to go back ({} saved)", self.forks.len())]))
+            },
+            ".unfork" => match self.forks.pop() {
+                Some(vm) => {
+                    self.vm = vm;
+                    Ok(CommandOutcome::Output(vec![format!("Back to the VM saved at pc {:04x}", self.vm.pc())]))
                 },
-                ".registers" => {
-                    println!("Listing registers and all contents:");
-                    println!("{:#?}", self.vm.registers);
-                    println!("End of Register Listing")
+                None => Err(ReplError::NoFork)
+            },
+            ".hard_reset" => {
+                self.vm.hard_reset();
+                Ok(CommandOutcome::Output(vec!["VM reset, the program was removed".to_string()]))
+            },
+            ".tutorial" => {
+                let tutorial = Tutorial::new(std::mem::take(&mut self.vm));
+                let lines = vec![
+                    "Welcome to the tutorial! Type .hint for help, .skip to skip a step or .exit to leave.".to_string(),
+                    tutorial.show(&mut self.vm),
+                ];
+                self.tutorial = Some(tutorial);
+                Ok(CommandOutcome::Output(lines))
+            },
+            ".loadb64" => {
+                let n = self.load_base64(args.positional(0, "a base64 program")?)?;
+                Ok(CommandOutcome::Output(vec![format!("Loaded {} bytes into the program", n)]))
+            },
+            ".load_file" => self.load_file(&args),
+            ".step" => {
+                let count = match args.rest(0).first() {
+                    Some(n) => n.parse().map_err(|_| ReplError::InvalidCount(n.clone()))?,
+                    None => 1
+                };
+                self.step(count).map(|outcome| self.with_displays(outcome))
+            },
+            ".patch" => {
+                self.patch_program(&args)?;
+                Ok(CommandOutcome::Output(vec![self.verification()]))
+            },
+            ".truncate" => {
+                self.truncate_program(&args)?;
+                Ok(CommandOutcome::Output(vec![self.verification()]))
+            },
+            ".profile" => self.profile(&args),
+            ".taint" => self.taint(&args),
+            ".break" => {
+                let offset = Self::parse_offset(args.positional(0, "an offset")?)?;
+                let condition = Self::parse_condition(&args)?;
+                self.debug_points.breakpoints.retain(|b| b.offset != offset);
+                self.debug_points.breakpoints.push(Breakpoint { offset: offset, condition: condition });
+                self.save_debug_points()
+            },
+            ".watch" => {
+                let addr = Self::parse_offset(args.positional(0, "a heap address")?)?;
+                let condition = Self::parse_condition(&args)?;
+                self.debug_points.watchpoints.retain(|w| w.addr != addr);
+                self.debug_points.watchpoints.push(Watchpoint { addr: addr, condition: condition });
+                self.save_debug_points()
+            },
+            ".unbreak" => {
+                let offset = Self::parse_offset(args.positional(0, "an offset")?)?;
+                let count = self.debug_points.breakpoints.len();
+                self.debug_points.breakpoints.retain(|b| b.offset != offset);
+                if count == self.debug_points.breakpoints.len() {
+                    return Err(ReplError::NoBreakpoint(offset));
+                }
+                self.save_debug_points()
+            },
+            ".unwatch" => {
+                let addr = Self::parse_offset(args.positional(0, "a heap address")?)?;
+                let count = self.debug_points.watchpoints.len();
+                self.debug_points.watchpoints.retain(|w| w.addr != addr);
+                if count == self.debug_points.watchpoints.len() {
+                    return Err(ReplError::NoWatchpoint(addr));
+                }
+                self.save_debug_points()
+            },
+            ".breakpoints" => {
+                let mut lines: Vec<String> = self.debug_points.breakpoints.iter().map(|b| b.to_string()).collect();
+                lines.extend(self.debug_points.watchpoints.iter().map(|w| w.to_string()));
+                Ok(CommandOutcome::Output(lines))
+            },
+            ".continue" => self.continue_execution().map(|outcome| self.with_displays(outcome)),
+            ".display" => {
+                if !args.rest(0).is_empty() {
+                    let src = args.rest(0).join(" ");
+                    let expr = Expr::parse(&src).map_err(ReplError::InvalidExpression)?;
+                    self.displays.push((src, expr));
+                }
+                Ok(CommandOutcome::Output(self.display_lines()))
+            },
+            ".eval" => {
+                let src = args.rest(0).join(" ");
+                let expr = Expr::parse(&src).map_err(ReplError::InvalidExpression)?;
+                let value = expr.eval(&self.vm).map_err(ReplError::Evaluation)?;
+                Ok(CommandOutcome::Output(vec![format!("{} (0x{:x})", value, value)]))
+            },
+            ".undisplay" => {
+                let n = args.positional(0, "a display number")?;
+                let index: usize = n.parse().map_err(|_| ReplError::InvalidCount(n.to_string()))?;
+                if index == 0 || index > self.displays.len() {
+                    return Err(ReplError::NoDisplay(index));
+                }
+                self.displays.remove(index - 1);
+                Ok(CommandOutcome::Output(vec![]))
+            },
+            ".heapdiff" => Ok(CommandOutcome::Output(self.heap_diff())),
+            ".programs" => Ok(CommandOutcome::Output(self.list_programs())),
+            ".priority" => self.priority(&args),
+            ".schedule" => self.schedule(&args),
+            ".use" => {
+                let name = args.positional(0, "a program name")?;
+                self.switch_program(name)?;
+                Ok(CommandOutcome::Output(vec![format!("Now using {}", name)]))
+            },
+            ".run" => {
+                if let Some(name) = args.rest(0).first() {
+                    self.switch_program(name)?;
+                }
+                self.vm.reset();
+                self.continue_execution().map(|outcome| self.with_displays(outcome))
+            },
+            ".mark" => match args.rest(0).first() {
+                Some(name) => {
+                    self.marks.insert(name.clone(), self.vm.snapshot());
+                    Ok(CommandOutcome::Output(vec![format!("Marked {} at pc {:04x}", name, self.vm.pc())]))
                 },
-                _ => {
-                    /* match self.parse_hex(buffer) {
-                        Ok(bytes) => {
-                            for b in bytes {
-                                self.vm.add_program_byte(b);
-                            }
-                        },
-                        Err(_) => println!("Unable to parse hex string (it should be 4 bytes).")
-                    } */
-                    let lex = Lexer::new();
-                    match lex.parse_instruction(buffer).unwrap().compile() {
-                        Ok(bytes) => {
-                            for byte in bytes {
-                                self.vm.add_program_byte(byte);
-                            }
-                        },
-                        Err(e) => println!("Unable to parse the instruction! ({})", e)
-                    }
-                    self.vm.run_once();
+                None => Ok(CommandOutcome::Output(self.marks.iter()
+                    .map(|(name, snapshot)| format!("{}: pc {:04x}, {} instructions", name, snapshot.pc, snapshot.stats.instructions))
+                    .collect())),
+            },
+            ".goto" => {
+                let name = args.positional(0, "a mark name")?;
+                let snapshot = self.marks.get(name).ok_or_else(|| ReplError::NoMark(name.to_string()))?;
+                self.vm.restore(snapshot);
+                let outcome = CommandOutcome::Output(vec![format!("Back to {} at pc {:04x}", name, self.vm.pc())]);
+                Ok(self.with_displays(outcome))
+            },
+            ".disassemble" => self.disassemble(args.rest(0).first().map(|s| s.as_str())),
+            name => Err(ReplError::UnknownCommand(name.to_string()))
+        }
+    }
+
+    /// Makes `name` the active program, setting the current one aside
+    fn switch_program(&mut self, name: &str) -> Result<(), ReplError> {
+        if name == self.current {
+            return Ok(());
+        }
+        let program = self.programs.remove(name).ok_or_else(|| ReplError::NoProgram(name.to_string()))?;
+        self.activate(name, program);
+        Ok(())
+    }
+
+    /// Installs `program` as the active one under `name`, parking the current program
+    fn activate(&mut self, name: &str, program: Program) {
+        let previous = Program {
+            vm: std::mem::replace(&mut self.vm, program.vm),
+            forks: std::mem::replace(&mut self.forks, program.forks),
+            debug_points: std::mem::replace(&mut self.debug_points, program.debug_points),
+            program_file: std::mem::replace(&mut self.program_file, program.program_file),
+            data_end: std::mem::replace(&mut self.data_end, program.data_end),
+            priority: std::mem::replace(&mut self.priority, program.priority),
+        };
+        let previous_name = std::mem::replace(&mut self.current, name.to_string());
+        if previous_name != name {
+            self.programs.insert(previous_name, previous);
+        }
+        self.heap_snapshot = None;
+    }
+
+    /// `.programs`: every program of the workspace, the active one marked with `*`
+    fn list_programs(&self) -> Vec<String> {
+        let mut names: Vec<&String> = self.programs.keys().chain(std::iter::once(&self.current)).collect();
+        names.sort();
+        names.into_iter().map(|name| {
+            let (marker, vm, file) = match self.programs.get(name) {
+                Some(program) => (" ", &program.vm, &program.program_file),
+                None => ("*", &self.vm, &self.program_file),
+            };
+            let file = file.as_ref().map_or(String::new(), |f| format!(" from {}", f));
+            format!("{} {} ({} bytes{})", marker, name, vm.program().len(), file)
+        }).collect()
+    }
+
+    /// `.priority <name> [low|normal|high]`: shows or changes the priority of a program of the
+    /// workspace under `.schedule`
+    fn priority(&mut self, args: &CommandArgs) -> Result<CommandOutcome, ReplError> {
+        let name = args.positional(0, "a program name")?;
+        let priority = match name == self.current {
+            true => &mut self.priority,
+            false => &mut self.programs.get_mut(name).ok_or_else(|| ReplError::NoProgram(name.to_string()))?.priority,
+        };
+        if let Some(level) = args.rest(1).first() {
+            *priority = Priority::parse(level).map_err(|_| ReplError::InvalidPriority(level.clone()))?;
+        }
+        Ok(CommandOutcome::Output(vec![format!("{} has {} priority", name, priority)]))
+    }
+
+    /// `.schedule [steps]`: runs every program of the workspace side by side from where it
+    /// stands, each with its `.priority`, until they all stopped or `steps` instructions were
+    /// executed in total, the step limit of the configuration by default
+    fn schedule(&mut self, args: &CommandArgs) -> Result<CommandOutcome, ReplError> {
+        let max_steps = match args.rest(0).first() {
+            Some(n) => n.parse().map_err(|_| ReplError::InvalidCount(n.clone()))?,
+            None => self.config.max_steps(),
+        };
+        let mut scheduler = Scheduler::new(scheduler::DEFAULT_SLICE);
+        scheduler.spawn(&self.current, std::mem::take(&mut self.vm), self.priority);
+        for (name, program) in self.programs.iter_mut() {
+            scheduler.spawn(name, std::mem::take(&mut program.vm), program.priority);
+        }
+        scheduler.run(max_steps);
+        let mut lines = vec![];
+        for task in scheduler.into_tasks() {
+            let instructions = task.vm.stats().instructions;
+            lines.push(match (task.stopped, task.vm.last_error()) {
+                (false, _) => format!("{}: still running at pc {:04x} after {} instructions", task.name, task.vm.pc(), instructions),
+                (true, Some(e)) => format!("{}: stopped after {} instructions: {}", task.name, instructions, e),
+                (true, None) => format!("{}: halted after {} instructions", task.name, instructions),
+            });
+            match self.programs.get_mut(&task.name) {
+                Some(program) => program.vm = task.vm,
+                None => self.vm = task.vm,
+            }
+        }
+        Ok(CommandOutcome::Output(lines))
+    }
+
+    /// `.disassemble [name]`: the labelled source of a program of the workspace, the active one by default
+    fn disassemble(&self, name: Option<&str>) -> Result<CommandOutcome, ReplError> {
+        let vm = match name {
+            Some(name) if name != self.current => &self.programs.get(name).ok_or_else(|| ReplError::NoProgram(name.to_string()))?.vm,
+            _ => &self.vm,
+        };
+        let source = disasm::disassemble(vm.program(), false).map_err(LoadError::from)?;
+        Ok(CommandOutcome::Output(source.lines().map(str::to_string).collect()))
+    }
+
+    /// `[if $register <op> value]` after the first argument of `.break` and `.watch`
+    fn parse_condition(args: &CommandArgs) -> Result<Option<Condition>, ReplError> {
+        match args.rest(1) {
+            [] => Ok(None),
+            [keyword, condition @ ..] if keyword == "if" => {
+                let src = condition.join(" ");
+                Condition::parse(&src).map(Some).map_err(|_| ReplError::InvalidCondition(src))
+            },
+            other => Err(ReplError::InvalidCondition(other.join(" "))),
+        }
+    }
+
+    /// Saves the breakpoints and watchpoints along with the loaded program file, then lists them
+    fn save_debug_points(&self) -> Result<CommandOutcome, ReplError> {
+        if let Some(program) = &self.program_file {
+            debug::save(&self.debug_file, program, &self.debug_points)
+                .map_err(|e| ReplError::Write { path: self.debug_file.display().to_string(), reason: e })?;
+        }
+        let count = self.debug_points.breakpoints.len() + self.debug_points.watchpoints.len();
+        Ok(CommandOutcome::Output(vec![format!("{} breakpoints and watchpoints set", count)]))
+    }
+
+    /// `.continue`: runs from the current pc until a breakpoint, a triggered watchpoint or the
+    /// end of the program. The breakpoint at the current pc, if any, is stepped over.
+    fn continue_execution(&mut self) -> Result<CommandOutcome, ReplError> {
+        let mut watched = self.debug_points.watched_words(&self.vm);
+        let mut first = true;
+        loop {
+            if !first {
+                if let Some(breakpoint) = self.debug_points.breakpoint_hit(&self.vm) {
+                    return Ok(CommandOutcome::Output(vec![format!("Stopped at {}", breakpoint)]));
                 }
             }
+            first = false;
+            let pc = self.vm.pc();
+            let running = self.vm.run_once();
+            let words = self.debug_points.watched_words(&self.vm);
+            let triggered = self.debug_points.watchpoints.iter().zip(watched.iter().zip(&words))
+                .find(|(w, (before, after))| before != after && w.condition.as_ref().is_none_or(|c| c.holds(&self.vm)));
+            if let Some((watchpoint, (Some(before), Some(after)))) = triggered {
+                let mut lines = vec![format!("Stopped at {}: {} -> {} by the instruction at {:04x}", watchpoint, before, after, pc)];
+                lines.extend(self.halt_summary(running)?);
+                return Ok(CommandOutcome::Output(lines));
+            }
+            watched = words;
+            if !running {
+                return Ok(CommandOutcome::Output(self.halt_summary(running)?));
+            }
+        }
+    }
+
+    /// `.profile on|off|detail|export <file.csv>`: records per-pc and per-opcode execution counts
+    /// and timings of everything executed while on, shows the latency histogram of every opcode,
+    /// and writes them as CSV
+    fn profile(&mut self, args: &CommandArgs) -> Result<CommandOutcome, ReplError> {
+        let usage = "on, off, detail or export <file.csv>";
+        let message = match args.positional(0, usage)? {
+            "on" => {
+                self.vm.start_profiling();
+                "Profiling started".to_string()
+            },
+            "off" => {
+                self.vm.stop_profiling();
+                "Profiling stopped".to_string()
+            },
+            "detail" => {
+                let profile = self.vm.profile().ok_or(ReplError::NoProfile)?;
+                let mut lines = vec![format!("Latency in ticks, sampled on 1 instruction in {} on average:", profile::SAMPLE_PERIOD)];
+                lines.extend(profile.histogram_lines());
+                return Ok(CommandOutcome::Output(lines));
+            },
+            "export" => {
+                let path = args.positional(1, "a file path")?;
+                let profile = self.vm.profile().ok_or(ReplError::NoProfile)?;
+                std::fs::write(path, profile.to_csv())
+                    .map_err(|e| ReplError::Write { path: path.to_string(), reason: e.to_string() })?;
+                let executed: u64 = profile.by_pc().map(|(_, _, sample)| sample.count).sum();
+                format!("Wrote the profile of {} executed instructions to {}", executed, path)
+            },
+            _ => return Err(ReplError::MissingArgument(usage)),
+        };
+        Ok(CommandOutcome::Output(vec![message]))
+    }
+
+    /// `.taint on|off|report|$<register>`: tracks the values derived from the registers marked
+    /// as guest input, and reports those used as jump targets or memory addresses
+    fn taint(&mut self, args: &CommandArgs) -> Result<CommandOutcome, ReplError> {
+        let usage = "on, off, report or a register";
+        let lines = match args.positional(0, usage)? {
+            "on" => {
+                self.vm.start_taint_tracking();
+                vec!["Taint tracking started".to_string()]
+            },
+            "off" => {
+                self.vm.stop_taint_tracking();
+                vec!["Taint tracking stopped".to_string()]
+            },
+            "report" => {
+                let taint = self.vm.taint().ok_or(ReplError::NoTaint)?;
+                let tainted: Vec<String> = self.vm.registers()
+                    .filter(|(i, _)| taint.is_tainted(*i))
+                    .map(|(i, _)| format!("${}", i))
+                    .collect();
+                let mut lines = vec![match tainted.is_empty() {
+                    true => "No tainted register".to_string(),
+                    false => format!("Tainted registers: {}", tainted.join(" ")),
+                }];
+                lines.extend(taint.reports().iter().map(|report| report.to_string()));
+                lines
+            },
+            register if register.starts_with('$') => {
+                let index = register[1..].parse().map_err(|_| ReplError::InvalidRegister(register.to_string()))?;
+                if self.vm.taint().is_none() {
+                    return Err(ReplError::NoTaint);
+                }
+                self.vm.taint_register(index).map_err(|_| ReplError::InvalidRegister(register.to_string()))?;
+                vec![format!("Marked {} as guest input", register)]
+            },
+            _ => return Err(ReplError::MissingArgument(usage)),
+        };
+        Ok(CommandOutcome::Output(lines))
+    }
+
+    /// Current value of every `.display` expression, numbered from 1
+    fn display_lines(&self) -> Vec<String> {
+        self.displays.iter().enumerate().map(|(i, (src, expr))| match expr.eval(&self.vm) {
+            Ok(value) => format!("{}: {} = {}", i + 1, src, value),
+            Err(e) => format!("{}: {} = <{}>", i + 1, src, e),
+        }).collect()
+    }
+
+    fn with_displays(&self, outcome: CommandOutcome) -> CommandOutcome {
+        match outcome {
+            CommandOutcome::Output(mut lines) => {
+                lines.extend(self.display_lines());
+                CommandOutcome::Output(lines)
+            },
+            CommandOutcome::Quit => CommandOutcome::Quit,
+        }
+    }
+
+    /// `.heapdiff`: takes a heap snapshot, then on the next invocations shows the words changed
+    /// since the previous one as `address: old bytes -> new bytes (old -> new)`
+    fn heap_diff(&mut self) -> Vec<String> {
+        let current = self.vm.heap_snapshot();
+        let previous = match self.heap_snapshot.replace(current.clone()) {
+            Some(previous) => previous,
+            None => return vec!["Heap snapshot taken, .heapdiff again to see what changed".to_string()],
+        };
+        let mut words: Vec<usize> = heap::diff(previous.as_ref(), current.as_ref()).iter().map(|(addr, _, _)| addr & !3).collect();
+        words.dedup();
+        if words.is_empty() {
+            return vec!["No heap change since the last snapshot".to_string()];
+        }
+        let endianness = self.vm.endianness();
+        let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ");
+        words.into_iter().map(|addr| {
+            let (mut old, mut new) = ([0; 4], [0; 4]);
+            let len = 4.min(current.len() - addr);
+            previous.read(addr, &mut old[..len]);
+            current.read(addr, &mut new[..len]);
+            if len < 4 {
+                return format!("{:04x}: {} -> {}", addr, hex(&old[..len]), hex(&new[..len]));
+            }
+            format!("{:04x}: {} -> {} ({} -> {})", addr, hex(&old), hex(&new),
+                endianness.word_from_bytes(old) as i32, endianness.word_from_bytes(new) as i32)
+        }).collect()
+    }
+
+    /// `.step [count]`: executes up to `count` instructions of the program from the current pc
+    fn step(&mut self, count: usize) -> Result<CommandOutcome, ReplError> {
+        let mut running = true;
+        for _ in 0..count {
+            running = self.vm.run_once();
+            if !running {
+                break;
+            }
+        }
+        let mut lines = vec![format!("pc: {:04x}", self.vm.pc())];
+        lines.extend(self.halt_summary(running)?);
+        Ok(CommandOutcome::Output(lines))
+    }
+
+    fn list_program(&self) -> Result<CommandOutcome, ReplError> {
+        let mut lines = vec!["Listing instructions currently in VM's program vector:".to_string()];
+        lines.extend(self.format_program());
+        lines.push("End of Program Listing".to_string());
+        Ok(CommandOutcome::Output(lines))
+    }
+
+    /// `.registers [--format=dec|hex|bin] [--width=8|16|32] [$n[:format]...]`: every register, or
+    /// the given ones, each in its own format when it has one such as `$3:bin8`
+    fn list_registers(&self, args: &CommandArgs) -> Result<CommandOutcome, ReplError> {
+        let mut format = match args.value("format") {
+            Some(src) => RegisterFormat::parse(src).map_err(ReplError::InvalidFormat)?,
+            None => RegisterFormat::default(),
+        };
+        if let Some(width) = args.value("width") {
+            format.width = format::parse_width(width).map_err(ReplError::InvalidFormat)?;
+        }
+        let mut registers = vec![];
+        for spec in args.rest(0) {
+            let (register, own_format) = match spec.split_once(':') {
+                Some((register, src)) => (register, RegisterFormat::parse(src).map_err(ReplError::InvalidFormat)?),
+                None => (spec.as_str(), format),
+            };
+            let index = register.strip_prefix('$').and_then(|n| n.parse().ok())
+                .filter(|index| self.vm.register(*index).is_ok())
+                .ok_or_else(|| ReplError::InvalidRegister(register.to_string()))?;
+            registers.push((index, own_format));
+        }
+        if registers.is_empty() {
+            registers = self.vm.registers().map(|(i, _)| (i, format)).collect();
+        }
+        let mut lines = vec!["Listing registers and all contents:".to_string()];
+        for (index, format) in registers {
+            let value = self.vm.register(index).expect("register indexes are checked above");
+            lines.push(format!("${}: {}", index, format.render(value)));
+        }
+        lines.push("End of Register Listing".to_string());
+        Ok(CommandOutcome::Output(lines))
+    }
+
+    /// Assembles a single instruction, appends it to the program and executes it. Returns the
+    /// execution summary if the instruction halted the VM.
+    fn execute_source(&mut self, src: &str) -> Result<Vec<String>, ReplError> {
+        let program = self.config.assembler().parse(src)?;
+        let instruction = match program.instructions() {
+            [instruction] if program.labels().is_empty() => instruction,
+            _ => return Err(ReplError::NotAnInstruction(strip_comment(src).trim().to_string())),
+        };
+        let bytes = instruction.node.compile().map_err(|e| program.line(instruction).error(e))?;
+        for byte in bytes {
+            self.vm.add_program_byte(byte);
         }
+        let running = self.vm.run_once();
+        let mut lines: Vec<String> = src.split_whitespace().next().and_then(instruction::deprecation)
+            .map(|w| format!("Warning: {}", w)).into_iter().collect();
+        lines.extend(self.halt_summary(running)?);
+        Ok(lines)
     }
 
-    fn parse_hex(&mut self, c: &str) -> Result<Vec<u8>, &str> {
+    /// Turns the end of an execution into either the error that stopped it or, once the VM
+    /// halted, a summary of its statistics
+    fn halt_summary(&self, running: bool) -> Result<Vec<String>, ReplError> {
+        match self.vm.last_error() {
+            Some(VMError::Aborted { pc }) => Err(ReplError::Aborted {
+                pc: pc,
+                message: self.vm.abort_message().unwrap_or_default().to_string(),
+                stats: *self.vm.stats(),
+            }),
+            Some(e) => Err(ReplError::Execution { error: e, stats: *self.vm.stats() }),
+            None if running => Ok(vec![]),
+            None => Ok(vec![format!("Halted after {}", self.vm.stats())])
+        }
+    }
+
+    /// A line typed during the tutorial, which runs on a fresh VM: `.hint`, `.skip`, `.exit`, or
+    /// an instruction attempting the current step. The VM of the user is put back once the
+    /// tutorial ends.
+    fn tutorial_command(&mut self, mut tutorial: Tutorial, line: &str) -> Vec<String> {
+        let mut lines = vec![];
+        let advance = match line {
+            ".hint" => {
+                lines.push(format!("Hint: {}", tutorial.step().hint));
+                false
+            },
+            ".skip" => true,
+            ".exit" => {
+                self.vm = tutorial.finish();
+                return vec!["Tutorial left, back to your own VM.".to_string()];
+            },
+            line => {
+                match self.execute_source(line) {
+                    Ok(output) => lines.extend(output),
+                    Err(e) => lines.push(e.render()),
+                }
+                let passed = (tutorial.step().check)(&self.vm);
+                lines.push(match passed {
+                    true => "Well done!".to_string(),
+                    false => format!("Not quite. Hint: {}", tutorial.step().hint),
+                });
+                passed
+            }
+        };
+        if advance {
+            match tutorial.next_step(&mut self.vm) {
+                Some(step) => lines.push(step),
+                None => {
+                    self.vm = tutorial.finish();
+                    lines.push("Tutorial complete, back to your own VM.".to_string());
+                    return lines;
+                }
+            }
+        }
+        self.tutorial = Some(tutorial);
+        lines
+    }
+
+    /// Renders the program as 4-byte instruction rows: byte offset, raw hex and the disassembled
+    /// instruction when the first byte is a known opcode. The row at the current pc is marked with `=>`.
+    fn format_program(&self) -> Vec<String> {
+        let pc = self.vm.pc();
+        let mut rows = vec![];
+        for (i, chunk) in self.vm.program().chunks(4).enumerate() {
+            let offset = i * 4;
+            let marker = if (offset..offset + 4).contains(&pc) { "=>" } else { "  " };
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let disassembly = match Instruction::decode(chunk) {
+                Ok(instruction) => instruction.to_string(),
+                Err(_) => String::new(),
+            };
+            rows.push(format!("{} {:04x}  {:<11}  {}", marker, offset, hex.join(" "), disassembly).trim_end().to_string());
+        }
+        rows
+    }
+
+    /// Decodes a base64-encoded bytecode blob and appends it to the VM's program, unless the
+    /// result does not verify
+    fn load_base64(&mut self, src: &str) -> Result<usize, ReplError> {
+        let bytes = STANDARD.decode(src.trim())?;
+        self.vm.patch_program(self.vm.program().len(), &bytes)?;
+        Ok(bytes.len())
+    }
+
+    /// Assembles a source file and appends it to the program, returning the number of bytes added
+    /// and a warning per deprecated mnemonic. The breakpoints and watchpoints saved for this file
+    /// are restored.
+    pub fn load_source_file(&mut self, path: &str) -> Result<(usize, Vec<String>), ReplError> {
+        let source = self.assemble_source_file(path)?;
+        self.append_source_file(path, &source)?;
+        Ok((source.bytes.len(), source.warnings))
+    }
+
+    fn assemble_source_file(&self, path: &str) -> Result<SourceFile, ReplError> {
+        let src = std::fs::read_to_string(path)
+            .map_err(|e| ReplError::Io { path: path.to_string(), reason: e.to_string() })?;
+        let assembler = self.config.assembler().with_source_path(Path::new(path));
+        let relocatable = assembler.assemble_relocatable(&src)?;
+        Ok(SourceFile {
+            bytes: relocatable.program,
+            relocations: relocatable.relocations,
+            data_size: relocatable.data_size,
+            warnings: assembler.deprecations(&src),
+        })
+    }
+
+    /// Links the bytecode of a source file after the program, so its labels still name its own
+    /// instructions, and its data section after those of the files linked before it
+    fn append_source_file(&mut self, path: &str, source: &SourceFile) -> Result<(), ReplError> {
+        if self.vm.program().is_empty() {
+            self.data_end = 0;
+        }
+        self.vm.link_program(&source.bytes, &source.relocations, self.data_end)?;
+        self.data_end += source.data_size;
+        let program = std::fs::canonicalize(path).map_or(path.to_string(), |p| p.display().to_string());
+        self.debug_points = debug::load(&self.debug_file, &program)
+            .map_err(|e| ReplError::Io { path: self.debug_file.display().to_string(), reason: e })?;
+        self.program_file = Some(program);
+        Ok(())
+    }
+
+    /// `.load_file <path> [as <name>] [--verify]`: assembles a source file and appends it to the
+    /// program. With `as <name>`, it becomes a new program of the workspace, with its own VM,
+    /// replacing any program of that name.
+    fn load_file(&mut self, args: &CommandArgs) -> Result<CommandOutcome, ReplError> {
+        let path = args.positional(0, "a file path")?;
+        let source = self.assemble_source_file(path)?;
+        match args.rest(1) {
+            [] => (),
+            [keyword, name] if keyword == "as" => {
+                self.programs.remove(name);
+                let program = Program {
+                    vm: self.config.vm_builder().build(),
+                    forks: vec![],
+                    debug_points: DebugPoints::default(),
+                    program_file: None,
+                    data_end: 0,
+                    priority: Priority::Normal,
+                };
+                self.activate(name, program);
+            },
+            _ => return Err(ReplError::MissingArgument("a file path, optionally followed by as <name>")),
+        }
+        self.append_source_file(path, &source)?;
+        let mut lines: Vec<String> = source.warnings.iter().map(|w| format!("Warning: {}", w)).collect();
+        lines.push(format!("Loaded {} bytes from {}", source.bytes.len(), path));
+        if !self.debug_points.is_empty() {
+            lines.push(format!("Restored {} breakpoints and {} watchpoints",
+                self.debug_points.breakpoints.len(), self.debug_points.watchpoints.len()));
+        }
+        if args.flag("verify") {
+            lines.push(self.verification());
+        }
+        Ok(CommandOutcome::Output(lines))
+    }
+
+    /// `.patch <offset> <hex bytes>`: overwrites the program from `offset`, growing it if needed,
+    /// unless the result does not verify
+    fn patch_program(&mut self, args: &CommandArgs) -> Result<(), ReplError> {
+        let offset = Self::parse_offset(args.positional(0, "an offset followed by hex bytes")?)?;
+        if args.rest(1).is_empty() {
+            return Err(ReplError::MissingArgument("an offset followed by hex bytes"));
+        }
+        let bytes = self.parse_hex(&args.rest(1).join(" "))?;
+        Ok(self.vm.patch_program(offset, &bytes)?)
+    }
+
+    /// `.truncate <offset>`: drops every program byte from `offset` onwards, unless what is left
+    /// does not verify
+    fn truncate_program(&mut self, args: &CommandArgs) -> Result<(), ReplError> {
+        let offset = Self::parse_offset(args.positional(0, "an offset")?)?;
+        Ok(self.vm.truncate_program(offset)?)
+    }
+
+    /// Parses a decimal or `0x`-prefixed hexadecimal program offset
+    fn parse_offset(src: &str) -> Result<usize, ReplError> {
+        let parsed = match src.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => src.parse()
+        };
+        parsed.map_err(|_| ReplError::InvalidOffset(src.to_string()))
+    }
+
+    fn verification(&self) -> String {
+        match verifier::verify(self.vm.program()) {
+            Ok(()) => format!("Program verified ({} bytes)", self.vm.program().len()),
+            Err(e) => format!("Warning, the program does not verify: {}", e)
+        }
+    }
+
+    fn parse_hex(&mut self, c: &str) -> Result<Vec<u8>, ReplError> {
         let split: Vec<&str> = c.split(" ").collect();
         if split.is_empty() {
-            return Err("Error parsing the command!")
+            return Err(ReplError::InvalidHex(c.to_string()))
         }
         let mut results: Vec<u8> = vec![];
         for hex in split {
             let byte = u8::from_str_radix(hex, 16);
             match byte {
                 Ok(res) => results.push(res),
-                Err(_) => return Err("Error parsing the command!")
+                Err(_) => return Err(ReplError::InvalidHex(c.to_string()))
             }
         }
         Ok(results)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_load_base64() {
+        let mut repl = REPL::new();
+        assert_eq!(repl.load_base64("AQAB9A=="), Ok(4));
+        assert_eq!(repl.vm.program(), &[1, 0, 1, 244]);
+        assert!(repl.load_base64("not base64!").is_err());
+        assert_eq!(repl.execute_command(".loadb64 AigAAA=="),
+            Err(ReplError::Load(LoadError::Invalid(verifier::VerifyError::InvalidRegister { offset: 4, register: 40 }))));
+        assert_eq!(repl.vm.program().len(), 4);
+        assert!(repl.execute_command(".step").is_ok());
+    }
+
+    #[test]
+    fn test_execute_command() {
+        let mut repl = REPL::new();
+        assert_eq!(repl.execute_command("load $0 #7"), Ok(CommandOutcome::Output(vec![])));
+        match repl.execute_command(".registers") {
+            Ok(CommandOutcome::Output(lines)) => {
+                assert_eq!(lines.len(), 34);
+                assert_eq!(lines[1], "$0: 7");
+            },
+            other => panic!("unexpected outcome {:?}", other)
+        }
+        assert!(matches!(repl.execute_command("load $0"), Err(ReplError::Assembler(_))));
+        assert!(matches!(repl.execute_command(".truncate x"), Err(ReplError::InvalidOffset(_))));
+        assert_eq!(repl.execute_command(".history"), Ok(CommandOutcome::Output(vec![
+            "load $0 #7".to_string(),
+            ".registers".to_string(),
+            "load $0".to_string(),
+            ".truncate x".to_string(),
+            ".history".to_string(),
+        ])));
+        assert_eq!(repl.execute_command("loop: hlt ; forever"), Err(ReplError::NotAnInstruction("loop: hlt".to_string())));
+        assert_eq!(repl.execute_command(".frobnicate"), Err(ReplError::UnknownCommand(".frobnicate".to_string())));
+        assert_eq!(repl.execute_command(".program --all"), Err(ReplError::UnknownFlag("all".to_string())));
+        assert_eq!(repl.execute_command(".quit"), Ok(CommandOutcome::Quit));
+    }
+
+    #[test]
+    fn test_tutorial() {
+        let mut repl = REPL::new();
+        assert!(repl.execute_command("load $7 #1").is_ok());
+        match repl.execute_command(".tutorial") {
+            Ok(CommandOutcome::Output(lines)) => assert!(lines[1].starts_with("Step 1/6: ")),
+            other => panic!("unexpected outcome {:?}", other)
+        }
+        assert_eq!(repl.prompt(), "tutorial> ");
+        assert_eq!(repl.execute_command("load $0 #99"), Ok(CommandOutcome::Output(vec![
+            "Not quite. Hint: the instruction is `load`, followed by the register and the integer".to_string(),
+        ])));
+        match repl.execute_command("load $0 #100") {
+            Ok(CommandOutcome::Output(lines)) => {
+                assert_eq!(lines[0], "Well done!");
+                assert!(lines[1].starts_with("Step 2/6: "));
+            },
+            other => panic!("unexpected outcome {:?}", other)
+        }
+        assert_eq!(repl.execute_command(".hint"), Ok(CommandOutcome::Output(vec!["Hint: `load $1 #50`".to_string()])));
+        for _ in 0..4 {
+            assert!(repl.execute_command(".skip").is_ok());
+        }
+        assert_eq!(repl.execute_command(".skip"), Ok(CommandOutcome::Output(vec!["Tutorial complete, back to your own VM.".to_string()])));
+        assert_eq!((repl.prompt(), repl.vm.register(7)), (">>> ", Ok(1)));
+        assert!(repl.execute_command(".tutorial").is_ok());
+        assert!(repl.execute_command(".exit").is_ok());
+        assert_eq!(repl.vm.register(7), Ok(1));
+    }
+
+    #[test]
+    fn test_load_file() {
+        let mut repl = REPL::new();
+        let outcome = repl.execute_command(".load_file \"tests/arithmetic.iasm\" --verify").unwrap();
+        match outcome {
+            CommandOutcome::Output(lines) => assert_eq!(lines[1], format!("Program verified ({} bytes)", repl.vm.program().len())),
+            CommandOutcome::Quit => panic!("unexpected quit")
+        }
+        assert!(matches!(repl.execute_command(".load_file \"no such file.iasm\""), Err(ReplError::Io { .. })));
+    }
+
+    #[test]
+    fn test_load_files_with_data() {
+        let dir = std::env::temp_dir().join(format!("simple-vm-repl-data-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.iasm"), ".data\nx: .word 7, 8\n.code\nload $5 @x\nlw $5 $5 #4").unwrap();
+        std::fs::write(dir.join("b.iasm"), ".data\ny: .word 9\n.code\nload $6 @y\nlw $6 $6 #0\nhlt").unwrap();
+        let mut repl = REPL::new();
+        for file in ["a.iasm", "b.iasm"] {
+            repl.execute_command(&format!(".load_file \"{}\"", dir.join(file).display())).unwrap();
+        }
+        repl.execute_command(".continue").unwrap();
+        assert_eq!((repl.vm.register(5), repl.vm.register(6)), (Ok(8), Ok(9)));
+        assert_eq!((repl.vm.heap_word(0), repl.vm.heap_word(4), repl.vm.heap_word(8)), (Some(7), Some(8), Some(9)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_step() {
+        let mut repl = REPL::new();
+        repl.vm.load_program(&[1, 0, 0, 5, 1, 1, 0, 0, 19, 0, 1, 2]).unwrap();
+        assert_eq!(repl.execute_command(".step"), Ok(CommandOutcome::Output(vec!["pc: 0004".to_string()])));
+        match repl.execute_command(".step 5") {
+            Err(ReplError::Execution { error, stats }) => {
+                assert_eq!(error, VMError::DivisionByZero { pc: 8 });
+                assert_eq!(stats.instructions, 3);
+            },
+            other => panic!("unexpected outcome {:?}", other)
+        }
+        assert_eq!(repl.execute_command(".step x"), Err(ReplError::InvalidCount("x".to_string())));
+        repl.vm.load_program(&[0, 0, 0, 0]).unwrap();
+        match repl.execute_command(".step") {
+            Ok(CommandOutcome::Output(lines)) => assert!(lines[1].starts_with("Halted after 1 instructions in ")),
+            other => panic!("unexpected outcome {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_abort_message() {
+        let mut repl = REPL::new();
+        let program = Assembler::new().assemble("load $0 #16705\nload $1 #0\nsw $0 $1 #0\nload $2 #2\nabort $2").unwrap();
+        repl.vm.load_program(&program).unwrap();
+        match repl.execute_command(".continue") {
+            Err(error @ ReplError::Aborted { .. }) => assert!(error.to_string().starts_with("Program aborted at pc 16: AA (after 5 instructions")),
+            other => panic!("unexpected outcome {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_take_output() {
+        let mut repl = REPL::new();
+        // "hi\n" at address 2
+        for line in ["load $0 #2560", "load $1 #2", "sw $0 $1 #0", "load $0 #26729", "load $1 #0", "sw $0 $1 #0", "load $0 #2"] {
+            assert!(repl.execute_command(line).is_ok());
+        }
+        assert_eq!(repl.take_output(), Vec::<String>::new());
+        assert!(repl.execute_command("sys #5").is_ok());
+        assert_eq!(repl.take_output(), vec!["hi".to_string()]);
+    }
+
+    #[test]
+    fn test_reset_commands() {
+        let mut repl = REPL::new();
+        assert!(repl.execute_command("load $0 #7").is_ok());
+        assert!(repl.execute_command(".reset").is_ok());
+        assert_eq!((repl.vm.register(0), repl.vm.pc(), repl.vm.program().len()), (Ok(0), 0, 4));
+        assert!(repl.execute_command(".step").is_ok());
+        assert_eq!(repl.vm.register(0), Ok(7));
+        assert!(repl.execute_command(".hard_reset").is_ok());
+        assert_eq!((repl.vm.register(0), repl.vm.program().len()), (Ok(0), 0));
+    }
+
+    #[test]
+    fn test_profile_export() {
+        let mut repl = REPL::new();
+        assert_eq!(repl.execute_command(".profile export x.csv"), Err(ReplError::NoProfile));
+        assert!(repl.execute_command("load $0 #7").is_ok());
+        assert!(repl.execute_command(".profile on").is_ok());
+        assert!(repl.execute_command("load $1 #8").is_ok());
+        assert!(repl.execute_command("add $0 $1 $2").is_ok());
+        let path = std::env::temp_dir().join(format!("profile-{}.csv", std::process::id()));
+        let command = format!(".profile export {}", path.display());
+        assert_eq!(repl.execute_command(&command),
+            Ok(CommandOutcome::Output(vec![format!("Wrote the profile of 2 executed instructions to {}", path.display())])));
+        let csv = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let rows: Vec<&str> = csv.lines().map(|l| l.rsplitn(3, ',').last().unwrap()).collect();
+        assert_eq!(rows, vec!["kind,pc,opcode,count", "pc,4,load,1", "pc,8,add,1", "opcode,,add,1", "opcode,,load,1"]);
+        // The first instruction is always sampled
+        match repl.execute_command(".profile detail") {
+            Ok(CommandOutcome::Output(lines)) => {
+                assert_eq!(lines[0], "Latency in ticks, sampled on 1 instruction in 16 on average:");
+                assert!(lines.contains(&"load: 1 samples".to_string()));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(repl.execute_command(".profile"), Err(ReplError::MissingArgument("on, off, detail or export <file.csv>")));
+    }
+
+    #[test]
+    fn test_taint() {
+        let mut repl = REPL::new();
+        assert_eq!(repl.execute_command(".taint $1"), Err(ReplError::NoTaint));
+        repl.vm.load_program(&Assembler::new().assemble("load $2 #4\nadd $1 $2 $3\nsw $2 $3 #0\njmp $2").unwrap()).unwrap();
+        assert!(repl.execute_command(".taint on").is_ok());
+        assert!(repl.execute_command(".taint $1").is_ok());
+        assert!(repl.execute_command(".step 3").is_ok());
+        assert_eq!(repl.execute_command(".taint report"), Ok(CommandOutcome::Output(vec![
+            "Tainted registers: $1 $3".to_string(), "0008 sw: tainted $3 used as a memory address".to_string(),
+        ])));
+        assert_eq!(repl.execute_command(".taint $99"), Err(ReplError::InvalidRegister("$99".to_string())));
+    }
+
+    #[test]
+    fn test_mark_and_goto() {
+        let mut repl = REPL::new();
+        repl.vm.load_program(&Assembler::new().assemble("load $0 #4\nload $1 #0\nsw $0 $1 #0\nadd $0 $0 $0\nhlt").unwrap()).unwrap();
+        assert!(repl.execute_command(".step 2").is_ok());
+        assert_eq!(repl.execute_command(".mark before_store"), Ok(CommandOutcome::Output(vec!["Marked before_store at pc 0008".to_string()])));
+        assert!(repl.execute_command(".display mem[0] + $0").is_ok());
+        assert!(repl.execute_command(".continue").is_ok());
+        assert_eq!(repl.vm.register(0), Ok(8));
+        assert_eq!(repl.execute_command(".goto before_store"), Ok(CommandOutcome::Output(vec![
+            "Back to before_store at pc 0008".to_string(),
+            "1: mem[0] + $0 = 4".to_string(),
+        ])));
+        assert_eq!(repl.execute_command(".mark"), Ok(CommandOutcome::Output(vec!["before_store: pc 0008, 2 instructions".to_string()])));
+        assert_eq!(repl.execute_command(".goto after"), Err(ReplError::NoMark("after".to_string())));
+    }
+
+    #[test]
+    fn test_workspace() {
+        let dir = std::env::temp_dir().join(format!("repl-workspace-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (one, two) = (dir.join("one.iasm"), dir.join("two.iasm"));
+        std::fs::write(&one, "load $0 #1\nload $1 #2\nhlt\n").unwrap();
+        std::fs::write(&two, "load $0 #5\nload $1 #6\nload $2 #7\nhlt\n").unwrap();
+        let mut repl = REPL::new();
+        repl.debug_file = dir.join("debug.toml");
+        assert!(repl.execute_command(&format!(".load_file {} as one", one.display())).is_ok());
+        assert!(repl.execute_command(".break 4").is_ok());
+        assert!(repl.execute_command(&format!(".load_file {} as two", two.display())).is_ok());
+        assert_eq!(repl.list_programs()[0], "  main (0 bytes)");
+        assert!(repl.list_programs()[2].starts_with("* two (16 bytes from "));
+        match repl.execute_command(".run") {
+            Ok(CommandOutcome::Output(lines)) => assert!(lines[0].starts_with("Halted after 4 instructions")),
+            other => panic!("unexpected outcome {:?}", other)
+        }
+        assert_eq!(repl.execute_command(".run one"), Ok(CommandOutcome::Output(vec!["Stopped at breakpoint 0004".to_string()])));
+        assert_eq!((repl.vm.register(0), repl.vm.register(1)), (Ok(1), Ok(0)));
+        assert_eq!(repl.execute_command(".disassemble two"), Ok(CommandOutcome::Output(vec![
+            "    load $0 #5".to_string(), "    load $1 #6".to_string(), "    load $2 #7".to_string(), "    hlt".to_string(),
+        ])));
+        assert_eq!(repl.execute_command(".use three"), Err(ReplError::NoProgram("three".to_string())));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let dir = std::env::temp_dir().join(format!("repl-schedule-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let batch = dir.join("batch.iasm");
+        std::fs::write(&batch, "load $0 #0\njmp $0\n").unwrap();
+        let mut repl = REPL::new();
+        repl.debug_file = dir.join("debug.toml");
+        repl.vm.load_program(&Assembler::new().assemble("load $1 #1\nhlt").unwrap()).unwrap();
+        assert!(repl.execute_command(&format!(".load_file {} as batch", batch.display())).is_ok());
+        assert_eq!(repl.execute_command(".priority batch low"), Ok(CommandOutcome::Output(vec!["batch has low priority".to_string()])));
+        assert_eq!(repl.execute_command(".priority main"), Ok(CommandOutcome::Output(vec!["main has normal priority".to_string()])));
+        assert_eq!(repl.execute_command(".priority main urgent"), Err(ReplError::InvalidPriority("urgent".to_string())));
+        assert_eq!(repl.execute_command(".priority other low"), Err(ReplError::NoProgram("other".to_string())));
+        // main runs first and halts, then batch gets slices of 50 instructions, the last one cut to 18
+        assert_eq!(repl.execute_command(".schedule 120"), Ok(CommandOutcome::Output(vec![
+            "batch: still running at pc 0000 after 118 instructions".to_string(),
+            "main: halted after 2 instructions".to_string(),
+        ])));
+        assert!(repl.execute_command(".use main").is_ok());
+        assert_eq!(repl.vm.register(1), Ok(1));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_breakpoints_are_persisted() {
+        let dir = std::env::temp_dir().join(format!("repl-debug-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("prog.iasm");
+        std::fs::write(&source, "load $0 #1\nload $1 #8\nload $2 #0\nsw $1 $2 #0\nadd $0 $0 $0\nhlt\n").unwrap();
+        let load = format!(".load_file {}", source.display());
+        let mut repl = REPL::new();
+        repl.debug_file = dir.join("debug.toml");
+        assert!(repl.execute_command(&load).is_ok());
+        assert!(repl.execute_command(".break 0x14 if $0 == 2").is_ok());
+        assert!(repl.execute_command(".watch 0").is_ok());
+        assert_eq!(repl.execute_command(".break 4 when"), Err(ReplError::InvalidCondition("when".to_string())));
+
+        let mut repl = REPL::new();
+        repl.debug_file = dir.join("debug.toml");
+        let outcome = repl.execute_command(&load);
+        assert_eq!(outcome, Ok(CommandOutcome::Output(vec![
+            "Loaded 24 bytes from ".to_string() + &source.display().to_string(),
+            "Restored 1 breakpoints and 1 watchpoints".to_string(),
+        ])));
+        assert_eq!(repl.execute_command(".continue"),
+            Ok(CommandOutcome::Output(vec!["Stopped at watchpoint 0000: 0 -> 8 by the instruction at 000c".to_string()])));
+        assert_eq!(repl.execute_command(".continue"),
+            Ok(CommandOutcome::Output(vec!["Stopped at breakpoint 0014 if $0 == 2".to_string()])));
+        assert!(repl.execute_command(".unbreak 0x14").is_ok());
+        assert_eq!(repl.execute_command(".unbreak 0x14"), Err(ReplError::NoBreakpoint(20)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_config_aliases_and_heap() {
+        let config = Config::parse("heap_size = 8\n[aliases]\nr = \".registers\"\nl = \"load\"\n").unwrap();
+        let mut repl = REPL::with_config(config);
+        assert!(repl.execute_command("l $0 #7").is_ok());
+        assert_eq!(repl.vm.register(0), Ok(7));
+        match repl.execute_command("r") {
+            Ok(CommandOutcome::Output(lines)) => assert_eq!(lines[1], "$0: 7"),
+            other => panic!("unexpected outcome {:?}", other)
+        }
+        assert_eq!(repl.vm.heap().len(), 8);
+        assert!(repl.execute_command(".reset").is_ok());
+        assert_eq!(repl.vm.heap().len(), 8);
+    }
+
+    #[test]
+    fn test_heapdiff() {
+        let mut repl = REPL::new();
+        assert!(repl.execute_command(".heapdiff").is_ok());
+        for line in ["load $0 #1589", "load $1 #8", "sw $0 $1 #4", "sw $0 $1 #12", "sw $1 $1 #12"] {
+            assert!(repl.execute_command(line).is_ok());
+        }
+        assert_eq!(repl.execute_command(".heapdiff"), Ok(CommandOutcome::Output(vec![
+            "000c: 00 00 00 00 -> 00 00 06 35 (0 -> 1589)".to_string(),
+            "0014: 00 00 00 00 -> 00 00 00 08 (0 -> 8)".to_string(),
+        ])));
+        assert_eq!(repl.execute_command(".heapdiff"),
+            Ok(CommandOutcome::Output(vec!["No heap change since the last snapshot".to_string()])));
+    }
+
+    #[test]
+    fn test_display() {
+        let mut repl = REPL::new();
+        repl.vm.load_program(&[1, 3, 0, 5, 1, 4, 0, 7, 0, 0, 0, 0]).unwrap();
+        assert_eq!(repl.execute_command(".display $3 + $4"), Ok(CommandOutcome::Output(vec!["1: $3 + $4 = 0".to_string()])));
+        assert!(repl.execute_command(".display mem[99999]").is_ok());
+        assert_eq!(repl.execute_command(".step 2"), Ok(CommandOutcome::Output(vec![
+            "pc: 0008".to_string(),
+            "1: $3 + $4 = 12".to_string(),
+            "2: mem[99999] = <heap address 99999 is out of bounds>".to_string(),
+        ])));
+        assert!(repl.execute_command(".undisplay 2").is_ok());
+        assert_eq!(repl.execute_command(".undisplay 2"), Err(ReplError::NoDisplay(2)));
+        assert_eq!(repl.execute_command(".display $3 +"), Err(ReplError::InvalidExpression("$3 +".to_string())));
+    }
+
+    #[test]
+    fn test_registers_format() {
+        let mut repl = REPL::new();
+        repl.vm.set_register(1, 0xa5).unwrap();
+        repl.vm.set_register(2, -1).unwrap();
+        let mut lines = |command: &str| match repl.execute_command(command) {
+            Ok(CommandOutcome::Output(lines)) => lines[1..lines.len() - 1].to_vec(),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(lines(".registers --format=hex --width=16 $1 $2:bin8 $3:dec"), vec!["$1: 0x00a5", "$2: 0b1111_1111", "$3: 0"]);
+        assert_eq!(lines(".registers --format=bin8").len(), 32);
+        assert_eq!(lines(".registers $2")[0], "$2: -1");
+        let mut repl = REPL::new();
+        assert_eq!(repl.execute_command(".registers --format=oct"), Err(ReplError::InvalidFormat("oct".to_string())));
+        assert_eq!(repl.execute_command(".registers $40"), Err(ReplError::InvalidRegister("$40".to_string())));
+        assert_eq!(repl.execute_command(".registers --base=2"), Err(ReplError::UnknownFlag("base".to_string())));
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut repl = REPL::new();
+        repl.vm.set_register(3, 5).unwrap();
+        assert_eq!(repl.execute_command(".eval ($3 * 4) + 0x10"), Ok(CommandOutcome::Output(vec!["36 (0x24)".to_string()])));
+        assert_eq!(repl.execute_command(".eval 0 - 1"), Ok(CommandOutcome::Output(vec!["-1 (0xffffffff)".to_string()])));
+        assert_eq!(repl.execute_command(".eval $3 / 0").unwrap_err().to_string(), "unable to evaluate the expression: division by zero");
+        assert_eq!(repl.execute_command(".eval"), Err(ReplError::InvalidExpression(String::new())));
+    }
+
+    #[test]
+    fn test_fork_commands() {
+        let mut repl = REPL::new();
+        assert!(repl.execute_command("load $0 #7").is_ok());
+        assert!(repl.execute_command(".fork").is_ok());
+        assert!(repl.execute_command("load $0 #9").is_ok());
+        assert_eq!(repl.vm.register(0), Ok(9));
+        assert!(repl.execute_command(".unfork").is_ok());
+        assert_eq!((repl.vm.register(0), repl.vm.program().len()), (Ok(7), 4));
+        assert_eq!(repl.execute_command(".unfork"), Err(ReplError::NoFork));
+    }
+
+    #[test]
+    fn test_format_program() {
+        let mut repl = REPL::new();
+        for byte in [1, 0, 1, 244, 2, 0, 1, 2, 200] {
+            repl.vm.add_program_byte(byte);
+        }
+        repl.vm.run_once();
+        assert_eq!(repl.format_program(), vec![
+            "   0000  01 00 01 f4  load $0 #500",
+            "=> 0004  02 00 01 02  add $0 $1 $2",
+            "   0008  c8",
+        ]);
+    }
+
+    #[test]
+    fn test_patch_and_truncate_program() {
+        let mut repl = REPL::new();
+        repl.vm.load_program(&[1, 0, 1, 244, 2, 0, 1, 2]).unwrap();
+        assert_eq!(repl.execute_command(".patch 0x4 03 00 01 02 00 00 00 00"),
+            Ok(CommandOutcome::Output(vec!["Program verified (12 bytes)".to_string()])));
+        assert_eq!(repl.vm.program(), &[1, 0, 1, 244, 3, 0, 1, 2, 0, 0, 0, 0]);
+        assert!(repl.execute_command(".truncate 8").is_ok());
+        assert_eq!(repl.vm.program(), &[1, 0, 1, 244, 3, 0, 1, 2]);
+        assert_eq!(repl.execute_command(".patch 0 02 28 00 00"),
+            Err(ReplError::Load(LoadError::Invalid(verifier::VerifyError::InvalidRegister { offset: 0, register: 40 }))));
+        assert_eq!(repl.execute_command(".truncate 6"),
+            Err(ReplError::Load(LoadError::Invalid(verifier::VerifyError::TruncatedInstruction { offset: 4 }))));
+        assert_eq!(repl.vm.program(), &[1, 0, 1, 244, 3, 0, 1, 2]);
+        assert_eq!(repl.execute_command(".step 2"), Ok(CommandOutcome::Output(vec!["pc: 0008".to_string()])));
+        assert!(repl.execute_command(".patch 12 00").is_err());
+        assert_eq!(repl.execute_command(".patch 4 zz"), Err(ReplError::InvalidHex("zz".to_string())));
+        assert_eq!(repl.execute_command(".patch 4"), Err(ReplError::MissingArgument("an offset followed by hex bytes")));
+        assert!(repl.execute_command(".truncate 9").is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use std::io;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use super::{CommandOutcome, REPL};
+
+/// Number of output lines kept under the command line
+const MESSAGE_LINES: usize = 4;
+
+/// Full-screen debugger over a REPL: every command line and key binding goes through
+/// `REPL::execute_command`, so it behaves exactly like the line-oriented REPL.
+///
+/// Keys: `Enter` runs the command line, `F10` steps one instruction, `Esc` quits.
+pub struct Debugger {
+    repl: REPL,
+    /// Register values before the last command, to highlight what it changed
+    previous_registers: Vec<i32>,
+    input: String,
+    messages: Vec<String>,
+}
+
+impl Debugger {
+    pub fn new(repl: REPL) -> Debugger {
+        let previous_registers = repl.vm.registers().map(|(_, v)| v).collect();
+        Debugger {
+            repl: repl,
+            previous_registers: previous_registers,
+            input: String::new(),
+            messages: vec!["F10: step, Enter: run command, Esc: quit".to_string()],
+        }
+    }
+
+    pub fn run(mut self) -> io::Result<()> {
+        let mut terminal = ratatui::init();
+        let result = self.event_loop(&mut terminal);
+        ratatui::restore();
+        result
+    }
+
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let key = match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+            let keep_going = match key.code {
+                KeyCode::Esc => false,
+                KeyCode::F(10) => self.execute(".step"),
+                KeyCode::Enter => {
+                    let line = std::mem::take(&mut self.input);
+                    self.execute(line.trim())
+                },
+                KeyCode::Backspace => {
+                    self.input.pop();
+                    true
+                },
+                KeyCode::Char(c) => {
+                    self.input.push(c);
+                    true
+                },
+                _ => true,
+            };
+            if !keep_going {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs a REPL command and records its output. Returns false when the command quits.
+    fn execute(&mut self, line: &str) -> bool {
+        if line.is_empty() {
+            return true;
+        }
+        self.previous_registers = self.repl.vm.registers().map(|(_, v)| v).collect();
+        let outcome = self.repl.execute_command(line);
+        self.messages = self.repl.take_output();
+        match outcome {
+            Ok(CommandOutcome::Output(lines)) => self.messages.extend(lines),
+            Ok(CommandOutcome::Quit) => return false,
+            Err(e) => self.messages.extend(e.render().lines().map(String::from)),
+        }
+        true
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, messages, command] = Layout::vertical([
+            Constraint::Min(8),
+            Constraint::Length(MESSAGE_LINES as u16 + 2),
+            Constraint::Length(3),
+        ]).areas(frame.area());
+        let [code, registers] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(main);
+        let [disassembly, heap] = Layout::vertical([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(code);
+
+        frame.render_widget(self.disassembly(disassembly), disassembly);
+        frame.render_widget(self.registers(), registers);
+        frame.render_widget(self.heap(heap), heap);
+        let shown = self.messages.iter().rev().take(MESSAGE_LINES).rev().map(|m| Line::from(m.as_str()));
+        frame.render_widget(Paragraph::new(shown.collect::<Vec<_>>()).block(titled("Output")), messages);
+        frame.render_widget(Paragraph::new(format!("> {}", self.input)).block(titled("Command")), command);
+        frame.set_cursor_position((command.x + 3 + self.input.len() as u16, command.y + 1));
+    }
+
+    /// The program rows around the one holding pc
+    fn disassembly(&self, area: Rect) -> Paragraph<'static> {
+        let rows = self.repl.format_program();
+        let height = area.height.saturating_sub(2) as usize;
+        let current = rows.iter().position(|r| r.starts_with("=>")).unwrap_or(rows.len());
+        let start = current.saturating_sub(height / 2).min(rows.len().saturating_sub(height));
+        let lines: Vec<Line> = rows.into_iter().skip(start).take(height)
+            .map(|row| {
+                let style = if row.starts_with("=>") { Style::default().add_modifier(Modifier::REVERSED) } else { Style::default() };
+                Line::styled(row, style)
+            })
+            .collect();
+        Paragraph::new(lines).block(titled("Program"))
+    }
+
+    /// Every register, with the ones changed by the last command highlighted
+    fn registers(&self) -> Paragraph<'static> {
+        let lines: Vec<Line> = self.repl.vm.registers()
+            .map(|(i, value)| {
+                let changed = self.previous_registers.get(i) != Some(&value);
+                let style = if changed { Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD) } else { Style::default() };
+                Line::from(Span::styled(format!("${:<3} {}", i, value), style))
+            })
+            .collect();
+        Paragraph::new(lines).block(titled("Registers"))
+    }
+
+    /// Hexdump of the heap rows holding non-zero bytes
+    fn heap(&self, area: Rect) -> Paragraph<'static> {
+        let height = area.height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = self.repl.vm.heap_rows()
+            .take(height)
+            .map(|(addr, row)| {
+                let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+                Line::from(format!("{:04x}: {}", addr, hex.join(" ")))
+            })
+            .collect();
+        Paragraph::new(lines).block(titled("Heap"))
+    }
+}
+
+fn titled(title: &'static str) -> Block<'static> {
+    Block::default().borders(Borders::ALL).title(title)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    #[test]
+    fn test_draw_after_step() {
+        let mut repl = REPL::new();
+        repl.vm.load_program(&[1, 3, 0, 42, 17, 3, 4, 0]).unwrap();
+        let mut debugger = Debugger::new(repl);
+        assert!(debugger.execute(".step"));
+        assert_eq!(debugger.messages, vec!["pc: 0004".to_string()]);
+        let mut terminal = Terminal::new(TestBackend::new(100, 40)).unwrap();
+        terminal.draw(|frame| debugger.draw(frame)).unwrap();
+        let screen: String = terminal.backend().buffer().content().iter().map(|c| c.symbol()).collect();
+        assert!(screen.contains("=> 0004  11 03 04 00  sw $3 $4 0"));
+        assert!(screen.contains("$3   42"));
+        assert!(debugger.execute(".step"));
+        terminal.draw(|frame| debugger.draw(frame)).unwrap();
+        let screen: String = terminal.backend().buffer().content().iter().map(|c| c.symbol()).collect();
+        assert!(screen.contains("0000: 00 00 00 2a 00 00 00 00"));
+        assert!(!debugger.execute(".quit"));
+    }
+}
@@ -0,0 +1,126 @@
+use crate::instruction::{Opcode, INSTRUCTION_SIZE};
+use crate::vm::VM;
+
+/// One guided exercise of the `.tutorial` command
+pub struct TutorialStep {
+    pub explanation: &'static str,
+    pub hint: &'static str,
+    /// The instruction that completes the step
+    pub solution: &'static str,
+    /// Prepares the VM before the step is shown
+    pub setup: fn(&mut VM),
+    /// Tells whether the VM state shows the step was completed
+    pub check: fn(&VM) -> bool,
+}
+
+/// A `.tutorial` in progress: the step being shown, and the VM of the user set aside until the
+/// tutorial ends
+pub struct Tutorial {
+    steps: Vec<TutorialStep>,
+    current: usize,
+    saved_vm: VM,
+}
+
+impl Tutorial {
+    pub fn new(saved_vm: VM) -> Tutorial {
+        Tutorial { steps: steps(), current: 0, saved_vm: saved_vm }
+    }
+
+    /// The step being shown
+    pub fn step(&self) -> &TutorialStep {
+        &self.steps[self.current]
+    }
+
+    /// Prepares `vm` for the step being shown and returns the line introducing it
+    pub fn show(&self, vm: &mut VM) -> String {
+        (self.step().setup)(vm);
+        format!("Step {}/{}: {}", self.current + 1, self.steps.len(), self.step().explanation)
+    }
+
+    /// Moves to the next step and shows it, returns None once every step was gone through
+    pub fn next_step(&mut self, vm: &mut VM) -> Option<String> {
+        self.current += 1;
+        match self.current < self.steps.len() {
+            true => Some(self.show(vm)),
+            false => None,
+        }
+    }
+
+    /// Ends the tutorial, giving back the VM of the user
+    pub fn finish(self) -> VM {
+        self.saved_vm
+    }
+}
+
+fn no_setup(_vm: &mut VM) {}
+
+pub fn steps() -> Vec<TutorialStep> {
+    vec![
+        TutorialStep {
+            explanation: "Registers are named $0 to $31 and integers are written with a '#'. Load the value 100 into register $0.",
+            hint: "the instruction is `load`, followed by the register and the integer",
+            solution: "load $0 #100",
+            setup: no_setup,
+            check: |vm| vm.register(0) == Ok(100),
+        },
+        TutorialStep {
+            explanation: "Now load the value 50 into register $1.",
+            hint: "`load $1 #50`",
+            solution: "load $1 #50",
+            setup: no_setup,
+            check: |vm| vm.register(1) == Ok(50),
+        },
+        TutorialStep {
+            explanation: "Arithmetic instructions take two source registers and a destination. Add $0 and $1 into $2.",
+            hint: "`add $0 $1 $2`",
+            solution: "add $0 $1 $2",
+            setup: no_setup,
+            check: |vm| vm.register(2) == Ok(150),
+        },
+        TutorialStep {
+            explanation: "Comparisons write 1 (true) or 0 (false) into their destination. Check whether $2 is greater than $0 and store the result into $3.",
+            hint: "`gt $2 $0 $3`",
+            solution: "gt $2 $0 $3",
+            setup: no_setup,
+            check: |vm| vm.register(3) == Ok(1),
+        },
+        TutorialStep {
+            explanation: "`jeq` jumps to the address held by its first register when the second one holds 1. \
+                $4 now holds the address right after your next instruction: branch to it using the result in $3.",
+            hint: "`jeq $4 $3`",
+            solution: "jeq $4 $3",
+            setup: |vm| vm.set_register(4, (vm.program().len() + INSTRUCTION_SIZE) as i32).unwrap(),
+            check: |vm| vm.register(3) == Ok(1) && vm.pc() == vm.program().len() && vm.program().len() >= INSTRUCTION_SIZE
+                && vm.program()[vm.program().len() - INSTRUCTION_SIZE] == Opcode::JEQ as u8,
+        },
+        TutorialStep {
+            explanation: "`sw` stores a register into the heap at the address held by a second register plus a byte offset. \
+                $5 now holds 16: store $2 at that address with an offset of #0.",
+            hint: "`sw $2 $5 #0`",
+            solution: "sw $2 $5 #0",
+            setup: |vm| vm.set_register(5, 16).unwrap(),
+            check: |vm| vm.heap_word(16) == Some(150),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    #[test]
+    fn test_solutions_pass_every_step() {
+        let lex = Lexer::new();
+        let mut vm = VM::new();
+        for step in steps() {
+            (step.setup)(&mut vm);
+            assert!(!(step.check)(&vm), "step already passing: {}", step.solution);
+            for byte in lex.parse_instruction(step.solution).unwrap().compile().unwrap() {
+                vm.add_program_byte(byte);
+            }
+            vm.run_once();
+            assert!((step.check)(&vm), "solution failed: {}", step.solution);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use serde::Serialize;
+use crate::bytecode::{self, Encoding, Endianness};
+use crate::config::Config;
+use crate::assembler::Assembler;
+use crate::signing::{self, SignatureError};
+use crate::test_runner::MAX_STEPS;
+use crate::trace::Trace;
+use crate::vm::{ExecutionStats, StopReason, Usage, VMError, VmState, VM};
+
+/// Exit code of a program that ran to completion
+pub const EXIT_OK: i32 = 0;
+/// Exit code of a program stopped by a VM error
+pub const EXIT_ERROR: i32 = 1;
+/// Exit code of a program that did not halt within the step limit, `MAX_STEPS` by default, or
+/// within the timeout
+pub const EXIT_STEP_LIMIT: i32 = 2;
+/// Exit code of a program that stopped itself with ABORT
+pub const EXIT_ABORT: i32 = 3;
+
+/// How the `run` subcommand prints its report
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn parse(src: &str) -> Result<OutputFormat, String> {
+        match src {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unknown output format '{}', expected text or json", src))
+        }
+    }
+}
+
+/// Outcome of the `run` subcommand
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct RunReport {
+    pub exit_code: i32,
+    pub state: VmState,
+    pub usage: Usage,
+    pub stats: ExecutionStats,
+    /// Text the program wrote with PRINTF
+    pub output: String,
+}
+
+impl RunReport {
+    pub fn render(&self, format: OutputFormat, vm: &VM) -> String {
+        match format {
+            OutputFormat::Json => serde_json::to_string_pretty(self).expect("a run report is always serializable"),
+            OutputFormat::Text => format!(
+                "{}{}exit_code: {}\nstats: {}\n",
+                self.output, vm.dump_state_text(), self.exit_code, self.stats
+            ),
+        }
+    }
+}
+
+/// Assembles `src` and runs it in `vm` until it halts, fails or hits the step limit
+pub fn run_source(vm: &mut VM, src: &str) -> Result<RunReport, String> {
+    let program = Assembler::new().assemble(src).map_err(|e| e.to_string())?;
+    vm.load_program(&program).map_err(|e| e.to_string())?;
+    Ok(run_loaded(vm))
+}
+
+/// Exit code of the program of `vm`, `stopped` telling whether it halted or failed before
+/// reaching the step limit
+pub fn exit_code(vm: &VM, stopped: bool) -> i32 {
+    match vm.last_error() {
+        Some(VMError::Aborted { .. }) => EXIT_ABORT,
+        Some(_) => EXIT_ERROR,
+        None if stopped => EXIT_OK,
+        None => EXIT_STEP_LIMIT,
+    }
+}
+
+/// Runs the program already loaded in `vm` until it halts, fails or hits the step limit
+pub fn run_loaded(vm: &mut VM) -> RunReport {
+    run_traced(vm, None, MAX_STEPS)
+}
+
+/// Same as `run_loaded` with a limit of `max_steps` instructions, recording every executed
+/// instruction into `trace` when given
+pub fn run_traced(vm: &mut VM, mut trace: Option<&mut Trace>, max_steps: usize) -> RunReport {
+    let mut steps = 0;
+    let mut step = |vm: &mut VM| match trace.as_deref_mut() {
+        Some(trace) => trace.step(vm),
+        None => vm.run_once(),
+    };
+    let mut stopped = true;
+    while step(vm) {
+        steps += 1;
+        if steps >= max_steps {
+            stopped = false;
+            vm.quota_exceeded();
+            break;
+        }
+    }
+    report(vm, stopped)
+}
+
+/// Runs the program already loaded in `vm` until it halts, fails or `timeout` expires, without
+/// a step limit. YIELD does not stop it.
+pub fn run_timed(vm: &mut VM, timeout: Duration) -> RunReport {
+    let mut remaining = timeout;
+    let stopped = loop {
+        let outcome = vm.run_for(remaining);
+        remaining = remaining.saturating_sub(outcome.elapsed);
+        match outcome.reason {
+            StopReason::Yielded if !remaining.is_zero() => continue,
+            StopReason::Halted | StopReason::Trapped(_) => break true,
+            StopReason::Yielded | StopReason::BudgetExpired => break false,
+        }
+    };
+    if !stopped {
+        vm.quota_exceeded();
+    }
+    report(vm, stopped)
+}
+
+fn report(vm: &mut VM, stopped: bool) -> RunReport {
+    let exit_code = exit_code(vm, stopped);
+    RunReport { exit_code: exit_code, state: vm.dump_state(), usage: vm.usage(), stats: *vm.stats(), output: vm.take_output() }
+}
+
+/// The `run <file> [--output text|json] [--trace <trace.json>]` subcommand: prints the report
+/// and returns the exit code. Files starting with the bytecode magic number are loaded as
+/// bytecode, others are assembled. With `trace`, the timeline of the run is also written there
+/// in the Chrome `trace_event` format. The VM and the step limit, or the timeout, follow `config`.
+pub fn run_file(path: &Path, format: OutputFormat, trace: Option<&Path>, config: &Config) -> Result<i32, String> {
+    if trace.is_some() && config.timeout().is_some() {
+        return Err("A run cannot be traced with a timeout".to_string());
+    }
+    let mut vm = config.vm_builder().build();
+    load_file(&mut vm, path, config)?;
+    let mut timeline = trace.map(|_| Trace::new());
+    let report = match config.timeout() {
+        Some(timeout) => run_timed(&mut vm, timeout),
+        None => run_traced(&mut vm, timeline.as_mut(), config.max_steps()),
+    };
+    if let (Some(path), Some(timeline)) = (trace, timeline) {
+        timeline.write(path)?;
+    }
+    println!("{}", report.render(format, &vm));
+    Ok(report.exit_code)
+}
+
+/// Loads `path` into `vm`, as bytecode if it starts with the magic number and as source otherwise,
+/// assembled with the register aliases of `config`. With trusted keys configured, only bytecode
+/// signed by one of them is loaded.
+pub fn load_file(vm: &mut VM, path: &Path, config: &Config) -> Result<(), String> {
+    let bytes = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    if let Some(keys) = config.trusted_keys()? {
+        let verified = match bytes.starts_with(&bytecode::MAGIC) {
+            true => signing::verify(&bytes, &keys).map(|_| ()),
+            false => Err(SignatureError::Unsigned),
+        };
+        verified.map_err(|e| format!("{}: refusing to run: {}", path.display(), e))?;
+    }
+    if bytes.starts_with(&bytecode::MAGIC) {
+        vm.load_bytecode(&bytes).map_err(|e| e.to_string())?;
+    } else {
+        let src = String::from_utf8(bytes).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let (program, entry) = assemble_source(config.assembler().with_endianness(vm.endianness()), &src, path)?;
+        vm.load_program(&program).map_err(|e| e.to_string())?;
+        vm.set_entry(entry).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Assembles the source of `path`, printing a warning for each deprecated mnemonic it uses.
+/// Returns the program and its entry point.
+fn assemble_source(assembler: Assembler, src: &str, path: &Path) -> Result<(Vec<u8>, usize), String> {
+    let assembler = assembler.with_source_path(path);
+    for warning in assembler.deprecations(src) {
+        eprintln!("warning: {}: {}", path.display(), warning);
+    }
+    assembler.assemble_with_entry(src).map_err(|e| format!("{}: {}", path.display(), e.render()))
+}
+
+/// The `assemble <source> <output> [--endian big|little] [--compact] [--sign <key file>]`
+/// subcommand: writes a bytecode file, signed with the secret key of `key` if given. The
+/// register aliases follow `config`.
+pub fn assemble_file(source: &Path, output: &Path, endianness: Endianness, encoding: Encoding, key: Option<&Path>, config: &Config) -> Result<usize, String> {
+    let src = fs::read_to_string(source).map_err(|e| format!("Unable to read {}: {}", source.display(), e))?;
+    let (program, entry) = assemble_source(config.assembler().with_endianness(endianness), &src, source)?;
+    let mut bytes = bytecode::write_encoded(&program, endianness, encoding, entry);
+    if let Some(key) = key {
+        bytes = signing::sign(&bytes, &signing::read_signing_key(key)?).map_err(|e| e.to_string())?;
+    }
+    fs::write(output, &bytes).map_err(|e| format!("Unable to write {}: {}", output.display(), e))?;
+    Ok(bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_source_json() {
+        let mut vm = VM::new();
+        let report = run_source(&mut vm, "load $0 #6\nload $1 #0\nqdiv $0 $1 $2\nhlt").unwrap();
+        assert_eq!(report.exit_code, EXIT_ERROR);
+        assert_eq!(report.usage, Usage { instructions: 3, program_bytes: 16, heap_bytes_used: 0 });
+        let json: serde_json::Value = serde_json::from_str(&report.render(OutputFormat::Json, &vm)).unwrap();
+        assert_eq!(json["exit_code"], 1);
+        assert_eq!(json["state"]["registers"][0], 6);
+        assert_eq!(json["state"]["last_error"]["DivisionByZero"]["pc"], 8);
+        assert_eq!(json["usage"]["instructions"], 3);
+        assert_eq!(json["stats"]["branches"], 0);
+    }
+
+    #[test]
+    fn test_run_source_abort() {
+        let mut vm = VM::new();
+        let report = run_source(&mut vm, "load $0 #18537\nload $1 #0\nsw $0 $1 #0\nload $2 #2\nabort $2\nhlt").unwrap();
+        assert_eq!(report.exit_code, EXIT_ABORT);
+        assert_eq!(report.state.abort_message.as_deref(), Some("Hi"));
+        assert!(report.render(OutputFormat::Text, &vm).contains("error: program aborted at pc 16: Hi\n"));
+        let json: serde_json::Value = serde_json::from_str(&report.render(OutputFormat::Json, &vm)).unwrap();
+        assert_eq!(json["state"]["abort_message"], "Hi");
+    }
+
+    #[test]
+    fn test_run_source_printf() {
+        let mut vm = VM::new();
+        // "%d\n" stored from address 2, then printf with $0 = 2 and $1 = 0 - 42
+        let src = [
+            "load $0 #2560", "load $1 #2", "sw $0 $1 #0", "load $0 #9572", "load $1 #0", "sw $0 $1 #0",
+            "load $0 #2", "load $1 #42", "load $2 #0", "sub $2 $1 $1", "sys #5", "hlt",
+        ].join("\n");
+        let report = run_source(&mut vm, &src).unwrap();
+        assert_eq!(report.output, "-42\n");
+        assert!(report.render(OutputFormat::Text, &vm).starts_with("-42\npc: "));
+    }
+
+    #[test]
+    fn test_run_source_step_limit() {
+        let mut vm = VM::new();
+        let report = run_source(&mut vm, "load $0 #0\njmp $0").unwrap();
+        assert_eq!(report.exit_code, EXIT_STEP_LIMIT);
+        assert_eq!(report.usage.instructions, MAX_STEPS as u64);
+        assert_eq!(report.stats.branches, MAX_STEPS as u64 / 2);
+        assert_eq!(OutputFormat::parse("yaml"), Err("Unknown output format 'yaml', expected text or json".to_string()));
+    }
+
+    #[test]
+    fn test_run_timed() {
+        let mut vm = VM::new();
+        vm.load_program(&Assembler::new().assemble("load $0 #0\nyield\njmp $0").unwrap()).unwrap();
+        let report = run_timed(&mut vm, Duration::from_millis(5));
+        assert_eq!(report.exit_code, EXIT_STEP_LIMIT);
+        assert!(report.stats.wall_time >= Duration::from_millis(5));
+        vm.load_program(&Assembler::new().assemble("yield\nhlt").unwrap()).unwrap();
+        assert_eq!(run_timed(&mut vm, Duration::from_secs(1)).exit_code, EXIT_OK);
+    }
+
+    #[test]
+    fn test_assemble_file_little_endian() {
+        let output = std::env::temp_dir().join("simple-vm-test-memory.le");
+        assert_eq!(assemble_file(Path::new("tests/memory.iasm"), &output, Endianness::Little, Encoding::Standard, None, &Config::default()), Ok(28));
+        let mut vm = VM::new();
+        vm.load_bytecode(&fs::read(&output).unwrap()).unwrap();
+        let report = run_loaded(&mut vm);
+        assert_eq!((report.exit_code, report.state.endianness), (EXIT_OK, Endianness::Little));
+        assert_eq!(report.state.registers[3], 1589);
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn test_assemble_file_compact() {
+        let output = std::env::temp_dir().join("simple-vm-test-memory.compact");
+        assert_eq!(assemble_file(Path::new("tests/memory.iasm"), &output, Endianness::Big, Encoding::Compact, None, &Config::default()), Ok(24));
+        let mut vm = VM::new();
+        vm.load_bytecode(&fs::read(&output).unwrap()).unwrap();
+        assert_eq!(vm.program().len(), 20);
+        assert_eq!(run_loaded(&mut vm).state.registers[3], 1589);
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn test_assemble_file_signed() {
+        let dir = std::env::temp_dir();
+        let (key, output) = (dir.join("simple-vm-test-memory.key"), dir.join("simple-vm-test-memory.signed"));
+        let _ = fs::remove_file(&key);
+        let public = signing::keygen_file(&key).unwrap();
+        assert_eq!(assemble_file(Path::new("tests/memory.iasm"), &output, Endianness::Big, Encoding::Standard, Some(&key), &Config::default()), Ok(28 + bytecode::SIGNATURE_SIZE));
+        let config = Config { trusted_keys: Some(vec![public]), ..Config::default() };
+        let mut vm = VM::new();
+        load_file(&mut vm, &output, &config).unwrap();
+        assert_eq!(run_loaded(&mut vm).state.registers[3], 1589);
+        assert_eq!(load_file(&mut vm, Path::new("tests/memory.iasm"), &config),
+            Err("tests/memory.iasm: refusing to run: the program is not signed".to_string()));
+        fs::remove_file(key).unwrap();
+        fs::remove_file(output).unwrap();
+    }
+}
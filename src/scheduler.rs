@@ -0,0 +1,316 @@
+use std::fmt;
+use std::path::Path;
+use crate::config::Config;
+use crate::runner;
+use crate::vm::VM;
+
+/// Instructions a VM of normal priority runs before the scheduler moves on to the next one,
+/// unless it yields first
+pub const DEFAULT_SLICE: usize = 100;
+
+/// Priority of a task. Higher priority tasks run first in every round and get longer slices.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn parse(src: &str) -> Result<Priority, String> {
+        match src {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("Unknown priority '{}', expected low, normal or high", src))
+        }
+    }
+
+    /// Time slice of a task of this priority: half the base slice for low priority tasks and
+    /// twice for high priority ones
+    pub fn slice(self, base: usize) -> usize {
+        let slice = match self {
+            Priority::Low => base / 2,
+            Priority::Normal => base,
+            Priority::High => base.saturating_mul(2),
+        };
+        slice.max(1)
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Priority::Low => write!(f, "low"),
+            Priority::Normal => write!(f, "normal"),
+            Priority::High => write!(f, "high"),
+        }
+    }
+}
+
+/// Pseudo-random numbers of the lockstep mode (xorshift64*), the same seed always giving the
+/// same sequence
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // xorshift never leaves the zero state
+        match seed ^ 0x9e37_79b9_7f4a_7c15 {
+            0 => Rng(0x9e37_79b9_7f4a_7c15),
+            state => Rng(state),
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `0..n`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// A VM run by the scheduler
+pub struct Task {
+    pub name: String,
+    pub vm: VM,
+    /// Set once the VM halted or failed
+    pub stopped: bool,
+    pub priority: Priority,
+}
+
+/// Runs several VMs on one thread, giving each a time slice in turn. A VM can end its slice
+/// early with YIELD, which lets cooperative programs switch at points of their choosing.
+///
+/// In lockstep mode the order of the tasks in every round and the length of their slices are
+/// drawn from a seeded generator instead, so that different seeds explore different
+/// interleavings while a given seed always replays the same one. VMs share nothing and their
+/// syscalls run in the order of the slices, so the whole run is reproduced.
+pub struct Scheduler {
+    tasks: Vec<Task>,
+    slice: usize,
+    rng: Option<Rng>,
+    /// Task id and executed instructions of every slice run so far
+    history: Vec<(usize, usize)>,
+}
+
+impl Scheduler {
+    /// A scheduler switching VMs of normal priority every `slice` instructions
+    pub fn new(slice: usize) -> Scheduler {
+        Scheduler { tasks: vec![], slice: slice.max(1), rng: None, history: vec![] }
+    }
+
+    /// A scheduler in lockstep mode, whose slices last from 1 to the usual number of instructions
+    pub fn lockstep(slice: usize, seed: u64) -> Scheduler {
+        Scheduler { rng: Some(Rng::new(seed)), ..Scheduler::new(slice) }
+    }
+
+    /// Adds a VM to the ones to run, returns its task id, which also becomes the id of the VM
+    pub fn spawn(&mut self, name: &str, mut vm: VM, priority: Priority) -> usize {
+        vm.set_id(self.tasks.len());
+        self.tasks.push(Task { name: name.to_string(), vm: vm, stopped: false, priority: priority });
+        self.tasks.len() - 1
+    }
+
+    /// Changes the priority of task `id`, taking effect from its next slice
+    pub fn set_priority(&mut self, id: usize, priority: Priority) -> Result<(), String> {
+        match self.tasks.get_mut(id) {
+            Some(task) => {
+                task.priority = priority;
+                Ok(())
+            },
+            None => Err(format!("Unknown task {}", id))
+        }
+    }
+
+    pub fn tasks(&self) -> &[Task] {
+        &self.tasks
+    }
+
+    /// Ends the scheduling, giving back the tasks in spawn order
+    pub fn into_tasks(self) -> Vec<Task> {
+        self.tasks
+    }
+
+    /// Task id and executed instructions of every slice run so far, the interleaving to compare
+    /// between two runs
+    pub fn history(&self) -> &[(usize, usize)] {
+        &self.history
+    }
+
+    /// Runs task `id` until it used up its slice, yielded or stopped. Returns the number of
+    /// instructions executed.
+    pub fn run_slice(&mut self, id: usize) -> usize {
+        let slice = self.tasks[id].priority.slice(self.slice);
+        self.run_for(id, slice)
+    }
+
+    fn run_for(&mut self, id: usize, slice: usize) -> usize {
+        let task = &mut self.tasks[id];
+        let mut steps = 0;
+        while !task.stopped && steps < slice {
+            steps += 1;
+            if !task.vm.run_once() {
+                task.stopped = true;
+            } else if task.vm.take_yield() {
+                break;
+            }
+        }
+        if steps > 0 {
+            self.history.push((id, steps));
+        }
+        steps
+    }
+
+    /// Runs the tasks round-robin until they all stopped or `max_steps` instructions were executed
+    /// in total, the tasks still running then getting their quota exceeded. The last slices are
+    /// cut short so as not to go over `max_steps`. Every round goes through the tasks by
+    /// decreasing priority, then in spawn order, unless in lockstep mode. Returns whether they
+    /// all stopped.
+    pub fn run(&mut self, max_steps: usize) -> bool {
+        let mut steps = 0;
+        while self.tasks.iter().any(|t| !t.stopped) {
+            let mut order: Vec<usize> = (0..self.tasks.len()).collect();
+            match &mut self.rng {
+                Some(rng) => for i in (1..order.len()).rev() {
+                    order.swap(i, rng.below(i + 1));
+                },
+                None => order.sort_by_key(|id| std::cmp::Reverse(self.tasks[*id].priority)),
+            }
+            for id in order {
+                if steps >= max_steps {
+                    for task in self.tasks.iter().filter(|t| !t.stopped) {
+                        task.vm.quota_exceeded();
+                    }
+                    return false;
+                }
+                let slice = self.tasks[id].priority.slice(self.slice);
+                let slice = match &mut self.rng {
+                    Some(rng) => 1 + rng.below(slice),
+                    None => slice,
+                };
+                steps += self.run_for(id, slice.min(max_steps - steps));
+            }
+        }
+        true
+    }
+}
+
+/// The `schedule [--priority <level>] <file>... [--slice <n>] [--seed <n>]` subcommand: runs
+/// the programs side by side, each in its own VM with the priority given before it, then prints
+/// the exit code, instruction count and output of each of them. Every program is also reported
+/// as soon as it stops. Returns the highest exit code.
+/// The step limit of `config` applies to all programs together. With a seed, the scheduler runs
+/// in lockstep mode.
+pub fn schedule_files(programs: &[(&Path, Priority)], slice: usize, seed: Option<u64>, config: &Config) -> Result<i32, String> {
+    let mut scheduler = match seed {
+        Some(seed) => Scheduler::lockstep(slice, seed),
+        None => Scheduler::new(slice),
+    };
+    for (path, priority) in programs {
+        let mut vm = config.vm_builder().build();
+        runner::load_file(&mut vm, path, config)?;
+        let name = path.file_stem().map_or(path.display().to_string(), |s| s.to_string_lossy().into_owned());
+        let task = name.clone();
+        vm.on_halt(move |_, usage| println!("{} halted after {} instructions", task, usage.instructions));
+        let task = name.clone();
+        vm.on_trap(move |state, usage| match state.last_error {
+            Some(e) => println!("{} stopped after {} instructions: {}", task, usage.instructions, e),
+            None => println!("{} stopped after {} instructions", task, usage.instructions),
+        });
+        let task = name.clone();
+        vm.on_quota_exceeded(move |_, usage| println!("{} ran out of steps after {} instructions", task, usage.instructions));
+        scheduler.spawn(&name, vm, *priority);
+    }
+    scheduler.run(config.max_steps());
+    if let Some(seed) = seed {
+        println!("lockstep seed {}: {} slices", seed, scheduler.history().len());
+    }
+    let mut code = runner::EXIT_OK;
+    for task in &mut scheduler.tasks {
+        let exit_code = runner::exit_code(&task.vm, task.stopped);
+        println!("{}: exit_code {}, {} instructions", task.name, exit_code, task.vm.stats().instructions);
+        for line in task.vm.take_output().lines() {
+            println!("  {}", line);
+        }
+        code = code.max(exit_code);
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn vm(src: &str) -> VM {
+        let mut vm = VM::new();
+        vm.load_program(&Assembler::new().assemble(src).unwrap()).unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_yield_ends_the_slice() {
+        let mut scheduler = Scheduler::new(10);
+        let id = scheduler.spawn("cooperative", vm("load $0 #1\nyield\nload $1 #2\nhlt"), Priority::Normal);
+        assert_eq!(scheduler.run_slice(id), 2);
+        assert_eq!(scheduler.tasks()[id].vm.register(1), Ok(0));
+        assert_eq!(scheduler.run_slice(id), 2);
+        assert!(scheduler.tasks()[id].stopped);
+        assert_eq!(scheduler.run_slice(id), 0);
+    }
+
+    #[test]
+    fn test_run_interleaves_tasks() {
+        let mut scheduler = Scheduler::new(3);
+        scheduler.spawn("short", vm("load $0 #1\nhlt"), Priority::Normal);
+        scheduler.spawn("loop", vm("load $0 #0\njmp $0"), Priority::Normal);
+        assert!(!scheduler.run(50));
+        let tasks = scheduler.tasks();
+        assert!(tasks[0].stopped && !tasks[1].stopped);
+        assert_eq!((tasks[0].vm.stats().instructions, tasks[1].vm.stats().instructions), (2, 48));
+        let mut scheduler = Scheduler::new(10);
+        scheduler.spawn("loop", vm("load $0 #0\njmp $0"), Priority::Normal);
+        assert!(!scheduler.run(15));
+        assert_eq!(scheduler.tasks()[0].vm.stats().instructions, 15);
+    }
+
+    #[test]
+    fn test_priorities() {
+        let mut scheduler = Scheduler::new(4);
+        let batch = scheduler.spawn("batch", vm("load $0 #0\njmp $0"), Priority::Low);
+        let interactive = scheduler.spawn("interactive", vm("load $0 #0\njmp $0"), Priority::High);
+        assert!(!scheduler.run(20));
+        // The high priority task runs first, for 8 instructions a round against 2
+        assert_eq!(scheduler.tasks()[interactive].vm.stats().instructions, 16);
+        assert_eq!(scheduler.tasks()[batch].vm.stats().instructions, 4);
+        assert_eq!(scheduler.set_priority(batch, Priority::High), Ok(()));
+        assert_eq!(scheduler.run_slice(batch), 8);
+        assert_eq!(scheduler.set_priority(2, Priority::Low), Err("Unknown task 2".to_string()));
+        assert_eq!(Priority::parse("urgent"), Err("Unknown priority 'urgent', expected low, normal or high".to_string()));
+    }
+
+    #[test]
+    fn test_lockstep_is_reproducible() {
+        let run = |seed| {
+            let mut scheduler = Scheduler::lockstep(8, seed);
+            for _ in 0..3 {
+                scheduler.spawn("counter", vm("load $0 #1\nadd $1 $0 $1\nload $2 #4\njmp $2"), Priority::Normal);
+            }
+            scheduler.run(200);
+            let registers: Vec<i32> = scheduler.tasks().iter().map(|t| t.vm.register(1).unwrap()).collect();
+            (scheduler.history().to_vec(), registers)
+        };
+        let (history, registers) = run(7);
+        assert_eq!(run(7), (history.clone(), registers));
+        assert_ne!(run(8).0, history);
+        assert!(history.iter().all(|(_, steps)| (1..=8).contains(steps)));
+    }
+}
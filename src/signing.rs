@@ -0,0 +1,131 @@
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey, SIGNATURE_LENGTH};
+use rand_core::OsRng;
+use thiserror::Error;
+use crate::bytecode::{Header, HeaderError, SIGNATURE_SIZE};
+
+/// Errors raised when signing a bytecode file or checking its signature
+#[derive(Debug, PartialEq, Clone, Error)]
+pub enum SignatureError {
+    #[error(transparent)]
+    Header(#[from] HeaderError),
+    #[error("the program is not signed")]
+    Unsigned,
+    #[error("the program is already signed")]
+    AlreadySigned,
+    #[error("the signature does not match the program, which was modified since it was signed")]
+    Mismatch,
+    #[error("the program is signed with the untrusted key {0}")]
+    UntrustedKey(String),
+    #[error("invalid key '{0}', expected 32 bytes in base64")]
+    InvalidKey(String),
+}
+
+/// A key written in base64, the way key files and the configuration hold them
+pub fn encode_key(key: &[u8]) -> String {
+    STANDARD.encode(key)
+}
+
+fn decode_key(src: &str) -> Result<[u8; 32], SignatureError> {
+    let src = src.trim();
+    STANDARD.decode(src).ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| SignatureError::InvalidKey(src.to_string()))
+}
+
+/// Reads a secret key written by `encode_key`
+pub fn parse_signing_key(src: &str) -> Result<SigningKey, SignatureError> {
+    decode_key(src).map(|bytes| SigningKey::from_bytes(&bytes))
+}
+
+/// Reads a public key written by `encode_key`
+pub fn parse_verifying_key(src: &str) -> Result<VerifyingKey, SignatureError> {
+    let bytes = decode_key(src)?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| SignatureError::InvalidKey(src.trim().to_string()))
+}
+
+/// Signs a bytecode file: the header gets flagged as signed, then the file is followed by the
+/// signature of everything before it and by the public key of `key`
+pub fn sign(bytes: &[u8], key: &SigningKey) -> Result<Vec<u8>, SignatureError> {
+    let (header, program) = Header::read(bytes)?;
+    if header.signed {
+        return Err(SignatureError::AlreadySigned);
+    }
+    let mut out = vec![];
+    header.with_signature(true).encode(&mut out);
+    out.extend_from_slice(program);
+    let signature = key.sign(&out);
+    out.extend_from_slice(&signature.to_bytes());
+    out.extend_from_slice(key.verifying_key().as_bytes());
+    Ok(out)
+}
+
+/// Checks that a bytecode file is signed, unmodified since, and with one of the `trusted` keys.
+/// Returns the key it was signed with.
+pub fn verify(bytes: &[u8], trusted: &[VerifyingKey]) -> Result<VerifyingKey, SignatureError> {
+    let (header, _) = Header::read(bytes)?;
+    if !header.signed {
+        return Err(SignatureError::Unsigned);
+    }
+    let (signed, trailer) = bytes.split_at(bytes.len() - SIGNATURE_SIZE);
+    let signature = Signature::from_slice(&trailer[..SIGNATURE_LENGTH]).map_err(|_| SignatureError::Mismatch)?;
+    let key = <[u8; 32]>::try_from(&trailer[SIGNATURE_LENGTH..]).expect("the signature ends with a 32-byte key");
+    let key = VerifyingKey::from_bytes(&key).map_err(|_| SignatureError::Mismatch)?;
+    key.verify_strict(signed, &signature).map_err(|_| SignatureError::Mismatch)?;
+    if !trusted.contains(&key) {
+        return Err(SignatureError::UntrustedKey(encode_key(key.as_bytes())));
+    }
+    Ok(key)
+}
+
+/// Reads the secret key stored in a file by `keygen_file`
+pub fn read_signing_key(path: &Path) -> Result<SigningKey, String> {
+    let src = fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    parse_signing_key(&src).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// The `keygen <key file>` subcommand: writes a new secret key to a file that must not exist
+/// yet, readable by its owner only, and returns the public key to add to the trusted keys
+pub fn keygen_file(path: &Path) -> Result<String, String> {
+    let key = SigningKey::generate(&mut OsRng);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+        .and_then(|mut file| writeln!(file, "{}", encode_key(key.as_bytes())))
+        .map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+    Ok(encode_key(key.verifying_key().as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{self, Endianness};
+    use crate::vm::VM;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = parse_signing_key(&encode_key(&[7; 32])).unwrap();
+        let other = SigningKey::from_bytes(&[8; 32]);
+        let bytes = bytecode::write(&[1, 0, 1, 244], Endianness::Little);
+        let signed = sign(&bytes, &key).unwrap();
+        assert_eq!(signed.len(), bytes.len() + SIGNATURE_SIZE);
+        assert_eq!(verify(&signed, &[other.verifying_key(), key.verifying_key()]), Ok(key.verifying_key()));
+        let mut vm = VM::new();
+        vm.load_bytecode(&signed).unwrap();
+        assert_eq!(vm.program(), &[1, 0, 1, 244]);
+        assert_eq!(verify(&signed, &[other.verifying_key()]), Err(SignatureError::UntrustedKey(encode_key(key.verifying_key().as_bytes()))));
+        let mut tampered = signed.clone();
+        tampered[bytecode::HEADER_SIZE + 3] = 2;
+        assert_eq!(verify(&tampered, &[key.verifying_key()]), Err(SignatureError::Mismatch));
+        assert_eq!(verify(&bytes, &[key.verifying_key()]), Err(SignatureError::Unsigned));
+        assert_eq!(sign(&signed, &key), Err(SignatureError::AlreadySigned));
+        assert!(parse_verifying_key("AAAA").is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use std::convert::TryFrom;
+use serde::Deserialize;
+use crate::vm::REGISTER_COUNT;
+
+/// Services a guest program can request from the VM with `sys #id`.
+///
+/// Math syscalls work on the float registers: the argument is read from `$f0` (and `$f1`
+/// for the exponent of `POW`) and the result is written back to `$f0`. `PRINTF` writes to the
+/// guest output, see `printf`. `LOG` sends the NUL-terminated string at the heap address in `$0`
+/// to the host's `log` pipeline, at the severity in `$1`, see `log_level`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Syscall {
+    Sqrt,
+    Sin,
+    Cos,
+    Pow,
+    Abs,
+    Printf,
+    Log,
+}
+
+impl Syscall {
+    pub fn from_id(id: u16) -> Option<Syscall> {
+        match id {
+            0 => Some(Syscall::Sqrt),
+            1 => Some(Syscall::Sin),
+            2 => Some(Syscall::Cos),
+            3 => Some(Syscall::Pow),
+            4 => Some(Syscall::Abs),
+            5 => Some(Syscall::Printf),
+            6 => Some(Syscall::Log),
+            _ => None
+        }
+    }
+
+    /// Group a `SyscallPolicy` allows or denies the syscall with
+    pub fn group(self) -> SyscallGroup {
+        match self {
+            Syscall::Sqrt | Syscall::Sin | Syscall::Cos | Syscall::Pow | Syscall::Abs => SyscallGroup::Math,
+            Syscall::Printf | Syscall::Log => SyscallGroup::Console,
+        }
+    }
+
+    /// Applies a math syscall to the float register file, PRINTF and LOG leaving it untouched
+    pub fn call(self, float_registers: &mut [f64; REGISTER_COUNT]) {
+        let x = float_registers[0];
+        float_registers[0] = match self {
+            Syscall::Sqrt => x.sqrt(),
+            Syscall::Sin => x.sin(),
+            Syscall::Cos => x.cos(),
+            Syscall::Pow => x.powf(float_registers[1]),
+            Syscall::Abs => x.abs(),
+            Syscall::Printf | Syscall::Log => x,
+        };
+    }
+}
+
+/// Kinds of services a `SyscallPolicy` grants or withholds as a whole, named in lowercase in the
+/// configuration. File, network, time and random number syscalls get their group up front, so
+/// that a policy written now keeps denying them once they exist.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyscallGroup {
+    Math,
+    Console,
+    File,
+    Net,
+    Time,
+    Random,
+}
+
+/// Syscalls the embedder lets a guest program make, all of them by default. A denied syscall
+/// stops the program with `VMError::PermissionDenied` instead of running.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SyscallPolicy {
+    denied: Vec<SyscallGroup>,
+}
+
+impl SyscallPolicy {
+    pub fn deny(mut self, group: SyscallGroup) -> SyscallPolicy {
+        if !self.denied.contains(&group) {
+            self.denied.push(group);
+        }
+        self
+    }
+
+    pub fn allow(mut self, group: SyscallGroup) -> SyscallPolicy {
+        self.denied.retain(|denied| *denied != group);
+        self
+    }
+
+    pub fn allows(&self, syscall: Syscall) -> bool {
+        !self.denied.contains(&syscall.group())
+    }
+}
+
+/// `log` target of the records of LOG, for hosts to filter guest diagnostics
+pub const LOG_TARGET: &str = "guest";
+
+/// Severity of a LOG record from the value of `$1`: 0 for errors, then warnings, information,
+/// debugging and tracing. Values past either end take the nearest severity.
+pub fn log_level(severity: i32) -> log::Level {
+    match severity {
+        i32::MIN..=0 => log::Level::Error,
+        1 => log::Level::Warn,
+        2 => log::Level::Info,
+        3 => log::Level::Debug,
+        _ => log::Level::Trace,
+    }
+}
+
+/// Output of PRINTF for the format string read from the heap address in `$0`. The `%d`, `%u`,
+/// `%x`, `%c` and `%s` conversions take `args`, the registers from `$1`, in turn: `%c` prints an
+/// ASCII character and `%s` the NUL-terminated string whose address the register holds, read
+/// with `string`. `%%` prints a percent sign, and conversions left without a register are
+/// printed as is.
+pub fn printf(format: &str, args: &[i32], string: impl Fn(usize) -> String) -> String {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => out.push('%'),
+            Some(conversion) if "ducxs".contains(conversion) => match args.next() {
+                Some(&value) => match conversion {
+                    'd' => out.push_str(&value.to_string()),
+                    'u' => out.push_str(&(value as u32).to_string()),
+                    'x' => out.push_str(&format!("{:x}", value as u32)),
+                    'c' => out.push(u8::try_from(value).ok().filter(u8::is_ascii).map_or('?', char::from)),
+                    _ => out.push_str(&string(value as u32 as usize)),
+                },
+                None => {
+                    out.push('%');
+                    out.push(conversion);
+                },
+            },
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            },
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_id() {
+        assert_eq!(Syscall::from_id(3), Some(Syscall::Pow));
+        assert_eq!(Syscall::from_id(500), None);
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = SyscallPolicy::default().deny(SyscallGroup::Console).deny(SyscallGroup::Math).allow(SyscallGroup::Math);
+        assert!(!policy.allows(Syscall::Printf));
+        assert!(policy.allows(Syscall::Sqrt));
+        assert!(SyscallPolicy::default().allows(Syscall::Printf));
+    }
+
+    #[test]
+    fn test_printf() {
+        let string = |addr| format!("<{}>", addr);
+        assert_eq!(printf("%d %u %x|%c%s 100%%", &[-1, -1, 255, 72, 40], string), "-1 4294967295 ff|H<40> 100%");
+        assert_eq!(printf("%c %q %d", &[200], string), "? %q %d");
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(log_level(-5), log::Level::Error);
+        assert_eq!(log_level(2), log::Level::Info);
+        assert_eq!(log_level(100), log::Level::Trace);
+    }
+
+    #[test]
+    fn test_pow() {
+        let mut float_registers = [0.0; 32];
+        float_registers[0] = 2.0;
+        float_registers[1] = 10.0;
+        Syscall::Pow.call(&mut float_registers);
+        assert_eq!(float_registers[0], 1024.0);
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+use std::fmt;
+use crate::instruction::{Instruction, Opcode, Operand};
+use crate::syscall::Syscall;
+use crate::vm::REGISTER_COUNT;
+
+/// Where a tainted value was used
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Sink {
+    /// The offset a jump goes to
+    JumpTarget,
+    /// The heap address an instruction reads or writes
+    MemoryAddress,
+}
+
+/// A tainted register used as a jump target or a memory address
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct TaintReport {
+    pub pc: usize,
+    pub opcode: Opcode,
+    pub register: u8,
+    pub sink: Sink,
+}
+
+impl fmt::Display for TaintReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sink = match self.sink {
+            Sink::JumpTarget => "a jump target",
+            Sink::MemoryAddress => "a memory address",
+        };
+        write!(f, "{:04x} {}: tainted ${} used as {}", self.pc, self.opcode, self.register, sink)
+    }
+}
+
+/// Shadow state of a VM tracking which values derive from guest input, see
+/// `VM::start_taint_tracking`. A value computed from a tainted one is tainted too, and so is a
+/// word loaded from tainted heap bytes or through a tainted address. A value loaded from an
+/// immediate or a counter is clean. The integer registers of every bank share the same shadow
+/// registers. Register operands that do not exist are left alone, the VM stops on them.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Taint {
+    registers: [bool; REGISTER_COUNT],
+    float_registers: [bool; REGISTER_COUNT],
+    /// Addresses of the tainted heap bytes
+    heap: HashSet<usize>,
+    reports: Vec<TaintReport>,
+}
+
+impl Taint {
+    pub fn new() -> Taint {
+        Taint::default()
+    }
+
+    /// Marks integer register `index` as holding guest input
+    pub fn taint_register(&mut self, index: usize) {
+        self.set(index, true);
+    }
+
+    pub fn is_tainted(&self, index: usize) -> bool {
+        self.registers.get(index).copied().unwrap_or(false)
+    }
+
+    fn set(&mut self, index: usize, tainted: bool) {
+        if let Some(register) = self.registers.get_mut(index) {
+            *register = tainted;
+        }
+    }
+
+    fn is_float_tainted(&self, index: usize) -> bool {
+        self.float_registers.get(index).copied().unwrap_or(false)
+    }
+
+    fn set_float(&mut self, index: usize, tainted: bool) {
+        if let Some(register) = self.float_registers.get_mut(index) {
+            *register = tainted;
+        }
+    }
+
+    /// Tainted values that reached a sink, in execution order
+    pub fn reports(&self) -> &[TaintReport] {
+        &self.reports
+    }
+
+    fn report(&mut self, pc: usize, opcode: Opcode, register: u8, sink: Sink) {
+        if self.is_tainted(register as usize) {
+            self.reports.push(TaintReport { pc: pc, opcode: opcode, register: register, sink: sink });
+        }
+    }
+
+    /// Propagates the taint through `instruction`, about to be executed at `pc` with the
+    /// integer registers holding `registers`, and reports its tainted sinks
+    pub fn step(&mut self, pc: usize, instruction: &Instruction, registers: &[i32; REGISTER_COUNT]) {
+        let opcode = instruction.opcode();
+        let register = |i: usize| match instruction.operands()[i] {
+            Operand::Register(r) => r,
+            _ => 0,
+        };
+        let (r1, r2, r3) = (register(0) as usize, register(1) as usize, register(2) as usize);
+        let address = || {
+            let offset = match instruction.operands()[2] {
+                Operand::Byte(offset) => offset as usize,
+                _ => 0,
+            };
+            (registers.get(r2).copied().unwrap_or(0) as usize).wrapping_add(offset)
+        };
+        match opcode {
+            Opcode::LOAD => self.set(r1, false),
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::ADDO | Opcode::SUBO | Opcode::MULO
+            | Opcode::ADDS | Opcode::SUBS | Opcode::QMUL | Opcode::QDIV | Opcode::EQ | Opcode::NEQ | Opcode::GT
+            | Opcode::LT | Opcode::GTQ | Opcode::LTQ => {
+                self.set(r3, self.is_tainted(r1) || self.is_tainted(r2));
+            },
+            Opcode::MAC => {
+                self.set(r1, self.is_tainted(r1) || self.is_tainted(r2) || self.is_tainted(r3));
+            },
+            Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JEQ => self.report(pc, opcode, r1 as u8, Sink::JumpTarget),
+            Opcode::LW => {
+                self.report(pc, opcode, r2 as u8, Sink::MemoryAddress);
+                let addr = address();
+                let loaded = (0..4).any(|i| self.heap.contains(&addr.wrapping_add(i)));
+                self.set(r1, loaded || self.is_tainted(r2));
+            },
+            Opcode::SW => {
+                self.report(pc, opcode, r2 as u8, Sink::MemoryAddress);
+                let addr = address();
+                let tainted = self.is_tainted(r1);
+                for byte in (0..4).map(|i| addr.wrapping_add(i)) {
+                    if tainted {
+                        self.heap.insert(byte);
+                    } else {
+                        self.heap.remove(&byte);
+                    }
+                }
+            },
+            Opcode::ABORT => self.report(pc, opcode, r1 as u8, Sink::MemoryAddress),
+            Opcode::ITOF => self.set_float(r2, self.is_tainted(r1)),
+            Opcode::FTOI => self.set(r2, self.is_float_tainted(r1)),
+            Opcode::FEQ | Opcode::FLT | Opcode::FGT => {
+                self.set(r3, self.is_float_tainted(r1) || self.is_float_tainted(r2));
+            },
+            // Math syscalls compute `$f0` from `$f0` and `$f1`, PRINTF and LOG read a string from `$0`
+            Opcode::SYS => match instruction.operands()[0] {
+                Operand::Integer(id) if matches!(Syscall::from_id(id), Some(Syscall::Printf | Syscall::Log)) => {
+                    self.report(pc, opcode, 0, Sink::MemoryAddress);
+                },
+                _ => self.float_registers[0] |= self.float_registers[1],
+            },
+            Opcode::RDCNT => self.set(r2, false),
+            Opcode::HLT | Opcode::ASSERT | Opcode::BANKSW | Opcode::YIELD | Opcode::PUSHTRAP | Opcode::POPTRAP | Opcode::IGL => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_step() {
+        let mut taint = Taint::new();
+        let mut registers = [0; REGISTER_COUNT];
+        registers[2] = 8;
+        taint.taint_register(1);
+        let op = |opcode, operands| Instruction::with_operands(opcode, operands);
+        let (r, none) = (Operand::Register, Operand::None);
+        taint.step(0, &op(Opcode::ADD, [r(1), r(2), r(3)]), &registers);
+        taint.step(4, &op(Opcode::SW, [r(3), r(2), Operand::Byte(4)]), &registers);
+        taint.step(8, &op(Opcode::LW, [r(4), r(2), Operand::Byte(6)]), &registers);
+        taint.step(12, &op(Opcode::LOAD, [r(3), Operand::Integer(0), none]), &registers);
+        taint.step(16, &op(Opcode::JMP, [r(4), none, none]), &registers);
+        taint.step(20, &op(Opcode::JMP, [r(3), none, none]), &registers);
+        assert_eq!((taint.is_tainted(3), taint.is_tainted(4)), (false, true));
+        assert_eq!(taint.reports(), &[TaintReport { pc: 16, opcode: Opcode::JMP, register: 4, sink: Sink::JumpTarget }]);
+        assert_eq!(taint.reports()[0].to_string(), "0010 jmp: tainted $4 used as a jump target");
+    }
+
+    #[test]
+    fn test_tainted_address() {
+        let mut taint = Taint::new();
+        let registers = [0; REGISTER_COUNT];
+        taint.taint_register(2);
+        let op = |opcode, operands| Instruction::with_operands(opcode, operands);
+        let r = Operand::Register;
+        // The heap is clean, but which word is read depends on the input
+        taint.step(0, &op(Opcode::LW, [r(1), r(2), Operand::Byte(0)]), &registers);
+        taint.step(4, &op(Opcode::LW, [r(3), r(4), Operand::Byte(0)]), &registers);
+        assert_eq!((taint.is_tainted(1), taint.is_tainted(3)), (true, false));
+        // Registers that do not exist are ignored rather than indexed
+        taint.step(8, &op(Opcode::ADD, [r(2), r(40), r(41)]), &registers);
+        taint.step(12, &op(Opcode::LW, [r(1), r(40), Operand::Byte(0)]), &registers);
+        assert_eq!((taint.is_tainted(40), taint.is_tainted(1), taint.reports().len()), (false, false, 1));
+    }
+}
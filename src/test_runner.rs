@@ -0,0 +1,148 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::assembler::Assembler;
+use crate::vm::VM;
+
+/// Upper bound on executed instructions, so a looping test fails instead of hanging the runner
+pub const MAX_STEPS: usize = 1_000_000;
+
+/// A `; expect $<register> == <value>` directive found in a test file
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Expectation {
+    pub register: usize,
+    pub value: i32,
+}
+
+/// Extracts the expectation directives of an assembly test file
+pub fn parse_expectations(src: &str) -> Result<Vec<Expectation>, String> {
+    let mut expectations = vec![];
+    for (i, line) in src.lines().enumerate() {
+        let directive = match line.trim().strip_prefix(';') {
+            Some(comment) => comment.trim(),
+            None => continue
+        };
+        let args = match directive.strip_prefix("expect ") {
+            Some(args) => args,
+            None => continue
+        };
+        let parts: Vec<&str> = args.split_whitespace().collect();
+        let parsed = match parts.as_slice() {
+            [register, "==", value] => register.strip_prefix('$')
+                .and_then(|r| r.parse().ok())
+                .zip(value.parse().ok()),
+            _ => None
+        };
+        match parsed {
+            Some((register, value)) => expectations.push(Expectation { register: register, value: value }),
+            None => return Err(format!("line {}: invalid expectation '{}', expected '; expect $<register> == <value>'", i + 1, directive))
+        }
+    }
+    Ok(expectations)
+}
+
+/// Assembles and runs a test source in a fresh VM, then checks its expectations. The files it
+/// includes are found relative to `path`, the file it was read from, if any.
+/// Returns the list of failures, empty when the test passed.
+pub fn run_test(src: &str, path: Option<&Path>) -> Result<(), Vec<String>> {
+    let expectations = parse_expectations(src).map_err(|e| vec![e])?;
+    let mut vm = VM::new();
+    let assembler = match path {
+        Some(path) => Assembler::new().with_source_path(path),
+        None => Assembler::new(),
+    };
+    let (program, entry) = assembler.assemble_with_entry(src).map_err(|e| vec![e.to_string()])?;
+    vm.load_program(&program).map_err(|e| vec![e.to_string()])?;
+    vm.set_entry(entry).map_err(|e| vec![e.to_string()])?;
+    let mut steps = 0;
+    while vm.run_once() {
+        steps += 1;
+        if steps >= MAX_STEPS {
+            return Err(vec![format!("no halt after {} instructions", MAX_STEPS)]);
+        }
+    }
+    if let Some(e) = vm.last_error() {
+        return Err(vec![format!("execution stopped: {}", e)]);
+    }
+    let failures: Vec<String> = expectations.iter()
+        .filter_map(|e| match vm.register(e.register) {
+            Ok(v) if v == e.value => None,
+            Ok(v) => Some(format!("expected ${} == {}, found {}", e.register, e.value, v)),
+            Err(err) => Some(err.to_string())
+        })
+        .collect();
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+/// Lists the `.iasm` files of a directory, sorted by name
+pub fn discover(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Unable to read {}: {}", dir.display(), e))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "iasm"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Runs every test file of `dir`, printing a pass/fail line per file. Returns true if all passed.
+pub fn run_directory(dir: &Path) -> Result<bool, String> {
+    let files = discover(dir)?;
+    let mut passed = 0;
+    for file in &files {
+        let result = fs::read_to_string(file)
+            .map_err(|e| vec![e.to_string()])
+            .and_then(|src| run_test(&src, Some(file)));
+        match result {
+            Ok(()) => {
+                passed += 1;
+                println!("PASS {}", file.display());
+            },
+            Err(failures) => {
+                println!("FAIL {}", file.display());
+                for failure in failures {
+                    println!("     {}", failure);
+                }
+            }
+        }
+    }
+    println!("{} passed, {} failed", passed, files.len() - passed);
+    Ok(passed == files.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_expectations() {
+        let src = "; expect $3 == 42\nload $3 #42\n;expect $0 == -1\n; just a comment";
+        assert_eq!(parse_expectations(src), Ok(vec![
+            Expectation { register: 3, value: 42 },
+            Expectation { register: 0, value: -1 },
+        ]));
+        assert!(parse_expectations("; expect $3 = 42").is_err());
+    }
+
+    #[test]
+    fn test_run_test() {
+        assert_eq!(run_test("; expect $1 == 7\nload $1 #7\nhlt", None), Ok(()));
+        assert_eq!(run_test("; expect $1 == 8\nload $1 #7\nhlt", None), Err(vec!["expected $1 == 8, found 7".to_string()]));
+        assert!(run_test("load $1 #7\nload $2 #8\nassert $1 $2", None).is_err());
+        assert_eq!(run_test("; expect $1 == 0\nload $1 #7\n.entry @end\nend: hlt", None), Ok(()));
+    }
+
+    #[test]
+    fn test_isa_suite() {
+        for dir in ["tests", "examples"] {
+            let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(dir);
+            for file in discover(&dir).unwrap() {
+                let src = fs::read_to_string(&file).unwrap();
+                assert_eq!(run_test(&src, Some(&file)), Ok(()), "{}", file.display());
+            }
+        }
+    }
+}
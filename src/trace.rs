@@ -0,0 +1,118 @@
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+use serde::Serialize;
+use crate::instruction::{Decode, Instruction, Opcode, Operand};
+use crate::syscall::Syscall;
+use crate::vm::VM;
+
+/// One complete event (`"ph": "X"`) of the Chrome `trace_event` format, timed in microseconds
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct TraceEvent {
+    pub name: String,
+    pub cat: &'static str,
+    pub ph: &'static str,
+    pub ts: f64,
+    pub dur: f64,
+    pub pid: u32,
+    pub tid: u32,
+    pub args: TraceArgs,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct TraceArgs {
+    pub pc: usize,
+    pub instruction: String,
+}
+
+/// Timeline of a run, with one event per executed instruction. Syscalls are recorded under
+/// their own category and name so they stand out in chrome://tracing or Perfetto.
+#[derive(Debug)]
+pub struct Trace {
+    start: Instant,
+    events: Vec<TraceEvent>,
+}
+
+#[derive(Serialize)]
+struct TraceFile<'a> {
+    #[serde(rename = "traceEvents")]
+    trace_events: &'a [TraceEvent],
+    #[serde(rename = "displayTimeUnit")]
+    display_time_unit: &'static str,
+}
+
+impl Default for Trace {
+    fn default() -> Trace {
+        Trace::new()
+    }
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace { start: Instant::now(), events: vec![] }
+    }
+
+    pub fn events(&self) -> &[TraceEvent] {
+        &self.events
+    }
+
+    /// Executes one instruction of `vm` like `VM::run_once`, recording it
+    pub fn step(&mut self, vm: &mut VM) -> bool {
+        let pc = vm.pc();
+        let instruction = vm.program().get(pc..).and_then(|bytes| Instruction::decode(bytes).ok());
+        let started = Instant::now();
+        let running = vm.run_once();
+        let dur = started.elapsed().as_secs_f64() * 1e6;
+        if let Some(instruction) = instruction {
+            let (cat, name) = match (instruction.opcode(), instruction.operands()[0]) {
+                (Opcode::SYS, Operand::Integer(id)) => match Syscall::from_id(id) {
+                    Some(syscall) => ("syscall", format!("{:?}", syscall).to_lowercase()),
+                    None => ("syscall", format!("sys #{}", id)),
+                },
+                (opcode, _) => ("instruction", opcode.mnemonic().to_string()),
+            };
+            self.events.push(TraceEvent {
+                name: name,
+                cat: cat,
+                ph: "X",
+                ts: (started - self.start).as_secs_f64() * 1e6,
+                dur: dur,
+                pid: 1,
+                tid: 1,
+                args: TraceArgs { pc: pc, instruction: instruction.to_string() },
+            });
+        }
+        running
+    }
+
+    pub fn to_json(&self) -> String {
+        let file = TraceFile { trace_events: &self.events, display_time_unit: "ns" };
+        serde_json::to_string(&file).expect("a trace is always serializable")
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.to_json()).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(all(test, feature = "float"))]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_trace_events() {
+        let mut vm = VM::new();
+        vm.load_program(&Assembler::new().assemble("load $0 #4\nitof $0 $0\nsys #0\nhlt").unwrap()).unwrap();
+        let mut trace = Trace::new();
+        while trace.step(&mut vm) {}
+        let names: Vec<(&str, &str)> = trace.events().iter().map(|e| (e.cat, e.name.as_str())).collect();
+        assert_eq!(names, vec![
+            ("instruction", "load"), ("instruction", "itof"), ("syscall", "sqrt"), ("instruction", "hlt"),
+        ]);
+        let json: serde_json::Value = serde_json::from_str(&trace.to_json()).unwrap();
+        assert_eq!(json["traceEvents"][2]["ph"], "X");
+        assert_eq!(json["traceEvents"][2]["args"]["instruction"], "sys #0");
+        assert!(json["traceEvents"][3]["ts"].as_f64().unwrap() >= json["traceEvents"][2]["ts"].as_f64().unwrap());
+    }
+}
@@ -0,0 +1,74 @@
+use thiserror::Error;
+use crate::instruction::{Decode, DecodeError, Extension, Instruction, Operand, INSTRUCTION_SIZE};
+use crate::vm::REGISTER_COUNT;
+
+/// Structural problems found in a program before running it
+#[derive(Debug, PartialEq, Copy, Clone, Error)]
+pub enum VerifyError {
+    #[error("illegal opcode {byte} at offset {offset}")]
+    IllegalOpcode { offset: usize, byte: u8 },
+    #[error("truncated instruction at offset {offset}")]
+    TruncatedInstruction { offset: usize },
+    #[error("instruction at offset {offset} needs the {extension} extension, which this VM was built without")]
+    MissingExtension { offset: usize, extension: Extension },
+    #[error("register {register} at offset {offset} does not exist, the VM has {REGISTER_COUNT} registers")]
+    InvalidRegister { offset: usize, register: u8 },
+    #[error("entry point {entry} is not the offset of an instruction")]
+    InvalidEntry { entry: usize },
+}
+
+/// Checks that the program is a sequence of complete 4-byte instructions with known opcodes,
+/// all of them supported by this build and naming existing integer or float registers
+pub fn verify(program: &[u8]) -> Result<(), VerifyError> {
+    for (i, instruction) in program.chunks(INSTRUCTION_SIZE).enumerate() {
+        let offset = i * INSTRUCTION_SIZE;
+        match Instruction::decode(instruction) {
+            Ok(instruction) => {
+                match instruction.opcode().extension() {
+                    Some(extension) if !extension.is_enabled() => {
+                        return Err(VerifyError::MissingExtension { offset: offset, extension: extension })
+                    }
+                    _ => (),
+                }
+                for operand in instruction.operands() {
+                    match *operand {
+                        Operand::Register(r) if r as usize >= REGISTER_COUNT => {
+                            return Err(VerifyError::InvalidRegister { offset: offset, register: r })
+                        }
+                        _ => (),
+                    }
+                }
+            },
+            Err(DecodeError::IllegalOpcode(byte)) => return Err(VerifyError::IllegalOpcode { offset: offset, byte: byte }),
+            Err(DecodeError::Truncated) => return Err(VerifyError::TruncatedInstruction { offset: offset }),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_valid_program() {
+        assert_eq!(verify(&[1, 0, 1, 244, 2, 0, 1, 2]), Ok(()));
+        assert_eq!(verify(&[]), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_invalid_program() {
+        assert_eq!(verify(&[1, 0, 1, 244, 200, 0, 0, 0]), Err(VerifyError::IllegalOpcode { offset: 4, byte: 200 }));
+        assert_eq!(verify(&[1, 0, 1, 244, 2, 0]), Err(VerifyError::TruncatedInstruction { offset: 4 }));
+        assert_eq!(verify(&[0, 0, 0, 0, 2, 40, 0, 0]), Err(VerifyError::InvalidRegister { offset: 4, register: 40 }));
+        assert_eq!(verify(&[1, 31, 0, 1, 36, 0, 0, 32]), Err(VerifyError::InvalidRegister { offset: 4, register: 32 }));
+        assert_eq!(verify(&[16, 0, 1, 255]), Ok(()));
+    }
+
+    #[test]
+    #[cfg(not(feature = "float"))]
+    fn test_verify_missing_extension() {
+        assert_eq!(verify(&[1, 0, 1, 244, 20, 0, 0, 0]),
+            Err(VerifyError::MissingExtension { offset: 4, extension: Extension::Float }));
+    }
+}
@@ -1,185 +1,1297 @@
-use crate::instruction::Opcode;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::bytecode::{Endianness, Header, HeaderError};
+use crate::heap::{FlatHeap, HeapBackend, SparseHeap, PAGE_SIZE};
+use crate::instruction::{Decode, Instruction, Opcode, Operand, INSTRUCTION_SIZE};
+use crate::profile::{self, Profile};
+use crate::relocation::{self, Placement, Relocation, RelocationError};
+use crate::syscall::{self, Syscall, SyscallPolicy};
+use crate::taint::Taint;
+use crate::verifier::{self, VerifyError};
 
+/// Number of integer registers, and of float registers
+pub const REGISTER_COUNT: usize = 32;
+
+/// Size in bytes of the heap
+pub const HEAP_SIZE: usize = 1000;
+
+/// Longest NUL-terminated string read from the heap by ABORT and PRINTF, in bytes
+pub const MAX_HEAP_STRING: usize = 256;
+
+/// Number of fractional bits of the Q16.16 values handled by QMUL and QDIV
+pub const FIXED_POINT_SHIFT: u32 = 16;
+
+/// Deepest nesting of the trap handlers pushed by PUSHTRAP
+pub const MAX_TRAP_HANDLERS: usize = 32;
+
+/// Errors that stop the execution of a program, or reject a host access to the VM state
+#[derive(Debug, PartialEq, Copy, Clone, Error, Serialize, Deserialize)]
+pub enum VMError {
+    #[error("register {index} does not exist")]
+    InvalidRegister { index: usize },
+    #[error("division by zero at pc {pc}")]
+    DivisionByZero { pc: usize },
+    #[error("NaN operand at pc {pc}")]
+    NaN { pc: usize },
+    #[error("unknown syscall {id} at pc {pc}")]
+    UnknownSyscall { pc: usize, id: u16 },
+    /// Raised by a syscall the `SyscallPolicy` of the VM denies
+    #[error("syscall {id} denied by the policy at pc {pc}")]
+    PermissionDenied { pc: usize, id: u16 },
+    #[error("arithmetic overflow at pc {pc}")]
+    Overflow { pc: usize },
+    #[error("register bank {bank} does not exist at pc {pc}")]
+    InvalidBank { pc: usize, bank: u16 },
+    #[error("assertion failed at pc {pc}: {left} != {right}")]
+    AssertionFailed { pc: usize, left: i32, right: i32 },
+    #[error("unknown counter {counter} at pc {pc}")]
+    UnknownCounter { pc: usize, counter: u16 },
+    /// Raised by ABORT, whose message is kept by the VM, see `VM::abort_message`
+    #[error("program aborted at pc {pc}")]
+    Aborted { pc: usize },
+    #[error("heap address {addr} is out of bounds at pc {pc}")]
+    OutOfBounds { pc: usize, addr: usize },
+    #[error("more than {MAX_TRAP_HANDLERS} trap handlers at pc {pc}")]
+    TooManyTrapHandlers { pc: usize },
+    #[error("no trap handler to pop at pc {pc}")]
+    NoTrapHandler { pc: usize },
+    #[error("relative jump out of the address space at pc {pc}")]
+    InvalidJump { pc: usize },
+}
+
+impl VMError {
+    /// Code of the recoverable traps, which jump to the innermost handler pushed by PUSHTRAP
+    /// instead of stopping the program. `None` for the other errors.
+    pub fn trap_code(&self) -> Option<i32> {
+        match self {
+            VMError::DivisionByZero { .. } => Some(1),
+            VMError::OutOfBounds { .. } => Some(2),
+            VMError::Overflow { .. } => Some(3),
+            _ => None,
+        }
+    }
+}
+
+/// Errors raised when installing or editing the program of a VM
+#[derive(Debug, PartialEq, Copy, Clone, Error)]
+pub enum LoadError {
+    #[error("invalid program: {0}")]
+    Invalid(#[from] VerifyError),
+    #[error("invalid bytecode file: {0}")]
+    Header(#[from] HeaderError),
+    #[error("offset {offset} is past the end of the program ({len} bytes)")]
+    OffsetOutOfBounds { offset: usize, len: usize },
+    #[error("entry point {0} is not the offset of an instruction")]
+    InvalidEntry(usize),
+    #[error("the program ends within an instruction, at offset {0}")]
+    Unaligned(usize),
+    #[error(transparent)]
+    Relocation(#[from] RelocationError),
+}
+
+/// Description of one executed instruction, yielded by `VM::steps`
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct StepInfo {
+    pub pc: usize,
+    pub opcode: Opcode,
+    pub operands: [Operand; 3],
+}
+
+/// Why `VM::run_for` returned
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum StopReason {
+    /// The program halted or reached its end
+    Halted,
+    /// The program stopped on an error
+    Trapped(VMError),
+    /// The program executed YIELD, handing control back to the host
+    Yielded,
+    /// The time budget ran out with the program still running
+    BudgetExpired,
+}
+
+/// Result of `VM::run_for`: why it stopped, and how much of the budget it used
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct RunOutcome {
+    pub reason: StopReason,
+    pub elapsed: Duration,
+}
+
+/// Structured snapshot of the VM returned by `VM::dump_state`, serializable for monitoring
+/// tools and remote clients
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct VmState {
+    pub pc: usize,
+    pub program_len: usize,
+    pub registers: Vec<i32>,
+    pub float_registers: Vec<f64>,
+    pub remainder: u32,
+    pub trap_on_nan: bool,
+    pub endianness: Endianness,
+    /// Index of the active register bank
+    pub register_bank: usize,
+    pub heap: HeapStats,
+    pub last_error: Option<VMError>,
+    /// Message of the ABORT that stopped the program
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub abort_message: Option<String>,
+}
+
+/// Resources consumed by a run
+#[derive(Debug, PartialEq, Copy, Clone, Serialize)]
+pub struct Usage {
+    pub instructions: u64,
+    pub program_bytes: usize,
+    pub heap_bytes_used: usize,
+}
+
+/// Callback of an embedder, given the final state and usage of a program that stopped
+pub type ExitHook = Rc<dyn Fn(&VmState, &Usage)>;
+
+/// Exit hooks registered on a VM, shared with its forks
+#[derive(Clone, Default)]
+struct ExitHooks {
+    halt: Option<ExitHook>,
+    trap: Option<ExitHook>,
+    quota_exceeded: Option<ExitHook>,
+}
+
+/// A handler pushed by PUSHTRAP: the offset a recoverable trap jumps to, and the register
+/// receiving the trap code
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct TrapHandler {
+    pub offset: usize,
+    pub register: u8,
+}
+
+/// Complete execution state of a VM, program included, taken by `VM::snapshot` and put back by
+/// `VM::restore`. Unlike `VmState` it holds the heap contents and every register bank. The
+/// configuration and the profile are not part of it.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct VmSnapshot {
+    pub pc: usize,
+    pub program: Vec<u8>,
+    pub registers: Vec<i32>,
+    pub banks: Vec<Vec<i32>>,
+    pub register_bank: usize,
+    pub float_registers: Vec<f64>,
+    pub remainder: u32,
+    /// Allocated heap pages holding a non-zero byte, with their index
+    pub heap_pages: Vec<(usize, Vec<u8>)>,
+    pub last_error: Option<VMError>,
+    pub abort_message: String,
+    pub stats: ExecutionStats,
+    /// Handlers pushed by PUSHTRAP, innermost last
+    #[serde(default)]
+    pub trap_handlers: Vec<TrapHandler>,
+}
+
+/// Summary of the heap usage, part of `VmState`
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct HeapStats {
+    pub size: usize,
+    /// Number of non-zero bytes
+    pub used: usize,
+}
+
+/// Counters accumulated while executing, see `VM::stats`
+#[derive(Debug, PartialEq, Copy, Clone, Default, Serialize, Deserialize)]
+pub struct ExecutionStats {
+    /// Executed instructions, including the one that halted or failed
+    pub instructions: u64,
+    /// Executed jump instructions, taken or not
+    pub branches: u64,
+    /// Executed SYS instructions
+    pub syscalls: u64,
+    /// End of the highest heap word read or written
+    pub heap_touched: usize,
+    pub wall_time: Duration,
+}
+
+impl ExecutionStats {
+    pub fn instructions_per_second(&self) -> f64 {
+        match self.wall_time.as_secs_f64() {
+            secs if secs > 0.0 => self.instructions as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Counters a guest program reads with `rdcnt #counter $dst`, which includes the RDCNT itself
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Counter {
+    Instructions,
+    Branches,
+    Syscalls,
+}
+
+impl Counter {
+    pub fn from_id(id: u16) -> Option<Counter> {
+        match id {
+            0 => Some(Counter::Instructions),
+            1 => Some(Counter::Branches),
+            2 => Some(Counter::Syscalls),
+            _ => None
+        }
+    }
+
+    /// Whether executing `opcode` increments the counter
+    pub fn counts(self, opcode: Opcode) -> bool {
+        match self {
+            Counter::Instructions => true,
+            Counter::Branches => matches!(opcode, Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JEQ),
+            Counter::Syscalls => opcode == Opcode::SYS,
+        }
+    }
+
+    pub fn read(self, stats: &ExecutionStats) -> u64 {
+        match self {
+            Counter::Instructions => stats.instructions,
+            Counter::Branches => stats.branches,
+            Counter::Syscalls => stats.syscalls,
+        }
+    }
+}
+
+impl fmt::Display for ExecutionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} instructions in {:?} ({:.0} instructions/sec), {} branches, {} heap bytes touched",
+            self.instructions, self.wall_time, self.instructions_per_second(), self.branches, self.heap_touched)
+    }
+}
+
+/// Iterator executing a program one instruction at a time, see `VM::steps`
+pub struct Steps<'a> {
+    vm: &'a mut VM,
+    done: bool,
+}
+
+impl<'a> Iterator for Steps<'a> {
+    type Item = Result<StepInfo, VMError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.vm.pc >= self.vm.program.len() {
+            return None;
+        }
+        let pc = self.vm.pc;
+        let instruction = Instruction::decode(&self.vm.program[pc..])
+            .unwrap_or_else(|_| Instruction::new(Opcode::from_byte(self.vm.program[pc])));
+        let info = StepInfo { pc: pc, opcode: instruction.opcode(), operands: *instruction.operands() };
+        self.done = !self.vm.run_once();
+        match self.vm.error {
+            Some(e) => Some(Err(e)),
+            None => Some(Ok(info)),
+        }
+    }
+}
+
+/// Configures a VM before creating it
+pub struct VMBuilder {
+    register_banks: usize,
+    heap_size: usize,
+    sparse_heap: bool,
+    trap_on_nan: bool,
+    endianness: Endianness,
+    trusted: bool,
+    syscall_policy: SyscallPolicy,
+    zero_register: bool,
+}
+
+impl Default for VMBuilder {
+    fn default() -> VMBuilder {
+        VMBuilder::new()
+    }
+}
+
+impl VMBuilder {
+    pub fn new() -> VMBuilder {
+        VMBuilder {
+            register_banks: 1,
+            heap_size: HEAP_SIZE,
+            sparse_heap: false,
+            trap_on_nan: false,
+            endianness: Endianness::Big,
+            trusted: false,
+            syscall_policy: SyscallPolicy::default(),
+            zero_register: false,
+        }
+    }
+
+    /// Number of banks of integer registers BANKSW can switch between, at least 1
+    pub fn register_banks(mut self, count: usize) -> VMBuilder {
+        self.register_banks = count.max(1);
+        self
+    }
+
+    /// Size of the heap in bytes, `HEAP_SIZE` by default
+    pub fn heap_size(mut self, size: usize) -> VMBuilder {
+        self.heap_size = size;
+        self
+    }
+
+    /// Allocates heap pages on first write instead of up front, for large and mostly empty heaps
+    pub fn sparse_heap(mut self, sparse: bool) -> VMBuilder {
+        self.sparse_heap = sparse;
+        self
+    }
+
+    /// See `VM::set_trap_on_nan`
+    pub fn trap_on_nan(mut self, trap: bool) -> VMBuilder {
+        self.trap_on_nan = trap;
+        self
+    }
+
+    /// See `VM::set_endianness`
+    pub fn endianness(mut self, endianness: Endianness) -> VMBuilder {
+        self.endianness = endianness;
+        self
+    }
+
+    /// See `VM::set_trusted`
+    pub fn trusted(mut self, trusted: bool) -> VMBuilder {
+        self.trusted = trusted;
+        self
+    }
+
+    /// See `VM::set_syscall_policy`
+    pub fn syscall_policy(mut self, policy: SyscallPolicy) -> VMBuilder {
+        self.syscall_policy = policy;
+        self
+    }
+
+    /// See `VM::set_zero_register`
+    pub fn zero_register(mut self, zero: bool) -> VMBuilder {
+        self.zero_register = zero;
+        self
+    }
+
+    pub fn build(self) -> VM {
+        let mut vm = VM::new();
+        vm.banks = vec![[0; REGISTER_COUNT]; self.register_banks];
+        vm.heap = if self.sparse_heap {
+            Box::new(SparseHeap::new(self.heap_size))
+        } else {
+            Box::new(FlatHeap::new(self.heap_size))
+        };
+        vm.trap_on_nan = self.trap_on_nan;
+        vm.endianness = self.endianness;
+        vm.trusted = self.trusted;
+        vm.syscall_policy = self.syscall_policy;
+        vm.zero_register = self.zero_register;
+        vm
+    }
+}
+
+#[derive(Clone)]
 pub struct VM {
-    pub registers: [i32; 32],
-    heap: [u8; 1000],
+    /// Identifies the VM in the records of LOG, see `set_id`
+    id: usize,
+    /// The active register bank
+    registers: [i32; REGISTER_COUNT],
+    /// Saved contents of every register bank, the active one being stale until switched out
+    banks: Vec<[i32; REGISTER_COUNT]>,
+    bank: usize,
+    pub float_registers: [f64; REGISTER_COUNT],
+    heap: Box<dyn HeapBackend>,
     pc: usize,
-    pub program: Vec<u8>,
+    program: Vec<u8>,
+    /// Offset execution starts from, see `set_entry`
+    entry: usize,
+    /// Offset the last program was loaded at, see `link_program`
+    base: usize,
     remainder: u32,
+    /// Handlers pushed by PUSHTRAP, innermost last
+    trap_handlers: Vec<TrapHandler>,
+    error: Option<VMError>,
+    abort_message: String,
+    /// Text written by the guest and not yet taken by the host
+    output: String,
+    /// Set by YIELD until the scheduler notices it
+    yielded: bool,
+    trap_on_nan: bool,
+    endianness: Endianness,
+    /// Opt-in unchecked fetching, see `set_trusted`
+    trusted: bool,
+    syscall_policy: SyscallPolicy,
+    /// Keeps `$0` at zero, see `set_zero_register`
+    zero_register: bool,
+    /// Whether the program is the one approved by the verifier, unedited since
+    verified: bool,
+    /// Set for the instruction being executed when its bytes can be fetched unchecked
+    fetch_unchecked: bool,
+    exit_hooks: ExitHooks,
+    stats: ExecutionStats,
+    profile: Option<Profile>,
+    taint: Option<Taint>,
+}
+
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
-            registers: [0; 32],
-            heap: [0; 1000],
+            id: 0,
+            registers: [0; REGISTER_COUNT],
+            banks: vec![[0; REGISTER_COUNT]],
+            bank: 0,
+            float_registers: [0.0; REGISTER_COUNT],
+            heap: Box::new(FlatHeap::new(HEAP_SIZE)),
             pc: 0,
             program: vec![],
+            entry: 0,
+            base: 0,
             remainder: 0,
+            trap_handlers: vec![],
+            error: None,
+            abort_message: String::new(),
+            output: String::new(),
+            yielded: false,
+            trap_on_nan: false,
+            endianness: Endianness::Big,
+            trusted: false,
+            syscall_policy: SyscallPolicy::default(),
+            zero_register: false,
+            verified: true,
+            fetch_unchecked: false,
+            exit_hooks: ExitHooks::default(),
+            stats: ExecutionStats::default(),
+            profile: None,
+            taint: None,
         }
     }
 
+    /// NaN policy of the float opcodes: by default a comparison involving NaN is false and
+    /// FTOI converts NaN to 0. When `trap` is set, both stop the execution with `VMError::NaN` instead.
+    pub fn set_trap_on_nan(&mut self, trap: bool) {
+        self.trap_on_nan = trap;
+    }
+
+    /// Trusted program mode: the bytes of an instruction are fetched without bounds checks, and
+    /// the dispatch is hinted for that case. Only programs approved by the verifier since their
+    /// last edit qualify, and only from an instruction boundary; anything else, such as a jump
+    /// into the middle of an instruction or a program grown with `add_program_byte`, still takes
+    /// the checked path. Nothing else is skipped: the values an instruction computes are checked as usual.
+    pub fn set_trusted(&mut self, trusted: bool) {
+        self.trusted = trusted;
+    }
+
+    /// Syscalls the program may make, all of them by default. The policy is checked on every
+    /// SYS, so it can be tightened while a program runs.
+    pub fn set_syscall_policy(&mut self, policy: SyscallPolicy) {
+        self.syscall_policy = policy;
+    }
+
+    /// Hard-wires `$0`, named `$zero` in assembly, to zero: what an instruction writes there is
+    /// discarded once it completes. Off by default, `$0` being a regular register then.
+    pub fn set_zero_register(&mut self, zero: bool) {
+        self.zero_register = zero;
+        if zero {
+            self.registers[0] = 0;
+        }
+    }
+
+    /// Id attached to the records of LOG, see `set_id`
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Sets the id attached to the records of LOG, 0 by default, to tell apart VMs sharing a host
+    pub fn set_id(&mut self, id: usize) {
+        self.id = id;
+    }
+
+    /// Index of the active register bank
+    pub fn register_bank(&self) -> usize {
+        self.bank
+    }
+
+    /// Returns the value of an integer register
+    pub fn register(&self, index: usize) -> Result<i32, VMError> {
+        self.registers.get(index).copied().ok_or(VMError::InvalidRegister { index: index })
+    }
+
+    /// Sets the value of an integer register
+    pub fn set_register(&mut self, index: usize, value: i32) -> Result<(), VMError> {
+        match self.registers.get_mut(index) {
+            Some(r) => {
+                *r = value;
+                Ok(())
+            },
+            None => Err(VMError::InvalidRegister { index: index })
+        }
+    }
+
+    /// Iterates over the integer registers as `(index, value)` pairs
+    pub fn registers(&self) -> impl Iterator<Item = (usize, i32)> + '_ {
+        self.registers.iter().copied().enumerate()
+    }
+
+    /// Returns the offset of the next instruction to execute
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Returns a copy of the whole heap. Expensive: the address space is copied in full, even
+    /// with a sparse heap, so prefer `heap_word` or `heap_rows` to read a part of it.
+    pub fn heap(&self) -> Vec<u8> {
+        self.heap.to_vec()
+    }
+
+    /// The 16-byte heap rows holding a non-zero byte, with their address, in address order. Only
+    /// the allocated pages are read, so it stays cheap for a large sparse heap.
+    pub fn heap_rows(&self) -> impl Iterator<Item = (usize, &[u8])> + '_ {
+        self.heap.pages().into_iter().flat_map(|(index, page)| {
+            page.chunks(16).enumerate()
+                .filter(|(_, row)| row.iter().any(|b| *b != 0))
+                .map(move |(i, row)| (index * PAGE_SIZE + i * 16, row))
+        })
+    }
+
+    /// The heap word at `addr`, in the configured byte order, None if it is out of bounds
+    pub fn heap_word(&self, addr: usize) -> Option<i32> {
+        self.load_word_from_heap(addr).ok().map(|w| w as i32)
+    }
+
+    /// A copy of the heap sharing its pages until the VM writes to them, cheap enough to take
+    /// before every step. See `heap::diff` to compare it with a later one.
+    pub fn heap_snapshot(&self) -> Box<dyn HeapBackend> {
+        self.heap.clone()
+    }
+
+    /// Number of heap pages still shared with `other`, typically a fork of this VM
+    pub fn shared_heap_pages(&self, other: &VM) -> usize {
+        self.heap.shared_pages(other.heap.as_ref())
+    }
+
+    /// Returns the counters accumulated since the program was loaded
+    pub fn stats(&self) -> &ExecutionStats {
+        &self.stats
+    }
+
+    /// Returns the error that stopped the last execution, if any
+    pub fn last_error(&self) -> Option<VMError> {
+        self.error
+    }
+
+    /// Takes the text the guest wrote with PRINTF since the last call
+    pub fn take_output(&mut self) -> String {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Whether the last instructions included a YIELD, clearing the flag
+    pub fn take_yield(&mut self) -> bool {
+        std::mem::take(&mut self.yielded)
+    }
+
+    /// The message of the ABORT that stopped the last execution, if it did
+    pub fn abort_message(&self) -> Option<&str> {
+        match self.error {
+            Some(VMError::Aborted { .. }) => Some(&self.abort_message),
+            _ => None,
+        }
+    }
+
+    /// Takes a structured snapshot of the registers, pc, flags, heap usage and last error
+    pub fn dump_state(&self) -> VmState {
+        VmState {
+            pc: self.pc,
+            program_len: self.program.len(),
+            registers: self.registers.to_vec(),
+            float_registers: self.float_registers.to_vec(),
+            remainder: self.remainder,
+            trap_on_nan: self.trap_on_nan,
+            endianness: self.endianness,
+            register_bank: self.bank,
+            heap: HeapStats {
+                size: self.heap.len(),
+                used: self.heap.pages().iter().map(|(_, page)| page.iter().filter(|b| **b != 0).count()).sum(),
+            },
+            last_error: self.error,
+            abort_message: self.abort_message().map(str::to_string),
+        }
+    }
+
+    /// Resources consumed since the program was loaded or the VM reset
+    pub fn usage(&self) -> Usage {
+        let state = self.dump_state();
+        Usage {
+            instructions: self.stats.instructions,
+            program_bytes: state.program_len,
+            heap_bytes_used: state.heap.used,
+        }
+    }
+
+    /// Calls `hook` every time the program halts, with HLT or by running past its last
+    /// instruction
+    pub fn on_halt(&mut self, hook: impl Fn(&VmState, &Usage) + 'static) {
+        self.exit_hooks.halt = Some(Rc::new(hook));
+    }
+
+    /// Calls `hook` every time the program stops on an error, ABORT included
+    pub fn on_trap(&mut self, hook: impl Fn(&VmState, &Usage) + 'static) {
+        self.exit_hooks.trap = Some(Rc::new(hook));
+    }
+
+    /// Calls `hook` when the host stops the program at its instruction quota, see `quota_exceeded`
+    pub fn on_quota_exceeded(&mut self, hook: impl Fn(&VmState, &Usage) + 'static) {
+        self.exit_hooks.quota_exceeded = Some(Rc::new(hook));
+    }
+
+    /// Runs the `on_quota_exceeded` hook. Hosts call it when they give up on a program that
+    /// used up its instruction quota without stopping.
+    pub fn quota_exceeded(&self) {
+        if let Some(hook) = &self.exit_hooks.quota_exceeded {
+            hook(&self.dump_state(), &self.usage());
+        }
+    }
+
+    /// Serializes the VM state into a stable, line-oriented text meant for golden-file
+    /// comparisons. Registers equal to zero and all-zero heap rows are omitted.
+    pub fn dump_state_text(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("pc: {}\n", self.pc));
+        out.push_str(&format!("program_len: {}\n", self.program.len()));
+        out.push_str(&format!("remainder: {}\n", self.remainder));
+        match (self.error, self.abort_message()) {
+            (Some(e), Some(message)) => out.push_str(&format!("error: {}: {}\n", e, message)),
+            (Some(e), None) => out.push_str(&format!("error: {}\n", e)),
+            (None, _) => out.push_str("error: none\n"),
+        }
+        out.push_str("registers:\n");
+        for (i, value) in self.registers.iter().enumerate().filter(|(_, v)| **v != 0) {
+            out.push_str(&format!("  ${} = {}\n", i, value));
+        }
+        out.push_str("float_registers:\n");
+        for (i, value) in self.float_registers.iter().enumerate().filter(|(_, v)| v.to_bits() != 0) {
+            out.push_str(&format!("  $f{} = {:?}\n", i, value));
+        }
+        out.push_str("heap:\n");
+        for (addr, row) in self.heap_rows() {
+            let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+            out.push_str(&format!("  {:04x}: {}\n", addr, hex.join(" ")));
+        }
+        out
+    }
+
+    /// Verifies and installs a new program, replacing the current one. Execution restarts
+    /// from its first instruction.
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), LoadError> {
+        verifier::verify(program)?;
+        self.program = program.to_vec();
+        self.verified = true;
+        self.entry = 0;
+        self.base = 0;
+        self.pc = 0;
+        self.trap_handlers.clear();
+        self.error = None;
+        self.stats = ExecutionStats::default();
+        Ok(())
+    }
+
+    /// Appends a program after the current one, which keeps running as before: the immediates
+    /// `relocations` point at are adjusted for the program to run from there, with its data
+    /// section at `data_base` in the heap. Returns the offset it was loaded at, from then on
+    /// `base`.
+    pub fn link_program(&mut self, program: &[u8], relocations: &[Relocation], data_base: usize) -> Result<usize, LoadError> {
+        let base = self.program.len();
+        if !base.is_multiple_of(INSTRUCTION_SIZE) {
+            return Err(LoadError::Unaligned(base));
+        }
+        let mut program = program.to_vec();
+        relocation::relocate(&mut program, relocations, Placement { code_base: base, data_base: data_base })?;
+        verifier::verify(&program)?;
+        self.program.extend(program);
+        self.base = base;
+        Ok(base)
+    }
+
+    /// Offset of the first instruction of the last program installed, 0 unless it was linked
+    /// after another one
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Makes the program start from the instruction at `entry` instead of its first one, now and
+    /// on every reset
+    pub fn set_entry(&mut self, entry: usize) -> Result<(), LoadError> {
+        if !entry.is_multiple_of(INSTRUCTION_SIZE) || (entry != 0 && entry >= self.program.len()) {
+            return Err(LoadError::InvalidEntry(entry));
+        }
+        self.entry = entry;
+        self.pc = entry;
+        Ok(())
+    }
+
+    /// Clones the complete execution state, program included, into an independent VM, to explore
+    /// what happens from this point without affecting the original. The heap pages are shared
+    /// until either VM writes to them.
+    pub fn fork(&self) -> VM {
+        self.clone()
+    }
+
+    /// Captures the complete execution state, see `VmSnapshot`
+    pub fn snapshot(&self) -> VmSnapshot {
+        let mut banks: Vec<Vec<i32>> = self.banks.iter().map(|bank| bank.to_vec()).collect();
+        banks[self.bank] = self.registers.to_vec();
+        VmSnapshot {
+            pc: self.pc,
+            program: self.program.clone(),
+            registers: self.registers.to_vec(),
+            banks: banks,
+            register_bank: self.bank,
+            float_registers: self.float_registers.to_vec(),
+            remainder: self.remainder,
+            heap_pages: self.heap.pages().into_iter()
+                .filter(|(_, page)| page.iter().any(|b| *b != 0))
+                .map(|(index, page)| (index, page.to_vec()))
+                .collect(),
+            last_error: self.error,
+            abort_message: self.abort_message.clone(),
+            stats: self.stats,
+            trap_handlers: self.trap_handlers.clone(),
+        }
+    }
+
+    /// Puts the VM back in the state captured by `snapshot`, keeping the current configuration.
+    /// Heap pages past the end of the current heap are dropped.
+    pub fn restore(&mut self, snapshot: &VmSnapshot) {
+        let bank_from = |source: &Vec<i32>| {
+            let mut bank = [0; REGISTER_COUNT];
+            for (r, value) in bank.iter_mut().zip(source) {
+                *r = *value;
+            }
+            bank
+        };
+        self.banks = snapshot.banks.iter().map(bank_from).collect();
+        if self.banks.is_empty() {
+            self.banks.push([0; REGISTER_COUNT]);
+        }
+        self.bank = snapshot.register_bank.min(self.banks.len() - 1);
+        self.registers = bank_from(&snapshot.registers);
+        self.float_registers = [0.0; REGISTER_COUNT];
+        for (r, value) in self.float_registers.iter_mut().zip(&snapshot.float_registers) {
+            *r = *value;
+        }
+        self.heap = self.heap.cleared();
+        for (index, page) in &snapshot.heap_pages {
+            let _ = self.heap.write(index * PAGE_SIZE, page);
+        }
+        self.pc = snapshot.pc;
+        self.program = snapshot.program.clone();
+        self.verified = verifier::verify(&self.program).is_ok();
+        self.remainder = snapshot.remainder;
+        self.trap_handlers = snapshot.trap_handlers.clone();
+        self.error = snapshot.last_error;
+        self.abort_message = snapshot.abort_message.clone();
+        self.stats = snapshot.stats;
+    }
+
+    /// Zeroes the registers of every bank and the heap, moves the pc back to the entry point,
+    /// clears the last error and the statistics, but keeps the program and the configuration
+    pub fn reset(&mut self) {
+        self.registers = [0; REGISTER_COUNT];
+        for bank in self.banks.iter_mut() {
+            *bank = [0; REGISTER_COUNT];
+        }
+        self.bank = 0;
+        self.float_registers = [0.0; REGISTER_COUNT];
+        self.heap = self.heap.cleared();
+        self.pc = self.entry;
+        self.remainder = 0;
+        self.trap_handlers.clear();
+        self.error = None;
+        self.output.clear();
+        self.yielded = false;
+        self.stats = ExecutionStats::default();
+    }
+
+    /// Same as `reset`, also removing the program
+    pub fn hard_reset(&mut self) {
+        self.entry = 0;
+        self.base = 0;
+        self.reset();
+        self.program.clear();
+        self.verified = true;
+    }
+
+    /// Installs a program from a bytecode file, honoring the byte order declared by its header:
+    /// immediates are converted to the native big-endian encoding of `program()`, while heap words
+    /// keep being read and written in the declared order. Execution starts from the declared entry
+    /// point. Programs declaring extensions this build lacks are rejected.
+    pub fn load_bytecode(&mut self, bytes: &[u8]) -> Result<Header, LoadError> {
+        let (header, program) = Header::read(bytes)?;
+        header.check_extensions()?;
+        self.load_program(&header.decode_program(program)?)?;
+        self.set_entry(header.entry)?;
+        self.endianness = header.endianness;
+        Ok(header)
+    }
+
+    /// Byte order of the heap words accessed by LW and SW
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    pub fn set_endianness(&mut self, endianness: Endianness) {
+        self.endianness = endianness;
+    }
+
+    /// Returns the loaded program
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    /// Overwrites the program from `offset`, growing it if needed. The edit is rejected, leaving
+    /// the program as it was, if the result does not verify.
+    pub fn patch_program(&mut self, offset: usize, bytes: &[u8]) -> Result<(), LoadError> {
+        if offset > self.program.len() {
+            return Err(LoadError::OffsetOutOfBounds { offset: offset, len: self.program.len() });
+        }
+        let mut program = self.program.clone();
+        let end = offset + bytes.len();
+        if end > program.len() {
+            program.resize(end, 0);
+        }
+        program[offset..end].copy_from_slice(bytes);
+        verifier::verify(&program)?;
+        self.program = program;
+        self.verified = true;
+        Ok(())
+    }
+
+    /// Drops every program byte from `offset` onwards, unless what is left does not verify
+    pub fn truncate_program(&mut self, offset: usize) -> Result<(), LoadError> {
+        if offset > self.program.len() {
+            return Err(LoadError::OffsetOutOfBounds { offset: offset, len: self.program.len() });
+        }
+        verifier::verify(&self.program[..offset])?;
+        self.program.truncate(offset);
+        self.verified = true;
+        Ok(())
+    }
+
     pub fn add_program_byte(&mut self, byte: u8) {
         self.program.push(byte);
+        self.verified = false;
+    }
+
+    /// The program byte at `at`, which belongs to the instruction being executed
+    #[inline(always)]
+    fn program_byte(&self, at: usize) -> u8 {
+        if self.fetch_unchecked {
+            // SAFETY: `fetch_unchecked` is only set for an instruction starting on a multiple of
+            // INSTRUCTION_SIZE below the program length, in a verified program. The verifier
+            // rejects truncated instructions so the length is a multiple of INSTRUCTION_SIZE,
+            // and no opcode reads past the end of its own instruction. This only covers the
+            // fetch: jump targets and heap addresses come from registers, which the verifier
+            // knows nothing about, so the handlers check them in both modes.
+            unsafe { *self.program.get_unchecked(at) }
+        } else {
+            self.program[at]
+        }
     }
 
     fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
+        let opcode = Opcode::from_byte(self.program_byte(self.pc));
         self.pc += 1;
-        return opcode;
+        opcode
     }
 
     fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
+        let result = self.program_byte(self.pc);
         self.pc += 1;
-        return result;
+        result
     }
 
     fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
+        let result = ((self.program_byte(self.pc) as u16) << 8) | self.program_byte(self.pc + 1) as u16;
         self.pc += 2;
-        return result;
+        result
     }
 
     fn load_word_from_heap(&self, addr: usize) -> Result<u32, String> {
-        match self.heap.get(addr..addr+4) {
-            Some(v) => {
-                let result: u32 = ((v[0] as u32) << 3*8) | ((v[1] as u32) << 2*8) | ((v[2] as u32) << 8) | v[3] as u32;
-                Ok(result)
-            }
+        let mut word = [0; 4];
+        match self.heap.read(addr, &mut word) {
+            Some(()) => Ok(self.endianness.word_from_bytes(word)),
             None => Err(format!("Error, memory addr ({}) is out of bounds!", addr))
         }
     }
 
-    fn store_word_into_heap(&mut self, value: i32, addr: usize) {
-        let mut bytes: Vec<u8> = vec!();
-        bytes.push((value >> 24) as u8);
-        bytes.push((value >> 16) as u8);
-        bytes.push((value >> 8) as u8);
-        bytes.push(value as u8);
-        for i in 0..4 {
-            self.heap[addr + i] = bytes[i];
+    /// The NUL-terminated string at `addr`, cut at the end of the heap or after
+    /// `MAX_HEAP_STRING` bytes. Invalid UTF-8 is replaced rather than rejected.
+    fn heap_string(&self, addr: usize) -> String {
+        let mut bytes = vec![];
+        let mut byte = [0];
+        while bytes.len() < MAX_HEAP_STRING && self.heap.read(addr.wrapping_add(bytes.len()), &mut byte).is_some() && byte[0] != 0 {
+            bytes.push(byte[0]);
         }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn store_word_into_heap(&mut self, value: i32, addr: usize) -> Option<()> {
+        let bytes = self.endianness.word_to_bytes(value as u32);
+        self.heap.write(addr, &bytes)
+    }
+
+    /// Starts recording a fresh per-instruction profile, replacing the previous one
+    pub fn start_profiling(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    /// Stops profiling and discards the profile
+    pub fn stop_profiling(&mut self) {
+        self.profile = None;
+    }
+
+    /// The profile recorded since `start_profiling`, None if profiling is off
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Starts tracking which values derive from guest input, from a clean state. The VM has no
+    /// input syscall yet, so the host marks the registers it fills with input with
+    /// `taint_register`. The tracking reports every tainted value used as a jump target or a
+    /// heap address, see `Taint`.
+    pub fn start_taint_tracking(&mut self) {
+        self.taint = Some(Taint::new());
+    }
+
+    /// Stops taint tracking and discards its state and reports
+    pub fn stop_taint_tracking(&mut self) {
+        self.taint = None;
+    }
+
+    /// The taint state since `start_taint_tracking`, None if taint tracking is off
+    pub fn taint(&self) -> Option<&Taint> {
+        self.taint.as_ref()
+    }
+
+    /// Marks an integer register as holding guest input, when taint tracking is on
+    pub fn taint_register(&mut self, index: usize) -> Result<(), VMError> {
+        if index >= REGISTER_COUNT {
+            return Err(VMError::InvalidRegister { index: index });
+        }
+        if let Some(taint) = self.taint.as_mut() {
+            taint.taint_register(index);
+        }
+        Ok(())
     }
 
     pub fn run(&mut self) {
-        let mut is_done = false;
-        while !is_done {
-            is_done = self.execute_instruction();
+        self.error = None;
+        let start = Instant::now();
+        while self.execute_next() {}
+        self.stats.wall_time += start.elapsed();
+    }
+
+    /// Executes the program until `budget` expires, it yields, stops on an error or halts,
+    /// without blocking the caller for longer than about one instruction past the budget. At
+    /// least one instruction is executed, so a loop calling it always makes progress.
+    pub fn run_for(&mut self, budget: Duration) -> RunOutcome {
+        self.error = None;
+        let start = Instant::now();
+        let reason = loop {
+            if !self.execute_next() {
+                break self.error.map_or(StopReason::Halted, StopReason::Trapped);
+            }
+            if self.take_yield() {
+                break StopReason::Yielded;
+            }
+            if start.elapsed() >= budget {
+                break StopReason::BudgetExpired;
+            }
+        };
+        let elapsed = start.elapsed();
+        self.stats.wall_time += elapsed;
+        RunOutcome { reason: reason, elapsed: elapsed }
+    }
+
+    /// Returns an iterator that executes the program one instruction per item, until it
+    /// halts, reaches its end or stops on an error (yielded as the last item)
+    pub fn steps(&mut self) -> Steps<'_> {
+        self.error = None;
+        Steps { vm: self, done: false }
+    }
+
+    /// Executes one instruction. Meant to allow for more controlled execution of the VM.
+    /// Returns false once the program has halted, stopped on an error or reached its end.
+    pub fn run_once(&mut self) -> bool {
+        self.error = None;
+        let start = Instant::now();
+        let running = self.execute_next();
+        self.stats.wall_time += start.elapsed();
+        running
+    }
+
+    /// Executes one instruction, then runs the exit hook matching the way it stopped the
+    /// program, if it did
+    fn execute_next(&mut self) -> bool {
+        if self.pc >= self.program.len() {
+            return false;
+        }
+        if let Some(taint) = self.taint.as_mut() {
+            if let Ok(instruction) = Instruction::decode(&self.program[self.pc..]) {
+                taint.step(self.pc, &instruction, &self.registers);
+            }
+        }
+        let running = self.execute_profiled() || self.catch_trap();
+        if self.zero_register {
+            self.registers[0] = 0;
+        }
+        if running && self.pc < self.program.len() {
+            return running;
+        }
+        let hook = match self.error {
+            Some(_) => &self.exit_hooks.trap,
+            None => &self.exit_hooks.halt,
+        };
+        if let Some(hook) = hook {
+            hook(&self.dump_state(), &self.usage());
         }
+        running
     }
 
-    /// Executes one instruction. Meant to allow for more controlled execution of the VM
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    /// Resumes a program stopped on a recoverable trap at the innermost trap handler, popping it
+    /// and writing the trap code into its register. Returns false when the program stopped
+    /// otherwise or has no handler left.
+    fn catch_trap(&mut self) -> bool {
+        let code = match self.error.and_then(|error| error.trap_code()) {
+            Some(code) if !self.trap_handlers.is_empty() => code,
+            _ => return false,
+        };
+        let handler = self.trap_handlers.pop().expect("a handler was found above");
+        // PUSHTRAP checks the register, but a restored snapshot may hold any handler
+        match self.registers.get_mut(handler.register as usize) {
+            Some(register) => *register = code,
+            None => return false,
+        }
+        self.pc = handler.offset;
+        self.error = None;
+        true
+    }
+
+    /// Executes one instruction, timing it into the profile when profiling is on. A sample of
+    /// the instructions also gets their latency measured with the cheaper `profile::ticks`.
+    fn execute_profiled(&mut self) -> bool {
+        if self.profile.is_none() || self.pc >= self.program.len() {
+            return self.execute_instruction();
+        }
+        let (pc, opcode) = (self.pc, Opcode::from_byte(self.program[self.pc]));
+        let sampled = self.profile.as_mut().is_some_and(Profile::sample_next);
+        let start_ticks = if sampled { Some(profile::ticks()) } else { None };
+        let start = Instant::now();
+        let running = self.execute_instruction();
+        let elapsed = start.elapsed();
+        let ticks = start_ticks.map(|start| profile::ticks().wrapping_sub(start));
+        if let Some(profile) = self.profile.as_mut() {
+            profile.record(pc, opcode, elapsed);
+            if let Some(ticks) = ticks {
+                profile.record_latency(opcode, ticks);
+            }
+        }
+        running
     }
 
     fn execute_instruction(&mut self) -> bool {
         if self.pc >= self.program.len() {
             return false;
         }
-        match self.decode_opcode() {
+        self.fetch_unchecked = false;
+        if self.trusted {
+            self.fetch_unchecked = self.verified && self.pc.is_multiple_of(INSTRUCTION_SIZE);
+            if !self.fetch_unchecked {
+                std::hint::cold_path();
+            }
+        }
+        let instruction_pc = self.pc;
+        let opcode = self.decode_opcode();
+        self.stats.instructions += 1;
+        if Counter::Branches.counts(opcode) {
+            self.stats.branches += 1;
+        }
+        if Counter::Syscalls.counts(opcode) {
+            self.stats.syscalls += 1;
+        }
+        match self.execute_opcode(opcode, instruction_pc) {
+            Ok(running) => running,
+            Err(error) => {
+                self.error = Some(error);
+                false
+            }
+        }
+    }
+
+    /// Value of the integer register named by the next operand byte
+    fn next_register(&mut self) -> Result<i32, VMError> {
+        let index = self.next_8_bits() as usize;
+        self.register(index)
+    }
+
+    /// Index of the register, integer or float, named by the next operand byte
+    fn next_register_index(&mut self) -> Result<usize, VMError> {
+        let index = self.next_8_bits() as usize;
+        match index < REGISTER_COUNT {
+            true => Ok(index),
+            false => Err(VMError::InvalidRegister { index: index }),
+        }
+    }
+
+    /// Executes the operands of `opcode`, the instruction at `instruction_pc`. Returns whether the
+    /// program keeps running, or the error that stops it. Register operands are checked, as an
+    /// unverified program may name registers that do not exist.
+    fn execute_opcode(&mut self, opcode: Opcode, instruction_pc: usize) -> Result<bool, VMError> {
+        match opcode {
             Opcode::LOAD => {
                 let register = self.next_8_bits() as usize;
                 let number = self.next_16_bits() as u32;
-                self.registers[register] = number as i32;
+                self.set_register(register, number as i32)?;
             }
             Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
+                let result = self.next_8_bits() as usize;
+                self.set_register(result, register1 + register2)?;
             }
             Opcode::SUB => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
+                let result = self.next_8_bits() as usize;
+                self.set_register(result, register1 - register2)?;
             }
             Opcode::MUL => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
+                let result = self.next_8_bits() as usize;
+                self.set_register(result, register1 * register2)?;
             }
             Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
-                self.remainder = (register1 % register2) as u32;
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
+                let result = self.next_8_bits() as usize;
+                if register2 == 0 {
+                    return Err(VMError::DivisionByZero { pc: instruction_pc });
+                }
+                self.set_register(result, register1.wrapping_div(register2))?;
+                self.remainder = register1.wrapping_rem(register2) as u32;
+            }
+            Opcode::ADDO | Opcode::SUBO | Opcode::MULO => { // addo $1 $2 $3
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
+                let result = self.next_8_bits() as usize;
+                let value = match opcode {
+                    Opcode::ADDO => register1.checked_add(register2),
+                    Opcode::SUBO => register1.checked_sub(register2),
+                    _ => register1.checked_mul(register2),
+                };
+                match value {
+                    Some(v) => self.set_register(result, v)?,
+                    None => return Err(VMError::Overflow { pc: instruction_pc }),
+                }
+            }
+            Opcode::ADDS => { // adds $1 $2 $3, clamped to i32::MIN..=i32::MAX
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
+                let result = self.next_8_bits() as usize;
+                self.set_register(result, register1.saturating_add(register2))?;
+            }
+            Opcode::SUBS => { // subs $1 $2 $3, clamped to i32::MIN..=i32::MAX
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
+                let result = self.next_8_bits() as usize;
+                self.set_register(result, register1.saturating_sub(register2))?;
+            }
+            Opcode::MAC => { // mac $acc $1 $2, $acc += $1 * $2 (wrapping)
+                let acc = self.next_8_bits() as usize;
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
+                let value = self.register(acc)?.wrapping_add(register1.wrapping_mul(register2));
+                self.set_register(acc, value)?;
             }
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
+                let target = self.next_register()?;
                 self.pc = target as usize;
             }
-            Opcode::JMPF => {
-                let value = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc += value;
-            }
-            Opcode::JMPB => {
-                let value = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc -= value;
+            Opcode::JMPF | Opcode::JMPB => {
+                let value = self.next_register()? as usize;
+                let target = match opcode {
+                    Opcode::JMPF => self.pc.checked_add(value),
+                    _ => self.pc.checked_sub(value),
+                };
+                match target {
+                    Some(target) => self.pc = target,
+                    None => return Err(VMError::InvalidJump { pc: instruction_pc }),
+                }
             }
             Opcode::EQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
                 let result = self.next_8_bits() as usize;
                 if register1 == register2 {
-                    self.registers[result] = 1;
+                    self.set_register(result, 1)?;
                 } else {
-                    self.registers[result] = 0;
+                    self.set_register(result, 0)?;
                 }
             }
             Opcode::NEQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
                 let result = self.next_8_bits() as usize;
                 if register1 != register2 {
-                    self.registers[result] = 1;
+                    self.set_register(result, 1)?;
                 } else {
-                    self.registers[result] = 0;
+                    self.set_register(result, 0)?;
                 }
             }
             Opcode::GT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
                 let result = self.next_8_bits() as usize;
                 if register1 > register2 {
-                    self.registers[result] = 1;
+                    self.set_register(result, 1)?;
                 } else {
-                    self.registers[result] = 0;
+                    self.set_register(result, 0)?;
                 }
             }
             Opcode::LT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
                 let result = self.next_8_bits() as usize;
                 if register1 < register2 {
-                    self.registers[result] = 1;
+                    self.set_register(result, 1)?;
                 } else {
-                    self.registers[result] = 0;
+                    self.set_register(result, 0)?;
                 }
             }
             Opcode::GTQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
                 let result = self.next_8_bits() as usize;
                 if register1 >= register2 {
-                    self.registers[result] = 1;
+                    self.set_register(result, 1)?;
                 } else {
-                    self.registers[result] = 0;
+                    self.set_register(result, 0)?;
                 }
             }
             Opcode::LTQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.next_register()?;
+                let register2 = self.next_register()?;
                 let result = self.next_8_bits() as usize;
                 if register1 <= register2 {
-                    self.registers[result] = 1;
+                    self.set_register(result, 1)?;
                 } else {
-                    self.registers[result] = 0;
+                    self.set_register(result, 0)?;
                 }
             }
             Opcode::JEQ => {
-                let target = self.registers[self.next_8_bits() as usize];
-                let compare_value = self.registers[self.next_8_bits() as usize];
+                let target = self.next_register()?;
+                let compare_value = self.next_register()?;
                 if compare_value == 1 {
                     self.pc = target as usize;
                 } else {
@@ -188,31 +1300,164 @@ impl VM {
             }
             Opcode::LW => { // lw $1, 100($2)
                 let reg_dst = self.next_8_bits() as usize;
-                let addr = self.registers[self.next_8_bits() as usize] as usize;
-                let offset = self.next_8_bits() as usize;
-                self.registers[reg_dst] = self.load_word_from_heap(addr + offset).unwrap() as i32;
+                let addr = self.next_register()? as usize;
+                let addr = addr.wrapping_add(self.next_8_bits() as usize);
+                match self.load_word_from_heap(addr) {
+                    Ok(word) => self.set_register(reg_dst, word as i32)?,
+                    Err(_) => return Err(VMError::OutOfBounds { pc: instruction_pc, addr: addr }),
+                }
+                self.stats.heap_touched = self.stats.heap_touched.max(addr + 4);
             }
             Opcode::SW => { // sw $1, 100($2)
-                let value = self.registers[self.next_8_bits() as usize];
-                let addr = self.registers[self.next_8_bits() as usize] as usize;
-                let offset = self.next_8_bits() as usize;
-                self.store_word_into_heap(value, addr + offset);
+                let value = self.next_register()?;
+                let addr = self.next_register()? as usize;
+                let addr = addr.wrapping_add(self.next_8_bits() as usize);
+                if self.store_word_into_heap(value, addr).is_none() {
+                    return Err(VMError::OutOfBounds { pc: instruction_pc, addr: addr });
+                }
+                self.stats.heap_touched = self.stats.heap_touched.max(addr + 4);
+            }
+            Opcode::QMUL => { // qmul $1 $2 $3, operands are Q16.16 values
+                let register1 = self.next_register()? as i64;
+                let register2 = self.next_register()? as i64;
+                let result = self.next_8_bits() as usize;
+                self.set_register(result, ((register1 * register2) >> FIXED_POINT_SHIFT) as i32)?;
+            }
+            Opcode::QDIV => { // qdiv $1 $2 $3, operands are Q16.16 values
+                let register1 = self.next_register()? as i64;
+                let register2 = self.next_register()? as i64;
+                let result = self.next_8_bits() as usize;
+                if register2 == 0 {
+                    return Err(VMError::DivisionByZero { pc: instruction_pc });
+                }
+                self.set_register(result, ((register1 << FIXED_POINT_SHIFT) / register2) as i32)?;
+            }
+            #[cfg(feature = "float")]
+            Opcode::ITOF => { // itof $1 $2, from integer register $1 to float register $2
+                let value = self.next_register()?;
+                self.float_registers[self.next_register_index()?] = value as f64;
+                self.next_8_bits();
+            }
+            #[cfg(feature = "float")]
+            Opcode::FTOI => { // ftoi $1 $2, from float register $1 to integer register $2 (truncated)
+                let value = self.float_registers[self.next_register_index()?];
+                let result = self.next_8_bits() as usize;
+                self.next_8_bits();
+                if value.is_nan() && self.trap_on_nan {
+                    return Err(VMError::NaN { pc: instruction_pc });
+                }
+                self.set_register(result, value as i32)?;
+            }
+            #[cfg(feature = "float")]
+            Opcode::FEQ | Opcode::FLT | Opcode::FGT => { // feq $1 $2 $3, float registers compared into integer register $3
+                let register1 = self.float_registers[self.next_register_index()?];
+                let register2 = self.float_registers[self.next_register_index()?];
+                let result = self.next_8_bits() as usize;
+                if (register1.is_nan() || register2.is_nan()) && self.trap_on_nan {
+                    return Err(VMError::NaN { pc: instruction_pc });
+                }
+                let is_true = match opcode {
+                    Opcode::FEQ => register1 == register2,
+                    Opcode::FLT => register1 < register2,
+                    _ => register1 > register2,
+                };
+                self.set_register(result, if is_true { 1 } else { 0 })?;
+            }
+            Opcode::ASSERT => { // assert $1 $2
+                let left = self.next_register()?;
+                let right = self.next_register()?;
+                self.next_8_bits();
+                if left != right {
+                    return Err(VMError::AssertionFailed { pc: instruction_pc, left: left, right: right });
+                }
+            }
+            Opcode::SYS => { // sys #id
+                let id = self.next_16_bits();
+                self.next_8_bits();
+                match Syscall::from_id(id) {
+                    Some(syscall) if !self.syscall_policy.allows(syscall) => {
+                        return Err(VMError::PermissionDenied { pc: instruction_pc, id: id });
+                    },
+                    Some(Syscall::Printf) => {
+                        let format = self.heap_string(self.registers[0] as u32 as usize);
+                        let text = syscall::printf(&format, &self.registers[1..], |addr| self.heap_string(addr));
+                        self.output.push_str(&text);
+                    },
+                    Some(Syscall::Log) => {
+                        let message = self.heap_string(self.registers[0] as u32 as usize);
+                        log::log!(target: syscall::LOG_TARGET, syscall::log_level(self.registers[1]),
+                            "[vm {}] pc {:04x}: {}", self.id, instruction_pc, message);
+                    },
+                    Some(syscall) => syscall.call(&mut self.float_registers),
+                    None => return Err(VMError::UnknownSyscall { pc: instruction_pc, id: id }),
+                }
+            }
+            Opcode::BANKSW => { // banksw #bank
+                let bank = self.next_16_bits();
+                self.next_8_bits();
+                if bank as usize >= self.banks.len() {
+                    return Err(VMError::InvalidBank { pc: instruction_pc, bank: bank });
+                }
+                self.banks[self.bank] = self.registers;
+                self.bank = bank as usize;
+                self.registers = self.banks[self.bank];
+            }
+            Opcode::ABORT => { // abort $message
+                let addr = self.next_register()?;
+                self.next_16_bits();
+                self.abort_message = self.heap_string(addr as u32 as usize);
+                return Err(VMError::Aborted { pc: instruction_pc });
+            }
+            Opcode::RDCNT => { // rdcnt #counter $dst
+                let id = self.next_16_bits();
+                let register = self.next_8_bits() as usize;
+                match Counter::from_id(id) {
+                    Some(counter) => self.set_register(register, counter.read(&self.stats) as i32)?,
+                    None => return Err(VMError::UnknownCounter { pc: instruction_pc, counter: id }),
+                }
+            }
+            Opcode::YIELD => {
+                self.next_8_bits();
+                self.next_16_bits();
+                self.yielded = true;
+            }
+            Opcode::PUSHTRAP => { // pushtrap @handler $code
+                let offset = self.next_16_bits() as usize;
+                let register = self.next_register_index()?;
+                if self.trap_handlers.len() == MAX_TRAP_HANDLERS {
+                    return Err(VMError::TooManyTrapHandlers { pc: instruction_pc });
+                }
+                self.trap_handlers.push(TrapHandler { offset: offset, register: register as u8 });
+            }
+            Opcode::POPTRAP => {
+                self.next_8_bits();
+                self.next_16_bits();
+                if self.trap_handlers.pop().is_none() {
+                    return Err(VMError::NoTrapHandler { pc: instruction_pc });
+                }
             }
             Opcode::HLT => {
-                println!("HLT encountered");
-                return false;
+                return Ok(false);
+            }
+            // Rejected by the verifier, like IGL
+            #[cfg(not(feature = "float"))]
+            Opcode::ITOF | Opcode::FTOI | Opcode::FEQ | Opcode::FLT | Opcode::FGT => {
+                return Ok(false);
             }
             Opcode::IGL => {
-                return false;
+                return Ok(false);
             }
         }
-        true
+        Ok(true)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bytecode;
+    use crate::assembler::{Assembler, Relocatable};
+    use crate::syscall::SyscallGroup;
 
     #[test]
     fn test_create_vm() {
@@ -220,6 +1465,289 @@ mod tests {
         assert_eq!(test_vm.registers[0], 0)
     }
 
+    #[test]
+    fn test_run_until_halt() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 0, 5, 2, 0, 0, 1, 0, 0, 0, 0, 1, 2, 0, 1];
+        test_vm.run();
+        assert_eq!(test_vm.registers[1], 10);
+        assert_eq!(test_vm.registers[2], 0);
+        assert_eq!(test_vm.pc, 9);
+    }
+
+    #[test]
+    fn test_dump_state_text() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[1] = -0.5;
+        test_vm.program = vec![1, 1, 6, 53, 1, 2, 0, 32, 17, 1, 2, 8, 32, 1, 2, 0];
+        test_vm.run();
+        assert_eq!(test_vm.dump_state_text(), "\
+pc: 16
+program_len: 16
+remainder: 0
+error: assertion failed at pc 12: 1589 != 32
+registers:
+  $1 = 1589
+  $2 = 32
+float_registers:
+  $f1 = -0.5
+heap:
+  0020: 00 00 00 00 00 00 00 00 00 00 06 35 00 00 00 00
+");
+    }
+
+    #[test]
+    fn test_execution_stats() {
+        let mut test_vm = VM::new();
+        // load $0 #16, load $1 #1, sw $1 $0 8, jeq $0 $1, hlt
+        test_vm.load_program(&[1, 0, 0, 16, 1, 1, 0, 1, 17, 1, 0, 8, 15, 0, 1, 0, 0, 0, 0, 0]).unwrap();
+        test_vm.run();
+        let stats = *test_vm.stats();
+        assert_eq!((stats.instructions, stats.branches, stats.heap_touched), (5, 1, 28));
+        assert!(stats.to_string().starts_with("5 instructions in "));
+        test_vm.load_program(&[0, 0, 0, 0]).unwrap();
+        assert_eq!(test_vm.stats().instructions, 0);
+    }
+
+    #[test]
+    fn test_load_little_endian_bytecode() {
+        let mut test_vm = VM::new();
+        // load $0 #500, load $1 #0, sw $0 $1 0, lw $2 $1 0
+        let file = bytecode::write(&[1, 0, 1, 244, 1, 1, 0, 0, 17, 0, 1, 0, 16, 2, 1, 0], Endianness::Little);
+        assert_eq!(test_vm.load_bytecode(&file), Ok(Header::new(Endianness::Little)));
+        assert_eq!(&test_vm.program()[..4], &[1, 0, 1, 244]);
+        test_vm.run();
+        assert_eq!(test_vm.endianness(), Endianness::Little);
+        assert_eq!(&test_vm.heap()[..4], &[244, 1, 0, 0]);
+        assert_eq!(test_vm.register(2), Ok(500));
+        assert_eq!(test_vm.load_bytecode(&[1, 0, 1, 244]), Err(LoadError::Header(HeaderError::MissingMagic)));
+    }
+
+    #[test]
+    fn test_entry_point() {
+        let mut test_vm = VM::new();
+        // load $0 #1, load $1 #2, hlt, starting from the second instruction
+        let program = [1, 0, 0, 1, 1, 1, 0, 2, 0, 0, 0, 0];
+        test_vm.load_bytecode(&bytecode::write_encoded(&program, Endianness::Big, bytecode::Encoding::Standard, 4)).unwrap();
+        test_vm.run();
+        assert_eq!((test_vm.register(0), test_vm.register(1)), (Ok(0), Ok(2)));
+        test_vm.reset();
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.set_entry(6), Err(LoadError::InvalidEntry(6)));
+        assert_eq!(test_vm.set_entry(12), Err(LoadError::InvalidEntry(12)));
+        test_vm.load_program(&program).unwrap();
+        assert_eq!(test_vm.pc, 0);
+    }
+
+    #[test]
+    fn test_run_for() {
+        let asm = Assembler::new();
+        let mut test_vm = VM::new();
+        test_vm.load_program(&asm.assemble("load $0 #1\nyield\nload $1 #0\ndiv $0 $1 $2").unwrap()).unwrap();
+        assert_eq!(test_vm.run_for(Duration::from_secs(1)).reason, StopReason::Yielded);
+        assert_eq!(test_vm.run_for(Duration::from_secs(1)).reason, StopReason::Trapped(VMError::DivisionByZero { pc: 12 }));
+        test_vm.load_program(&asm.assemble("loop: load $0 @loop\njmp $0").unwrap()).unwrap();
+        let outcome = test_vm.run_for(Duration::from_millis(5));
+        assert_eq!(outcome.reason, StopReason::BudgetExpired);
+        assert!(outcome.elapsed >= Duration::from_millis(5));
+        test_vm.load_program(&[0, 0, 0, 0]).unwrap();
+        assert_eq!(test_vm.run_for(Duration::ZERO).reason, StopReason::Halted);
+    }
+
+    #[test]
+    fn test_opcode_banksw() {
+        let mut test_vm = VMBuilder::new().register_banks(2).build();
+        // load $0 #7, banksw #1, load $0 #9, banksw #0, banksw #2
+        test_vm.load_program(&[1, 0, 0, 7, 33, 0, 1, 0, 1, 0, 0, 9, 33, 0, 0, 0, 33, 0, 2, 0]).unwrap();
+        for _ in 0..3 {
+            test_vm.run_once();
+        }
+        assert_eq!((test_vm.register_bank(), test_vm.register(0)), (1, Ok(9)));
+        test_vm.run();
+        assert_eq!((test_vm.register_bank(), test_vm.register(0)), (0, Ok(7)));
+        assert_eq!(test_vm.last_error(), Some(VMError::InvalidBank { pc: 16, bank: 2 }));
+        assert_eq!(VMBuilder::new().register_banks(0).build().banks.len(), 1);
+    }
+
+    #[test]
+    fn test_sparse_heap() {
+        let mut test_vm = VMBuilder::new().heap_size(1 << 31).sparse_heap(true).build();
+        test_vm.set_register(1, 1 << 30).unwrap();
+        test_vm.set_register(2, 77).unwrap();
+        test_vm.load_program(&[17, 2, 1, 4, 16, 3, 1, 4, 0, 0, 0, 0]).unwrap(); // sw $2 $1 4, lw $3 $1 4, hlt
+        test_vm.run();
+        assert_eq!(test_vm.register(3), Ok(77));
+        assert_eq!(test_vm.heap_word((1 << 30) + 4), Some(77));
+        assert_eq!(test_vm.dump_state().heap, HeapStats { size: 1 << 31, used: 1 });
+        assert!(test_vm.dump_state_text().ends_with("heap:\n  40000000: 00 00 00 00 00 00 00 4d 00 00 00 00 00 00 00 00\n"));
+        assert_eq!(test_vm.heap_rows().map(|(addr, row)| (addr, row[7])).collect::<Vec<_>>(), vec![(1 << 30, 77)]);
+    }
+
+    #[test]
+    fn test_fork() {
+        let mut test_vm = VM::new();
+        // load $0 #16, load $1 #1, jeq $0 $1, load $2 #5, hlt
+        test_vm.load_program(&[1, 0, 0, 16, 1, 1, 0, 1, 15, 0, 1, 0, 1, 2, 0, 5, 0, 0, 0, 0]).unwrap();
+        test_vm.run_once();
+        test_vm.run_once();
+        let mut fork = test_vm.fork();
+        fork.set_register(1, 0).unwrap();
+        fork.run();
+        test_vm.run();
+        assert_eq!((fork.register(2), test_vm.register(2)), (Ok(5), Ok(0)));
+        assert_eq!((fork.stats().instructions, test_vm.stats().instructions), (5, 4));
+        assert_eq!(fork.shared_heap_pages(&test_vm), 4);
+        fork.store_word_into_heap(1, 600);
+        assert_eq!(fork.shared_heap_pages(&test_vm), 3);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut test_vm = VMBuilder::new().register_banks(2).build();
+        // load $0 #7, banksw #1, load $0 #9, sw $0 $0 0, load $1 #3
+        test_vm.load_program(&[1, 0, 0, 7, 33, 0, 1, 0, 1, 0, 0, 9, 17, 0, 0, 0, 1, 1, 0, 3]).unwrap();
+        for _ in 0..4 {
+            test_vm.run_once();
+        }
+        let json = serde_json::to_string(&test_vm.snapshot()).unwrap();
+        test_vm.run();
+        test_vm.restore(&serde_json::from_str(&json).unwrap());
+        assert_eq!((test_vm.pc(), test_vm.register_bank(), test_vm.stats().instructions), (16, 1, 4));
+        assert_eq!((test_vm.register(0), test_vm.register(1), test_vm.heap_word(9)), (Ok(9), Ok(0), Some(9)));
+        test_vm.run_once();
+        assert_eq!(test_vm.register(1), Ok(3));
+    }
+
+    #[test]
+    fn test_rdcnt() {
+        let mut test_vm = VM::new();
+        // load $0 #12, jmp $0, load $5 #1, sys #0, rdcnt #0 $1, rdcnt #1 $2, rdcnt #2 $3, rdcnt #3 $4
+        test_vm.load_program(&[1, 0, 0, 12, 6, 0, 0, 0, 1, 5, 0, 1, 25, 0, 0, 0,
+            36, 0, 0, 1, 36, 0, 1, 2, 36, 0, 2, 3, 36, 0, 3, 4]).unwrap();
+        test_vm.run();
+        assert_eq!((test_vm.register(1), test_vm.register(2), test_vm.register(3)), (Ok(4), Ok(1), Ok(1)));
+        assert_eq!(test_vm.last_error(), Some(VMError::UnknownCounter { pc: 28, counter: 3 }));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut test_vm = VMBuilder::new().register_banks(2).trap_on_nan(true).build();
+        // load $0 #7, banksw #1, load $0 #9, sw $0 $0 0
+        test_vm.load_program(&[1, 0, 0, 7, 33, 0, 1, 0, 1, 0, 0, 9, 17, 0, 0, 0]).unwrap();
+        test_vm.float_registers[3] = 1.5;
+        test_vm.run();
+        test_vm.reset();
+        assert_eq!((test_vm.pc(), test_vm.register_bank(), test_vm.stats().instructions), (0, 0, 0));
+        assert!(test_vm.registers().all(|(_, v)| v == 0));
+        assert!(test_vm.heap().iter().all(|b| *b == 0));
+        assert_eq!(test_vm.float_registers[3], 0.0);
+        assert_eq!(test_vm.program().len(), 16);
+        assert!(test_vm.trap_on_nan);
+        test_vm.run();
+        assert_eq!(test_vm.register(0), Ok(9));
+        test_vm.hard_reset();
+        assert_eq!(test_vm.program(), &[] as &[u8]);
+        assert_eq!(test_vm.register(0), Ok(0));
+    }
+
+    #[test]
+    fn test_dump_state() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 1, 6, 53, 1, 2, 0, 32, 17, 1, 2, 8, 32, 1, 2, 0];
+        test_vm.run();
+        let state = test_vm.dump_state();
+        assert_eq!(state.pc, 16);
+        assert_eq!(state.registers[1], 1589);
+        assert_eq!(state.heap, HeapStats { size: 1000, used: 2 });
+        assert_eq!(state.last_error, Some(VMError::AssertionFailed { pc: 12, left: 1589, right: 32 }));
+        let json = serde_json::to_string(&state).unwrap();
+        assert!(json.contains("\"last_error\":{\"AssertionFailed\":{\"pc\":12,\"left\":1589,\"right\":32}}"));
+        assert_eq!(serde_json::from_str::<VmState>(&json).unwrap(), state);
+    }
+
+    #[test]
+    fn test_load_program() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.load_program(&[1, 0, 1, 244]), Ok(()));
+        assert_eq!(test_vm.pc, 0);
+        assert_eq!(test_vm.program(), &[1, 0, 1, 244]);
+        let error = test_vm.load_program(&[1, 0, 1, 244, 200, 0, 0, 0]);
+        assert_eq!(error, Err(LoadError::Invalid(VerifyError::IllegalOpcode { offset: 4, byte: 200 })));
+        assert_eq!(test_vm.program(), &[1, 0, 1, 244]);
+    }
+
+    #[test]
+    fn test_link_program() {
+        let asm = Assembler::new();
+        let mut test_vm = VM::new();
+        test_vm.load_program(&asm.assemble("load $0 #1\nhlt").unwrap()).unwrap();
+        let src = ".data\nvalue: .word 42\n.code\nload $3 @value\nlw $4 $3 #0\nload $5 @end\njmp $5\nload $4 #0\nend: hlt";
+        let Relocatable { program, entry, relocations, .. } = asm.assemble_relocatable(src).unwrap();
+        let base = test_vm.link_program(&program, &relocations, 64).unwrap();
+        assert_eq!((base, test_vm.base()), (8, 8));
+        test_vm.set_entry(base + entry).unwrap();
+        test_vm.run();
+        assert_eq!((test_vm.register(3), test_vm.register(4), test_vm.last_error()), (Ok(64), Ok(42), None));
+        test_vm.add_program_byte(0);
+        assert_eq!(test_vm.link_program(&program, &relocations, 0), Err(LoadError::Unaligned(program.len() + 9)));
+    }
+
+    #[test]
+    fn test_patch_and_truncate_program() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 1, 244];
+        assert_eq!(test_vm.patch_program(2, &[0, 5, 0, 0, 0, 0]), Ok(()));
+        assert_eq!(test_vm.program(), &[1, 0, 0, 5, 0, 0, 0, 0]);
+        assert_eq!(test_vm.patch_program(4, &[2, 40, 0, 0]), Err(LoadError::Invalid(VerifyError::InvalidRegister { offset: 4, register: 40 })));
+        assert_eq!(test_vm.patch_program(6, &[0, 0, 0]), Err(LoadError::Invalid(VerifyError::TruncatedInstruction { offset: 8 })));
+        assert_eq!(test_vm.truncate_program(6), Err(LoadError::Invalid(VerifyError::TruncatedInstruction { offset: 4 })));
+        assert_eq!(test_vm.program(), &[1, 0, 0, 5, 0, 0, 0, 0]);
+        assert_eq!(test_vm.truncate_program(4), Ok(()));
+        assert_eq!(test_vm.program(), &[1, 0, 0, 5]);
+        assert_eq!(test_vm.patch_program(5, &[0]), Err(LoadError::OffsetOutOfBounds { offset: 5, len: 4 }));
+        assert_eq!(test_vm.truncate_program(5), Err(LoadError::OffsetOutOfBounds { offset: 5, len: 4 }));
+    }
+
+    #[test]
+    fn test_register_accessors() {
+        let mut test_vm = VM::new();
+        assert_eq!(test_vm.set_register(31, -4), Ok(()));
+        assert_eq!(test_vm.register(31), Ok(-4));
+        assert_eq!(test_vm.register(32), Err(VMError::InvalidRegister { index: 32 }));
+        assert_eq!(test_vm.set_register(32, 1), Err(VMError::InvalidRegister { index: 32 }));
+        assert_eq!(test_vm.registers().count(), 32);
+        assert_eq!(test_vm.registers().last(), Some((31, -4)));
+    }
+
+    #[test]
+    fn test_unverified_register_operands() {
+        let mut test_vm = VM::new();
+        test_vm.start_taint_tracking();
+        // load $40 #0, then add $0 $1 $40 and add $40 $1 $2, none of them verified
+        for program in [vec![1, 40, 0, 0], vec![2, 0, 1, 40], vec![2, 40, 1, 2]] {
+            test_vm.program = program;
+            test_vm.pc = 0;
+            assert!(!test_vm.run_once());
+            assert_eq!(test_vm.last_error(), Some(VMError::InvalidRegister { index: 40 }));
+        }
+    }
+
+    #[test]
+    fn test_steps() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 0, 5, 32, 0, 1, 0, 1, 2, 0, 1];
+        let steps: Vec<Result<StepInfo, VMError>> = test_vm.steps().collect();
+        assert_eq!(steps, vec![
+            Ok(StepInfo { pc: 0, opcode: Opcode::LOAD, operands: [Operand::Register(0), Operand::Integer(5), Operand::None] }),
+            Err(VMError::AssertionFailed { pc: 4, left: 5, right: 0 }),
+        ]);
+        test_vm.pc = 8;
+        assert_eq!(test_vm.steps().count(), 1);
+        assert_eq!(test_vm.registers[2], 1);
+    }
+
     #[test]
     fn test_opcode_hlt() {
         let mut test_vm = VM::new();
@@ -294,4 +1822,279 @@ mod tests {
         test_vm.run_once();
         assert_eq!(test_vm.registers[3], 1589);
     }
+
+    #[test]
+    fn test_qmul_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 3 << FIXED_POINT_SHIFT; // 3.0
+        test_vm.registers[1] = 1 << (FIXED_POINT_SHIFT - 1); // 0.5
+        test_vm.registers[2] = -2 << FIXED_POINT_SHIFT; // -2.0
+        test_vm.program = vec![18, 0, 1, 3, 18, 1, 2, 3];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[3], 3 << (FIXED_POINT_SHIFT - 1)); // 1.5
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[3], -1 << FIXED_POINT_SHIFT); // -1.0
+    }
+
+    #[test]
+    fn test_qdiv_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 3 << FIXED_POINT_SHIFT; // 3.0
+        test_vm.registers[1] = 2 << FIXED_POINT_SHIFT; // 2.0
+        test_vm.program = vec![19, 0, 1, 3, 19, 0, 2, 3];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[3], 3 << (FIXED_POINT_SHIFT - 1)); // 1.5
+        assert_eq!(test_vm.last_error(), None);
+        test_vm.run_once();
+        assert_eq!(test_vm.last_error(), Some(VMError::DivisionByZero { pc: 4 }));
+    }
+
+    #[test]
+    #[cfg(feature = "float")]
+    fn test_itof_ftoi_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -7;
+        test_vm.float_registers[2] = 2.9;
+        test_vm.program = vec![20, 0, 1, 0, 21, 2, 3, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.float_registers[1], -7.0);
+        assert_eq!(test_vm.pc, 4);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[3], 2);
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    #[cfg(feature = "float")]
+    fn test_float_comparison_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 1.5;
+        test_vm.float_registers[1] = 2.5;
+        test_vm.program = vec![22, 0, 1, 2, 23, 0, 1, 3, 24, 0, 1, 4];
+        test_vm.run_once();
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], 0);
+        assert_eq!(test_vm.registers[3], 1);
+        assert_eq!(test_vm.registers[4], 0);
+    }
+
+    #[test]
+    #[cfg(feature = "float")]
+    fn test_float_comparison_nan_policy() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = f64::NAN;
+        test_vm.registers[2] = 1;
+        test_vm.program = vec![22, 0, 0, 2, 22, 0, 0, 2];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], 0);
+        assert_eq!(test_vm.last_error(), None);
+        test_vm.set_trap_on_nan(true);
+        test_vm.run_once();
+        assert_eq!(test_vm.last_error(), Some(VMError::NaN { pc: 4 }));
+    }
+
+    #[test]
+    fn test_sys_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 16.0;
+        test_vm.program = vec![25, 0, 0, 0, 25, 1, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.float_registers[0], 4.0);
+        assert_eq!(test_vm.pc, 4);
+        test_vm.run_once();
+        assert_eq!(test_vm.last_error(), Some(VMError::UnknownSyscall { pc: 4, id: 256 }));
+    }
+
+    #[test]
+    fn test_syscall_policy() {
+        let mut test_vm = VMBuilder::new().syscall_policy(SyscallPolicy::default().deny(SyscallGroup::Console)).build();
+        test_vm.float_registers[0] = 16.0;
+        test_vm.program = vec![25, 0, 0, 0, 25, 0, 5, 0];
+        test_vm.run();
+        assert_eq!(test_vm.float_registers[0], 4.0);
+        assert_eq!(test_vm.last_error(), Some(VMError::PermissionDenied { pc: 4, id: 5 }));
+        assert_eq!(test_vm.take_output(), "");
+    }
+
+    /// Keeps the LOG records of the current thread, every test running on its own
+    struct CaptureLogger;
+
+    thread_local! {
+        static RECORDS: std::cell::RefCell<Vec<(log::Level, String)>> = const { std::cell::RefCell::new(vec![]) };
+    }
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            if record.target() == syscall::LOG_TARGET {
+                RECORDS.with(|records| records.borrow_mut().push((record.level(), record.args().to_string())));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_syscall_log() {
+        static LOGGER: CaptureLogger = CaptureLogger;
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+        let src = ".data\nmsg: .asciiz \"disk full\"\n.code\nload $0 @msg\nload $1 #1\nsys #6\nhlt";
+        let mut test_vm = VM::new();
+        test_vm.load_program(&Assembler::new().assemble(src).unwrap()).unwrap();
+        test_vm.set_id(3);
+        test_vm.run();
+        let pc = test_vm.program().len() - 2 * INSTRUCTION_SIZE;
+        let records = RECORDS.with(|records| records.borrow().clone());
+        assert_eq!(records, vec![(log::Level::Warn, format!("[vm 3] pc {:04x}: disk full", pc))]);
+    }
+
+    #[test]
+    fn test_checked_arithmetic_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 1;
+        test_vm.registers[2] = -1;
+        test_vm.program = vec![26, 0, 2, 3, 26, 0, 1, 3, 27, 2, 0, 4, 28, 0, 0, 4];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[3], i32::MAX - 1);
+        test_vm.run_once();
+        assert_eq!(test_vm.last_error(), Some(VMError::Overflow { pc: 4 }));
+        assert_eq!(test_vm.registers[3], i32::MAX - 1);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[4], i32::MIN);
+        test_vm.run_once();
+        assert_eq!(test_vm.last_error(), Some(VMError::Overflow { pc: 12 }));
+    }
+
+    #[test]
+    fn test_saturating_arithmetic_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX - 1;
+        test_vm.registers[1] = 5;
+        test_vm.registers[2] = i32::MIN + 1;
+        test_vm.program = vec![29, 0, 1, 3, 30, 2, 1, 4, 29, 1, 1, 5];
+        test_vm.run_once();
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[3], i32::MAX);
+        assert_eq!(test_vm.registers[4], i32::MIN);
+        assert_eq!(test_vm.registers[5], 10);
+    }
+
+    #[test]
+    fn test_mac_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 3;
+        test_vm.registers[2] = -4;
+        test_vm.program = vec![31, 0, 1, 2, 31, 0, 1, 1];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], -2);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 7);
+    }
+
+    #[test]
+    fn test_assert_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 42;
+        test_vm.registers[1] = 42;
+        test_vm.registers[2] = 7;
+        test_vm.program = vec![32, 0, 1, 0, 32, 0, 2, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.last_error(), None);
+        assert_eq!(test_vm.pc, 4);
+        test_vm.run_once();
+        let error = test_vm.last_error().unwrap();
+        assert_eq!(error, VMError::AssertionFailed { pc: 4, left: 42, right: 7 });
+        assert_eq!(error.to_string(), "assertion failed at pc 4: 42 != 7");
+    }
+
+    #[test]
+    fn test_trap_handlers() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 10;
+        test_vm.registers[2] = 2000;
+        // pushtrap @12 $5, div $1 $0 $3, hlt, sw $1 $2 #0, poptrap
+        test_vm.program = vec![37, 0, 12, 5, 5, 1, 0, 3, 0, 0, 0, 0, 17, 1, 2, 0, 38, 0, 0, 0];
+        test_vm.run();
+        assert_eq!(test_vm.last_error(), Some(VMError::OutOfBounds { pc: 12, addr: 2000 }));
+        assert_eq!(test_vm.registers[5], 1);
+        assert!(test_vm.trap_handlers.is_empty());
+        test_vm.pc = 16;
+        test_vm.run_once();
+        assert_eq!(test_vm.last_error(), Some(VMError::NoTrapHandler { pc: 16 }));
+        test_vm.program = vec![37, 0, 0, 5];
+        test_vm.pc = 0;
+        test_vm.run_once();
+        assert_eq!(test_vm.trap_handlers, vec![TrapHandler { offset: 0, register: 5 }]);
+        test_vm.reset();
+        assert!(test_vm.trap_handlers.is_empty());
+        // pushtrap @12 $40, div $1 $0 $3
+        test_vm.program = vec![37, 0, 12, 40, 5, 1, 0, 3];
+        test_vm.run();
+        assert_eq!(test_vm.last_error(), Some(VMError::InvalidRegister { index: 40 }));
+        test_vm.reset();
+        test_vm.trap_handlers.push(TrapHandler { offset: 0, register: 40 });
+        test_vm.pc = 4;
+        test_vm.run();
+        assert_eq!(test_vm.last_error(), Some(VMError::DivisionByZero { pc: 4 }));
+    }
+
+    #[test]
+    fn test_trusted_mode_matches_safe_mode() {
+        // Counts $0 to 100, then jumps into the middle of the JMP, whose register byte decodes
+        // as a DIV reading into the HLT: the checked fetch must take over
+        let program = Assembler::new().assemble("load $0 #0\nload $1 #1\nload $2 #100\nload $3 #16\nadd $0 $1 $0\nlt $0 $2 $4\njeq $3 $4\nload $5 #33\njmp $5\nhlt").unwrap();
+        let mut safe = VM::new();
+        let mut trusted = VMBuilder::new().trusted(true).build();
+        for vm in [&mut safe, &mut trusted] {
+            vm.load_program(&program).unwrap();
+            vm.run();
+        }
+        assert_eq!((trusted.pc, trusted.registers, trusted.remainder), (safe.pc, safe.registers, safe.remainder));
+        assert_eq!((trusted.register(0), trusted.register(5)), (Ok(1), Ok(33)));
+        assert_eq!(trusted.stats().instructions, 308);
+        trusted.add_program_byte(0);
+        assert!(!trusted.verified);
+        trusted.patch_program(40, &[0, 0, 0, 0]).unwrap();
+        assert!(trusted.verified);
+        trusted.patch_program(0, &[1, 0, 0, 7]).unwrap();
+        trusted.truncate_program(4).unwrap();
+        trusted.reset();
+        trusted.run();
+        assert_eq!(trusted.register(0), Ok(7));
+        trusted.load_program(&Assembler::new().assemble("load $0 #8\njmpb $0").unwrap()).unwrap();
+        trusted.run();
+        assert_eq!(trusted.last_error(), Some(VMError::InvalidJump { pc: 4 }));
+    }
+
+    #[test]
+    fn test_exit_hooks() {
+        let events = Rc::new(std::cell::RefCell::new(vec![]));
+        let mut vm = VM::new();
+        let log = events.clone();
+        vm.on_halt(move |state, usage| log.borrow_mut().push(format!("halt at {} after {}", state.pc, usage.instructions)));
+        let log = events.clone();
+        vm.on_trap(move |state, _| log.borrow_mut().push(format!("trap: {}", state.last_error.unwrap())));
+        let log = events.clone();
+        vm.on_quota_exceeded(move |_, usage| log.borrow_mut().push(format!("quota after {}", usage.instructions)));
+        for src in ["load $0 #1\nhlt", "load $0 #1", "load $0 #6\nload $1 #0\nqdiv $0 $1 $2\nhlt"] {
+            vm.load_program(&Assembler::new().assemble(src).unwrap()).unwrap();
+            vm.run();
+        }
+        vm.fork().quota_exceeded();
+        assert_eq!(*events.borrow(), vec![
+            "halt at 5 after 2",
+            "halt at 4 after 1",
+            "trap: division by zero at pc 8",
+            "quota after 3",
+        ]);
+    }
 }